@@ -3,7 +3,10 @@
 //! This crate provides semantic analysis for LC-3 assembly, designed for
 //! integration with Monaco editor and similar code editors.
 
-use lc3_parser::{Directive, Instruction, Line, Program, Span, Spanned, parse};
+use lc3_parser::{
+    AddSrc2, AndSrc2, Code, Directive, EvalError, Expr, Instruction, Line, Operand, Program, Span,
+    Spanned, XorSrc2, parse, scope_local_labels,
+};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
@@ -22,6 +25,8 @@ pub enum Severity {
 pub struct Diagnostic {
     pub message: String,
     pub severity: Severity,
+    /// The assembler's code for the diagnostic, e.g. `E0001`.
+    pub code: String,
     pub start_line: u32,
     pub start_col: u32,
     pub end_line: u32,
@@ -44,6 +49,8 @@ pub struct SymbolInfo {
     pub kind: SymbolKind,
     pub location: Location,
     pub address: Option<u16>,
+    /// Value of a constant, if it could be evaluated.
+    pub value: Option<i32>,
     pub documentation: Option<String>,
 }
 
@@ -54,6 +61,10 @@ pub enum SymbolKind {
     Label,
     Subroutine,
     Data,
+    /// A `.EQU` or `.SET` constant.
+    Constant,
+    /// A symbol declared `.EXTERNAL`, defined in another module.
+    External,
 }
 
 /// A completion item.
@@ -113,6 +124,10 @@ struct Symbol {
     span: Span,
     address: u16,
     kind: SymbolKind,
+    /// Value of a constant; constants have no address.
+    value: Option<i32>,
+    /// The comments above the definition, or on its line.
+    documentation: Option<String>,
     /// Line number (1-based) for quick lookup
     #[allow(dead_code)]
     line: u32,
@@ -134,6 +149,10 @@ pub struct AnalyzedDocument {
     parse_errors: Vec<Diagnostic>,
     symbols: HashMap<String, Symbol>,
     label_refs: Vec<LabelRef>,
+    /// Duplicate and confusing label definitions.
+    label_diagnostics: Vec<Diagnostic>,
+    /// Whether the document has `.INCLUDE`s, whose labels are unknown here.
+    has_includes: bool,
     line_starts: Vec<usize>,
 }
 
@@ -143,7 +162,10 @@ impl AnalyzedDocument {
         let line_starts = compute_line_starts(source);
 
         let (program, parse_errors) = match parse(source) {
-            Ok(prog) => (Some(prog), Vec::new()),
+            Ok(mut prog) => {
+                scope_local_labels(&mut prog);
+                (Some(prog), Vec::new())
+            }
             Err(errors) => {
                 let diagnostics = errors
                     .into_iter()
@@ -154,6 +176,7 @@ impl AnalyzedDocument {
                         Diagnostic {
                             message: e.message,
                             severity: Severity::Error,
+                            code: e.code.to_string(),
                             start_line,
                             start_col,
                             end_line,
@@ -171,12 +194,15 @@ impl AnalyzedDocument {
             parse_errors,
             symbols: HashMap::new(),
             label_refs: Vec::new(),
+            label_diagnostics: Vec::new(),
+            has_includes: false,
             line_starts,
         };
 
         if doc.program.is_some() {
             let prog = doc.program.as_ref().unwrap();
             doc.analyze_symbols_from(prog.lines.clone());
+            doc.attach_documentation();
         }
 
         doc
@@ -185,22 +211,44 @@ impl AnalyzedDocument {
     /// Analyze symbols and references from the parsed program.
     fn analyze_symbols_from(&mut self, lines: Vec<lc3_parser::SpannedLine>) {
         let mut pc = 0x3000u16;
+        // `.EQU` constants that refer to later symbols
+        let mut pending = Vec::new();
+        // Where each name was first defined, and whether by `.SET`
+        let mut defined: HashMap<String, (Span, bool)> = HashMap::new();
 
         for spanned_line in &lines {
             let line_num = self.offset_to_line(spanned_line.span.start);
+            if let Some((label, is_set)) = defined_label(&spanned_line.line) {
+                self.check_definition(label, is_set, &mut defined);
+            }
 
             match &spanned_line.line {
                 Line::Label(label) => {
                     self.add_symbol(label, pc, SymbolKind::Label, line_num);
                 }
+                Line::LabeledDirective(
+                    label,
+                    dir @ (Directive::Equ(expr) | Directive::Set(expr)),
+                ) => {
+                    let value = expr.eval(&mut |name, span| self.lookup(name, span)).ok();
+                    self.add_symbol(label, 0, SymbolKind::Constant, line_num);
+                    self.set_value(&label.value, value);
+                    self.collect_expr_refs(expr, line_num);
+                    if value.is_none() && matches!(dir, Directive::Equ(_)) {
+                        pending.push((label.value.clone(), expr));
+                    }
+                }
                 Line::LabeledDirective(label, dir) => {
                     let kind = match dir {
-                        Directive::Stringz(_) | Directive::Fill(_) | Directive::Blkw(_) => {
-                            SymbolKind::Data
-                        }
+                        Directive::Stringz(_)
+                        | Directive::Stringp(_)
+                        | Directive::Fill(_)
+                        | Directive::Blkw(..) => SymbolKind::Data,
                         _ => SymbolKind::Label,
                     };
                     self.add_symbol(label, pc, kind, line_num);
+                    self.declare_externals(dir, line_num);
+                    self.collect_directive_refs(dir, line_num);
                     pc = self.advance_pc(dir, pc);
                 }
                 Line::LabeledInstruction(label, instr) => {
@@ -215,59 +263,258 @@ impl AnalyzedDocument {
                     pc += 1;
                 }
                 Line::Directive(dir) => {
+                    self.has_includes |= matches!(dir, Directive::Include(_));
+                    self.declare_externals(dir, line_num);
+                    self.collect_directive_refs(dir, line_num);
                     pc = self.advance_pc(dir, pc);
                 }
                 Line::Instruction(instr) => {
                     self.collect_label_refs(instr, line_num);
                     pc += 1;
                 }
-                Line::Empty | Line::Error => {}
+                Line::LabeledMacroCall(label, _) => {
+                    self.add_symbol(label, pc, SymbolKind::Label, line_num);
+                }
+                // Bodies are raw text; their labels are local to each expansion
+                Line::Macro(_) | Line::MacroCall(_) | Line::Empty | Line::Error => {}
+            }
+        }
+
+        // Resolve forward references until nothing changes
+        while !pending.is_empty() {
+            let before = pending.len();
+            pending.retain(|(name, expr)| {
+                let value = expr.eval(&mut |name, span| self.lookup(name, span)).ok();
+                self.set_value(name, value);
+                value.is_none()
+            });
+            if pending.len() == before {
+                break;
+            }
+        }
+    }
+
+    /// Document each label with the comments above it, or on its line.
+    fn attach_documentation(&mut self) {
+        let Some(program) = &self.program else {
+            return;
+        };
+        for (i, spanned_line) in program.lines.iter().enumerate() {
+            if let Some((label, _)) = defined_label(&spanned_line.line)
+                && let Some(symbol) = self.symbols.get_mut(&label.value)
+                && symbol.span == label.span
+            {
+                symbol.documentation = program.doc_comment(i);
             }
         }
     }
 
+    /// Report a name defined twice (other than by `.SET` twice), and names
+    /// that read as registers or numbers.
+    fn check_definition(
+        &mut self,
+        label: &Spanned<String>,
+        is_set: bool,
+        defined: &mut HashMap<String, (Span, bool)>,
+    ) {
+        let spelling = &self.source[label.span.clone()];
+        let mut report = |message: String, code: Code| {
+            let (start_line, start_col) = offset_to_position(&self.line_starts, label.span.start);
+            let (end_line, end_col) = offset_to_position(&self.line_starts, label.span.end);
+            self.label_diagnostics.push(Diagnostic {
+                message,
+                severity: match code.severity() {
+                    lc3_parser::Severity::Error => Severity::Error,
+                    lc3_parser::Severity::Warning => Severity::Warning,
+                },
+                code: code.to_string(),
+                start_line,
+                start_col,
+                end_line,
+                end_col,
+            });
+        };
+        if let Some(message) = lc3_parser::confusing_label(spelling) {
+            report(message, Code::ConfusingLabel);
+        }
+        match defined.get(&label.value) {
+            Some((_, true)) if is_set => {}
+            Some((first, _)) => {
+                let (line, _) = offset_to_position(&self.line_starts, first.start);
+                let before = &self.source[first.clone()];
+                if before != spelling {
+                    report(
+                        format!(
+                            "label {spelling} differs only in case from {before} at line {line}; \
+                             labels are not case-sensitive, so that definition is used"
+                        ),
+                        Code::LabelCase,
+                    );
+                } else {
+                    report(
+                        format!("{spelling} is already defined at line {line}"),
+                        Code::DuplicateDefinition,
+                    );
+                }
+            }
+            None => {
+                defined.insert(label.value.clone(), (label.span.clone(), is_set));
+            }
+        }
+    }
+
+    fn set_value(&mut self, name: &str, value: Option<i32>) {
+        if let Some(symbol) = self.symbols.get_mut(name) {
+            symbol.value = value;
+        }
+    }
+
+    /// The value of a symbol in an expression: a constant's value or a label's address.
+    fn lookup(&self, name: &str, span: &Span) -> Result<i32, EvalError> {
+        match self.symbols.get(name) {
+            Some(Symbol {
+                kind: SymbolKind::Constant,
+                value,
+                ..
+            }) => value
+                .ok_or_else(|| EvalError::new(span.clone(), Code::UndefinedSymbol, "unresolved")),
+            Some(Symbol {
+                kind: SymbolKind::External,
+                ..
+            }) => Err(EvalError::new(span.clone(), Code::Linking, "external")),
+            Some(symbol) => Ok(symbol.address as i32),
+            None => Err(EvalError::new(
+                span.clone(),
+                Code::UndefinedSymbol,
+                "undefined",
+            )),
+        }
+    }
+
+    /// Add a symbol, unless it is already defined; as in the assembler, the
+    /// first definition stands.
     fn add_symbol(&mut self, label: &Spanned<String>, address: u16, kind: SymbolKind, line: u32) {
-        self.symbols.insert(
-            label.value.clone(),
-            Symbol {
-                name: label.value.clone(),
-                span: label.span.clone(),
-                address,
-                kind,
-                line,
-            },
-        );
+        self.symbols.entry(label.value.clone()).or_insert(Symbol {
+            name: label.value.clone(),
+            span: label.span.clone(),
+            address,
+            kind,
+            value: None,
+            documentation: None,
+            line,
+        });
+    }
+
+    fn declare_externals(&mut self, dir: &Directive, line: u32) {
+        if let Directive::External(names) = dir {
+            for name in names {
+                self.add_symbol(name, 0, SymbolKind::External, line);
+            }
+        }
     }
 
     fn advance_pc(&self, dir: &Directive, pc: u16) -> u16 {
         match dir {
-            Directive::Orig(addr) => *addr,
-            Directive::Fill(_) => pc + 1,
-            Directive::Blkw(n) => pc + n,
-            Directive::Stringz(s) => pc + s.len() as u16 + 1,
-            Directive::End => pc,
+            Directive::Orig(addr) => addr
+                .eval(&mut |name, span| self.lookup(name, span))
+                .ok()
+                .and_then(|addr| u16::try_from(addr).ok())
+                .unwrap_or(pc),
+            Directive::Fill(values) => pc.wrapping_add(values.len() as u16),
+            Directive::Blkw(n, _) => {
+                let count = n.eval(&mut |name, span| self.lookup(name, span));
+                pc.wrapping_add(count.ok().and_then(|n| u16::try_from(n).ok()).unwrap_or(0))
+            }
+            Directive::Stringz(s) => pc.wrapping_add(s.chars().count() as u16 + 1),
+            // Two characters to a word, and a zero byte
+            Directive::Stringp(s) => pc.wrapping_add(s.chars().count() as u16 / 2 + 1),
+            Directive::End
+            | Directive::Equ(_)
+            | Directive::Set(_)
+            | Directive::Include(_)
+            | Directive::External(_)
+            | Directive::Global(_)
+            | Directive::If(_)
+            | Directive::Ifdef(_)
+            | Directive::Ifndef(_)
+            | Directive::Elseif(_)
+            | Directive::Else
+            | Directive::Endif => pc,
         }
     }
 
     fn collect_label_refs(&mut self, instr: &Instruction, line: u32) {
-        let label = match instr {
-            Instruction::Br { label, .. } => Some(label),
-            Instruction::Jsr { label } => Some(label),
-            Instruction::Ld { label, .. } => Some(label),
-            Instruction::Ldi { label, .. } => Some(label),
-            Instruction::Lea { label, .. } => Some(label),
-            Instruction::St { label, .. } => Some(label),
-            Instruction::Sti { label, .. } => Some(label),
-            _ => None,
+        use Instruction::*;
+        let expr = match instr {
+            Br { label, .. }
+            | Jsr { label }
+            | Ld { label, .. }
+            | Ldi { label, .. }
+            | Lea { label, .. }
+            | St { label, .. }
+            | Sti { label, .. } => label,
+            Add {
+                src2: AddSrc2::Immediate(value),
+                ..
+            }
+            | And {
+                src2: AndSrc2::Immediate(value),
+                ..
+            }
+            | Xor {
+                src2: XorSrc2::Immediate(value),
+                ..
+            }
+            | Ldr { offset: value, .. }
+            | Str { offset: value, .. }
+            | Ldb { offset: value, .. }
+            | Stb { offset: value, .. }
+            | Ldw { offset: value, .. }
+            | Stw { offset: value, .. }
+            | Trap { trapvect: value }
+            | Shf { amount: value, .. } => value,
+            _ => return,
         };
+        self.collect_expr_refs(expr, line);
+    }
 
-        if let Some(lbl) = label {
+    fn collect_directive_refs(&mut self, dir: &Directive, line: u32) {
+        match dir {
+            Directive::Fill(values) => {
+                for value in values {
+                    if let Operand::Expr(expr) = value {
+                        self.collect_expr_refs(expr, line);
+                    }
+                }
+            }
+            Directive::Blkw(count, value) => {
+                self.collect_expr_refs(count, line);
+                if let Some(value) = value {
+                    self.collect_expr_refs(value, line);
+                }
+            }
+            Directive::Orig(expr) => self.collect_expr_refs(expr, line),
+            Directive::Global(names) => {
+                for name in names {
+                    self.label_refs.push(LabelRef {
+                        name: name.value.clone(),
+                        span: name.span.clone(),
+                        line,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn collect_expr_refs(&mut self, expr: &Spanned<Expr>, line: u32) {
+        expr.symbols(&mut |name, span| {
             self.label_refs.push(LabelRef {
-                name: lbl.value.clone(),
-                span: lbl.span.clone(),
+                name: name.to_string(),
+                span: span.clone(),
                 line,
             });
-        }
+        });
     }
 
     fn is_subroutine_target(&self, _label: &Spanned<String>) -> bool {
@@ -283,22 +530,51 @@ impl AnalyzedDocument {
     /// Get all diagnostics (parse errors + semantic errors).
     pub fn diagnostics(&self) -> Vec<Diagnostic> {
         let mut diagnostics = self.parse_errors.clone();
-
-        // Check for undefined labels
-        for label_ref in &self.label_refs {
-            if !self.symbols.contains_key(&label_ref.name) {
-                let (start_line, start_col) =
-                    offset_to_position(&self.line_starts, label_ref.span.start);
-                let (end_line, end_col) = offset_to_position(&self.line_starts, label_ref.span.end);
-                diagnostics.push(Diagnostic {
-                    message: format!("undefined label: {}", label_ref.name),
-                    severity: Severity::Error,
-                    start_line,
-                    start_col,
-                    end_line,
-                    end_col,
-                });
-            }
+        diagnostics.extend(self.label_diagnostics.iter().cloned());
+
+        // Check for undefined labels. Included files are not analyzed, so
+        // any label might be defined in one of them.
+        let label_refs = if self.has_includes {
+            &[][..]
+        } else {
+            &self.label_refs[..]
+        };
+        for label_ref in label_refs {
+            let (message, severity, code) = match self.symbols.get(&label_ref.name) {
+                None => (
+                    format!("undefined label: {}", label_ref.name),
+                    Severity::Error,
+                    Code::UndefinedSymbol,
+                ),
+                Some(symbol) => {
+                    let written = self.source.get(label_ref.span.clone()).unwrap_or_default();
+                    let spelling = self.source.get(symbol.span.clone()).unwrap_or_default();
+                    if written == spelling || !written.eq_ignore_ascii_case(spelling) {
+                        continue;
+                    }
+                    let line = self.offset_to_line(symbol.span.start);
+                    (
+                        format!(
+                            "{written} refers to {spelling} at line {line}, \
+                             which is written in a different case"
+                        ),
+                        Severity::Warning,
+                        Code::LabelCase,
+                    )
+                }
+            };
+            let (start_line, start_col) =
+                offset_to_position(&self.line_starts, label_ref.span.start);
+            let (end_line, end_col) = offset_to_position(&self.line_starts, label_ref.span.end);
+            diagnostics.push(Diagnostic {
+                message,
+                severity,
+                code: code.to_string(),
+                start_line,
+                start_col,
+                end_line,
+                end_col,
+            });
         }
 
         diagnostics
@@ -368,11 +644,28 @@ impl AnalyzedDocument {
                     SymbolKind::Label => "label",
                     SymbolKind::Subroutine => "subroutine",
                     SymbolKind::Data => "data",
+                    SymbolKind::Constant => "constant",
+                    SymbolKind::External => "external",
+                };
+                let contents = match (symbol.kind, symbol.value) {
+                    (SymbolKind::Constant, Some(value)) => format!(
+                        "**{}** ({})\n\nValue: `{}` (`x{:04X}`)",
+                        symbol.name, kind_str, value, value as u16
+                    ),
+                    (SymbolKind::Constant, None) => format!("**{}** ({})", symbol.name, kind_str),
+                    (SymbolKind::External, _) => format!(
+                        "**{}** ({})\n\nDefined in another module",
+                        symbol.name, kind_str
+                    ),
+                    _ => format!(
+                        "**{}** ({})\n\nAddress: `x{:04X}`",
+                        symbol.name, kind_str, symbol.address
+                    ),
+                };
+                let contents = match &symbol.documentation {
+                    Some(doc) => format!("{contents}\n\n{doc}"),
+                    None => contents,
                 };
-                let contents = format!(
-                    "**{}** ({})\n\nAddress: `x{:04X}`",
-                    symbol.name, kind_str, symbol.address
-                );
                 return Some(HoverInfo {
                     contents,
                     range: None,
@@ -395,16 +688,21 @@ impl AnalyzedDocument {
 
         // Add all defined labels
         for symbol in self.symbols.values() {
-            let kind_str = match symbol.kind {
-                SymbolKind::Label => "label",
-                SymbolKind::Subroutine => "subroutine",
-                SymbolKind::Data => "data",
+            let detail = match symbol.kind {
+                SymbolKind::Label => format!("label at x{:04X}", symbol.address),
+                SymbolKind::Subroutine => format!("subroutine at x{:04X}", symbol.address),
+                SymbolKind::Data => format!("data at x{:04X}", symbol.address),
+                SymbolKind::Constant => match symbol.value {
+                    Some(value) => format!("constant = {value}"),
+                    None => "constant".to_string(),
+                },
+                SymbolKind::External => "external".to_string(),
             };
             items.push(CompletionItem {
                 label: symbol.name.clone(),
                 kind: CompletionKind::Label,
-                detail: Some(format!("{} at x{:04X}", kind_str, symbol.address)),
-                documentation: None,
+                detail: Some(detail),
+                documentation: symbol.documentation.clone(),
                 insert_text: None,
             });
         }
@@ -450,8 +748,10 @@ impl AnalyzedDocument {
                         end_line,
                         end_col,
                     },
-                    address: Some(s.address),
-                    documentation: None,
+                    address: (!matches!(s.kind, SymbolKind::Constant | SymbolKind::External))
+                        .then_some(s.address),
+                    value: s.value,
+                    documentation: s.documentation.clone(),
                 }
             })
             .collect()
@@ -499,7 +799,7 @@ impl AnalyzedDocument {
             if c == '"' {
                 let start = i;
                 let mut end = i + 1;
-                while let Some((j, ch)) = chars.next() {
+                for (j, ch) in chars.by_ref() {
                     end = j + 1;
                     if ch == '"' {
                         break;
@@ -535,11 +835,56 @@ impl AnalyzedDocument {
                 continue;
             }
 
+            // Character literal
+            if c == '\'' {
+                let start = i;
+                let mut end = i + 1;
+                let mut escaped = false;
+                for (j, ch) in chars.by_ref() {
+                    end = j + 1;
+                    if ch == '\'' && !escaped && j > start + 1 {
+                        break;
+                    }
+                    escaped = ch == '\\' && !escaped;
+                }
+                tokens.push(SemanticToken {
+                    line: line_num,
+                    start_col: col,
+                    length: (end - start) as u32,
+                    token_type: TokenType::Number,
+                });
+                continue;
+            }
+
+            // Hex or binary number with a 0x/0b prefix, or a binary number
+            // (b or B followed by binary digits, up to the end of the word)
+            let word_len = line[i..]
+                .find(|ch: char| !ch.is_ascii_alphanumeric() && ch != '_')
+                .unwrap_or(line.len() - i);
+            let word = &line[i..i + word_len];
+            let prefixed = word.len() > 2
+                && (word[..2].eq_ignore_ascii_case("0x")
+                    && word[2..].chars().all(|ch| ch.is_ascii_hexdigit())
+                    || word[..2].eq_ignore_ascii_case("0b")
+                        && word[2..].chars().all(|ch| ch == '0' || ch == '1'));
+            let binary = word.len() > 1
+                && (c == 'b' || c == 'B')
+                && word[1..].chars().all(|ch| ch == '0' || ch == '1');
+            if prefixed || binary {
+                for _ in 1..word_len {
+                    chars.next();
+                }
+                tokens.push(SemanticToken {
+                    line: line_num,
+                    start_col: col,
+                    length: word_len as u32,
+                    token_type: TokenType::Number,
+                });
+                continue;
+            }
+
             // Hex number (x or X followed by hex digits)
-            if (c == 'x' || c == 'X')
-                && chars
-                    .peek()
-                    .map_or(false, |&(_, ch)| ch.is_ascii_hexdigit())
+            if (c == 'x' || c == 'X') && chars.peek().is_some_and(|&(_, ch)| ch.is_ascii_hexdigit())
             {
                 let start = i;
                 let mut end = i + 1;
@@ -563,7 +908,7 @@ impl AnalyzedDocument {
             // Decimal number (starts with # or digit or -)
             if c == '#'
                 || c.is_ascii_digit()
-                || (c == '-' && chars.peek().map_or(false, |&(_, ch)| ch.is_ascii_digit()))
+                || (c == '-' && chars.peek().is_some_and(|&(_, ch)| ch.is_ascii_digit()))
             {
                 let start = i;
                 let mut end = i + 1;
@@ -585,8 +930,7 @@ impl AnalyzedDocument {
             }
 
             // Register (R0-R7)
-            if (c == 'R' || c == 'r') && chars.peek().map_or(false, |&(_, ch)| ch.is_ascii_digit())
-            {
+            if (c == 'R' || c == 'r') && chars.peek().is_some_and(|&(_, ch)| ch.is_ascii_digit()) {
                 chars.next(); // consume the digit
                 tokens.push(SemanticToken {
                     line: line_num,
@@ -621,7 +965,7 @@ impl AnalyzedDocument {
                     let is_definition = self
                         .symbols
                         .get(&word_upper)
-                        .map_or(false, |s| s.span.contains(&offset));
+                        .is_some_and(|s| s.span.contains(&offset));
                     if is_definition {
                         TokenType::Label
                     } else {
@@ -641,8 +985,11 @@ impl AnalyzedDocument {
                 continue;
             }
 
-            // Comma and other operators
-            if c == ',' {
+            // Comma and expression operators
+            if matches!(
+                c,
+                ',' | '+' | '-' | '*' | '/' | '&' | '|' | '^' | '~' | '(' | ')'
+            ) {
                 tokens.push(SemanticToken {
                     line: line_num,
                     start_col: col,
@@ -652,6 +999,18 @@ impl AnalyzedDocument {
                 continue;
             }
 
+            // Shift operators
+            if matches!(c, '<' | '>') && chars.peek().is_some_and(|&(_, ch)| ch == c) {
+                chars.next();
+                tokens.push(SemanticToken {
+                    line: line_num,
+                    start_col: col,
+                    length: 2,
+                    token_type: TokenType::Operator,
+                });
+                continue;
+            }
+
             // Colon (label separator)
             if c == ':' {
                 tokens.push(SemanticToken {
@@ -850,14 +1209,14 @@ const DIRECTIVES: &[InstrDoc] = &[
     },
     InstrDoc {
         name: ".FILL",
-        signature: ".FILL value",
-        description: "Allocate one word and initialize with value",
+        signature: ".FILL value, ...",
+        description: "Allocate one word for each value and initialize it",
         snippet: ".FILL ${1:0}",
     },
     InstrDoc {
         name: ".BLKW",
-        signature: ".BLKW count",
-        description: "Allocate count words of memory",
+        signature: ".BLKW count[, value]",
+        description: "Allocate count words of memory, filled with value or zero",
         snippet: ".BLKW ${1:1}",
     },
     InstrDoc {
@@ -866,6 +1225,78 @@ const DIRECTIVES: &[InstrDoc] = &[
         description: "Allocate null-terminated string",
         snippet: ".STRINGZ \"${1:text}\"",
     },
+    InstrDoc {
+        name: ".STRINGP",
+        signature: ".STRINGP \"string\"",
+        description: "Allocate null-terminated string packed two characters per word, for PUTSP",
+        snippet: ".STRINGP \"${1:text}\"",
+    },
+    InstrDoc {
+        name: ".EQU",
+        signature: "NAME .EQU value",
+        description: "Define a named constant",
+        snippet: ".EQU ${1:0}",
+    },
+    InstrDoc {
+        name: ".SET",
+        signature: "NAME .SET value",
+        description: "Define a named constant that may be redefined later",
+        snippet: ".SET ${1:0}",
+    },
+    InstrDoc {
+        name: ".INCLUDE",
+        signature: ".INCLUDE \"file\"",
+        description: "Insert the contents of another source file",
+        snippet: ".INCLUDE \"${1:file.asm}\"",
+    },
+    InstrDoc {
+        name: ".EXTERNAL",
+        signature: ".EXTERNAL name, ...",
+        description: "Declare symbols defined in another module, resolved when linking",
+        snippet: ".EXTERNAL ${1:NAME}",
+    },
+    InstrDoc {
+        name: ".GLOBAL",
+        signature: ".GLOBAL name, ...",
+        description: "Export symbols for other modules to link against",
+        snippet: ".GLOBAL ${1:NAME}",
+    },
+    InstrDoc {
+        name: ".IF",
+        signature: ".IF condition",
+        description: "Assemble the following lines only if the condition is nonzero",
+        snippet: ".IF ${1:DEBUG}",
+    },
+    InstrDoc {
+        name: ".IFDEF",
+        signature: ".IFDEF name",
+        description: "Assemble the following lines only if the constant is defined",
+        snippet: ".IFDEF ${1:DEBUG}",
+    },
+    InstrDoc {
+        name: ".IFNDEF",
+        signature: ".IFNDEF name",
+        description: "Assemble the following lines only if the constant is not defined",
+        snippet: ".IFNDEF ${1:DEBUG}",
+    },
+    InstrDoc {
+        name: ".ELSEIF",
+        signature: ".ELSEIF condition",
+        description: "Start a branch taken if no earlier one was and the condition is nonzero",
+        snippet: ".ELSEIF ${1:0}",
+    },
+    InstrDoc {
+        name: ".ELSE",
+        signature: ".ELSE",
+        description: "Start the branch taken if no earlier one was",
+        snippet: ".ELSE",
+    },
+    InstrDoc {
+        name: ".ENDIF",
+        signature: ".ENDIF",
+        description: "End a conditional block",
+        snippet: ".ENDIF",
+    },
 ];
 
 /// Check if a word is an LC-3 instruction (case-insensitive).
@@ -926,6 +1357,17 @@ fn offset_to_position(line_starts: &[usize], offset: usize) -> (u32, u32) {
     (line, col)
 }
 
+/// The label a line defines, and whether it is a `.SET`.
+fn defined_label(line: &Line) -> Option<(&Spanned<String>, bool)> {
+    match line {
+        Line::LabeledDirective(label, dir) => Some((label, matches!(dir, Directive::Set(_)))),
+        Line::Label(label)
+        | Line::LabeledInstruction(label, _)
+        | Line::LabeledMacroCall(label, _) => Some((label, false)),
+        _ => None,
+    }
+}
+
 /// Convert (line, column) to byte offset. Both are 1-based.
 fn position_to_offset(line_starts: &[usize], line: u32, col: u32) -> Option<usize> {
     let line_idx = (line as usize).checked_sub(1)?;
@@ -955,6 +1397,36 @@ DONE    HALT
         assert_eq!(doc.symbols().len(), 2); // LOOP and DONE
     }
 
+    #[test]
+    fn test_data_addresses() {
+        let source = r#".ORIG x3000
+A       .FILL 1, 2, 3
+B       .STRINGP "abc"
+C       .BLKW 2, x20
+D       .STRINGP "ab"
+E       .STRINGZ "é"
+F       .STRINGP "éé"
+G       HALT
+.END"#;
+
+        let doc = AnalyzedDocument::new(source);
+        let address = |name: &str| doc.symbols.get(name).map(|symbol| symbol.address);
+        assert_eq!(address("B"), Some(0x3003));
+        assert_eq!(address("C"), Some(0x3005));
+        assert_eq!(address("D"), Some(0x3007));
+        assert_eq!(address("E"), Some(0x3009));
+        // Characters are counted, not their UTF-8 bytes
+        assert_eq!(address("F"), Some(0x300B));
+        assert_eq!(address("G"), Some(0x300D));
+
+        // Strings at the top of memory wrap around
+        let doc =
+            AnalyzedDocument::new(".ORIG xFFFF\n.STRINGZ \"ab\"\nA .STRINGP \"abcd\"\nB HALT\n");
+        let address = |name: &str| doc.symbols.get(name).map(|symbol| symbol.address);
+        assert_eq!(address("A"), Some(0x0002));
+        assert_eq!(address("B"), Some(0x0005));
+    }
+
     #[test]
     fn test_undefined_label() {
         let source = r#".ORIG x3000
@@ -966,6 +1438,117 @@ DONE    HALT
 
         assert_eq!(diags.len(), 1);
         assert!(diags[0].message.contains("undefined label"));
+
+        // The label may come from the included file
+        let doc = AnalyzedDocument::new(&format!(".INCLUDE \"lib.asm\"\n{source}"));
+        assert!(doc.diagnostics().is_empty());
+    }
+
+    #[test]
+    fn test_label_diagnostics() {
+        let source =
+            "LOOP ADD R0, R0, #1\nLoop BRp LOOP\nR2 .FILL 0\nN .SET 1\nN .SET 2\nBR loop\n";
+        let doc = AnalyzedDocument::new(source);
+        let diags: Vec<_> = doc
+            .diagnostics()
+            .into_iter()
+            .map(|d| (d.start_line, d.severity, d.message))
+            .collect();
+        assert_eq!(
+            diags,
+            [
+                (
+                    2,
+                    Severity::Warning,
+                    "label Loop differs only in case from LOOP at line 1; \
+                     labels are not case-sensitive, so that definition is used"
+                        .to_string()
+                ),
+                (
+                    3,
+                    Severity::Warning,
+                    "label R2 looks like register R2".to_string()
+                ),
+                (
+                    6,
+                    Severity::Warning,
+                    "loop refers to LOOP at line 1, which is written in a different case"
+                        .to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels_in_expressions() {
+        let source = r#".ORIG x3000
+        LD R0, TABLE + (SIZE - 1)
+TABLE   .BLKW 4
+        .FILL TABLE+3
+.END"#;
+
+        let doc = AnalyzedDocument::new(source);
+        let diags = doc.diagnostics();
+
+        assert_eq!(diags.len(), 1);
+        assert!(diags[0].message.contains("SIZE"));
+        assert_eq!(doc.symbols["TABLE"].address, 0x3001);
+        assert_eq!(
+            doc.label_refs.iter().filter(|r| r.name == "TABLE").count(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_constants() {
+        let source = r#".ORIG BASE
+        ADD R0, R0, STEP
+BUF     .BLKW STEP
+.END
+STEP    .EQU SIZE / 2
+SIZE    .EQU 8
+BASE    .EQU x3000"#;
+
+        let doc = AnalyzedDocument::new(source);
+
+        assert!(doc.diagnostics().is_empty());
+        assert_eq!(doc.symbols["STEP"].value, Some(4));
+        assert_eq!(doc.symbols["BUF"].address, 0x3001);
+        let hover = doc.hover(5, 1).unwrap();
+        assert!(hover.contents.contains("Value: `4` (`x0004`)"));
+    }
+
+    #[test]
+    fn test_doc_comments() {
+        let source = r#".ORIG x3000
+        JSR PRINT
+        HALT
+; Print the character in R0.
+PRINT   OUT
+        RET
+COUNT   .FILL 0 ; characters printed
+.END"#;
+
+        let doc = AnalyzedDocument::new(source);
+        let hover = doc.hover(2, 13).unwrap();
+        assert!(hover.contents.ends_with("\n\nPrint the character in R0."));
+        let symbols = doc.symbols();
+        let count = symbols.iter().find(|s| s.name == "COUNT").unwrap();
+        assert_eq!(count.documentation.as_deref(), Some("characters printed"));
+    }
+
+    #[test]
+    fn test_externals() {
+        let source = ".EXTERNAL PRINT\n.GLOBAL MAIN, NOPE\nMAIN JSR PRINT";
+
+        let doc = AnalyzedDocument::new(source);
+
+        let messages: Vec<_> = doc.diagnostics().into_iter().map(|d| d.message).collect();
+        assert_eq!(messages, ["undefined label: NOPE"]);
+        assert_eq!(doc.symbols["PRINT"].kind, SymbolKind::External);
+        let hover = doc.hover(3, 11).unwrap();
+        assert!(hover.contents.contains("(external)"));
+        assert_eq!(doc.references(2, 9).len(), 2);
     }
 
     #[test]
@@ -984,6 +1567,26 @@ TARGET  ADD R0, R0, #1
         assert_eq!(loc.start_line, 2); // Definition is on line 2
     }
 
+    #[test]
+    fn test_local_labels() {
+        let source = r#".ORIG x3000
+MAIN    BRp .loop
+.loop   BRp .loop
+PRINT   BRp .loop
+.loop:  BRp PRINT.loop
+.END"#;
+
+        let doc = AnalyzedDocument::new(source);
+        assert!(doc.diagnostics().is_empty());
+        let loc = doc.definition(4, 13).unwrap();
+        assert_eq!((loc.start_line, loc.start_col), (5, 1));
+        let loc = doc.definition(2, 13).unwrap();
+        assert_eq!((loc.start_line, loc.start_col), (3, 1));
+        // The definition and three references, one qualified
+        assert_eq!(doc.references(5, 2).len(), 3);
+        assert_eq!(doc.references(3, 2).len(), 3);
+    }
+
     #[test]
     fn test_completions() {
         let source = r#".ORIG x3000
@@ -1050,6 +1653,16 @@ DONE    HALT
             .collect();
         assert_eq!(labels.len(), 2); // LOOP, DONE (definitions)
 
+        let doc =
+            AnalyzedDocument::new(".FILL b0101\n.FILL 0x1F\n.FILL '\\''\n.FILL BIN\n.FILL 0b11");
+        let numbers: Vec<_> = doc
+            .tokens()
+            .into_iter()
+            .filter(|t| t.token_type == TokenType::Number)
+            .map(|t| (t.line, t.start_col, t.length))
+            .collect();
+        assert_eq!(numbers, [(1, 7, 5), (2, 7, 4), (3, 7, 4), (5, 7, 4)]);
+
         let label_refs: Vec<_> = tokens
             .iter()
             .filter(|t| t.token_type == TokenType::LabelRef)
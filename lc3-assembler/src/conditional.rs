@@ -0,0 +1,241 @@
+//! Conditional assembly: `.IF`, `.IFDEF` and `.IFNDEF` blocks, selected by
+//! the constants given to [`Assembler::define`] and the `.EQU` and `.SET`
+//! constants defined above them.
+
+use crate::{
+    Assembler, Code, Directive, EvalError, Expr, Line, Program, SemanticError, Span, Spanned,
+    did_you_mean, eval_error, make_error,
+};
+use std::collections::{HashMap, HashSet};
+
+/// An open `.IF` block.
+struct Block {
+    /// The `.IF`, for an unclosed block.
+    span: Span,
+    /// Whether the lines around the block are assembled.
+    outer: bool,
+    /// Whether the current branch is assembled.
+    active: bool,
+    /// Whether a branch has been taken.
+    taken: bool,
+    /// Whether `.ELSE` has been seen.
+    seen_else: bool,
+}
+
+/// The constants a condition may use, as defined so far.
+struct Constants<'a> {
+    defines: &'a HashMap<String, i32>,
+    equates: HashMap<String, Spanned<Expr>>,
+    /// `.SET` values; `None` where the value depends on a label.
+    sets: HashMap<String, Option<i32>>,
+    labels: HashSet<String>,
+}
+
+impl Constants<'_> {
+    fn is_defined(&self, name: &str) -> bool {
+        self.defines.contains_key(name)
+            || self.equates.contains_key(name)
+            || self.sets.contains_key(name)
+    }
+
+    fn eval(&self, expr: &Spanned<Expr>) -> Result<i32, EvalError> {
+        expr.eval(&mut |name, span| self.lookup(name, span, &mut Vec::new()))
+    }
+
+    fn lookup(
+        &self,
+        name: &str,
+        span: &Span,
+        resolving: &mut Vec<String>,
+    ) -> Result<i32, EvalError> {
+        let not_constant = || {
+            EvalError::new(
+                span.clone(),
+                Code::NonConstantCondition,
+                format!("conditions can only use constants, and {name} is a label"),
+            )
+        };
+        if let Some(&value) = self.defines.get(name) {
+            Ok(value)
+        } else if let Some(value) = self.sets.get(name) {
+            value.ok_or_else(|| {
+                EvalError::new(
+                    span.clone(),
+                    Code::NonConstantCondition,
+                    format!("conditions can only use constants, and {name} is set from a label"),
+                )
+            })
+        } else if let Some(value) = self.equates.get(name) {
+            if resolving.iter().any(|n| n == name) {
+                return Err(EvalError::new(
+                    span.clone(),
+                    Code::CircularDefinition,
+                    format!("circular definition of {name}"),
+                ));
+            }
+            resolving.push(name.to_string());
+            let result = value.eval(&mut |name, span| self.lookup(name, span, resolving));
+            resolving.pop();
+            result
+        } else if self.labels.contains(name) {
+            Err(not_constant())
+        } else {
+            let mut error = EvalError::new(
+                span.clone(),
+                Code::UndefinedSymbol,
+                format!(
+                    "undefined symbol: {name}; conditions see only constants defined above \
+                     them, and .IFDEF tests whether one is defined"
+                ),
+            );
+            let names = (self.defines.keys())
+                .chain(self.equates.keys())
+                .chain(self.sets.keys())
+                .map(String::as_str);
+            error
+                .suggestions
+                .extend(did_you_mean(name, span.clone(), names));
+            Err(error)
+        }
+    }
+}
+
+impl Assembler {
+    /// Drop the lines in branches of conditional blocks that are not taken,
+    /// and the conditional directives themselves.
+    pub(crate) fn select_conditionals(
+        &self,
+        program: Program,
+        source: &str,
+        errors: &mut Vec<SemanticError>,
+    ) -> Program {
+        let mut constants = Constants {
+            defines: &self.defines,
+            equates: HashMap::new(),
+            sets: HashMap::new(),
+            labels: program
+                .lines
+                .iter()
+                .filter_map(|line| match &line.line {
+                    Line::LabeledDirective(_, Directive::Equ(_) | Directive::Set(_)) => None,
+                    Line::Label(label)
+                    | Line::LabeledDirective(label, _)
+                    | Line::LabeledInstruction(label, _)
+                    | Line::LabeledMacroCall(label, _) => Some(label.value.clone()),
+                    _ => None,
+                })
+                .collect(),
+        };
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut lines = Vec::new();
+        for spanned_line in program.lines {
+            let first_error = errors.len();
+            let active = blocks.last().is_none_or(|block| block.active);
+            let dir = match &spanned_line.line {
+                Line::Directive(dir) => Some(dir),
+                _ => None,
+            };
+            match dir {
+                Some(dir @ (Directive::If(_) | Directive::Ifdef(_) | Directive::Ifndef(_))) => {
+                    let taken = active && self.condition(dir, &constants, source, errors);
+                    blocks.push(Block {
+                        span: spanned_line.span.clone(),
+                        outer: active,
+                        active: taken,
+                        taken,
+                        seen_else: false,
+                    });
+                }
+                Some(dir @ (Directive::Elseif(_) | Directive::Else)) => {
+                    let name = match dir {
+                        Directive::Else => ".ELSE",
+                        _ => ".ELSEIF",
+                    };
+                    match blocks.last_mut() {
+                        None => errors.push(make_error(
+                            source,
+                            spanned_line.span.clone(),
+                            Code::UnmatchedConditional,
+                            format!("{name} without .IF"),
+                        )),
+                        Some(block) if block.seen_else => errors.push(make_error(
+                            source,
+                            spanned_line.span.clone(),
+                            Code::UnmatchedConditional,
+                            format!("{name} after .ELSE"),
+                        )),
+                        Some(block) => {
+                            block.seen_else = matches!(dir, Directive::Else);
+                            block.active = block.outer
+                                && !block.taken
+                                && self.condition(dir, &constants, source, errors);
+                            block.taken |= block.active;
+                        }
+                    }
+                }
+                Some(Directive::Endif) => {
+                    if blocks.pop().is_none() {
+                        errors.push(make_error(
+                            source,
+                            spanned_line.span.clone(),
+                            Code::UnmatchedConditional,
+                            ".ENDIF without .IF".into(),
+                        ));
+                    }
+                }
+                _ if active => {
+                    match &spanned_line.line {
+                        Line::LabeledDirective(label, Directive::Equ(value)) => {
+                            constants.equates.insert(label.value.clone(), value.clone());
+                        }
+                        Line::LabeledDirective(label, Directive::Set(value)) => {
+                            let value = constants.eval(value).ok();
+                            constants.sets.insert(label.value.clone(), value);
+                        }
+                        _ => {}
+                    }
+                    lines.push(spanned_line);
+                    continue;
+                }
+                _ => continue,
+            }
+            for error in &mut errors[first_error..] {
+                error.expanded_from = spanned_line.expanded_from.clone();
+            }
+        }
+        for block in blocks {
+            errors.push(make_error(
+                source,
+                block.span,
+                Code::UnclosedBlock,
+                ".IF is missing .ENDIF".into(),
+            ));
+        }
+        Program { lines }
+    }
+
+    /// Whether the condition of `.IF`, `.ELSEIF`, `.IFDEF` or `.IFNDEF`
+    /// holds; `.ELSE` always does.
+    fn condition(
+        &self,
+        dir: &Directive,
+        constants: &Constants,
+        source: &str,
+        errors: &mut Vec<SemanticError>,
+    ) -> bool {
+        match dir {
+            Directive::If(condition) | Directive::Elseif(condition) => {
+                match constants.eval(condition) {
+                    Ok(value) => value != 0,
+                    Err(e) => {
+                        errors.push(eval_error(source, e));
+                        false
+                    }
+                }
+            }
+            Directive::Ifdef(name) => constants.is_defined(&name.value),
+            Directive::Ifndef(name) => !constants.is_defined(&name.value),
+            _ => true,
+        }
+    }
+}
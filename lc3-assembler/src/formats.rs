@@ -0,0 +1,443 @@
+//! Program image formats: how assembled segments are written to a file, and
+//! read back by loaders and `lc3 convert`.
+
+use crate::{Segment, lc3tools_format};
+
+/// A program image format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// lc3tools `.obj`; see [`lc3tools_format`].
+    Lc3tools,
+    /// The original `.obj`: the origin, then the words, all big-endian. It
+    /// holds one segment.
+    Legacy,
+    /// lc3convert `.hex` text: the origin, then one word per line, as four
+    /// hex digits. It holds one segment.
+    Hex,
+    /// lc3convert `.bin` text: like [`Format::Hex`], with sixteen binary
+    /// digits per line.
+    Bin,
+    /// Intel HEX. Each word is two bytes, high byte first, at twice its
+    /// address.
+    IntelHex,
+    /// A Verilog `$readmemh` image: `@address` before each segment, then one
+    /// word per line.
+    Memh,
+}
+
+impl Format {
+    /// Every format, in the order they are listed to users.
+    pub const ALL: [Format; 6] = [
+        Format::Lc3tools,
+        Format::Legacy,
+        Format::Hex,
+        Format::Bin,
+        Format::IntelHex,
+        Format::Memh,
+    ];
+
+    /// The name the format is selected by, e.g. on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Lc3tools => "obj",
+            Format::Legacy => "legacy",
+            Format::Hex => "hex",
+            Format::Bin => "bin",
+            Format::IntelHex => "ihex",
+            Format::Memh => "memh",
+        }
+    }
+
+    /// The format's name in messages.
+    pub fn description(self) -> &'static str {
+        match self {
+            Format::Lc3tools => "lc3tools",
+            Format::Legacy => "legacy .obj",
+            Format::Hex => "hex text",
+            Format::Bin => "binary text",
+            Format::IntelHex => "Intel HEX",
+            Format::Memh => "$readmemh",
+        }
+    }
+
+    /// The format named `name`, ignoring case.
+    pub fn from_name(name: &str) -> Option<Format> {
+        Format::ALL
+            .into_iter()
+            .find(|format| format.name().eq_ignore_ascii_case(name))
+    }
+
+    /// The format a file is written in, going by its extension. `.obj` is
+    /// taken to be lc3tools.
+    pub fn from_path(path: &str) -> Option<Format> {
+        let (_, extension) = path.rsplit_once('.')?;
+        match extension.to_ascii_lowercase().as_str() {
+            "obj" => Some(Format::Lc3tools),
+            "hex" => Some(Format::Hex),
+            "bin" => Some(Format::Bin),
+            "ihex" | "ihx" => Some(Format::IntelHex),
+            "mem" | "memh" => Some(Format::Memh),
+            _ => None,
+        }
+    }
+
+    /// The format `data` appears to be in. Text in none of the text formats
+    /// is taken to be a legacy `.obj`; so is a `$readmemh` image with no
+    /// `@address`, which reads as lc3convert hex.
+    pub fn detect(data: &[u8]) -> Format {
+        if lc3tools_format::is_lc3tools_format(data) {
+            return Format::Lc3tools;
+        }
+        let Ok(text) = std::str::from_utf8(data) else {
+            return Format::Legacy;
+        };
+        let lines: Vec<&str> = text_lines(text).collect();
+        let all = |digits: usize, radix: u32| {
+            !lines.is_empty()
+                && lines
+                    .iter()
+                    .all(|line| line.len() == digits && line.chars().all(|c| c.is_digit(radix)))
+        };
+        if lines.first().is_some_and(|line| line.starts_with(':')) {
+            Format::IntelHex
+        } else if lines.iter().any(|line| line.starts_with('@')) {
+            Format::Memh
+        } else if all(16, 2) {
+            Format::Bin
+        } else if all(4, 16) {
+            Format::Hex
+        } else {
+            Format::Legacy
+        }
+    }
+
+    /// Write `segments` in this format.
+    pub fn encode(self, segments: &[Segment]) -> Result<Vec<u8>, String> {
+        let single = || match segments {
+            [segment] => Ok(segment),
+            _ => Err(format!(
+                "the {} format holds a single segment, but there are {}",
+                self.name(),
+                segments.len()
+            )),
+        };
+        Ok(match self {
+            Format::Lc3tools => lc3tools_format::encode(segments),
+            Format::Legacy => {
+                let segment = single()?;
+                std::iter::once(segment.origin)
+                    .chain(segment.code.iter().copied())
+                    .flat_map(u16::to_be_bytes)
+                    .collect()
+            }
+            Format::Hex | Format::Bin => {
+                let segment = single()?;
+                let mut out = String::new();
+                for word in std::iter::once(segment.origin).chain(segment.code.iter().copied()) {
+                    out += &match self {
+                        Format::Hex => format!("{word:04X}\n"),
+                        _ => format!("{word:016b}\n"),
+                    };
+                }
+                out.into_bytes()
+            }
+            Format::IntelHex => encode_intel_hex(segments).into_bytes(),
+            Format::Memh => {
+                let mut out = String::new();
+                for segment in segments {
+                    out += &format!("@{:04X}\n", segment.origin);
+                    for word in &segment.code {
+                        out += &format!("{word:04X}\n");
+                    }
+                }
+                out.into_bytes()
+            }
+        })
+    }
+
+    /// Read the segments of `data`, written in this format.
+    pub fn decode(self, data: &[u8]) -> Result<Vec<Segment>, String> {
+        let text = || {
+            std::str::from_utf8(data).map_err(|_| format!("a {} file must be text", self.name()))
+        };
+        match self {
+            Format::Lc3tools => {
+                let entries = lc3tools_format::decode(data)?;
+                Ok(lc3tools_format::entries_to_segments(&entries))
+            }
+            Format::Legacy => {
+                if data.len() < 2 || !data.len().is_multiple_of(2) {
+                    return Err("a legacy .obj file must have an even number of bytes".into());
+                }
+                let mut words = data
+                    .chunks(2)
+                    .map(|pair| u16::from_be_bytes([pair[0], pair[1]]));
+                let origin = words.next().unwrap_or(0);
+                Ok(vec![Segment {
+                    origin,
+                    code: words.collect(),
+                }])
+            }
+            Format::Hex | Format::Bin => {
+                let radix = if self == Format::Hex { 16 } else { 2 };
+                let mut words = Vec::new();
+                for (i, line) in text()?.lines().enumerate() {
+                    let line = strip_comment(line).trim();
+                    if line.is_empty() {
+                        continue;
+                    }
+                    let digits = match self {
+                        Format::Hex => strip_hex_prefix(line),
+                        _ => line,
+                    };
+                    let word = u16::from_str_radix(digits, radix)
+                        .map_err(|e| format!("line {}: invalid word '{line}': {e}", i + 1))?;
+                    words.push(word);
+                }
+                let Some((&origin, code)) = words.split_first() else {
+                    return Err(format!("the {} file has no origin", self.name()));
+                };
+                Ok(vec![Segment {
+                    origin,
+                    code: code.to_vec(),
+                }])
+            }
+            Format::IntelHex => decode_intel_hex(text()?),
+            Format::Memh => decode_memh(text()?),
+        }
+    }
+}
+
+/// Read a program image in any format, going by its contents.
+pub fn decode(data: &[u8]) -> Result<Vec<Segment>, String> {
+    Format::detect(data).decode(data)
+}
+
+/// The lines of `text` that hold something, without comments.
+fn text_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .map(|line| strip_comment(line).trim())
+        .filter(|line| !line.is_empty())
+}
+
+/// `line` without a `;` or `//` comment.
+fn strip_comment(line: &str) -> &str {
+    let end = [line.find(';'), line.find("//")]
+        .into_iter()
+        .flatten()
+        .min()
+        .unwrap_or(line.len());
+    &line[..end]
+}
+
+fn strip_hex_prefix(digits: &str) -> &str {
+    digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+        .or_else(|| digits.strip_prefix(['x', 'X']))
+        .unwrap_or(digits)
+}
+
+/// An Intel HEX record: its type, address and data.
+fn intel_hex_record(kind: u8, address: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(address.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend(data);
+    let checksum = bytes
+        .iter()
+        .fold(0u8, |sum, &b| sum.wrapping_add(b))
+        .wrapping_neg();
+    bytes.push(checksum);
+    let hex: String = bytes.iter().map(|b| format!("{b:02X}")).collect();
+    format!(":{hex}\n")
+}
+
+/// Data records of at most 16 bytes, with an extended linear address
+/// record whenever the upper half of the byte address changes.
+fn encode_intel_hex(segments: &[Segment]) -> String {
+    let mut out = String::new();
+    let mut upper = 0u16;
+    for segment in segments {
+        let bytes: Vec<u8> = segment
+            .code
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .collect();
+        let mut address = segment.origin as u32 * 2;
+        let mut rest = &bytes[..];
+        while !rest.is_empty() {
+            if (address >> 16) as u16 != upper {
+                upper = (address >> 16) as u16;
+                out += &intel_hex_record(4, 0, &upper.to_be_bytes());
+            }
+            // Records do not cross a 64K boundary
+            let room = 0x10000 - (address & 0xFFFF) as usize;
+            let (record, next) = rest.split_at(rest.len().min(16).min(room));
+            out += &intel_hex_record(0, address as u16, record);
+            address += record.len() as u32;
+            rest = next;
+        }
+    }
+    out += &intel_hex_record(1, 0, &[]);
+    out
+}
+
+fn decode_intel_hex(text: &str) -> Result<Vec<Segment>, String> {
+    let mut bytes: Vec<(u32, u8)> = Vec::new();
+    let mut base = 0u32;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: &str| format!("line {}: {message}", i + 1);
+        let digits = line
+            .strip_prefix(':')
+            .ok_or_else(|| error("a record must start with ':'"))?;
+        if digits.len() % 2 != 0 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(error("a record must be pairs of hex digits"));
+        }
+        let record: Vec<u8> = (0..digits.len())
+            .step_by(2)
+            .map(|j| u8::from_str_radix(&digits[j..j + 2], 16).unwrap_or(0))
+            .collect();
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(error("the record length does not match its byte count"));
+        }
+        if record.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0 {
+            return Err(error("bad checksum"));
+        }
+        let address = u16::from_be_bytes([record[1], record[2]]) as u32;
+        let data = &record[4..record.len() - 1];
+        match record[3] {
+            0 => bytes.extend(
+                data.iter()
+                    .enumerate()
+                    .map(|(j, &b)| (base + address + j as u32, b)),
+            ),
+            1 => break,
+            2 if data.len() == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4,
+            4 if data.len() == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16,
+            // Start addresses mean nothing to the LC-3
+            3 | 5 => {}
+            kind => return Err(error(&format!("unsupported record type {kind:02X}"))),
+        }
+    }
+
+    // Pair the bytes into words; a missing half is zero
+    let mut words: Vec<(u16, u16)> = Vec::new();
+    for (address, byte) in bytes {
+        let word = (address / 2) as u16;
+        let shift = if address % 2 == 0 { 8 } else { 0 };
+        match words.last_mut() {
+            Some((at, value)) if *at == word => *value |= (byte as u16) << shift,
+            _ => words.push((word, (byte as u16) << shift)),
+        }
+    }
+    Ok(contiguous(words))
+}
+
+fn decode_memh(text: &str) -> Result<Vec<Segment>, String> {
+    let mut words = Vec::new();
+    let mut address = 0u16;
+    for (i, line) in text.lines().enumerate() {
+        for token in strip_comment(line).split_whitespace() {
+            let error = |e| format!("line {}: invalid word '{token}': {e}", i + 1);
+            if let Some(digits) = token.strip_prefix('@') {
+                address = u16::from_str_radix(digits, 16).map_err(error)?;
+            } else {
+                words.push((address, u16::from_str_radix(token, 16).map_err(error)?));
+                address = address.wrapping_add(1);
+            }
+        }
+    }
+    Ok(contiguous(words))
+}
+
+/// Segments of `words`, by address, starting one wherever an address does
+/// not follow the one before.
+fn contiguous(words: Vec<(u16, u16)>) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = Vec::new();
+    for (address, word) in words {
+        match segments.last_mut() {
+            Some(segment) if segment.origin.wrapping_add(segment.code.len() as u16) == address => {
+                segment.code.push(word);
+            }
+            _ => segments.push(Segment {
+                origin: address,
+                code: vec![word],
+            }),
+        }
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segments() -> Vec<Segment> {
+        vec![
+            Segment {
+                origin: 0x3000,
+                code: vec![0x1042, 0xF025],
+            },
+            Segment {
+                origin: 0xFFFF,
+                code: vec![0xABCD],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for format in Format::ALL {
+            let segments = match format {
+                Format::Legacy | Format::Hex | Format::Bin => segments()[..1].to_vec(),
+                _ => segments(),
+            };
+            let data = format.encode(&segments).unwrap();
+            assert_eq!(Format::detect(&data), format, "{}", format.name());
+            let decoded = decode(&data).unwrap();
+            let pairs = |segments: &[Segment]| -> Vec<(u16, Vec<u16>)> {
+                segments
+                    .iter()
+                    .map(|segment| (segment.origin, segment.code.clone()))
+                    .collect()
+            };
+            assert_eq!(pairs(&decoded), pairs(&segments), "{}", format.name());
+        }
+        assert!(Format::Hex.encode(&segments()).is_err());
+    }
+
+    #[test]
+    fn test_text_formats() {
+        let segment = &segments()[..1];
+        let hex = Format::Hex.encode(segment).unwrap();
+        assert_eq!(String::from_utf8(hex).unwrap(), "3000\n1042\nF025\n");
+        let bin = Format::Bin.encode(segment).unwrap();
+        assert!(
+            String::from_utf8(bin)
+                .unwrap()
+                .starts_with("0011000000000000\n")
+        );
+        let memh = Format::Memh.encode(segment).unwrap();
+        assert_eq!(String::from_utf8(memh).unwrap(), "@3000\n1042\nF025\n");
+
+        // The word at xFFFF lies above byte address x10000
+        let ihex = String::from_utf8(Format::IntelHex.encode(&segments()).unwrap()).unwrap();
+        assert_eq!(
+            ihex,
+            ":046000001042F02535\n:020000040001F9\n:02FFFE00ABCD89\n:00000001FF\n"
+        );
+
+        let memh = "// image\n@3000 1042 // ADD\nF025\n@4000\n0001\n";
+        let decoded = decode(memh.as_bytes()).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[1].origin, 0x4000);
+        assert!(decode(b":0100000001FF\n").is_err());
+        assert_eq!(Format::from_path("prog.ihx"), Some(Format::IntelHex));
+        assert_eq!(Format::from_name("MEMH"), Some(Format::Memh));
+    }
+}
@@ -19,13 +19,32 @@
 //! assert_eq!(code[0], 0x1042); // ADD R0, R1, R2
 //! assert_eq!(code[1], 0xF025); // HALT
 //! ```
+//!
+//! LC-3b sources are assembled after [`Assembler::set_isa`]; addresses are
+//! then byte addresses and PC offsets count words.
 
 pub use lc3_parser::{
-    AddSrc2, AndSrc2, Directive, Instruction, Line, Operand, ParseError, Program, Register, Span,
-    Spanned, SpannedLine, format_errors, parse,
+    AddSrc2, AndSrc2, Code, Diagnostic, Dialect, Directive, EvalError, Expr, Extension,
+    ExtensionFormat, IncludeResolver, Instruction, Isa, Line, Operand, ParseError, Program, Pseudo,
+    Register, Related, Severity, ShiftKind, SourceFile, SourceMap, Span, Spanned, SpannedLine,
+    Suggestion, SyntaxLine, SyntaxTree, XorSrc2, confusing_label, did_you_mean, expand_macros,
+    format_errors, format_errors_in, join_path, normalize_path, parse, parse_with,
+    resolve_includes, scope_local_labels, symbol_references,
 };
 
-use std::collections::HashMap;
+mod conditional;
+pub mod formats;
+mod lint;
+mod listing;
+pub mod object;
+mod pseudo;
+mod relax;
+
+pub use listing::SourceLine;
+use object::{Object, ObjectSymbol, RelocKind, RelocTarget, Relocation, Section};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ops::{Range, RangeInclusive};
 
 /// A semantic error with location information.
 #[derive(Debug, Clone)]
@@ -34,10 +53,36 @@ pub struct SemanticError {
     pub line: usize,
     pub column: usize,
     pub span: std::ops::Range<usize>,
+    /// Span of the macro invocation, for errors inside an expansion.
+    pub expanded_from: Option<Span>,
+    /// The included file the error is in; `None` for the main file.
+    /// `line` and `column` count within that file.
+    pub file: Option<String>,
+    pub code: Code,
+    /// Other places that bear on the error.
+    pub related: Vec<Related>,
+    pub suggestions: Vec<Suggestion>,
+}
+
+impl SemanticError {
+    /// The error, for [`SourceMap::report`] and [`SourceMap::json`].
+    pub fn diagnostic(&self) -> Diagnostic<'_> {
+        Diagnostic {
+            code: self.code,
+            message: &self.message,
+            span: &self.span,
+            expanded_from: self.expanded_from.as_ref(),
+            related: &self.related,
+            suggestions: &self.suggestions,
+        }
+    }
 }
 
 impl std::fmt::Display for SemanticError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{file}:")?;
+        }
         write!(f, "{}:{}: {}", self.line, self.column, self.message)
     }
 }
@@ -72,6 +117,16 @@ impl std::fmt::Display for AssemblyError {
 
 impl std::error::Error for AssemblyError {}
 
+impl AssemblyError {
+    /// Each error, with its code and spans.
+    pub fn diagnostics(&self) -> Vec<Diagnostic<'_>> {
+        match self {
+            Self::ParseErrors(errors) => errors.iter().map(ParseError::diagnostic).collect(),
+            Self::SemanticErrors(errors) => errors.iter().map(SemanticError::diagnostic).collect(),
+        }
+    }
+}
+
 /// A segment of assembled code with its origin address.
 #[derive(Debug, Clone)]
 pub struct Segment {
@@ -83,6 +138,8 @@ pub struct Segment {
 
 /// lc3tools .obj file format constants and utilities.
 pub mod lc3tools_format {
+    use std::collections::HashMap;
+
     /// Magic header bytes for lc3tools .obj files.
     pub const MAGIC: &[u8] = &[0x1c, 0x30, 0x15, 0xc0, 0x01];
     /// Version bytes for lc3tools .obj files.
@@ -99,24 +156,31 @@ pub mod lc3tools_format {
     ///   - num_chars: u32 (little-endian, length of source line)
     ///   - line: [u8; num_chars] (source line, not null-terminated)
     pub fn encode(segments: &[super::Segment]) -> Vec<u8> {
+        encode_with_lines(segments, &[])
+    }
+
+    /// Encode segments along with their source lines: for each segment, the
+    /// line of its `.ORIG` followed by the line of each word. Missing lines
+    /// are left empty.
+    pub fn encode_with_lines(segments: &[super::Segment], lines: &[Vec<String>]) -> Vec<u8> {
         let mut out = Vec::new();
 
         // Header
         out.extend_from_slice(MAGIC);
         out.extend_from_slice(VERSION);
 
-        // Each segment
-        for seg in segments {
-            // Origin entry
-            out.extend_from_slice(&seg.origin.to_le_bytes());
-            out.push(1); // is_orig = true
-            out.extend_from_slice(&0u32.to_le_bytes()); // no source line
-
-            // Code entries
-            for &word in &seg.code {
-                out.extend_from_slice(&word.to_le_bytes());
-                out.push(0); // is_orig = false
-                out.extend_from_slice(&0u32.to_le_bytes()); // no source line
+        let mut entry = |value: u16, is_orig: bool, line: Option<&String>| {
+            let line = line.map_or(&[][..], |line| line.as_bytes());
+            out.extend_from_slice(&value.to_le_bytes());
+            out.push(is_orig as u8);
+            out.extend_from_slice(&(line.len() as u32).to_le_bytes());
+            out.extend_from_slice(line);
+        };
+        for (i, seg) in segments.iter().enumerate() {
+            let lines = lines.get(i).map_or(&[][..], |lines| lines.as_slice());
+            entry(seg.origin, true, lines.first());
+            for (j, &word) in seg.code.iter().enumerate() {
+                entry(word, false, lines.get(j + 1));
             }
         }
 
@@ -214,6 +278,24 @@ pub mod lc3tools_format {
         segments
     }
 
+    /// The non-empty source lines of the words in `entries`, by address;
+    /// `step` is the size of a word in addresses.
+    pub fn entries_to_lines(entries: &[MemEntry], step: u16) -> HashMap<u16, String> {
+        let mut lines = HashMap::new();
+        let mut address = 0u16;
+        for entry in entries {
+            if entry.is_orig {
+                address = entry.value;
+                continue;
+            }
+            if !entry.line.is_empty() {
+                lines.insert(address, entry.line.clone());
+            }
+            address = address.wrapping_add(step);
+        }
+        lines
+    }
+
     /// Check if data looks like lc3tools format (has correct magic header).
     pub fn is_lc3tools_format(data: &[u8]) -> bool {
         data.len() >= MAGIC.len() && &data[..MAGIC.len()] == MAGIC
@@ -224,8 +306,74 @@ pub mod lc3tools_format {
 #[derive(Debug, Default)]
 pub struct Assembler {
     symbols: HashMap<String, u16>,
+    /// `.EQU` definitions, evaluated on use so they may refer to later symbols.
+    equates: HashMap<String, Spanned<Expr>>,
+    /// Current `.SET` values; each pass updates them as it reaches them.
+    sets: HashMap<String, i32>,
+    /// Labels the first pass has not reached yet.
+    later_labels: HashSet<String>,
     origin: u16,
     segments: Vec<Segment>,
+    dialect: Dialect,
+    includes: Option<Includes>,
+    /// The files that went into the last assembly.
+    sources: SourceMap,
+    /// Symbols declared `.EXTERNAL`.
+    externals: HashSet<String>,
+    /// Symbols declared `.GLOBAL`.
+    globals: Vec<Spanned<String>>,
+    /// Whether an object file is being assembled.
+    object: bool,
+    /// Whether the module being assembled is placed by the linker, because
+    /// it has no `.ORIG`. Its labels are then offsets from its start.
+    relocatable: bool,
+    /// Relocations of the object file, by address.
+    relocations: Vec<(u16, Relocation)>,
+    /// The words each line emitted, for [`Assembler::listing`].
+    listing: Vec<listing::Entry>,
+    /// The `.ORIG` line of each segment; `None` for a relocatable module.
+    origin_lines: Vec<Option<Span>>,
+    /// Whether code may be placed in system space.
+    system_space: bool,
+    /// Warnings from the last assembly.
+    warnings: Vec<SemanticError>,
+    /// The addresses of the literal pool entries, named by
+    /// [`pseudo::literal_name`].
+    literals: HashMap<String, u16>,
+    /// Whether to rewrite instructions whose target is out of range.
+    relax: bool,
+    /// The register far branches jump through; R7 if not set.
+    relax_register: Option<Register>,
+    /// The lines, by index, whose instructions are rewritten.
+    relaxed: HashSet<usize>,
+    /// Constants given by [`Assembler::define`].
+    defines: HashMap<String, i32>,
+}
+
+/// An instruction being emitted: its address, and where to report problems
+/// with it.
+struct Emit<'a, 'e> {
+    pc: u16,
+    source: &'a str,
+    errors: &'e mut Vec<SemanticError>,
+}
+
+/// How far a symbol is displaced while checking whether an expression
+/// depends on it; see [`Assembler::relocate`].
+const MOVE: i32 = 0x10000;
+
+/// How to resolve `.INCLUDE`, and the name of the main source.
+struct Includes {
+    main: String,
+    resolver: Box<dyn IncludeResolver>,
+}
+
+impl std::fmt::Debug for Includes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Includes")
+            .field("main", &self.main)
+            .finish()
+    }
 }
 
 impl Assembler {
@@ -233,11 +381,108 @@ impl Assembler {
         Self::default()
     }
 
+    /// Select the instruction set to assemble for (LC-3 by default).
+    pub fn set_isa(&mut self, isa: Isa) {
+        self.dialect.isa = isa;
+    }
+
+    /// Get the instruction set being assembled for.
+    pub fn isa(&self) -> Isa {
+        self.dialect.isa
+    }
+
+    /// Register a custom instruction on the reserved opcode (1101).
+    pub fn add_extension(&mut self, extension: Extension) {
+        self.dialect.extensions.push(extension);
+    }
+
+    /// Accept the pseudo-instructions MOV, CLR, INC, DEC, NEG, SUB, PUSH,
+    /// POP, LDIMM, `JMP label` and CALL (LC-3 only). They are off by
+    /// default, leaving their names free for labels.
+    pub fn set_pseudo_ops(&mut self, enabled: bool) {
+        self.dialect.pseudo_ops = enabled;
+    }
+
+    /// Rewrite BR, JSR, LD, LDI, LEA and ST instructions whose target is
+    /// out of range, rather than reporting an error (LC-3 only). Each
+    /// rewrite is reported as a warning. A far branch loads its target into
+    /// the register set by [`Assembler::set_relax_register`], R7 by
+    /// default, so that register must not hold a value the code still
+    /// needs at any branch that is rewritten. Far branches and calls also
+    /// set the condition codes from the loaded address, so a rewritten
+    /// conditional branch leaves them changed whichever way it goes.
+    pub fn set_relax(&mut self, enabled: bool) {
+        self.relax = enabled;
+    }
+
+    /// Make far branches rewritten by [`Assembler::set_relax`] jump through
+    /// `register` instead of R7.
+    pub fn set_relax_register(&mut self, register: Register) {
+        self.relax_register = Some(register);
+    }
+
+    /// The register far branches jump through.
+    fn relax_register(&self) -> Register {
+        self.relax_register.unwrap_or(Register(7))
+    }
+
+    /// Define the constant `name` (uppercased) before assembly, as with
+    /// `lc3 assemble -D NAME=value`. It is usable anywhere, including in
+    /// `.IF` conditions and `.IFDEF`.
+    pub fn define(&mut self, name: &str, value: i32) {
+        self.defines.insert(name.to_uppercase(), value);
+    }
+
+    /// Resolve `.INCLUDE` with `resolver`. `main` names the source passed to
+    /// the `assemble` methods; its includes are resolved relative to it.
+    /// Without a resolver, `.INCLUDE` is an error.
+    pub fn set_resolver(
+        &mut self,
+        main: impl Into<String>,
+        resolver: impl IncludeResolver + 'static,
+    ) {
+        self.includes = Some(Includes {
+            main: main.into(),
+            resolver: Box::new(resolver),
+        });
+    }
+
+    /// Get the files that went into the last assembly: the main source
+    /// followed by the files it included. Spans in errors index its text.
+    pub fn sources(&self) -> &SourceMap {
+        &self.sources
+    }
+
     /// Get the first origin address (set by first .ORIG directive during assembly).
     pub fn origin(&self) -> u16 {
         self.origin
     }
 
+    /// Get the labels of the last assembly and their addresses.
+    pub fn symbols(&self) -> &HashMap<String, u16> {
+        &self.symbols
+    }
+
+    /// Allow code in system space (x0000-x2FFF), as for an operating system.
+    /// Without it, such code gets a warning.
+    pub fn set_system_space(&mut self, allowed: bool) {
+        self.system_space = allowed;
+    }
+
+    /// Warnings from the last assembly, whether or not it succeeded.
+    pub fn warnings(&self) -> &[SemanticError] {
+        &self.warnings
+    }
+
+    /// Format the warnings of the last assembly with source context, as
+    /// [`Assembler::format_error`] does for errors.
+    pub fn format_warnings(&self) -> String {
+        self.warnings
+            .iter()
+            .map(|w| self.sources.report(&w.diagnostic()))
+            .collect()
+    }
+
     /// Get all assembled segments.
     /// Each segment has its own origin and code.
     pub fn segments(&self) -> &[Segment] {
@@ -254,91 +499,601 @@ impl Assembler {
     /// Assemble with detailed error information.
     /// For multi-segment programs, this returns all segments concatenated.
     pub fn assemble_with_errors(&mut self, source: &str) -> Result<Vec<u16>, AssemblyError> {
-        self.symbols.clear();
-        self.origin = 0x3000;
-        self.segments.clear();
+        self.assemble_program(source)?;
+        // Return concatenated code from all segments for backward compatibility
+        let code: Vec<u16> = self
+            .segments
+            .iter()
+            .flat_map(|s| s.code.iter().copied())
+            .collect();
+        Ok(code)
+    }
 
-        let program = parse(source).map_err(AssemblyError::ParseErrors)?;
+    /// Assemble and return separate segments with their origins.
+    pub fn assemble_segments(&mut self, source: &str) -> Result<Vec<Segment>, AssemblyError> {
+        self.assemble_program(source)?;
+        Ok(self.segments.clone())
+    }
 
-        let mut errors = Vec::new();
-        self.first_pass(&program, source, &mut errors);
-        self.second_pass(&program, source, &mut errors);
+    /// Assemble source and return lc3tools-compatible .obj bytes.
+    ///
+    /// This produces binary output compatible with the lc3tools simulator,
+    /// which explicitly marks segment origins with an `is_orig` flag.
+    pub fn assemble_to_lc3tools(&mut self, source: &str) -> Result<Vec<u8>, AssemblyError> {
+        self.assemble_program(source)?;
+        Ok(self.lc3tools_object())
+    }
 
-        if errors.is_empty() {
-            // Return concatenated code from all segments for backward compatibility
-            let code: Vec<u16> = self
-                .segments
-                .iter()
-                .flat_map(|s| s.code.iter().copied())
-                .collect();
-            Ok(code)
+    /// Assemble a module into an object file for a linker.
+    ///
+    /// A module without `.ORIG` becomes a single section that the linker
+    /// places; a module with `.ORIG` keeps its addresses. Words that depend
+    /// on where the linker puts things, or on `.EXTERNAL` symbols, get
+    /// relocations, and `.GLOBAL` symbols are exported.
+    pub fn assemble_object(&mut self, source: &str) -> Result<Object, AssemblyError> {
+        if self.isa() != Isa::Lc3 {
+            let message = "object files are only supported for LC-3".into();
+            return Err(AssemblyError::SemanticErrors(vec![make_error(
+                source,
+                0..0,
+                Code::Linking,
+                message,
+            )]));
+        }
+        self.object = true;
+        let result = self.assemble_program(source).map(|()| self.object_file());
+        self.object = false;
+        result
+    }
+
+    /// Format an error with source context for display. If the last
+    /// assembly included other files, they are shown as well, and the main
+    /// file goes by the name given to [`Assembler::set_resolver`].
+    pub fn format_error(&self, filename: &str, source: &str, error: &AssemblyError) -> String {
+        let sources = self.sources_named(filename, source);
+        match error {
+            AssemblyError::ParseErrors(errors) => format_errors_in(&sources, errors),
+            AssemblyError::SemanticErrors(errors) => format_semantic_errors_in(&sources, errors),
+        }
+    }
+
+    /// The warnings of the last assembly and the errors of `error`, as JSON
+    /// for tools, one diagnostic per line; see [`SourceMap::json`]. Files
+    /// are named as by [`Assembler::format_error`].
+    pub fn format_json(
+        &self,
+        filename: &str,
+        source: &str,
+        error: Option<&AssemblyError>,
+    ) -> String {
+        let sources = self.sources_named(filename, source);
+        let warnings = self.warnings.iter().map(SemanticError::diagnostic);
+        let errors = error.map(AssemblyError::diagnostics).unwrap_or_default();
+        warnings
+            .chain(errors)
+            .map(|diagnostic| sources.json(&diagnostic) + "\n")
+            .collect()
+    }
+
+    /// The files of the last assembly, with the main file named `filename`
+    /// unless the resolver named it.
+    fn sources_named(&self, filename: &str, source: &str) -> Cow<'_, SourceMap> {
+        if self.sources.files().len() > 1 {
+            Cow::Borrowed(&self.sources)
         } else {
-            Err(AssemblyError::SemanticErrors(errors))
+            Cow::Owned(SourceMap::new(filename, source))
         }
     }
 
-    /// Assemble and return separate segments with their origins.
-    pub fn assemble_segments(&mut self, source: &str) -> Result<Vec<Segment>, AssemblyError> {
+    fn reset(&mut self) {
         self.symbols.clear();
+        self.equates.clear();
+        self.sets.clear();
+        self.later_labels.clear();
         self.origin = 0x3000;
         self.segments.clear();
+        self.externals.clear();
+        self.globals.clear();
+        self.relocatable = false;
+        self.relocations.clear();
+        self.listing.clear();
+        self.origin_lines.clear();
+        self.warnings.clear();
+        self.literals.clear();
+        self.relaxed.clear();
+    }
 
-        let program = parse(source).map_err(AssemblyError::ParseErrors)?;
+    /// Resolve includes, then run both passes, leaving the result in
+    /// `self.segments`.
+    fn assemble_program(&mut self, source: &str) -> Result<(), AssemblyError> {
+        self.reset();
+        let failed_includes;
+        (self.sources, failed_includes) = match &mut self.includes {
+            Some(includes) => resolve_includes(&includes.main, source, includes.resolver.as_mut()),
+            None => (SourceMap::new("", source), Vec::new()),
+        };
+        // Spans index the text with includes expanded
+        let source = self.sources.text().to_string();
+
+        let program = self.parse(&source, failed_includes).map_err(|mut error| {
+            match &mut error {
+                AssemblyError::ParseErrors(errors) => {
+                    for e in errors {
+                        (e.file, e.line, e.column) = self.position(&e.span);
+                    }
+                }
+                AssemblyError::SemanticErrors(errors) => {
+                    for e in errors {
+                        (e.file, e.line, e.column) = self.position(&e.span);
+                    }
+                }
+            }
+            error
+        })?;
+
+        self.relocatable = self.object
+            && !program.lines.iter().any(|line| {
+                matches!(
+                    line.line,
+                    Line::Directive(Directive::Orig(_))
+                        | Line::LabeledDirective(_, Directive::Orig(_))
+                )
+            });
+        if self.relocatable {
+            self.origin = 0;
+        }
 
+        if self.relax && self.isa() == Isa::Lc3 {
+            self.find_far_instructions(&program, &source);
+        }
         let mut errors = Vec::new();
-        self.first_pass(&program, source, &mut errors);
-        self.second_pass(&program, source, &mut errors);
+        let addresses = self.first_pass(&program, &source, &mut errors);
+        self.second_pass(&program, &source, &mut errors);
+        if self.object {
+            self.exports(&source, &mut errors);
+        }
+        // Errors would make the warnings pass unreliable
+        if errors.is_empty() {
+            self.check_warnings(&program, &addresses, &source);
+        }
+        self.allow_warnings(&source);
 
+        let mut warnings = std::mem::take(&mut self.warnings);
+        for w in &mut warnings {
+            (w.file, w.line, w.column) = self.position(&w.span);
+        }
+        self.warnings = warnings;
         if errors.is_empty() {
-            Ok(self.segments.clone())
+            Ok(())
         } else {
+            for e in &mut errors {
+                (e.file, e.line, e.column) = self.position(&e.span);
+            }
             Err(AssemblyError::SemanticErrors(errors))
         }
     }
 
-    /// Assemble source and return lc3tools-compatible .obj bytes.
+    /// Build the object file from the last assembly.
+    fn object_file(&self) -> Object {
+        let mut sections: Vec<Section> = self
+            .segments
+            .iter()
+            .map(|segment| Section {
+                origin: (!self.relocatable).then_some(segment.origin),
+                code: segment.code.clone(),
+                relocations: Vec::new(),
+            })
+            .collect();
+        for (addr, relocation) in &self.relocations {
+            // Every relocation comes with a word of code, so lies in a segment
+            let Some(i) = self
+                .segments
+                .iter()
+                .position(|segment| addr.wrapping_sub(segment.origin) < segment.code.len() as u16)
+            else {
+                continue;
+            };
+            sections[i].relocations.push(Relocation {
+                offset: addr - self.segments[i].origin,
+                ..relocation.clone()
+            });
+        }
+        Object {
+            sections,
+            symbols: self.exports(self.sources.text(), &mut Vec::new()),
+        }
+    }
+
+    /// The `.GLOBAL` symbols and their values.
+    fn exports(&self, source: &str, errors: &mut Vec<SemanticError>) -> Vec<ObjectSymbol> {
+        let mut symbols: Vec<ObjectSymbol> = Vec::new();
+        for name in &self.globals {
+            if symbols.iter().any(|symbol| symbol.name == name.value) {
+                continue;
+            }
+            let defined =
+                self.symbols.contains_key(&name.value) || self.equates.contains_key(&name.value);
+            if !defined || self.externals.contains(&name.value) {
+                errors.push(make_error(
+                    source,
+                    name.span.clone(),
+                    Code::Linking,
+                    format!("global symbol {} is not defined", name.value),
+                ));
+                continue;
+            }
+            let symbol = Spanned::new(Expr::Symbol(name.value.clone()), name.span.clone());
+            let Some((value, target)) = self.relocate(&symbol, source, errors) else {
+                continue;
+            };
+            let relocatable = match target {
+                RelocTarget::Absolute => false,
+                RelocTarget::Section => true,
+                RelocTarget::External(_) => {
+                    errors.push(make_error(
+                        source,
+                        name.span.clone(),
+                        Code::Linking,
+                        format!("global symbol {} refers to an external symbol", name.value),
+                    ));
+                    continue;
+                }
+            };
+            if !(-0x8000..=0xFFFF).contains(&value) {
+                errors.push(make_error(
+                    source,
+                    name.span.clone(),
+                    Code::ValueOutOfRange,
+                    format!("value {value} does not fit in 16 bits"),
+                ));
+                continue;
+            }
+            symbols.push(ObjectSymbol {
+                name: name.value.clone(),
+                value: value as u16,
+                relocatable,
+            });
+        }
+        symbols
+    }
+
+    /// The file, line and column of `span` in the last assembly.
+    fn position(&self, span: &Span) -> (Option<String>, usize, usize) {
+        let (file, line, column) = self.sources.position(span.start);
+        (file.map(str::to_string), line, column)
+    }
+
+    /// Parse `source`, select its conditional blocks, expand its macros and
+    /// scope its local labels. Blocks are selected before expansion, so that
+    /// macros may be defined conditionally, and again after, for blocks in
+    /// macro bodies.
     ///
-    /// This produces binary output compatible with the lc3tools simulator,
-    /// which explicitly marks segment origins with an `is_orig` flag.
-    pub fn assemble_to_lc3tools(&mut self, source: &str) -> Result<Vec<u8>, AssemblyError> {
-        let segments = self.assemble_segments(source)?;
-        Ok(lc3tools_format::encode(&segments))
+    /// `failed_includes` are the errors of `.INCLUDE` lines that could not
+    /// be resolved, which are left in `source`. They are reported only for
+    /// lines that are assembled, so a block that is not taken may include a
+    /// file that does not exist.
+    fn parse(
+        &self,
+        source: &str,
+        failed_includes: Vec<ParseError>,
+    ) -> Result<Program, AssemblyError> {
+        let mut errors = Vec::new();
+        let program = parse_with(source, &self.dialect).map_err(AssemblyError::ParseErrors)?;
+        let program = self.select_conditionals(program, source, &mut errors);
+        if !errors.is_empty() {
+            return Err(AssemblyError::SemanticErrors(errors));
+        }
+        let program =
+            expand_macros(program, source, &self.dialect).map_err(AssemblyError::ParseErrors)?;
+        let mut program = self.select_conditionals(program, source, &mut errors);
+        if !errors.is_empty() {
+            return Err(AssemblyError::SemanticErrors(errors));
+        }
+        let failed_includes: Vec<_> = failed_includes
+            .into_iter()
+            .filter(|error| {
+                program.lines.iter().any(|line| {
+                    matches!(&line.line, Line::Directive(Directive::Include(path))
+                        if path.span == error.span)
+                })
+            })
+            .collect();
+        if !failed_includes.is_empty() {
+            return Err(AssemblyError::ParseErrors(failed_includes));
+        }
+        scope_local_labels(&mut program);
+        Ok(program)
     }
 
-    /// Format an error with source context for display.
-    pub fn format_error(&self, filename: &str, source: &str, error: &AssemblyError) -> String {
-        match error {
-            AssemblyError::ParseErrors(errors) => format_errors(filename, source, errors),
-            AssemblyError::SemanticErrors(errors) => {
-                format_semantic_errors(filename, source, errors)
+    /// Record the `.EQU` definitions and all label names, and check that no
+    /// name is defined twice, except by `.SET`.
+    fn define_constants(
+        &mut self,
+        program: &Program,
+        source: &str,
+        errors: &mut Vec<SemanticError>,
+    ) {
+        let mut sets = HashSet::new();
+        let mut externals = Vec::new();
+        // Where and how each label was first written
+        let mut labels: HashMap<&str, (&Span, &str)> = HashMap::new();
+        // The same for every name, outside macro expansions
+        let mut spellings: HashMap<&str, (&Span, &str)> = HashMap::new();
+        for spanned_line in &program.lines {
+            let first_error = errors.len();
+            if let Line::Directive(dir) | Line::LabeledDirective(_, dir) = &spanned_line.line {
+                match dir {
+                    Directive::External(names) => {
+                        externals.extend(names.iter().map(|name| (name, spanned_line)));
+                    }
+                    Directive::Global(names) => self.globals.extend(names.iter().cloned()),
+                    _ => {}
+                }
+            }
+            let (label, constant) = match &spanned_line.line {
+                Line::LabeledDirective(label, Directive::Equ(value)) => (label, Some(Some(value))),
+                Line::LabeledDirective(label, Directive::Set(_)) => (label, Some(None)),
+                Line::Label(label)
+                | Line::LabeledDirective(label, _)
+                | Line::LabeledInstruction(label, _) => (label, None),
+                _ => continue,
+            };
+            let name = &label.value;
+            // The label as written, unless it comes from a macro
+            let (span, spelling) = match &spanned_line.expanded_from {
+                Some(call) => (call, name.as_str()),
+                None => (&label.span, &source[label.span.clone()]),
+            };
+            if let Some(message) = confusing_label(spelling) {
+                let mut warning =
+                    make_error(source, label.span.clone(), Code::ConfusingLabel, message);
+                warning.expanded_from = spanned_line.expanded_from.clone();
+                self.warnings.push(warning);
+            }
+            if spanned_line.expanded_from.is_none() {
+                spellings.entry(name.as_str()).or_insert((span, spelling));
+            }
+            if constant.is_none() {
+                if let Some(&(first, before)) = labels.get(name.as_str()) {
+                    // A label that differs only in case is the same label;
+                    // the first definition stands
+                    let (code, message) = if before != spelling {
+                        let message = format!(
+                            "label {spelling} differs only in case from {before} at {}; \
+                             labels are not case-sensitive, so that definition is used",
+                            self.location(first)
+                        );
+                        (Code::LabelCase, message)
+                    } else {
+                        let message =
+                            format!("{spelling} is already defined at {}", self.location(first));
+                        (Code::DuplicateDefinition, message)
+                    };
+                    let mut diagnostic = make_error(source, label.span.clone(), code, message);
+                    let message = if code == Code::LabelCase {
+                        format!("first defined here as {before}")
+                    } else {
+                        "first defined here".into()
+                    };
+                    diagnostic.related.push(Related {
+                        span: first.clone(),
+                        message,
+                    });
+                    if code == Code::LabelCase {
+                        diagnostic.expanded_from = spanned_line.expanded_from.clone();
+                        self.warnings.push(diagnostic);
+                    } else {
+                        errors.push(diagnostic);
+                    }
+                } else {
+                    labels.insert(name, (span, spelling));
+                }
+            }
+            // `.SET` may redefine itself, but nothing else may share a name
+            let clash = self.equates.contains_key(name)
+                || match constant {
+                    Some(Some(_)) => sets.contains(name) || self.later_labels.contains(name),
+                    Some(None) => self.later_labels.contains(name),
+                    None => sets.contains(name),
+                };
+            if self.defines.contains_key(name) {
+                errors.push(make_error(
+                    source,
+                    label.span.clone(),
+                    Code::DuplicateDefinition,
+                    format!("{name} is already defined as a predefined constant"),
+                ));
+            } else if clash {
+                errors.push(make_error(
+                    source,
+                    label.span.clone(),
+                    Code::DuplicateDefinition,
+                    format!("{name} is already defined"),
+                ));
+            }
+            match constant {
+                Some(Some(value)) => {
+                    self.equates
+                        .entry(name.clone())
+                        .or_insert_with(|| value.clone());
+                }
+                Some(None) => {
+                    sets.insert(name.clone());
+                }
+                None => {
+                    self.later_labels.insert(name.clone());
+                }
+            }
+            for error in &mut errors[first_error..] {
+                error.expanded_from = spanned_line.expanded_from.clone();
+            }
+        }
+        for (name, spanned_line) in externals {
+            if self.equates.contains_key(&name.value)
+                || sets.contains(&name.value)
+                || self.later_labels.contains(&name.value)
+            {
+                let mut error = make_error(
+                    source,
+                    name.span.clone(),
+                    Code::DuplicateDefinition,
+                    format!("{} is already defined", name.value),
+                );
+                error.expanded_from = spanned_line.expanded_from.clone();
+                errors.push(error);
+            }
+            self.externals.insert(name.value.clone());
+        }
+        self.check_reference_case(program, source, &spellings);
+    }
+
+    /// Where `span` is, as `line 3` or `file.asm:3`.
+    fn location(&self, span: &Span) -> String {
+        let (file, line, _) = self.sources.position(span.start);
+        match file {
+            Some(file) => format!("{file}:{line}"),
+            None => format!("line {line}"),
+        }
+    }
+
+    /// Warn about references to a name written in a different case from
+    /// its definition; `spellings` holds the span and spelling of each
+    /// definition.
+    fn check_reference_case(
+        &mut self,
+        program: &Program,
+        source: &str,
+        spellings: &HashMap<&str, (&Span, &str)>,
+    ) {
+        for line in &program.lines {
+            if line.expanded_from.is_some() {
+                continue;
+            }
+            for reference in symbol_references(&line.line) {
+                let Some(&(span, spelling)) = spellings.get(reference.value.as_str()) else {
+                    continue;
+                };
+                let written = source.get(reference.span.clone()).unwrap_or_default();
+                if written == spelling || !written.eq_ignore_ascii_case(spelling) {
+                    continue;
+                }
+                let mut warning = make_error(
+                    source,
+                    reference.span.clone(),
+                    Code::LabelCase,
+                    format!(
+                        "{written} refers to {spelling} at {}, which is written in a different case",
+                        self.location(span)
+                    ),
+                );
+                warning.related.push(Related {
+                    span: span.clone(),
+                    message: format!("defined here as {spelling}"),
+                });
+                self.warnings.push(warning);
             }
         }
     }
 
-    fn first_pass(&mut self, program: &Program, _source: &str, _errors: &mut Vec<SemanticError>) {
+    /// Lay out memory, defining the labels. Returns the address of each
+    /// line.
+    fn first_pass(
+        &mut self,
+        program: &Program,
+        source: &str,
+        errors: &mut Vec<SemanticError>,
+    ) -> Vec<u16> {
+        let step = self.isa().word_size();
         let mut pc = self.origin;
         let mut first_orig = true;
-
-        for spanned_line in &program.lines {
+        // The literals of the current segment, numbered in order
+        let mut pool = 0..0;
+        let mut addresses = Vec::with_capacity(program.lines.len());
+        self.define_constants(program, source, errors);
+
+        for (i, spanned_line) in program.lines.iter().enumerate() {
+            let first_error = errors.len();
+            if ends_segment(&spanned_line.line) {
+                pc = self.place_literals(&mut pool, pc);
+            }
+            addresses.push(pc);
+            let relaxed = self.relaxed.contains(&i);
             match &spanned_line.line {
-                Line::Label(label) => {
-                    self.symbols.insert(label.value.clone(), pc);
+                Line::Label(label) => self.define_label(label, pc),
+                Line::LabeledDirective(_, Directive::Equ(_)) => {}
+                Line::LabeledDirective(label, Directive::Set(value)) => {
+                    // Later labels are unknown yet; the second pass reports errors
+                    self.set(label, value, source, &mut Vec::new());
                 }
                 Line::LabeledDirective(label, dir) => {
-                    self.symbols.insert(label.value.clone(), pc);
-                    pc = self.advance_pc_directive_first_pass(dir, pc, &mut first_orig);
+                    self.define_label(label, pc);
+                    pc = self.advance_pc_directive_first_pass(
+                        dir,
+                        pc,
+                        &mut first_orig,
+                        source,
+                        errors,
+                    );
                 }
-                Line::LabeledInstruction(label, _) => {
-                    self.symbols.insert(label.value.clone(), pc);
-                    pc += 1;
+                Line::LabeledInstruction(label, instr) => {
+                    self.define_label(label, pc);
+                    pc = instruction_end(instr, pc, step, relaxed, &mut pool);
                 }
                 Line::Directive(dir) => {
-                    pc = self.advance_pc_directive_first_pass(dir, pc, &mut first_orig);
+                    pc = self.advance_pc_directive_first_pass(
+                        dir,
+                        pc,
+                        &mut first_orig,
+                        source,
+                        errors,
+                    );
                 }
-                Line::Instruction(_) => pc += 1,
+                Line::Instruction(instr) => {
+                    pc = instruction_end(instr, pc, step, relaxed, &mut pool);
+                }
+                // Removed by `expand_macros`
+                Line::Macro(_) | Line::MacroCall(_) | Line::LabeledMacroCall(..) => {}
                 Line::Empty | Line::Error => {}
             }
+            for error in &mut errors[first_error..] {
+                error.expanded_from = spanned_line.expanded_from.clone();
+            }
+        }
+        self.place_literals(&mut pool, pc);
+        self.sets.clear();
+        addresses
+    }
+
+    fn define_label(&mut self, label: &Spanned<String>, pc: u16) {
+        self.later_labels.remove(&label.value);
+        // A second definition is an error, or a label written in another
+        // case; either way the first stands
+        self.symbols.entry(label.value.clone()).or_insert(pc);
+    }
+
+    /// Give the literals in `pool` addresses from `pc` on, at the end of
+    /// their segment, and empty it. Returns the address after them.
+    fn place_literals(&mut self, pool: &mut Range<usize>, mut pc: u16) -> u16 {
+        for n in pool.clone() {
+            self.literals.insert(pseudo::literal_name(n), pc);
+            pc = pc.wrapping_add(1);
         }
+        pool.start = pool.end;
+        pc
+    }
+
+    /// Give a `.SET` symbol its new value, or forget it if the value has errors.
+    fn set(
+        &mut self,
+        label: &Spanned<String>,
+        value: &Spanned<Expr>,
+        source: &str,
+        errors: &mut Vec<SemanticError>,
+    ) {
+        match self.absolute(value, source, errors) {
+            Some(v) => self.sets.insert(label.value.clone(), v),
+            None => self.sets.remove(&label.value),
+        };
     }
 
     /// Advance PC for directive during first pass.
@@ -348,31 +1103,72 @@ impl Assembler {
         dir: &Directive,
         pc: u16,
         first_orig: &mut bool,
+        source: &str,
+        errors: &mut Vec<SemanticError>,
     ) -> u16 {
         match dir {
             Directive::Orig(addr) => {
+                let addr = self.origin_address(addr, source, errors);
                 if *first_orig {
-                    self.origin = *addr;
+                    self.origin = addr;
                     *first_orig = false;
                 }
-                *addr
+                addr
+            }
+            Directive::Fill(values) => {
+                pc.wrapping_add((values.len() as u16).wrapping_mul(self.isa().word_size()))
             }
-            Directive::Fill(_) => pc + 1,
-            Directive::Blkw(n) => pc + n,
-            Directive::Stringz(s) => pc + s.len() as u16 + 1,
-            Directive::End => pc,
+            Directive::Blkw(n, _) => pc.wrapping_add(
+                self.block_size(n, source, errors)
+                    .wrapping_mul(self.isa().word_size()),
+            ),
+            Directive::Stringz(s) => pc.wrapping_add(
+                (stringz_words(s, self.isa()).len() as u16).wrapping_mul(self.isa().word_size()),
+            ),
+            Directive::Stringp(s) => pc
+                .wrapping_add((stringp_words(s).len() as u16).wrapping_mul(self.isa().word_size())),
+            Directive::End
+            | Directive::Equ(_)
+            | Directive::Set(_)
+            | Directive::Include(_)
+            | Directive::External(_)
+            | Directive::Global(_) => pc,
+            // Removed by `select_conditionals`
+            Directive::If(_)
+            | Directive::Ifdef(_)
+            | Directive::Ifndef(_)
+            | Directive::Elseif(_)
+            | Directive::Else
+            | Directive::Endif => pc,
         }
     }
 
     fn second_pass(&mut self, program: &Program, source: &str, errors: &mut Vec<SemanticError>) {
+        let step = self.isa().word_size();
         let mut current_origin = self.origin;
         let mut current_code: Vec<u16> = Vec::new();
         let mut pc = self.origin;
-        let mut in_segment = false;
-
-        for spanned_line in &program.lines {
+        // A relocatable module is a single segment without `.ORIG`
+        let mut in_segment = self.relocatable;
+        let mut origin_line = None;
+        let mut pool = pseudo::LiteralPool::default();
+
+        for (i, spanned_line) in program.lines.iter().enumerate() {
+            let first_error = errors.len();
+            let (line_pc, emitted) = (pc, current_code.len());
+            let mut expansion = Vec::new();
+            if ends_segment(&spanned_line.line) {
+                self.place_pool(&mut pool, &mut current_code);
+            }
             match &spanned_line.line {
                 Line::Label(_) => {}
+                Line::LabeledDirective(_, Directive::Equ(value)) => {
+                    // Report errors in the definition even if it is never used
+                    self.eval(value, source, errors);
+                }
+                Line::LabeledDirective(label, Directive::Set(value)) => {
+                    self.set(label, value, source, errors);
+                }
                 Line::LabeledDirective(_, dir) | Line::Directive(dir) => {
                     if let Directive::Orig(addr) = dir {
                         // Save current segment if it has code
@@ -381,10 +1177,22 @@ impl Assembler {
                                 origin: current_origin,
                                 code: std::mem::take(&mut current_code),
                             });
+                            self.origin_lines.push(origin_line.take());
+                        }
+                        // Any error was already reported by the first pass
+                        let addr = self.origin_address(addr, source, &mut Vec::new());
+                        if !addr.is_multiple_of(step) {
+                            errors.push(make_error(
+                                source,
+                                spanned_line.span.clone(),
+                                Code::SegmentLayout,
+                                format!("origin x{addr:04X} is not word-aligned"),
+                            ));
                         }
                         // Start new segment
-                        current_origin = *addr;
-                        pc = *addr;
+                        origin_line = Some(listing::line_span(spanned_line));
+                        current_origin = addr;
+                        pc = addr;
                         in_segment = true;
                     } else if let Directive::End = dir {
                         // Save current segment if it has code
@@ -393,6 +1201,7 @@ impl Assembler {
                                 origin: current_origin,
                                 code: std::mem::take(&mut current_code),
                             });
+                            self.origin_lines.push(origin_line.take());
                         }
                         in_segment = false;
                     } else {
@@ -402,6 +1211,22 @@ impl Assembler {
                         pc = new_pc;
                     }
                 }
+                Line::LabeledInstruction(_, Instruction::Pseudo(op))
+                | Line::Instruction(Instruction::Pseudo(op)) => {
+                    let words;
+                    (words, expansion) =
+                        self.emit_pseudo(op, pc, spanned_line, &mut pool, source, errors);
+                    pc = pc.wrapping_add(words.len() as u16 * step);
+                    current_code.extend(words);
+                }
+                Line::LabeledInstruction(_, instr) | Line::Instruction(instr)
+                    if self.relaxed.contains(&i) =>
+                {
+                    let words;
+                    (words, expansion) = self.emit_relaxed(instr, pc, spanned_line, source, errors);
+                    pc = pc.wrapping_add(words.len() as u16);
+                    current_code.extend(words);
+                }
                 Line::LabeledInstruction(_, instr) | Line::Instruction(instr) => {
                     current_code.push(self.emit_instruction(
                         instr,
@@ -410,68 +1235,232 @@ impl Assembler {
                         spanned_line.span.clone(),
                         errors,
                     ));
-                    pc += 1;
+                    pc = pc.wrapping_add(step);
                 }
+                Line::Macro(_) | Line::MacroCall(_) | Line::LabeledMacroCall(..) => {}
                 Line::Empty | Line::Error => {}
             }
+            for error in &mut errors[first_error..] {
+                error.expanded_from = spanned_line.expanded_from.clone();
+            }
+            if in_segment && current_code.len() > emitted {
+                self.listing.push(listing::Entry::new(
+                    line_pc,
+                    &current_code[emitted..],
+                    spanned_line,
+                    expansion,
+                ));
+            }
         }
 
         // Handle case where file doesn't end with .END
+        self.place_pool(&mut pool, &mut current_code);
         if in_segment && !current_code.is_empty() {
             self.segments.push(Segment {
                 origin: current_origin,
                 code: current_code,
             });
+            self.origin_lines.push(origin_line);
+        }
+        self.check_segments(source, errors);
+    }
+
+    /// Emit the literal pool at the end of the current segment.
+    fn place_pool(&mut self, pool: &mut pseudo::LiteralPool, code: &mut Vec<u16>) {
+        for entry in pool.entries.drain(..) {
+            code.extend(entry.words());
+            self.listing.push(entry);
+        }
+    }
+
+    /// Check that the segments fit in memory below the device registers,
+    /// don't overlap, and stay out of system space unless that is allowed.
+    fn check_segments(&mut self, source: &str, errors: &mut Vec<SemanticError>) {
+        let step = self.isa().word_size() as u32;
+        // Start and end (exclusive) of each segment with an `.ORIG`
+        let ranges: Vec<(u32, u32, &Span)> = self
+            .segments
+            .iter()
+            .zip(&self.origin_lines)
+            .filter_map(|(segment, line)| {
+                let start = segment.origin as u32;
+                Some((
+                    start,
+                    start + segment.code.len() as u32 * step,
+                    line.as_ref()?,
+                ))
+            })
+            .collect();
+        let describe = |start: u32, end: u32| format!("x{start:04X}-x{:04X}", end - 1);
+
+        for (i, &(start, end, span)) in ranges.iter().enumerate() {
+            let error = |code, message| make_error(source, span.clone(), code, message);
+            if end > 0x10000 {
+                errors.push(error(
+                    Code::SegmentLayout,
+                    format!(
+                        "segment at x{start:04X} is {} words long and runs past xFFFF",
+                        (end - start) / step
+                    ),
+                ));
+            } else if end > 0xFE00 {
+                errors.push(error(
+                    Code::SegmentLayout,
+                    format!(
+                        "segment at {} runs into the device registers (xFE00-xFFFF)",
+                        describe(start, end)
+                    ),
+                ));
+            }
+            if start < 0x3000 && !self.system_space {
+                self.warnings.push(error(
+                    Code::SystemSpace,
+                    format!(
+                        "segment at {} is in system space (x0000-x2FFF); \
+                     enable system space to assemble operating system code",
+                        describe(start, end.min(0x10000))
+                    ),
+                ));
+            }
+            for &(other_start, other_end, other_span) in &ranges[..i] {
+                if start < other_end && other_start < end {
+                    let (file, line, _) = self.sources.position(other_span.start);
+                    let location = match file {
+                        Some(file) => format!("{file}:{line}"),
+                        None => format!("line {line}"),
+                    };
+                    let mut overlap = error(
+                        Code::SegmentLayout,
+                        format!(
+                            "segment at {} overlaps the segment at {} from {location}",
+                            describe(start, end.min(0x10000)),
+                            describe(other_start, other_end.min(0x10000))
+                        ),
+                    );
+                    overlap.related.push(Related {
+                        span: other_span.clone(),
+                        message: "the other segment starts here".into(),
+                    });
+                    errors.push(overlap);
+                }
+            }
         }
     }
 
     fn emit_directive(
-        &self,
+        &mut self,
         dir: &Directive,
         pc: u16,
         source: &str,
         span: Span,
         errors: &mut Vec<SemanticError>,
     ) -> (Vec<u16>, u16) {
+        let step = self.isa().word_size();
+        // Each character of a string takes a byte, or a word holding one
+        if let Directive::Stringz(s) | Directive::Stringp(s) = dir
+            && let Some(c) = s.chars().find(|&c| c as u32 > 0xFF)
+        {
+            errors.push(make_error(
+                source,
+                span,
+                Code::ValueOutOfRange,
+                format!("character {c} (U+{:04X}) does not fit in a byte", c as u32),
+            ));
+            return (vec![], pc);
+        }
         match dir {
-            Directive::Orig(addr) => (vec![], *addr),
-            Directive::Fill(op) => (vec![self.resolve_operand(op, source, span, errors)], pc + 1),
-            Directive::Blkw(n) => (vec![0; *n as usize], pc + n),
+            Directive::Orig(_) => (vec![], pc),
+            Directive::Fill(values) => {
+                let mut words = Vec::with_capacity(values.len());
+                let mut pc = pc;
+                for op in values {
+                    words.push(self.resolve_operand(op, pc, source, span.clone(), errors));
+                    pc = pc.wrapping_add(step);
+                }
+                (words, pc)
+            }
+            Directive::Blkw(n, value) => {
+                // Any error in the count was already reported by the first pass
+                let count = self.block_size(n, source, &mut Vec::new());
+                let words = match value {
+                    // Each word may need its own relocation; report errors once
+                    Some(value) => (0..count)
+                        .map(|i| {
+                            let at = pc.wrapping_add(i.wrapping_mul(step));
+                            let mut ignored = Vec::new();
+                            let errors = if i == 0 { &mut *errors } else { &mut ignored };
+                            self.fill_word(value, at, source, errors).unwrap_or(0)
+                        })
+                        .collect(),
+                    None => vec![0; count as usize],
+                };
+                (words, pc.wrapping_add(count.wrapping_mul(step)))
+            }
             Directive::Stringz(s) => {
-                let mut words: Vec<u16> = s.chars().map(|c| c as u16).collect();
-                words.push(0);
+                let words = stringz_words(s, self.isa());
                 let len = words.len() as u16;
-                (words, pc + len)
+                (words, pc.wrapping_add(len.wrapping_mul(step)))
             }
-            Directive::End => (vec![], pc),
-        }
-    }
-
-    fn resolve_operand(
+            Directive::Stringp(s) => {
+                let words = stringp_words(s);
+                let len = words.len() as u16;
+                (words, pc.wrapping_add(len.wrapping_mul(step)))
+            }
+            Directive::Include(path) => {
+                // Without a resolver, `.INCLUDE` lines survive to here
+                errors.push(make_error(
+                    source,
+                    path.span.clone(),
+                    Code::IncludeFailed,
+                    format!("cannot include {}: no include resolver", path.value),
+                ));
+                (vec![], pc)
+            }
+            Directive::End
+            | Directive::Equ(_)
+            | Directive::Set(_)
+            | Directive::External(_)
+            | Directive::Global(_) => (vec![], pc),
+            // Removed by `select_conditionals`
+            Directive::If(_)
+            | Directive::Ifdef(_)
+            | Directive::Ifndef(_)
+            | Directive::Elseif(_)
+            | Directive::Else
+            | Directive::Endif => (vec![], pc),
+        }
+    }
+
+    fn origin_address(
         &self,
+        addr: &Spanned<Expr>,
+        source: &str,
+        errors: &mut Vec<SemanticError>,
+    ) -> u16 {
+        self.resolve_in(addr, 0..=0xFFFF, "origin", source, errors)
+            .unwrap_or(0) as u16
+    }
+
+    fn block_size(&self, n: &Spanned<Expr>, source: &str, errors: &mut Vec<SemanticError>) -> u16 {
+        self.resolve_in(n, 0..=0xFFFF, "block size", source, errors)
+            .unwrap_or(0) as u16
+    }
+
+    fn resolve_operand(
+        &mut self,
         op: &Operand,
+        pc: u16,
         source: &str,
         span: Span,
         errors: &mut Vec<SemanticError>,
     ) -> u16 {
         match op {
-            Operand::Immediate(v) => *v as u16,
-            Operand::Label(label) => {
-                if let Some(&addr) = self.symbols.get(&label.value) {
-                    addr
-                } else {
-                    errors.push(make_error(
-                        source,
-                        label.span.clone(),
-                        format!("undefined symbol: {}", label.value),
-                    ));
-                    0
-                }
-            }
+            Operand::Expr(value) => self.fill_word(value, pc, source, errors).unwrap_or(0),
             Operand::Register(_) => {
                 errors.push(make_error(
                     source,
                     span,
+                    Code::InvalidOperand,
                     "cannot use register as value".into(),
                 ));
                 0
@@ -480,6 +1469,7 @@ impl Assembler {
                 errors.push(make_error(
                     source,
                     span,
+                    Code::InvalidOperand,
                     "cannot use string as value".into(),
                 ));
                 0
@@ -487,27 +1477,293 @@ impl Assembler {
         }
     }
 
-    fn resolve_label(
-        &self,
-        label: &Spanned<String>,
+    /// Evaluate a `.FILL` word at `pc`, recording a relocation if it holds
+    /// an address known only after linking.
+    fn fill_word(
+        &mut self,
+        value: &Spanned<Expr>,
         pc: u16,
         source: &str,
         errors: &mut Vec<SemanticError>,
-    ) -> i16 {
-        if let Some(&addr) = self.symbols.get(&label.value) {
-            addr.wrapping_sub(pc + 1) as i16
-        } else {
+    ) -> Option<u16> {
+        let (v, target) = self.relocate(value, source, errors)?;
+        if !(-0x8000..=0xFFFF).contains(&v) {
+            errors.push(make_error(
+                source,
+                value.span.clone(),
+                Code::ValueOutOfRange,
+                format!("value {v} does not fit in 16 bits"),
+            ));
+            return None;
+        }
+        if target != RelocTarget::Absolute {
+            self.relocations.push((
+                pc,
+                Relocation {
+                    offset: 0,
+                    kind: RelocKind::Word,
+                    target,
+                    addend: v,
+                },
+            ));
+        }
+        Some(v as u16)
+    }
+
+    /// The `bits`-bit PC offset from the instruction at `at` to `label`. If
+    /// the offset is known only after linking, it is left 0 and a
+    /// relocation is recorded instead.
+    fn pc_offset(&mut self, label: &Spanned<Expr>, bits: u8, op: &str, at: &mut Emit) -> u16 {
+        let (pc, source, errors) = (at.pc, at.source, &mut *at.errors);
+        let Some((value, target)) = self.relocate(label, source, errors) else {
+            return 0;
+        };
+        // Within a relocatable module, addresses move together with the PC
+        let linked = match target {
+            RelocTarget::Absolute => self.relocatable,
+            RelocTarget::Section => false,
+            RelocTarget::External(_) => true,
+        };
+        if linked {
+            self.relocations.push((
+                pc,
+                Relocation {
+                    offset: 0,
+                    kind: RelocKind::PcOffset(bits),
+                    target,
+                    addend: value,
+                },
+            ));
+            return 0;
+        }
+        let Ok(addr) = u16::try_from(value) else {
             errors.push(make_error(
                 source,
                 label.span.clone(),
-                format!("undefined symbol: {}", label.value),
+                Code::ValueOutOfRange,
+                format!("address {value} is outside memory (0 to xFFFF)"),
             ));
-            0
+            return 0;
+        };
+        // Offsets count words; on LC-3b both addresses are even
+        let step = self.isa().word_size();
+        let offset = (addr.wrapping_sub(pc.wrapping_add(step)) as i16) / step as i16;
+        check_offset(offset, bits, op, source, label.span.clone(), errors);
+        offset as u16 & ((1 << bits) - 1)
+    }
+
+    /// Evaluate an expression over the labels and constants.
+    fn eval(
+        &self,
+        value: &Spanned<Expr>,
+        source: &str,
+        errors: &mut Vec<SemanticError>,
+    ) -> Option<i32> {
+        match self.eval_moved(value, None) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                // An error inside a constant shows up at every use; report it once
+                if !errors
+                    .iter()
+                    .any(|old| old.span == e.span && old.message == e.message)
+                {
+                    errors.push(eval_error(source, e));
+                }
+                None
+            }
         }
     }
 
-    fn emit_instruction(
+    /// Evaluate an expression with the addresses of `moved` displaced by
+    /// [`MOVE`].
+    fn eval_moved(
+        &self,
+        value: &Spanned<Expr>,
+        moved: Option<&RelocTarget>,
+    ) -> Result<i32, EvalError> {
+        value.eval(&mut |name, span| self.lookup(name, span, &mut Vec::new(), moved))
+    }
+
+    /// Evaluate an expression and find the address it is relative to: the
+    /// module's own section, an external symbol, or nothing. The value is
+    /// the offset from that address. Outside object files, nothing moves.
+    fn relocate(
+        &self,
+        value: &Spanned<Expr>,
+        source: &str,
+        errors: &mut Vec<SemanticError>,
+    ) -> Option<(i32, RelocTarget)> {
+        let v = self.eval(value, source, errors)?;
+        if !self.object {
+            return Some((v, RelocTarget::Absolute));
+        }
+        // Displace each address that may move in turn; the value must
+        // follow at most one of them, exactly
+        let mut candidates: Vec<RelocTarget> = self
+            .externals
+            .iter()
+            .map(|name| RelocTarget::External(name.clone()))
+            .collect();
+        if self.relocatable {
+            candidates.push(RelocTarget::Section);
+        }
+        let mut target = RelocTarget::Absolute;
+        for candidate in candidates {
+            match self.eval_moved(value, Some(&candidate)) {
+                Ok(moved) if moved == v => {}
+                Ok(moved) if moved - v == MOVE && target == RelocTarget::Absolute => {
+                    target = candidate;
+                }
+                _ => {
+                    errors.push(make_error(
+                        source,
+                        value.span.clone(),
+                        Code::Linking,
+                        "expression cannot be relocated".into(),
+                    ));
+                    return None;
+                }
+            }
+        }
+        Some((v, target))
+    }
+
+    /// Evaluate an expression that must not depend on addresses known only
+    /// after linking.
+    fn absolute(
+        &self,
+        value: &Spanned<Expr>,
+        source: &str,
+        errors: &mut Vec<SemanticError>,
+    ) -> Option<i32> {
+        let (v, target) = self.relocate(value, source, errors)?;
+        if target == RelocTarget::Absolute {
+            Some(v)
+        } else {
+            errors.push(make_error(
+                source,
+                value.span.clone(),
+                Code::Linking,
+                "value depends on an address known only after linking".into(),
+            ));
+            None
+        }
+    }
+
+    /// Resolve a symbol. `resolving` holds the constants being evaluated,
+    /// to detect circular definitions; `moved` is displaced by [`MOVE`].
+    fn lookup(
+        &self,
+        name: &str,
+        span: &Span,
+        resolving: &mut Vec<String>,
+        moved: Option<&RelocTarget>,
+    ) -> Result<i32, EvalError> {
+        if self.externals.contains(name) {
+            if !self.object {
+                return Err(EvalError::new(
+                    span.clone(),
+                    Code::Linking,
+                    format!("{name} is declared .EXTERNAL; assemble an object file and link it"),
+                ));
+            }
+            let is_moved = matches!(moved, Some(RelocTarget::External(n)) if n == name);
+            Ok(if is_moved { MOVE } else { 0 })
+        } else if let Some(&addr) = self.symbols.get(name).or(self.literals.get(name)) {
+            let is_moved = self.relocatable && moved == Some(&RelocTarget::Section);
+            Ok(addr as i32 + if is_moved { MOVE } else { 0 })
+        } else if let Some(&value) = self.sets.get(name).or(self.defines.get(name)) {
+            Ok(value)
+        } else if let Some(value) = self.equates.get(name) {
+            if resolving.iter().any(|n| n == name) {
+                return Err(EvalError::new(
+                    span.clone(),
+                    Code::CircularDefinition,
+                    format!("circular definition of {name}"),
+                ));
+            }
+            resolving.push(name.to_string());
+            let result = value.eval(&mut |name, span| self.lookup(name, span, resolving, moved));
+            resolving.pop();
+            result
+        } else if self.later_labels.contains(name) {
+            // Only possible in the first pass, while laying out memory
+            Err(EvalError::new(
+                span.clone(),
+                Code::CircularDefinition,
+                format!(
+                    "circular definition: {name} is defined after this directive, \
+                     so its address may depend on it"
+                ),
+            ))
+        } else {
+            let mut error = EvalError::new(
+                span.clone(),
+                Code::UndefinedSymbol,
+                format!("undefined symbol: {name}"),
+            );
+            let names = (self.symbols.keys())
+                .chain(self.equates.keys())
+                .chain(self.sets.keys())
+                .chain(self.defines.keys());
+            let names = names.map(String::as_str);
+            error
+                .suggestions
+                .extend(did_you_mean(name, span.clone(), names));
+            Err(error)
+        }
+    }
+
+    /// Evaluate an expression that must fit in a 16-bit word, signed or not.
+    fn resolve_word(
+        &self,
+        value: &Spanned<Expr>,
+        source: &str,
+        errors: &mut Vec<SemanticError>,
+    ) -> Option<u16> {
+        let v = self.absolute(value, source, errors)?;
+        if (-0x8000..=0xFFFF).contains(&v) {
+            Some(v as u16)
+        } else {
+            errors.push(make_error(
+                source,
+                value.span.clone(),
+                Code::ValueOutOfRange,
+                format!("value {v} does not fit in 16 bits"),
+            ));
+            None
+        }
+    }
+
+    /// Evaluate an expression that must lie in `range`.
+    fn resolve_in(
+        &self,
+        value: &Spanned<Expr>,
+        range: RangeInclusive<i32>,
+        what: &str,
+        source: &str,
+        errors: &mut Vec<SemanticError>,
+    ) -> Option<i32> {
+        let v = self.absolute(value, source, errors)?;
+        check_range(v, range, what, value, source, errors)
+    }
+
+    /// Evaluate a signed field. Words from x8000 to xFFFF count as negative,
+    /// so `xFFFF` is -1.
+    fn resolve_signed(
         &self,
+        value: &Spanned<Expr>,
+        range: RangeInclusive<i32>,
+        what: &str,
+        source: &str,
+        errors: &mut Vec<SemanticError>,
+    ) -> Option<i32> {
+        let v = self.resolve_word(value, source, errors)? as i16 as i32;
+        check_range(v, range, what, value, source, errors)
+    }
+
+    fn emit_instruction(
+        &mut self,
         instr: &Instruction,
         pc: u16,
         source: &str,
@@ -515,27 +1771,27 @@ impl Assembler {
         errors: &mut Vec<SemanticError>,
     ) -> u16 {
         use Instruction::*;
+        let at = &mut Emit { pc, source, errors };
         match instr {
-            Add { dr, sr1, src2 } => self.emit_alu(0b0001, *dr, *sr1, src2, source, span, errors),
-            And { dr, sr1, src2 } => self.emit_alu(0b0101, *dr, *sr1, src2, source, span, errors),
+            Add { dr, sr1, src2 } => self.emit_alu(0b0001, *dr, *sr1, src2, at),
+            And { dr, sr1, src2 } => self.emit_alu(0b0101, *dr, *sr1, src2, at),
             Not { dr, sr } => (0b1001 << 12) | (dr.0 as u16) << 9 | (sr.0 as u16) << 6 | 0x3F,
-            Br { n, z, p, label } => self.emit_br(*n, *z, *p, label, pc, source, errors),
+            Br { n, z, p, label } => self.emit_br(*n, *z, *p, label, at),
             Jmp { base } => (0b1100 << 12) | (base.0 as u16) << 6,
             Ret => 0xC1C0,
-            Jsr { label } => self.emit_jsr(label, pc, source, errors),
+            Jsr { label } => self.emit_jsr(label, at),
             Jsrr { base } => (0b0100 << 12) | (base.0 as u16) << 6,
-            Ld { dr, label } => self.emit_pc_offset(0b0010, dr.0, label, pc, 9, source, errors),
-            Ldi { dr, label } => self.emit_pc_offset(0b1010, dr.0, label, pc, 9, source, errors),
-            Ldr { dr, base, offset } => {
-                self.emit_base_offset(0b0110, dr.0, base.0, *offset, source, span, errors)
-            }
-            Lea { dr, label } => self.emit_pc_offset(0b1110, dr.0, label, pc, 9, source, errors),
-            St { sr, label } => self.emit_pc_offset(0b0011, sr.0, label, pc, 9, source, errors),
-            Sti { sr, label } => self.emit_pc_offset(0b1011, sr.0, label, pc, 9, source, errors),
-            Str { sr, base, offset } => {
-                self.emit_base_offset(0b0111, sr.0, base.0, *offset, source, span, errors)
-            }
-            Trap { trapvect } => 0xF000 | (*trapvect as u16),
+            Ld { dr, label } => self.emit_pc_offset(0b0010, dr.0, label, 9, at),
+            Ldi { dr, label } => self.emit_pc_offset(0b1010, dr.0, label, 9, at),
+            Ldr { dr, base, offset } => self.emit_base_offset(0b0110, dr.0, base.0, offset, at),
+            Lea { dr, label } => self.emit_pc_offset(0b1110, dr.0, label, 9, at),
+            St { sr, label } => self.emit_pc_offset(0b0011, sr.0, label, 9, at),
+            Sti { sr, label } => self.emit_pc_offset(0b1011, sr.0, label, 9, at),
+            Str { sr, base, offset } => self.emit_base_offset(0b0111, sr.0, base.0, offset, at),
+            Trap { trapvect } => {
+                let vector = self.resolve_in(trapvect, 0..=0xFF, "trap vector", source, at.errors);
+                0xF000 | vector.unwrap_or(0) as u16
+            }
             Getc => 0xF020,
             Out => 0xF021,
             Puts => 0xF022,
@@ -543,7 +1799,97 @@ impl Assembler {
             Putsp => 0xF024,
             Halt => 0xF025,
             Rti => 0x8000,
+            Xor { dr, sr1, src2 } => self.emit_alu(0b1001, *dr, *sr1, src2, at),
+            Shf {
+                dr,
+                sr,
+                kind,
+                amount,
+            } => {
+                let kind_bits = match kind {
+                    ShiftKind::Left => 0b00,
+                    ShiftKind::RightLogical => 0b01,
+                    ShiftKind::RightArithmetic => 0b11,
+                };
+                let amount = self.resolve_in(amount, 0..=15, "shift amount", source, at.errors);
+                (0b1101 << 12)
+                    | (dr.0 as u16) << 9
+                    | (sr.0 as u16) << 6
+                    | kind_bits << 4
+                    | amount.unwrap_or(0) as u16
+            }
+            Ldb { dr, base, offset } => self.emit_base_offset(0b0010, dr.0, base.0, offset, at),
+            Stb { sr, base, offset } => self.emit_base_offset(0b0011, sr.0, base.0, offset, at),
+            Ldw { dr, base, offset } => self.emit_base_offset(0b0110, dr.0, base.0, offset, at),
+            Stw { sr, base, offset } => self.emit_base_offset(0b0111, sr.0, base.0, offset, at),
+            Custom { mnemonic, operands } => self.emit_custom(mnemonic, operands, span, at),
+            Pseudo(_) => unreachable!("pseudo-instructions are expanded by emit_pseudo"),
+        }
+    }
+
+    fn emit_custom(
+        &mut self,
+        mnemonic: &str,
+        operands: &[Operand],
+        span: Span,
+        at: &mut Emit,
+    ) -> u16 {
+        let (source, errors) = (at.source, &mut *at.errors);
+        let Some(ext) = self
+            .dialect
+            .extensions
+            .iter()
+            .find(|ext| ext.mnemonic == mnemonic)
+        else {
+            errors.push(make_error(
+                source,
+                span,
+                Code::UnknownInstruction,
+                format!("unknown instruction: {mnemonic}"),
+            ));
+            return 0;
+        };
+        let mut word = (0b1101 << 12) | (ext.bits & 0x0FFF);
+        let reg = |i: usize| match operands.get(i) {
+            Some(Operand::Register(r)) => r.0 as u16,
+            _ => 0,
+        };
+        let mut immediate = |i: usize, min: i16, max: i16| match operands.get(i) {
+            Some(Operand::Expr(value)) => {
+                let n = self.resolve_word(value, source, errors).unwrap_or(0) as i16;
+                if n < min || n > max {
+                    errors.push(make_error(
+                        source,
+                        span.clone(),
+                        Code::ValueOutOfRange,
+                        format!("{mnemonic} operand out of range ({min} to {max})"),
+                    ));
+                }
+                Some(n as u16)
+            }
+            _ => None,
+        };
+        match ext.format {
+            ExtensionFormat::None => {}
+            ExtensionFormat::Alu => {
+                word |= reg(0) << 9 | reg(1) << 6;
+                word |= match immediate(2, -16, 15) {
+                    Some(imm) => (1 << 5) | (imm & 0x1F),
+                    None => reg(2),
+                };
+            }
+            ExtensionFormat::RegReg => word |= reg(0) << 9 | reg(1) << 6,
+            ExtensionFormat::BaseOffset => {
+                let offset = immediate(2, -32, 31).unwrap_or(0);
+                word |= reg(0) << 9 | reg(1) << 6 | (offset & 0x3F);
+            }
+            ExtensionFormat::PcOffset => {
+                if let Some(Operand::Expr(label)) = operands.get(1) {
+                    word |= reg(0) << 9 | self.pc_offset(label, 9, mnemonic, at);
+                }
+            }
         }
+        word
     }
 
     fn emit_alu<T: AluSrc2>(
@@ -552,62 +1898,39 @@ impl Assembler {
         dr: Register,
         sr1: Register,
         src2: &T,
-        source: &str,
-        span: Span,
-        errors: &mut Vec<SemanticError>,
+        at: &mut Emit,
     ) -> u16 {
         let base = (op << 12) | (dr.0 as u16) << 9 | (sr1.0 as u16) << 6;
-        src2.encode(base, source, span, errors)
+        match src2.src2() {
+            Src2::Register(r) => base | r.0 as u16,
+            Src2::Immediate(imm) => {
+                let imm =
+                    self.resolve_signed(imm, -16..=15, "immediate value", at.source, at.errors);
+                base | (1 << 5) | (imm.unwrap_or(0) as u16 & 0x1F)
+            }
+        }
     }
 
-    fn emit_br(
-        &self,
-        n: bool,
-        z: bool,
-        p: bool,
-        label: &Spanned<String>,
-        pc: u16,
-        source: &str,
-        errors: &mut Vec<SemanticError>,
-    ) -> u16 {
-        let offset = self.resolve_label(label, pc, source, errors);
-        check_offset(offset, 9, "BR", source, label.span.clone(), errors);
-        (n as u16) << 11 | (z as u16) << 10 | (p as u16) << 9 | (offset as u16 & 0x1FF)
+    fn emit_br(&mut self, n: bool, z: bool, p: bool, label: &Spanned<Expr>, at: &mut Emit) -> u16 {
+        let offset = self.pc_offset(label, 9, "BR", at);
+        (n as u16) << 11 | (z as u16) << 10 | (p as u16) << 9 | offset
     }
 
-    fn emit_jsr(
-        &self,
-        label: &Spanned<String>,
-        pc: u16,
-        source: &str,
-        errors: &mut Vec<SemanticError>,
-    ) -> u16 {
-        let offset = self.resolve_label(label, pc, source, errors);
-        check_offset(offset, 11, "JSR", source, label.span.clone(), errors);
-        (0b0100 << 12) | (1 << 11) | (offset as u16 & 0x7FF)
+    fn emit_jsr(&mut self, label: &Spanned<Expr>, at: &mut Emit) -> u16 {
+        let offset = self.pc_offset(label, 11, "JSR", at);
+        (0b0100 << 12) | (1 << 11) | offset
     }
 
     fn emit_pc_offset(
-        &self,
+        &mut self,
         op: u16,
         reg: u8,
-        label: &Spanned<String>,
-        pc: u16,
+        label: &Spanned<Expr>,
         bits: u8,
-        source: &str,
-        errors: &mut Vec<SemanticError>,
+        at: &mut Emit,
     ) -> u16 {
-        let offset = self.resolve_label(label, pc, source, errors);
-        check_offset(
-            offset,
-            bits,
-            op_name(op),
-            source,
-            label.span.clone(),
-            errors,
-        );
-        let mask = (1u16 << bits) - 1;
-        (op << 12) | (reg as u16) << 9 | (offset as u16 & mask)
+        let offset = self.pc_offset(label, bits, op_name(op, self.isa()), at);
+        (op << 12) | (reg as u16) << 9 | offset
     }
 
     fn emit_base_offset(
@@ -615,58 +1938,79 @@ impl Assembler {
         op: u16,
         reg: u8,
         base: u8,
-        offset: i8,
-        source: &str,
-        span: Span,
-        errors: &mut Vec<SemanticError>,
+        offset: &Spanned<Expr>,
+        at: &mut Emit,
     ) -> u16 {
-        if !(-32..=31).contains(&offset) {
-            errors.push(make_error(
-                source,
-                span,
-                format!("{} offset out of range (-32 to 31)", op_name(op)),
-            ));
+        let what = format!("{} offset", op_name(op, self.isa()));
+        let offset = self.resolve_signed(offset, -32..=31, &what, at.source, at.errors);
+        (op << 12) | (reg as u16) << 9 | (base as u16) << 6 | (offset.unwrap_or(0) as u16 & 0x3F)
+    }
+}
+
+/// Whether `line` ends the current segment, so its literal pool goes
+/// before it.
+fn ends_segment(line: &Line) -> bool {
+    matches!(
+        line,
+        Line::Directive(Directive::Orig(_) | Directive::End)
+            | Line::LabeledDirective(_, Directive::Orig(_) | Directive::End)
+    )
+}
+
+/// The address after `instr` at `pc`, which may have been `relaxed`, adding
+/// its literal, if any, to `pool`.
+fn instruction_end(
+    instr: &Instruction,
+    pc: u16,
+    step: u16,
+    relaxed: bool,
+    pool: &mut Range<usize>,
+) -> u16 {
+    match instr {
+        _ if relaxed => pc.wrapping_add(relax::size(instr)),
+        Instruction::Pseudo(op) => {
+            if pseudo::literal(op).is_some() {
+                pool.end += 1;
+            }
+            pc.wrapping_add(pseudo::size(op).wrapping_mul(step))
         }
-        (op << 12) | (reg as u16) << 9 | (base as u16) << 6 | (offset as u16 & 0x3F)
+        _ => pc.wrapping_add(step),
     }
 }
 
+/// The second source operand of ADD, AND and XOR.
+enum Src2<'a> {
+    Register(Register),
+    Immediate(&'a Spanned<Expr>),
+}
+
 trait AluSrc2 {
-    fn encode(&self, base: u16, source: &str, span: Span, errors: &mut Vec<SemanticError>) -> u16;
+    fn src2(&self) -> Src2<'_>;
 }
 
 impl AluSrc2 for AddSrc2 {
-    fn encode(&self, base: u16, source: &str, span: Span, errors: &mut Vec<SemanticError>) -> u16 {
+    fn src2(&self) -> Src2<'_> {
         match self {
-            AddSrc2::Register(r) => base | r.0 as u16,
-            AddSrc2::Immediate(imm) => {
-                if *imm < -16 || *imm > 15 {
-                    errors.push(make_error(
-                        source,
-                        span,
-                        "immediate value out of range (-16 to 15)".into(),
-                    ));
-                }
-                base | (1 << 5) | (*imm as u16 & 0x1F)
-            }
+            AddSrc2::Register(r) => Src2::Register(*r),
+            AddSrc2::Immediate(imm) => Src2::Immediate(imm),
+        }
+    }
+}
+
+impl AluSrc2 for XorSrc2 {
+    fn src2(&self) -> Src2<'_> {
+        match self {
+            XorSrc2::Register(r) => Src2::Register(*r),
+            XorSrc2::Immediate(imm) => Src2::Immediate(imm),
         }
     }
 }
 
 impl AluSrc2 for AndSrc2 {
-    fn encode(&self, base: u16, source: &str, span: Span, errors: &mut Vec<SemanticError>) -> u16 {
+    fn src2(&self) -> Src2<'_> {
         match self {
-            AndSrc2::Register(r) => base | r.0 as u16,
-            AndSrc2::Immediate(imm) => {
-                if *imm < -16 || *imm > 15 {
-                    errors.push(make_error(
-                        source,
-                        span,
-                        "immediate value out of range (-16 to 15)".into(),
-                    ));
-                }
-                base | (1 << 5) | (*imm as u16 & 0x1F)
-            }
+            AndSrc2::Register(r) => Src2::Register(*r),
+            AndSrc2::Immediate(imm) => Src2::Immediate(imm),
         }
     }
 }
@@ -685,11 +2029,34 @@ fn check_offset(
         errors.push(make_error(
             source,
             span,
+            Code::OffsetOutOfRange,
             format!("{op} offset out of range ({min} to {max})"),
         ));
     }
 }
 
+/// Report `v` if it lies outside `range`.
+fn check_range(
+    v: i32,
+    range: RangeInclusive<i32>,
+    what: &str,
+    value: &Spanned<Expr>,
+    source: &str,
+    errors: &mut Vec<SemanticError>,
+) -> Option<i32> {
+    if range.contains(&v) {
+        Some(v)
+    } else {
+        errors.push(make_error(
+            source,
+            value.span.clone(),
+            Code::ValueOutOfRange,
+            format!("{what} out of range ({} to {})", range.start(), range.end()),
+        ));
+        None
+    }
+}
+
 /// Convert a byte offset to (line, column) in source.
 fn offset_to_pos(source: &str, offset: usize) -> (usize, usize) {
     let mut line = 1;
@@ -709,48 +2076,74 @@ fn offset_to_pos(source: &str, offset: usize) -> (usize, usize) {
 }
 
 /// Create a semantic error from a span.
-fn make_error(source: &str, span: Span, message: String) -> SemanticError {
+fn make_error(source: &str, span: Span, code: Code, message: String) -> SemanticError {
     let (line, column) = offset_to_pos(source, span.start);
     SemanticError {
         message,
         line,
         column,
         span,
+        expanded_from: None,
+        file: None,
+        code,
+        related: Vec::new(),
+        suggestions: Vec::new(),
     }
 }
 
+/// Create a semantic error from an expression that failed to evaluate.
+fn eval_error(source: &str, e: EvalError) -> SemanticError {
+    let mut error = make_error(source, e.span, e.code, e.message);
+    error.suggestions = e.suggestions;
+    error
+}
+
 /// Format semantic errors with source context for pretty display.
 pub fn format_semantic_errors(filename: &str, source: &str, errors: &[SemanticError]) -> String {
-    use ariadne::{Color, Label, Report, ReportKind, Source};
-
-    let mut output = Vec::new();
-    for error in errors {
-        Report::<(&str, std::ops::Range<usize>)>::build(
-            ReportKind::Error,
-            (filename, error.span.clone()),
-        )
-        .with_message(&error.message)
-        .with_label(
-            Label::new((filename, error.span.clone()))
-                .with_message(&error.message)
-                .with_color(Color::Red),
-        )
-        .finish()
-        .write((filename, Source::from(source)), &mut output)
-        .unwrap();
-    }
-    String::from_utf8(output).unwrap_or_else(|_| "error formatting output".into())
+    format_semantic_errors_in(&SourceMap::new(filename, source), errors)
+}
+
+/// Format semantic errors in a program that may include other files.
+pub fn format_semantic_errors_in(sources: &SourceMap, errors: &[SemanticError]) -> String {
+    errors
+        .iter()
+        .map(|error| sources.report(&error.diagnostic()))
+        .collect()
+}
+
+/// Encode a `.STRINGZ` string: one character per word on LC-3, packed
+/// little-endian bytes on LC-3b. Both include the terminating NUL. Every
+/// character must fit in a byte.
+fn stringz_words(s: &str, isa: Isa) -> Vec<u16> {
+    match isa {
+        Isa::Lc3 => s.chars().map(|c| c as u16).chain([0]).collect(),
+        Isa::Lc3b => stringp_words(s),
+    }
 }
 
-const fn op_name(op: u16) -> &'static str {
-    match op {
-        0b0010 => "LD",
-        0b1010 => "LDI",
-        0b0110 => "LDR",
-        0b1110 => "LEA",
-        0b0011 => "ST",
-        0b1011 => "STI",
-        0b0111 => "STR",
+/// Encode a `.STRINGP` string: two characters per word, low byte first,
+/// then a NUL byte, as on LC-3b.
+fn stringp_words(s: &str) -> Vec<u16> {
+    let bytes: Vec<u8> = s.chars().map(|c| c as u8).chain([0]).collect();
+    bytes
+        .chunks(2)
+        .map(|pair| pair[0] as u16 | (*pair.get(1).unwrap_or(&0) as u16) << 8)
+        .collect()
+}
+
+const fn op_name(op: u16, isa: Isa) -> &'static str {
+    match (op, isa) {
+        (0b0010, Isa::Lc3) => "LD",
+        (0b1010, _) => "LDI",
+        (0b0110, Isa::Lc3) => "LDR",
+        (0b1110, _) => "LEA",
+        (0b0011, Isa::Lc3) => "ST",
+        (0b1011, _) => "STI",
+        (0b0111, Isa::Lc3) => "STR",
+        (0b0010, Isa::Lc3b) => "LDB",
+        (0b0011, Isa::Lc3b) => "STB",
+        (0b0110, Isa::Lc3b) => "LDW",
+        (0b0111, Isa::Lc3b) => "STW",
         _ => "instruction",
     }
 }
@@ -773,6 +2166,17 @@ mod tests {
         let mut asm = Assembler::new();
         let code = asm.assemble(source).unwrap();
         assert_eq!(code[0], 0x1021);
+
+        // Binary and character literals; values that don't fit are errors,
+        // not truncated
+        let source = ".ORIG x3000\nADD R0, R0, b111\nAND R1, R1, 0x1F - 32\n.FILL '0'\n.END";
+        assert_eq!(asm.assemble(source).unwrap(), [0x1027, 0x527F, 0x0030]);
+        let source = ".ORIG x3000\nADD R0, R0, 0b111\n.END";
+        assert_eq!(asm.assemble(source).unwrap(), [0x1027]);
+        for imm in ["#300", "#16", "x1F", "'A'"] {
+            let source = format!(".ORIG x3000\nADD R0, R0, {imm}\n.END");
+            assert!(asm.assemble(&source).is_err(), "{imm}");
+        }
     }
 
     #[test]
@@ -790,6 +2194,57 @@ mod tests {
         assert_eq!(code.len(), 3 + 6); // LEA, PUTS, HALT + "Hello\0"
     }
 
+    #[test]
+    fn test_data_directives() {
+        let source = r#"
+.ORIG x3000
+TABLE   .FILL 1, -1, END
+PACKED  .STRINGP "abc"
+EVEN    .STRINGP "ab"
+BUF     .BLKW 3, xFFFF
+PTRS    .BLKW 2, TABLE
+END     .BLKW 1
+.END
+"#;
+        let mut asm = Assembler::new();
+        let code = asm.assemble(source).unwrap();
+        assert_eq!(code[..3], [1, 0xFFFF, 0x300C]);
+        assert_eq!(code[3..5], [0x6261, 0x0063]); // "ab", "c\0"
+        assert_eq!(code[5..7], [0x6261, 0x0000]); // "ab", "\0"
+        assert_eq!(code[7..10], [0xFFFF; 3]);
+        assert_eq!(code[10..12], [0x3000; 2]);
+        assert_eq!(asm.symbols()["END"], 0x300C);
+
+        // Characters are bytes; Latin-1 fits, anything beyond is an error
+        let code = asm
+            .assemble(".ORIG x3000\n.STRINGZ \"é\"\n.STRINGP \"é\"\n")
+            .unwrap();
+        assert_eq!(code, [0xE9, 0, 0x00E9]);
+        let source = ".ORIG x3000\nA .STRINGZ \"π\"\nB .STRINGP \"a→\"\nC HALT\n";
+        match asm.assemble_with_errors(source) {
+            Err(AssemblyError::SemanticErrors(errors)) => {
+                let messages: Vec<_> = errors
+                    .iter()
+                    .map(|e| (e.line, e.message.as_str()))
+                    .collect();
+                assert_eq!(
+                    messages,
+                    [
+                        (2, "character π (U+03C0) does not fit in a byte"),
+                        (3, "character → (U+2192) does not fit in a byte"),
+                    ]
+                );
+            }
+            other => panic!("expected semantic errors, got {other:?}"),
+        }
+
+        // Every word of the block is relocated
+        let object = Assembler::new()
+            .assemble_object(".GLOBAL A\nA .BLKW 2, A\n")
+            .unwrap();
+        assert_eq!(object.sections[0].relocations.len(), 2);
+    }
+
     #[test]
     fn test_multi_segment() {
         let source = r#"
@@ -858,6 +2313,41 @@ HANDLER ADD R0, R0, #1
         assert_eq!(segments[1].origin, 0x0400);
     }
 
+    #[test]
+    fn test_segment_checks() {
+        let errors = |source: &str| match Assembler::new().assemble_with_errors(source) {
+            Err(AssemblyError::SemanticErrors(errors)) => errors
+                .iter()
+                .map(|e| format!("{}:{}", e.line, e.message))
+                .collect::<Vec<_>>(),
+            other => panic!("expected semantic errors, got {other:?}"),
+        };
+        assert_eq!(
+            errors(".ORIG x3000\n.BLKW 4\n.END\n.ORIG x3003\nHALT\n.END"),
+            ["4:segment at x3003-x3003 overlaps the segment at x3000-x3003 from line 1"]
+        );
+        assert_eq!(
+            errors(".ORIG xFFFF\n.FILL 1\n.FILL 2\n.END"),
+            ["1:segment at xFFFF is 2 words long and runs past xFFFF"]
+        );
+        assert_eq!(
+            errors(".ORIG xFDFE\n.STRINGZ \"ab\"\n.END"),
+            ["1:segment at xFDFE-xFE00 runs into the device registers (xFE00-xFFFF)"]
+        );
+
+        let source = ".ORIG x0200\nRTI\n.END";
+        let mut asm = Assembler::new();
+        asm.assemble(source).unwrap();
+        assert_eq!(
+            asm.warnings()[0].message,
+            "segment at x0200-x0200 is in system space (x0000-x2FFF); \
+             enable system space to assemble operating system code"
+        );
+        asm.set_system_space(true);
+        asm.assemble(source).unwrap();
+        assert!(asm.warnings().is_empty());
+    }
+
     #[test]
     fn test_lc3tools_format_roundtrip() {
         // Test encoding and decoding of lc3tools format
@@ -889,6 +2379,717 @@ HALT
         assert_eq!(segments[1].code, vec![0x1021, 0xF025]); // ADD R0,R0,#1 and HALT
     }
 
+    #[test]
+    fn test_lc3b_addresses_and_offsets() {
+        let source = r#"
+.ORIG x3000
+LOOP    LDB R1, R0, #1
+        RSHFA R1, R1, #3
+        XOR R2, R1, #-1
+        BRp LOOP
+        LEA R0, MSG
+        HALT
+MSG     .STRINGZ "Hi!"
+DATA    .FILL MSG
+.END
+"#;
+        let mut asm = Assembler::new();
+        asm.set_isa(Isa::Lc3b);
+        let code = asm.assemble(source).unwrap();
+
+        assert_eq!(code[0], 0x2201); // LDB R1, R0, #1
+        assert_eq!(code[1], 0xD273); // RSHFA R1, R1, #3
+        assert_eq!(code[2], 0x947F); // XOR R2, R1, #-1
+        assert_eq!(code[3], 0x03FC); // BRp -4 words
+        assert_eq!(code[4], 0xE001); // LEA R0, +1 word (MSG at x300C)
+        assert_eq!(code[6], 0x6948); // "Hi" packed
+        assert_eq!(code[7], 0x0021); // "!\0"
+        assert_eq!(code[8], 0x300C); // byte address of MSG
+    }
+
+    #[test]
+    fn test_lc3b_rejects_lc3_only_instructions() {
+        let mut asm = Assembler::new();
+        asm.set_isa(Isa::Lc3b);
+        assert!(
+            asm.assemble(".ORIG x3000\nLDI R0, X\nX .FILL 0\n.END")
+                .is_err()
+        );
+        assert!(asm.assemble(".ORIG x3001\nHALT\n.END").is_err());
+    }
+
+    #[test]
+    fn test_custom_instruction_encoding() {
+        let source = r#"
+.ORIG x3000
+        MUL R0, R1, R2
+        MUL R3, R4, #-2
+        MULX R5, VALUE
+        HALT
+VALUE   .FILL 7
+.END
+"#;
+        let mut asm = Assembler::new();
+        asm.add_extension(Extension::new("MUL", ExtensionFormat::Alu));
+        asm.add_extension(Extension::new("MULX", ExtensionFormat::PcOffset).with_bits(0x100));
+        let code = asm.assemble(source).unwrap();
+
+        assert_eq!(code[0], 0xD042);
+        assert_eq!(code[1], 0xD73E);
+        assert_eq!(code[2], 0xDB01); // bit 8 set by the extension, offset 1
+        assert!(asm.assemble(".ORIG x3000\nMUL R0, R1, #16\n.END").is_err());
+
+        asm.set_isa(Isa::Lc3b);
+        assert!(asm.assemble(".ORIG x3000\nMUL R0, R1, R2\n.END").is_err());
+    }
+
+    #[test]
+    fn test_pseudo_ops() {
+        let source = r#"
+.ORIG x3000
+        MOV R1, R2
+        CLR R0
+        INC R1
+        DEC R1
+        NEG R2, R3
+        SUB R0, R1, #3
+        SUB R0, R1, R2
+        PUSH R0
+        POP R1
+        LDIMM R3, x1234
+        CALL FAR
+        JMP FAR
+        HALT
+.END
+.ORIG x4000
+FAR     LDIMM R0, -1
+        RET
+"#;
+        let mut asm = Assembler::new();
+        assert!(asm.assemble(source).is_err());
+        asm.set_pseudo_ops(true);
+        asm.assemble(source).unwrap();
+
+        let segments = asm.segments();
+        #[rustfmt::skip]
+        assert_eq!(
+            segments[0].code,
+            [
+                0x12A0, 0x5020, 0x1261, 0x127F,
+                0x94FF, 0x14A1, // NEG: NOT, ADD #1
+                0x107D,
+                0x907F, 0x1002, 0x903F, // SUB: NOT, ADD, NOT
+                0x1DBF, 0x7180, 0x6380, 0x1DA1,
+                0x2605, // LD R3 from the pool
+                0x2E05, 0x41C0, 0x2E04, 0xC1C0,
+                0xF025,
+                0x1234, 0x4000, 0x4000, // the pool
+            ]
+        );
+        assert_eq!(segments[1].code, [0x2001, 0xC1C0, 0xFFFF]);
+        assert_eq!(asm.symbols().len(), 1);
+
+        let source = ".ORIG x3000\nLDIMM R0, 5\n.BLKW 300\n.END";
+        let Err(AssemblyError::SemanticErrors(errors)) = asm.assemble_with_errors(source) else {
+            panic!("expected a semantic error");
+        };
+        assert_eq!(
+            errors[0].message,
+            "the literal pool at x312D is out of range of LDIMM \
+             (300 words ahead, at most 255); split the segment"
+        );
+    }
+
+    #[test]
+    fn test_relaxation() {
+        // Rewriting the LEA pushes LAST out of the BRp's range, so the BRp
+        // is rewritten too
+        let source = r#"
+.ORIG x3000
+        BRp LAST
+        LEA R0, FAR
+        .BLKW 254
+LAST    HALT
+        .BLKW 300
+FAR     .FILL 0
+.END
+"#;
+        let mut asm = Assembler::new();
+        assert!(asm.assemble(source).is_err());
+        asm.set_relax(true);
+        let code = asm.assemble(source).unwrap();
+        assert_eq!(code[..4], [0x0C03, 0x2E01, 0xC1C0, 0x3105]); // BRnz over a jump
+        assert_eq!(code[4..7], [0x2001, 0x0E01, 0x3232]); // LD the address
+        assert_eq!(asm.symbols()["LAST"], 0x3105);
+        let warnings: Vec<_> = asm
+            .warnings()
+            .iter()
+            .filter(|w| w.code == Code::Relaxed)
+            .map(|w| &w.message)
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                "BRp target LAST is out of range; rewritten as 4 words that clobber R7 \
+                 and the condition codes",
+                "LEA target FAR is out of range; rewritten as 3 words",
+            ]
+        );
+
+        // Far branches may jump through another register
+        asm.set_relax_register(Register(6));
+        let code = asm.assemble(source).unwrap();
+        assert_eq!(code[..4], [0x0C03, 0x2C01, 0xC180, 0x3105]);
+        assert!(asm.warnings().iter().any(|w| {
+            w.message
+                .ends_with("4 words that clobber R6 and the condition codes")
+        }));
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let source = r#"
+LEVEL   .EQU 2
+.ORIG x3000
+.IFDEF DEBUG
+        LEA R0, TRACE
+        PUTS
+.ENDIF
+.IF LEVEL - 2
+        ADD R1, R1, #1
+.ELSEIF SOLUTION
+        ADD R1, R1, #2
+.ELSE
+        ADD R1, R1, #3
+.ENDIF
+        HALT
+.IFDEF DEBUG
+TRACE   .STRINGZ "here"
+.ENDIF
+.END
+"#;
+        let mut asm = Assembler::new();
+        asm.define("solution", 0);
+        let code = asm.assemble(source).unwrap();
+        assert_eq!(code, [0x1263, 0xF025]);
+
+        asm.define("DEBUG", 1);
+        asm.define("SOLUTION", 1);
+        let code = asm.assemble(source).unwrap();
+        assert_eq!(code[..4], [0xE003, 0xF022, 0x1262, 0xF025]);
+        assert_eq!(asm.symbols()["TRACE"], 0x3004);
+
+        let errors = |source: &str| match Assembler::new().assemble_with_errors(source) {
+            Err(AssemblyError::SemanticErrors(errors)) => {
+                errors.into_iter().map(|e| e.message).collect::<Vec<_>>()
+            }
+            other => panic!("unexpected {other:?}"),
+        };
+        assert_eq!(
+            errors(".ORIG x3000\n.IF FLAG\n.ENDIF\n.ELSE\n.IF 1\n.END\n"),
+            [
+                "undefined symbol: FLAG; conditions see only constants defined above them, \
+                 and .IFDEF tests whether one is defined",
+                ".ELSE without .IF",
+                ".IF is missing .ENDIF",
+            ]
+        );
+        assert_eq!(
+            errors(".ORIG x3000\nL HALT\n.IF L\n.ENDIF\n.END\n"),
+            ["conditions can only use constants, and L is a label"]
+        );
+        assert_eq!(
+            errors(".ORIG x3000\n.IF DATA\n.ENDIF\nDATA .FILL 1\n.END\n"),
+            ["conditions can only use constants, and DATA is a label"]
+        );
+
+        let mut asm = Assembler::new();
+        asm.define("N", 3);
+        assert!(asm.assemble(".ORIG x3000\nN .EQU 4\n.END\n").is_err());
+        assert_eq!(asm.assemble(".ORIG x3000\n.FILL N\n.END\n").unwrap(), [3]);
+    }
+
+    #[test]
+    fn test_local_labels() {
+        let source = r#"
+.MACRO WAIT
+.spin   ADD R2, R2, #-1
+        BRp .spin
+.ENDM
+.ORIG x3000
+MAIN    AND R1, R1, #0
+.loop   ADD R1, R1, #1
+        WAIT
+        BRn .loop
+        JSR PRINT
+        BRnzp PRINT.done
+PRINT   ADD R0, R0, #1
+.loop:  ADD R0, R0, #-1
+        BRp .loop
+.done   RET
+.END
+"#;
+        let mut asm = Assembler::new();
+        let code = asm.assemble(source).unwrap();
+        assert_eq!(code[3], 0x03FE); // BRp .spin, within the expansion
+        assert_eq!(code[4..6], [0x09FC, 0x4801]); // BRn MAIN.LOOP, JSR PRINT
+        assert_eq!(code[9], 0x03FE); // BRp PRINT.LOOP
+        let symbols = asm.symbols();
+        assert_eq!(symbols["MAIN.LOOP"], 0x3001);
+        assert_eq!(symbols["PRINT.LOOP"], 0x3008);
+        assert_eq!(symbols["PRINT.DONE"], 0x300A);
+
+        let errors = match asm.assemble_with_errors(".ORIG x3000\nA\n.x HALT\n.x HALT\n.END\n") {
+            Err(AssemblyError::SemanticErrors(errors)) => errors,
+            other => panic!("unexpected {other:?}"),
+        };
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 4);
+    }
+
+    #[test]
+    fn test_expressions() {
+        let source = r#"
+.ORIG x3000
+        LD R0, ARRAY+2
+        LEA R1, ARRAY + (END - ARRAY) / 2
+        BRnzp ARRAY-1
+ARRAY   .BLKW 2
+END     .BLKW (END - ARRAY) * 2 + 1
+        .FILL ARRAY+3
+        .FILL ~x0F & xFF | 1 << 8
+        .FILL -END
+.END
+"#;
+        let mut asm = Assembler::new();
+        let code = asm.assemble(source).unwrap();
+
+        assert_eq!(code[0], 0x2004); // ARRAY+2 = x3005
+        assert_eq!(code[1], 0xE202); // x3004
+        assert_eq!(code[2], 0x0FFF); // x3002
+        assert_eq!(code.len(), 3 + 2 + 5 + 3);
+        assert_eq!(code[10], 0x3006);
+        assert_eq!(code[11], 0x01F0);
+        assert_eq!(code[12], 0x3005u16.wrapping_neg());
+    }
+
+    #[test]
+    fn test_expression_errors() {
+        let errors = |body: &str| {
+            let source = format!(".ORIG x3000\n{body}\n.END\n");
+            match Assembler::new().assemble_with_errors(&source) {
+                Err(AssemblyError::SemanticErrors(errors)) => errors
+                    .iter()
+                    .map(|e| (e.message.clone(), &source[e.span.clone()]))
+                    .map(|(m, s)| format!("{m} at {s}"))
+                    .collect::<Vec<_>>(),
+                other => panic!("expected semantic errors, got {other:?}"),
+            }
+        };
+
+        assert_eq!(
+            errors(".FILL 1 / (A - A)\nA HALT"),
+            ["division by zero at (A - A)"]
+        );
+        assert_eq!(
+            errors(".FILL 200 * 400"),
+            ["value 80000 does not fit in 16 bits at 200 * 400"]
+        );
+        assert_eq!(
+            errors(".FILL x7FFF * x7FFF * 4"),
+            ["arithmetic overflow in expression at x7FFF * x7FFF * 4"]
+        );
+        assert_eq!(
+            errors("LD R0, -1"),
+            ["address -1 is outside memory (0 to xFFFF) at -1"]
+        );
+        assert_eq!(
+            errors("A .BLKW B - A\nB HALT"),
+            [
+                "circular definition: B is defined after this directive, so its address may depend on it at B"
+            ]
+        );
+        assert_eq!(errors(".BLKW C"), ["undefined symbol: C at C"]);
+    }
+
+    #[test]
+    fn test_constants() {
+        let source = "\
+            .ORIG BASE\n\
+            ADD R0, R0, STEP\n\
+            LDR R1, R6, -STEP\n\
+            TRAP HALT_VECTOR\n\
+            N .SET 2\n\
+            .FILL N\n\
+            N .SET N + 1\n\
+            .FILL N\n\
+            .BLKW COUNT\n\
+            .END\n\
+            BASE .EQU x3000\n\
+            STEP .EQU COUNT * 2\n\
+            COUNT .EQU 2\n\
+            HALT_VECTOR .EQU x25\n";
+        let mut asm = Assembler::new();
+        let code = asm.assemble(source).unwrap();
+        assert_eq!(asm.origin(), 0x3000);
+        assert_eq!(code, [0x1024, 0x63BC, 0xF025, 2, 3, 0, 0]);
+
+        let errors = |source: &str| match Assembler::new().assemble_with_errors(source) {
+            Err(AssemblyError::SemanticErrors(errors)) => errors
+                .iter()
+                .map(|e| format!("{} at {}", e.message, &source[e.span.clone()]))
+                .collect::<Vec<_>>(),
+            other => panic!("expected semantic errors, got {other:?}"),
+        };
+        assert_eq!(
+            errors(".ORIG x3000\nA .EQU B\nB .EQU A + 1\n.FILL A\n.END"),
+            [
+                "circular definition of B at B",
+                "circular definition of A at A"
+            ]
+        );
+        assert_eq!(
+            errors(".ORIG x3000\nX .EQU 1\nX HALT\n.END"),
+            ["X is already defined at X"]
+        );
+        assert_eq!(
+            errors(".ORIG x3000\nADD R0, R0, BIG\nBIG .EQU 16\n.END"),
+            ["immediate value out of range (-16 to 15) at BIG"]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_labels() {
+        let source = ".ORIG x3000\nLOOP ADD R0, R0, #1\nLOOP BRp LOOP\nloop HALT\n.END";
+        let mut asm = Assembler::new();
+        let Err(AssemblyError::SemanticErrors(errors)) = asm.assemble_with_errors(source) else {
+            panic!("expected semantic errors");
+        };
+        let messages: Vec<_> = errors
+            .iter()
+            .map(|e| (e.line, e.message.as_str()))
+            .collect();
+        assert_eq!(messages, [(3, "LOOP is already defined at line 2")]);
+
+        // Labels that differ only in case are one label, with a warning at
+        // the second spelling, and at references spelled differently
+        let source = ".ORIG x3000\nLoop ADD R0, R0, #1\nloop BRp LOOP\nBR Loop\nHALT\n.END";
+        assert_eq!(asm.assemble(source).unwrap()[1], 0x03FE);
+        let warnings: Vec<_> = asm
+            .warnings()
+            .iter()
+            .filter(|w| w.code == Code::LabelCase)
+            .map(|w| {
+                let related = &w.related[0];
+                let at = &source[related.span.clone()];
+                (w.line, w.message.as_str(), related.message.as_str(), at)
+            })
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                (
+                    3,
+                    "label loop differs only in case from Loop at line 2; \
+                     labels are not case-sensitive, so that definition is used",
+                    "first defined here as Loop",
+                    "Loop"
+                ),
+                (
+                    3,
+                    "LOOP refers to Loop at line 2, which is written in a different case",
+                    "defined here as Loop",
+                    "Loop"
+                ),
+            ]
+        );
+
+        // Confusing names are only warnings
+        let source = ".ORIG x3000\nR1 .FILL 1\nxAB .FILL 2\nB10 .FILL 3\n.END";
+        asm.assemble(source).unwrap();
+        let warnings: Vec<_> = asm
+            .warnings()
+            .iter()
+            .filter(|w| w.code == Code::ConfusingLabel)
+            .map(|w| (w.line, w.message.as_str()))
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                (2, "label R1 looks like register R1"),
+                (3, "label xAB reads as the number xAB in operands"),
+                (4, "label B10 reads as the number b10 in operands"),
+            ]
+        );
+        assert!(asm.format_warnings().contains("Warning"));
+    }
+
+    #[test]
+    fn test_warnings() {
+        let source = r#"
+.ORIG x3000
+MAIN    ADD R0, R0, xFFFF
+        BR NEXT
+NEXT    LD R1, DATA
+DATA    .FILL 5
+        .END
+        ADD R0, R0, #1
+.ORIG x4000
+        HALT
+"#;
+        let mut asm = Assembler::new();
+        asm.assemble(source).unwrap();
+        let warnings: Vec<_> = asm
+            .warnings()
+            .iter()
+            .map(|w| (w.line, w.code.as_str()))
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                (8, "W0004"),
+                (9, "W0005"),
+                (6, "W0007"),
+                (2, "W0006"),
+                (4, "W0008"),
+                (3, "W0009"),
+                (3, "W0010"),
+            ]
+        );
+        let bare = &asm.warnings()[4].suggestions[0];
+        assert_eq!(
+            (&source[bare.span.clone()], &*bare.replacement),
+            ("BR", "BRnzp")
+        );
+        assert_eq!(asm.warnings()[6].suggestions[0].replacement, "#-1");
+
+        // A comment on the line, or alone above it, allows a warning
+        let source = r#"
+.ORIG x3000 ; lc3-allow(W0006)
+MAIN    BR MAIN ; lc3-allow(w0008, W0009)
+        ; lc3-allow(W0007)
+        .STRINGZ "a;b"
+        ADD R0, R0, #0
+        .STRINGZ "; lc3-allow(W0007)"
+.END
+"#;
+        asm.assemble(source).unwrap();
+        let codes: Vec<_> = asm.warnings().iter().map(|w| (w.line, w.code)).collect();
+        assert_eq!(codes, [(7, Code::FallIntoData)]);
+
+        // Only comments allow warnings, not text in strings
+        let warned = |line: &str| {
+            let source = format!(".ORIG x3000\nADD R0, R0, #0\n{line}\nHALT\n.END\n");
+            let mut asm = Assembler::new();
+            asm.assemble(&source).unwrap();
+            asm.warnings().iter().any(|w| w.code == Code::FallIntoData)
+        };
+        assert!(warned(".STRINGZ \"lc3-allow(W0007)\""));
+        assert!(warned(".STRINGZ \"; lc3-allow(W0007)\""));
+        assert!(warned(".STRINGZ \"\\\"; lc3-allow(W0007)\" ; W0007"));
+        assert!(!warned(".STRINGZ \";\" ; lc3-allow(W0007)"));
+
+        // In a macro, the comment at the invocation counts
+        let source = ".MACRO GO to\nBR \\to\n.ENDM\n.ORIG x3000\nGO NEXT ; lc3-allow(W0008)\n\
+                      NEXT GO DONE\nDONE HALT\n.END\n";
+        asm.assemble(source).unwrap();
+        let codes: Vec<_> = asm.warnings().iter().map(|w| w.code).collect();
+        assert_eq!(codes, [Code::BareBranch]);
+
+        // A subroutine that halts is reached through JSR
+        let source = ".ORIG x3000\nJSR STOP\nRET\nSTOP TRAP x25\n.END\n";
+        asm.assemble(source).unwrap();
+        assert!(asm.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_macro_expansion() {
+        let source = r#"
+.MACRO PUSH reg
+        ADD R6, R6, #-1
+        STR \reg, R6, #0
+.ENDM
+.MACRO WAIT count
+        LD R1, N
+SPIN    ADD R1, R1, #-1
+        BRp SPIN
+        BR DONE
+N       .FILL \count
+DONE
+.ENDM
+.ORIG x3000
+        PUSH R0
+        WAIT #3
+        WAIT #4
+        HALT
+.END
+"#;
+        let mut asm = Assembler::new();
+        let code = asm.assemble(source).unwrap();
+
+        assert_eq!(&code[..2], &[0x1DBF, 0x7180]);
+        // Each expansion branches to its own SPIN and N
+        assert_eq!(&code[2..7], &[0x2203, 0x127F, 0x03FE, 0x0E01, 0x0003]);
+        assert_eq!(&code[7..12], &[0x2203, 0x127F, 0x03FE, 0x0E01, 0x0004]);
+        assert_eq!(code[12], 0xF025);
+
+        let source = ".MACRO LOAD label\nLD R0, \\label\n.ENDM\n.ORIG x3000\nLOAD MISSING\n.END\n";
+        let Err(AssemblyError::SemanticErrors(errors)) = asm.assemble_with_errors(source) else {
+            panic!("expected a semantic error");
+        };
+        let call = source.find("LOAD MISSING").unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].expanded_from, Some(call..call + 12));
+        assert!(errors[0].span.end < call);
+    }
+
+    #[test]
+    fn test_include() {
+        let lib = "\
+            .MACRO NEWLINE\n\
+            LD R0, NL\n\
+            OUT\n\
+            .ENDM\n\
+            NL .FILL x0A\n";
+        let files = HashMap::from([
+            ("lib/io.asm".to_string(), lib.to_string()),
+            (
+                "lib/bad.asm".to_string(),
+                "\n  ADD R0, R0, #99\n".to_string(),
+            ),
+        ]);
+        let source = ".ORIG x3000\nNEWLINE\nHALT\n.INCLUDE \"lib/io.asm\"\n.END\n";
+
+        let mut asm = Assembler::new();
+        asm.set_resolver("main.asm", files.clone());
+        let code = asm.assemble(source).unwrap();
+        assert_eq!(code, [0x2002, 0xF021, 0xF025, 0x000A]);
+
+        let source = ".ORIG x3000\n.INCLUDE \"lib/bad.asm\"\n.END\n";
+        match asm.assemble_with_errors(source) {
+            Err(AssemblyError::SemanticErrors(errors)) => {
+                assert_eq!(errors[0].file.as_deref(), Some("lib/bad.asm"));
+                assert_eq!((errors[0].line, errors[0].column), (2, 15));
+                assert!(errors[0].to_string().starts_with("lib/bad.asm:2:15: "));
+            }
+            other => panic!("expected semantic errors, got {other:?}"),
+        }
+
+        // A file that is missing is an error only where it is assembled
+        let source = ".ORIG x3000\n\
+            .IFDEF SOLUTION\n\
+            .INCLUDE \"solution.asm\"\n\
+            .ELSE\n\
+            .INCLUDE \"lib/io.asm\"\n\
+            .ENDIF\n\
+            .END\n";
+        assert_eq!(asm.assemble(source).unwrap(), [0x000A]);
+        asm.define("SOLUTION", 1);
+        match asm.assemble_with_errors(source) {
+            Err(AssemblyError::ParseErrors(errors)) => {
+                let messages: Vec<_> = errors.iter().map(|e| e.message.as_str()).collect();
+                assert_eq!(
+                    messages,
+                    ["cannot include solution.asm: file not found: solution.asm"]
+                );
+                assert_eq!((errors[0].line, errors[0].column), (3, 10));
+            }
+            other => panic!("expected parse errors, got {other:?}"),
+        }
+
+        let mut asm = Assembler::new();
+        let source = ".ORIG x3000\n.INCLUDE \"lib/bad.asm\"\n.END\n";
+        let err = asm.assemble(source).unwrap_err();
+        assert_eq!(
+            err,
+            "2:10: cannot include lib/bad.asm: no include resolver\n"
+        );
+    }
+
+    #[test]
+    fn test_object() {
+        let source = "\
+            .EXTERNAL PRINT, BUF\n\
+            .GLOBAL MAIN, SIZE\n\
+            SIZE .EQU 4\n\
+            MAIN LEA R0, MSG\n\
+            JSR PRINT\n\
+            LD R1, PTR\n\
+            LDI R2, x4000\n\
+            HALT\n\
+            PTR .FILL BUF + 2\n\
+            .FILL MAIN\n\
+            MSG .STRINGZ \"A\"\n";
+        let mut asm = Assembler::new();
+        let object = asm.assemble_object(source).unwrap();
+        let reloc = |offset, kind, target, addend| Relocation {
+            offset,
+            kind,
+            target,
+            addend,
+        };
+        assert_eq!(
+            object.sections,
+            [Section {
+                origin: None,
+                code: vec![0xE006, 0x4800, 0x2202, 0xA400, 0xF025, 2, 0, 0x41, 0],
+                relocations: vec![
+                    reloc(
+                        1,
+                        RelocKind::PcOffset(11),
+                        RelocTarget::External("PRINT".into()),
+                        0
+                    ),
+                    reloc(3, RelocKind::PcOffset(9), RelocTarget::Absolute, 0x4000),
+                    reloc(5, RelocKind::Word, RelocTarget::External("BUF".into()), 2),
+                    reloc(6, RelocKind::Word, RelocTarget::Section, 0),
+                ],
+            }]
+        );
+        let symbols: Vec<_> = object
+            .symbols
+            .iter()
+            .map(|s| (s.name.as_str(), s.value, s.relocatable))
+            .collect();
+        assert_eq!(symbols, [("MAIN", 0, true), ("SIZE", 4, false)]);
+
+        // With .ORIG, only external references are relocated
+        let source = ".ORIG x3000\n.EXTERNAL F\nLD R0, F\nX .FILL X\n.END\n";
+        let object = asm.assemble_object(source).unwrap();
+        assert_eq!(object.sections[0].origin, Some(0x3000));
+        assert_eq!(object.sections[0].code, [0x2000, 0x3001]);
+        assert_eq!(object.sections[0].relocations.len(), 1);
+
+        let source = "\
+            .EXTERNAL F\n\
+            .GLOBAL NOPE\n\
+            A ADD R0, R0, A\n\
+            .FILL A * 2\n\
+            .FILL F - A\n";
+        let result = asm.assemble_object(source);
+        let Err(AssemblyError::SemanticErrors(errors)) = result else {
+            panic!("expected semantic errors, got {result:?}");
+        };
+        let messages: Vec<_> = errors.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "value depends on an address known only after linking",
+                "expression cannot be relocated",
+                "expression cannot be relocated",
+                "global symbol NOPE is not defined",
+            ]
+        );
+
+        let err = asm
+            .assemble(".ORIG x3000\n.EXTERNAL F\n.FILL F\n.END")
+            .unwrap_err();
+        assert_eq!(
+            err,
+            "3:7: F is declared .EXTERNAL; assemble an object file and link it\n"
+        );
+    }
+
     #[test]
     fn test_lc3tools_format_detection() {
         // Test that we can detect lc3tools format
@@ -899,4 +3100,48 @@ HALT
         let legacy = [0x30, 0x00, 0xF0, 0x25]; // origin x3000, HALT
         assert!(!lc3tools_format::is_lc3tools_format(&legacy));
     }
+
+    #[test]
+    fn test_diagnostics() {
+        let source = ".ORIG x3000\nLOOP ADD R1, R1, #1\nBRp LOPP\nLOOP HALT\n.END\n";
+        let mut asm = Assembler::new();
+        let err = asm.assemble_with_errors(source).unwrap_err();
+        let diagnostics = err.diagnostics();
+        let codes: Vec<_> = diagnostics.iter().map(|d| d.code.as_str()).collect();
+        assert_eq!(codes, ["E0002", "E0001"]);
+        assert_eq!(diagnostics[0].related[0].span, 12..16);
+        assert_eq!(diagnostics[0].related[0].message, "first defined here");
+        let suggestion = &diagnostics[1].suggestions[0];
+        assert_eq!(
+            (suggestion.span.clone(), &*suggestion.replacement),
+            (36..40, "LOOP")
+        );
+
+        let json = asm.format_json("prog.asm", source, Some(&err));
+        let lines: Vec<_> = json.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].starts_with(
+            "{\"code\":\"E0001\",\"severity\":\"error\",\"message\":\"undefined symbol: LOPP\",\
+             \"location\":{\"file\":\"prog.asm\",\"line\":3,\"column\":5,\"end_line\":3,\
+             \"end_column\":9,\"start\":36,\"end\":40},\"expanded_from\":null,\"related\":[],"
+        ));
+        assert!(lines[1].contains("\"replacement\":\"LOOP\""));
+        assert!(
+            asm.format_error("prog.asm", source, &err)
+                .contains("[E0001]")
+        );
+
+        // Parse errors, warnings and errors from conditionals have codes too
+        let err = asm.assemble_with_errors("ADD R9, R1, R1\n").unwrap_err();
+        assert_eq!(err.diagnostics()[0].code, Code::InvalidRegister);
+        let err = asm.assemble_with_errors(".ENDIF\n").unwrap_err();
+        assert_eq!(err.diagnostics()[0].code, Code::UnmatchedConditional);
+        asm.assemble_with_errors(".ORIG x3000\nR1 HALT\n.END\n")
+            .unwrap();
+        assert_eq!(asm.warnings()[0].code, Code::ConfusingLabel);
+        assert!(
+            asm.format_json("prog.asm", "", None)
+                .contains("\"severity\":\"warning\"")
+        );
+    }
 }
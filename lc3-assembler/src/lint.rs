@@ -0,0 +1,464 @@
+//! The warnings pass: code that assembles but probably does not do what
+//! was meant. Any warning is suppressed by a `; lc3-allow(W0009)` comment,
+//! naming its code, on the line it points at or alone on the line above.
+
+use crate::{
+    Assembler, Code, Directive, Expr, Instruction, Line, Program, Pseudo, Related, SemanticError,
+    Span, Spanned, SpannedLine, Suggestion, SyntaxLine, SyntaxTree, listing, make_error,
+    symbol_references,
+};
+use std::collections::{HashMap, HashSet};
+
+/// Where execution may go after an instruction.
+struct Flow<'a> {
+    /// Whether it may go on to the next instruction.
+    falls_through: bool,
+    /// The label it may branch, jump or call to.
+    target: Option<&'a Spanned<Expr>>,
+    /// Whether it may go to an address known only at run time.
+    computed: bool,
+}
+
+impl Assembler {
+    /// Warn about the assembled `program`, whose lines start at
+    /// `addresses`.
+    pub(crate) fn check_warnings(&mut self, program: &Program, addresses: &[u16], source: &str) {
+        self.check_segment_ends(program, source);
+        self.check_fallthrough(program, source);
+        self.check_halt(program, addresses, source);
+        self.check_branches(program, source);
+        self.check_unused_labels(program, source);
+        self.check_signed_hex(program, source);
+    }
+
+    /// Drop every warning that a comment allows.
+    pub(crate) fn allow_warnings(&mut self, source: &str) {
+        let tree = SyntaxTree::parse(source);
+        self.warnings.retain(|warning| !allowed(&tree, warning));
+    }
+
+    /// Warn about code after `.END`, and about a last segment without one.
+    fn check_segment_ends(&mut self, program: &Program, source: &str) {
+        let mut in_segment = self.relocatable;
+        // The `.ORIG`, or first line, of the open segment
+        let mut open: Option<&SpannedLine> = None;
+        let mut ended = false;
+        for line in &program.lines {
+            match directive(&line.line) {
+                Some(Directive::Orig(_)) => {
+                    in_segment = true;
+                    open = Some(line);
+                    ended = false;
+                }
+                Some(Directive::End) => {
+                    in_segment = false;
+                    open = None;
+                    ended = true;
+                }
+                _ if !emits(&line.line) => {}
+                _ if in_segment => {
+                    open.get_or_insert(line);
+                }
+                _ if ended => {
+                    self.warnings.push(warning(
+                        source,
+                        line,
+                        line.span.clone(),
+                        Code::CodeAfterEnd,
+                        "code after .END is outside any segment".into(),
+                    ));
+                    // Once for all the lines up to the next `.ORIG`
+                    ended = false;
+                }
+                _ => {}
+            }
+        }
+        if let Some(line) = open {
+            self.warnings.push(warning(
+                source,
+                line,
+                line.span.clone(),
+                Code::MissingEnd,
+                "the segment starting here has no .END".into(),
+            ));
+        }
+    }
+
+    /// Warn about data that execution runs into from the instruction above.
+    fn check_fallthrough(&mut self, program: &Program, source: &str) {
+        let mut previous: Option<(&SpannedLine, &Instruction)> = None;
+        for line in &program.lines {
+            if let Some(instr) = instruction(&line.line) {
+                previous = Some((line, instr));
+                continue;
+            }
+            match directive(&line.line) {
+                Some(Directive::Orig(_) | Directive::End) => previous = None,
+                Some(
+                    Directive::Fill(_)
+                    | Directive::Blkw(..)
+                    | Directive::Stringz(_)
+                    | Directive::Stringp(_),
+                ) => {
+                    if let Some((from, instr)) = previous.take()
+                        && self.flow(instr).falls_through
+                    {
+                        let mut warning = warning(
+                            source,
+                            line,
+                            line.span.clone(),
+                            Code::FallIntoData,
+                            "execution falls through from the instruction above into data".into(),
+                        );
+                        warning.related.push(Related {
+                            span: listing::line_span(from),
+                            message: "this instruction does not branch away".into(),
+                        });
+                        self.warnings.push(warning);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Warn if no HALT can be reached from the first instruction. Operating
+    /// systems and modules for the linker are not programs of their own,
+    /// and a jump to a computed address may go anywhere, so they are not
+    /// checked.
+    fn check_halt(&mut self, program: &Program, addresses: &[u16], source: &str) {
+        if self.object || self.system_space {
+            return;
+        }
+        let lines = &program.lines;
+        // The line at each address, and the line after each
+        let mut at: HashMap<u16, usize> = HashMap::new();
+        let mut next = vec![None; lines.len()];
+        let mut previous: Option<usize> = None;
+        let mut in_segment = false;
+        let mut start: Option<(&SpannedLine, usize)> = None;
+        let mut origin = None;
+        for (i, line) in lines.iter().enumerate() {
+            match directive(&line.line) {
+                Some(Directive::Orig(_)) => {
+                    in_segment = true;
+                    previous = None;
+                    origin.get_or_insert(line);
+                }
+                Some(Directive::End) => {
+                    in_segment = false;
+                    previous = None;
+                }
+                _ if in_segment && emits(&line.line) => {
+                    at.entry(addresses[i]).or_insert(i);
+                    if let Some(previous) = previous {
+                        next[previous] = Some(i);
+                    }
+                    previous = Some(i);
+                    start.get_or_insert((origin.unwrap_or(line), i));
+                }
+                _ => {}
+            }
+        }
+        let Some((origin, entry)) = start else {
+            return;
+        };
+        if !lines.iter().any(|line| instruction(&line.line).is_some()) {
+            return;
+        }
+
+        let mut seen = HashSet::new();
+        let mut pending = vec![entry];
+        while let Some(i) = pending.pop() {
+            if !seen.insert(i) {
+                continue;
+            }
+            // Data is not run on purpose; falling into it has its own warning
+            let Some(instr) = instruction(&lines[i].line) else {
+                continue;
+            };
+            if self.halts(instr) {
+                return;
+            }
+            let flow = self.flow(instr);
+            if flow.computed {
+                return;
+            }
+            if let Some(target) = flow.target {
+                let address = self.eval_moved(target, None).ok();
+                let line = address.and_then(|a| at.get(&u16::try_from(a).ok()?));
+                match line {
+                    Some(&line) => pending.push(line),
+                    // Somewhere outside the program
+                    None => return,
+                }
+            }
+            if flow.falls_through
+                && let Some(line) = next[i]
+            {
+                pending.push(line);
+            }
+        }
+        self.warnings.push(warning(
+            source,
+            origin,
+            origin.span.clone(),
+            Code::NoHalt,
+            "no HALT is reachable from the start of the program".into(),
+        ));
+    }
+
+    /// Warn about `BR` written without condition flags, which branches
+    /// always, as `BRnzp` does.
+    fn check_branches(&mut self, program: &Program, source: &str) {
+        for line in &program.lines {
+            let Some(Instruction::Br {
+                n: true,
+                z: true,
+                p: true,
+                ..
+            }) = instruction(&line.line)
+            else {
+                continue;
+            };
+            let span = mnemonic_span(line, source);
+            if !source
+                .get(span.clone())
+                .is_some_and(|name| name.eq_ignore_ascii_case("BR"))
+            {
+                continue;
+            }
+            let mut warning = warning(
+                source,
+                line,
+                span.clone(),
+                Code::BareBranch,
+                "BR without condition flags always branches".into(),
+            );
+            warning.suggestions.push(Suggestion {
+                message: "write BRnzp to make that clear".into(),
+                span,
+                replacement: "BRnzp".into(),
+            });
+            self.warnings.push(warning);
+        }
+    }
+
+    /// Warn about labels that nothing refers to. Labels made by macro
+    /// expansions are left alone, since not every expansion uses them all.
+    fn check_unused_labels(&mut self, program: &Program, source: &str) {
+        let used: HashSet<String> = program
+            .lines
+            .iter()
+            .flat_map(|line| symbol_references(&line.line))
+            .map(|reference| reference.value)
+            .collect();
+        for line in &program.lines {
+            let label = match &line.line {
+                Line::LabeledDirective(_, Directive::Equ(_) | Directive::Set(_)) => continue,
+                Line::Label(label)
+                | Line::LabeledDirective(label, _)
+                | Line::LabeledInstruction(label, _) => label,
+                _ => continue,
+            };
+            if line.expanded_from.is_some() || used.contains(&label.value) {
+                continue;
+            }
+            let name = source.get(label.span.clone()).unwrap_or(&label.value);
+            self.warnings.push(warning(
+                source,
+                line,
+                label.span.clone(),
+                Code::UnusedLabel,
+                format!("label {name} is never used"),
+            ));
+        }
+    }
+
+    /// Warn about hex immediates in signed fields that are negative there,
+    /// such as `xFFFF` for -1.
+    fn check_signed_hex(&mut self, program: &Program, source: &str) {
+        for line in &program.lines {
+            let Some(value) = instruction(&line.line).and_then(signed_field) else {
+                continue;
+            };
+            let Expr::Number(n) = value.value else {
+                continue;
+            };
+            let text = source.get(value.span.clone()).unwrap_or_default();
+            let hex =
+                text.starts_with(['x', 'X']) || text.starts_with("0x") || text.starts_with("0X");
+            if !hex || n < 0x8000 {
+                continue;
+            }
+            let signed = n as u16 as i16;
+            let mut warning = warning(
+                source,
+                line,
+                value.span.clone(),
+                Code::SignedHex,
+                format!("{text} is #{signed} in this signed field"),
+            );
+            warning.suggestions.push(Suggestion {
+                message: format!("write #{signed} to make that clear"),
+                span: value.span.clone(),
+                replacement: format!("#{signed}"),
+            });
+            self.warnings.push(warning);
+        }
+    }
+
+    /// Whether `instr` stops the machine.
+    fn halts(&self, instr: &Instruction) -> bool {
+        match instr {
+            Instruction::Halt => true,
+            Instruction::Trap { trapvect } => self.eval_moved(trapvect, None) == Ok(0x25),
+            _ => false,
+        }
+    }
+
+    fn flow<'a>(&self, instr: &'a Instruction) -> Flow<'a> {
+        let (falls_through, target, computed) = match instr {
+            Instruction::Br { n, z, p, label } => (!(*n && *z && *p), Some(label), false),
+            Instruction::Jsr { label } | Instruction::Pseudo(Pseudo::Call { target: label }) => {
+                (true, Some(label), false)
+            }
+            Instruction::Pseudo(Pseudo::Jmp { target }) => (false, Some(target), false),
+            // `JMP R7` returns like RET
+            Instruction::Jmp { base } => (false, None, base.0 != 7),
+            Instruction::Jsrr { .. } => (true, None, true),
+            Instruction::Ret | Instruction::Rti => (false, None, false),
+            _ => (!self.halts(instr), None, false),
+        };
+        Flow {
+            falls_through,
+            target,
+            computed,
+        }
+    }
+}
+
+/// Create a warning about `line`.
+fn warning(
+    source: &str,
+    line: &SpannedLine,
+    span: Span,
+    code: Code,
+    message: String,
+) -> SemanticError {
+    let mut warning = make_error(source, span, code, message);
+    warning.expanded_from = line.expanded_from.clone();
+    warning
+}
+
+fn instruction(line: &Line) -> Option<&Instruction> {
+    match line {
+        Line::Instruction(instr) | Line::LabeledInstruction(_, instr) => Some(instr),
+        _ => None,
+    }
+}
+
+fn directive(line: &Line) -> Option<&Directive> {
+    match line {
+        Line::Directive(dir) | Line::LabeledDirective(_, dir) => Some(dir),
+        _ => None,
+    }
+}
+
+/// Whether `line` puts words in memory.
+fn emits(line: &Line) -> bool {
+    instruction(line).is_some()
+        || matches!(
+            directive(line),
+            Some(
+                Directive::Fill(_)
+                    | Directive::Blkw(..)
+                    | Directive::Stringz(_)
+                    | Directive::Stringp(_)
+            )
+        )
+}
+
+/// The immediate or offset of `instr` that is encoded as a signed field.
+fn signed_field(instr: &Instruction) -> Option<&Spanned<Expr>> {
+    use crate::{AddSrc2, AndSrc2, XorSrc2};
+    match instr {
+        Instruction::Add {
+            src2: AddSrc2::Immediate(value),
+            ..
+        }
+        | Instruction::And {
+            src2: AndSrc2::Immediate(value),
+            ..
+        }
+        | Instruction::Xor {
+            src2: XorSrc2::Immediate(value),
+            ..
+        }
+        | Instruction::Pseudo(Pseudo::Sub {
+            src2: AddSrc2::Immediate(value),
+            ..
+        })
+        | Instruction::Ldr { offset: value, .. }
+        | Instruction::Str { offset: value, .. }
+        | Instruction::Ldb { offset: value, .. }
+        | Instruction::Stb { offset: value, .. }
+        | Instruction::Ldw { offset: value, .. }
+        | Instruction::Stw { offset: value, .. } => Some(value),
+        _ => None,
+    }
+}
+
+/// The span of the mnemonic of the instruction on `line`.
+fn mnemonic_span(line: &SpannedLine, source: &str) -> Span {
+    let start = match &line.line {
+        Line::LabeledInstruction(label, _) => label.span.end,
+        _ => line.span.start,
+    };
+    let text = source.get(start..line.span.end).unwrap_or_default();
+    let rest = text.trim_start_matches(|c: char| c == ':' || c.is_whitespace());
+    let start = start + text.len() - rest.len();
+    let len = rest
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(rest.len());
+    start..start + len
+}
+
+/// Whether a comment allows `warning`: on its line or alone on the line
+/// above, or, in a macro expansion, at the invocation.
+fn allowed(tree: &SyntaxTree, warning: &SemanticError) -> bool {
+    std::iter::once(&warning.span)
+        .chain(&warning.expanded_from)
+        .any(|span| allows(tree, span.start, warning.code))
+}
+
+/// Whether the comments around `offset` allow `code`. The comments are
+/// tokens of the syntax tree, so a `;` in a string starts none.
+fn allows(tree: &SyntaxTree, offset: usize, code: Code) -> bool {
+    let Some(i) = tree
+        .lines
+        .partition_point(|line| line.span.start <= offset)
+        .checked_sub(1)
+    else {
+        return false;
+    };
+    let above = i
+        .checked_sub(1)
+        .map(|i| &tree.lines[i])
+        .filter(|line| line.is_blank());
+    std::iter::once(&tree.lines[i])
+        .chain(above)
+        .filter_map(SyntaxLine::comment)
+        .any(|comment| allowed_codes(&comment.text).any(|allowed| allowed == code))
+}
+
+/// The codes named by `lc3-allow(W0001, W0002)` in `comment`.
+fn allowed_codes(comment: &str) -> impl Iterator<Item = Code> + '_ {
+    const ALLOW: &str = "lc3-allow(";
+    let list = comment
+        .find(ALLOW)
+        .map(|i| &comment[i + ALLOW.len()..])
+        .and_then(|rest| Some(&rest[..rest.find(')')?]))
+        .unwrap_or_default();
+    list.split(',').filter_map(|code| code.trim().parse().ok())
+}
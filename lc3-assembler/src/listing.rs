@@ -0,0 +1,403 @@
+//! Listing files and debug information: each emitted word next to the
+//! source line it came from.
+
+use crate::{Assembler, Directive, Isa, Line, Span, SpannedLine, lc3tools_format, offset_to_pos};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// The words one line emitted.
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    address: u16,
+    words: Vec<u16>,
+    /// The line, or the macro invocation it was expanded from.
+    span: Span,
+    kind: Kind,
+    /// For a pseudo-instruction, the instruction each word encodes; for a
+    /// literal, its `.FILL`.
+    expansion: Vec<String>,
+}
+
+/// How to show the words after the first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Code,
+    /// `.STRINGZ`: one row per word, showing its characters.
+    String,
+    /// `.STRINGP`: like `String`, two characters to a word.
+    PackedString,
+    /// `.BLKW`: one row for the rest of the block.
+    Block,
+    /// A pseudo-instruction or rewritten far instruction: the source on a
+    /// row of its own, then one row per word with the instruction it encodes.
+    Pseudo,
+    /// A word of a literal pool.
+    Literal,
+}
+
+impl Entry {
+    /// The words `line` emitted at `address`; `expansion` holds the
+    /// instructions of a pseudo-instruction or rewritten far instruction.
+    pub(crate) fn new(
+        address: u16,
+        words: &[u16],
+        line: &SpannedLine,
+        expansion: Vec<String>,
+    ) -> Self {
+        let kind = match &line.line {
+            _ if !expansion.is_empty() => Kind::Pseudo,
+            Line::Directive(Directive::Stringz(_))
+            | Line::LabeledDirective(_, Directive::Stringz(_)) => Kind::String,
+            Line::Directive(Directive::Stringp(_))
+            | Line::LabeledDirective(_, Directive::Stringp(_)) => Kind::PackedString,
+            Line::Directive(Directive::Blkw(..))
+            | Line::LabeledDirective(_, Directive::Blkw(..)) => Kind::Block,
+            _ => Kind::Code,
+        };
+        Self {
+            address,
+            words: words.to_vec(),
+            span: line_span(line),
+            kind,
+            expansion,
+        }
+    }
+
+    /// A literal pool word, attributed to the line that loads it.
+    pub(crate) fn literal(address: u16, word: u16, span: Span, fill: String) -> Self {
+        Self {
+            address,
+            words: vec![word],
+            span,
+            kind: Kind::Literal,
+            expansion: vec![fill],
+        }
+    }
+
+    pub(crate) fn words(&self) -> &[u16] {
+        &self.words
+    }
+}
+
+/// The span a line's words are attributed to: the line, or the macro
+/// invocation it was expanded from.
+pub(crate) fn line_span(line: &SpannedLine) -> Span {
+    line.expanded_from.clone().unwrap_or(line.span.clone())
+}
+
+/// Where an assembled word came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    pub address: u16,
+    /// The file, as named in [`Assembler::sources`].
+    pub file: String,
+    /// 1-based line and column of the start of the statement.
+    pub line: usize,
+    pub column: usize,
+    /// The whole line.
+    pub text: String,
+}
+
+impl Assembler {
+    /// The listing of the last successful assembly: the address, the word
+    /// in hex and binary, and the source line of every emitted word,
+    /// followed by the symbol table. Words from a macro are listed at its
+    /// invocation; those of a pseudo-instruction, a rewritten far
+    /// instruction or a literal pool are marked with `+` and the real
+    /// instruction or `.FILL`.
+    pub fn listing(&self) -> String {
+        let step = self.isa().word_size();
+        let rows: Vec<(String, &str)> = self
+            .listing
+            .iter()
+            .map(|entry| self.source_line(&entry.span))
+            .collect();
+        let width = rows
+            .iter()
+            .map(|(location, _)| location.len())
+            .max()
+            .unwrap_or(0)
+            .max(4);
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "Address  Hex    Binary            {:>width$}  Source",
+            "Line"
+        );
+        let mut row = |cells: Option<(u16, u16)>, location: &str, text: &str| {
+            let cells = match cells {
+                Some((address, word)) => format!("x{address:04X}    x{word:04X}  {word:016b}"),
+                None => " ".repeat(32),
+            };
+            let line = format!("{cells}  {location:>width$}  {text}");
+            let _ = writeln!(out, "{}", line.trim_end());
+        };
+        for (entry, (location, text)) in self.listing.iter().zip(&rows) {
+            let address = |i: usize| entry.address.wrapping_add(i as u16 * step);
+            match entry.kind {
+                Kind::Pseudo => {
+                    row(None, location, text);
+                    for (i, (&word, instr)) in entry.words.iter().zip(&entry.expansion).enumerate()
+                    {
+                        row(Some((address(i), word)), "", &format!("+ {instr}"));
+                    }
+                    continue;
+                }
+                Kind::Literal => {
+                    let fill = format!("+ {}", entry.expansion[0]);
+                    row(Some((entry.address, entry.words[0])), "", &fill);
+                    continue;
+                }
+                _ => row(Some((entry.address, entry.words[0])), location, text),
+            }
+            let rest = &entry.words[1..];
+            let next = entry.address.wrapping_add(step);
+            match entry.kind {
+                Kind::Block if !rest.is_empty() => {
+                    let last = next.wrapping_add((rest.len() as u16 - 1) * step);
+                    let more = match rest.len() {
+                        1 => "1 more word".to_string(),
+                        n => format!("{n} more words, to x{last:04X}"),
+                    };
+                    row(Some((next, rest[0])), "", &format!("... {more}"));
+                }
+                Kind::String | Kind::PackedString => {
+                    let packed = entry.kind == Kind::PackedString || self.isa() == Isa::Lc3b;
+                    for (i, &word) in rest.iter().enumerate() {
+                        row(
+                            Some((address(i + 1), word)),
+                            "",
+                            &string_chars(word, packed),
+                        );
+                    }
+                }
+                _ => {
+                    for (i, &word) in rest.iter().enumerate() {
+                        row(Some((address(i + 1), word)), "", "");
+                    }
+                }
+            }
+        }
+
+        let mut labels: Vec<_> = self.symbols.iter().collect();
+        labels.sort_by_key(|&(name, &address)| (address, name));
+        let mut constants: Vec<_> = self
+            .equates
+            .iter()
+            .filter_map(|(name, value)| Some((name, self.eval_moved(value, None).ok()?)))
+            .collect();
+        constants.sort();
+        if labels.is_empty() && constants.is_empty() {
+            return out;
+        }
+        let width = labels
+            .iter()
+            .map(|(name, _)| name.len())
+            .chain(constants.iter().map(|(name, _)| name.len()))
+            .max()
+            .unwrap_or(0)
+            .max(6);
+        let _ = writeln!(out, "\nSymbol table\n{:width$}  Value", "Symbol");
+        for (name, address) in labels {
+            let _ = writeln!(out, "{name:width$}  x{address:04X}");
+        }
+        for (name, value) in constants {
+            let _ = writeln!(
+                out,
+                "{name:width$}  x{:04X}  (constant {value})",
+                value as u16
+            );
+        }
+        out
+    }
+
+    /// The source of every word of the last successful assembly, in the
+    /// order they were emitted.
+    pub fn debug_info(&self) -> Vec<SourceLine> {
+        let step = self.isa().word_size();
+        let mut info = Vec::new();
+        for entry in &self.listing {
+            let line = self.locate_line(&entry.span);
+            for i in 0..entry.words.len() {
+                info.push(SourceLine {
+                    address: entry.address.wrapping_add(i as u16 * step),
+                    ..line.clone()
+                });
+            }
+        }
+        info
+    }
+
+    /// The last successful assembly in the lc3tools `.obj` format, with the
+    /// source line of every word.
+    pub fn lc3tools_object(&self) -> Vec<u8> {
+        let words: HashMap<u16, String> = self
+            .debug_info()
+            .into_iter()
+            .map(|line| (line.address, line.text))
+            .collect();
+        let step = self.isa().word_size();
+        let lines: Vec<Vec<String>> = self
+            .segments
+            .iter()
+            .zip(&self.origin_lines)
+            .map(|(segment, line)| {
+                let origin = line
+                    .as_ref()
+                    .map(|span| self.source_line(span).1.to_string())
+                    .unwrap_or_default();
+                let code = (0..segment.code.len()).map(|i| {
+                    let address = segment.origin.wrapping_add(i as u16 * step);
+                    words.get(&address).cloned().unwrap_or_default()
+                });
+                std::iter::once(origin).chain(code).collect()
+            })
+            .collect();
+        lc3tools_format::encode_with_lines(&self.segments, &lines)
+    }
+
+    /// The file, line, column and text of where `span` starts.
+    fn locate_line(&self, span: &Span) -> SourceLine {
+        let (file, offset) = self.sources.locate(span.start);
+        let text = &self.sources.files()[file].text;
+        let indent = text[offset..].len() - text[offset..].trim_start_matches([' ', '\t']).len();
+        let (line, column) = offset_to_pos(text, offset + indent);
+        SourceLine {
+            address: 0,
+            file: self.sources.files()[file].name.clone(),
+            line,
+            column,
+            text: self.source_line(span).1.to_string(),
+        }
+    }
+
+    /// Where `span` starts, as a line number (prefixed with the file name in
+    /// included files), and the text of that line.
+    fn source_line(&self, span: &Span) -> (String, &str) {
+        let (file, offset) = self.sources.locate(span.start);
+        let text = &self.sources.files()[file].text;
+        let start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let end = text[offset..].find('\n').map_or(text.len(), |i| offset + i);
+        let (line, _) = offset_to_pos(text, offset);
+        let location = match file {
+            0 => line.to_string(),
+            _ => format!("{}:{line}", self.sources.files()[file].name),
+        };
+        (location, text[start..end].trim_end())
+    }
+}
+
+/// The characters a string word holds, one or, `packed`, two; quoted.
+fn string_chars(word: u16, packed: bool) -> String {
+    let bytes = match packed {
+        false => vec![word],
+        // Packed little-endian; a trailing NUL pads the last word
+        true => vec![word & 0xFF, word >> 8],
+    };
+    let chars: String = bytes
+        .into_iter()
+        .map(|b| match char::from_u32(b as u32) {
+            Some('\0') => "\\0".to_string(),
+            Some(c) if !c.is_control() => c.to_string(),
+            Some(c) => c.escape_default().to_string(),
+            None => format!("\\u{{{b:x}}}"),
+        })
+        .collect();
+    format!("'{chars}'")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listing() {
+        let source = "\
+.ORIG x3000
+LOOP ADD R0, R0, #-1 ; count down
+     BRp LOOP
+MSG  .STRINGZ \"Hi\"
+BUF  .BLKW 3
+SIZE .EQU 3
+PMSG .STRINGP \"Hey\"
+.END
+";
+        let mut asm = Assembler::new();
+        asm.assemble(source).unwrap();
+        let listing = asm.listing();
+        assert_eq!(
+            listing,
+            "\
+Address  Hex    Binary            Line  Source
+x3000    x103F  0001000000111111     2  LOOP ADD R0, R0, #-1 ; count down
+x3001    x03FE  0000001111111110     3       BRp LOOP
+x3002    x0048  0000000001001000     4  MSG  .STRINGZ \"Hi\"
+x3003    x0069  0000000001101001        'i'
+x3004    x0000  0000000000000000        '\\0'
+x3005    x0000  0000000000000000     5  BUF  .BLKW 3
+x3006    x0000  0000000000000000        ... 2 more words, to x3007
+x3008    x6548  0110010101001000     7  PMSG .STRINGP \"Hey\"
+x3009    x0079  0000000001111001        'y\\0'
+
+Symbol table
+Symbol  Value
+LOOP    x3000
+MSG     x3002
+BUF     x3005
+PMSG    x3008
+SIZE    x0003  (constant 3)
+"
+        );
+    }
+
+    #[test]
+    fn test_pseudo_op_listing() {
+        let source = ".ORIG x3000\nPUSH R1\nLDIMM R0, x1234\n.END\n";
+        let mut asm = Assembler::new();
+        asm.set_pseudo_ops(true);
+        asm.assemble(source).unwrap();
+        assert_eq!(
+            asm.listing(),
+            "\
+Address  Hex    Binary            Line  Source
+                                     2  PUSH R1
+x3000    x1DBF  0001110110111111        + ADD R6, R6, #-1
+x3001    x7380  0111001110000000        + STR R1, R6, #0
+                                     3  LDIMM R0, x1234
+x3002    x2000  0010000000000000        + LD R0, =x1234
+x3003    x1234  0001001000110100        + .FILL x1234
+"
+        );
+        assert_eq!(asm.debug_info()[3].line, 3);
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let source = ".ORIG x3000\n  LD R0, DATA\nDATA .STRINGZ \"a\"\n.END\n";
+        let mut asm = Assembler::new();
+        asm.assemble(source).unwrap();
+        let info = asm.debug_info();
+        assert_eq!(info.len(), 3);
+        assert_eq!(
+            (info[0].address, info[0].line, info[0].column),
+            (0x3000, 2, 3)
+        );
+        assert_eq!(info[0].text, "  LD R0, DATA");
+        assert_eq!((info[2].address, info[2].line), (0x3002, 3));
+
+        let entries = lc3tools_format::decode(&asm.lc3tools_object()).unwrap();
+        let lines: Vec<_> = entries.iter().map(|e| e.line.as_str()).collect();
+        assert_eq!(
+            lines,
+            [
+                ".ORIG x3000",
+                "  LD R0, DATA",
+                "DATA .STRINGZ \"a\"",
+                "DATA .STRINGZ \"a\""
+            ]
+        );
+        let lines = lc3tools_format::entries_to_lines(&entries, 1);
+        assert_eq!(lines[&0x3001], "DATA .STRINGZ \"a\"");
+    }
+}
@@ -0,0 +1,284 @@
+//! Relocatable object files, produced by [`Assembler::assemble_object`]
+//! and combined by a linker.
+//!
+//! A module without `.ORIG` assembles to a single relocatable section that
+//! the linker places; a module with `.ORIG` keeps its absolute sections.
+//! Relocations patch the words whose value depends on where sections end up
+//! or on `.EXTERNAL` symbols.
+//!
+//! [`Assembler::assemble_object`]: crate::Assembler::assemble_object
+
+/// Magic header bytes for object files.
+pub const MAGIC: &[u8] = b"LC3O";
+/// Format version.
+pub const VERSION: u8 = 1;
+
+/// An assembled module.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Object {
+    pub sections: Vec<Section>,
+    /// Symbols exported with `.GLOBAL`.
+    pub symbols: Vec<ObjectSymbol>,
+}
+
+/// A block of code, either at a fixed address or placed by the linker.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Section {
+    /// Fixed address, or `None` for the module's relocatable section.
+    pub origin: Option<u16>,
+    pub code: Vec<u16>,
+    pub relocations: Vec<Relocation>,
+}
+
+/// A word to patch once addresses are known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    /// Index of the word in its section.
+    pub offset: u16,
+    pub kind: RelocKind,
+    pub target: RelocTarget,
+    /// Added to the target's address.
+    pub addend: i32,
+}
+
+/// Which part of a word a relocation fills in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocKind {
+    /// The whole word, as for `.FILL`.
+    Word,
+    /// A PC-relative offset in the low `n` bits, as for `LD` or `BR`.
+    PcOffset(u8),
+}
+
+/// What a relocation refers to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelocTarget {
+    /// A fixed address, referenced from a relocatable section.
+    Absolute,
+    /// The start of the module's relocatable section.
+    Section,
+    /// A symbol defined in another module.
+    External(String),
+}
+
+/// An exported symbol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectSymbol {
+    pub name: String,
+    /// Address or constant value; an offset into the relocatable section if
+    /// `relocatable`.
+    pub value: u16,
+    pub relocatable: bool,
+}
+
+impl Object {
+    /// Encode the object in the binary object file format.
+    ///
+    /// Format (little-endian):
+    /// - Magic header (4 bytes) and version (1 byte)
+    /// - Section count: u16; for each section:
+    ///   - has_origin: u8, origin: u16
+    ///   - word count: u32, then the words as u16
+    ///   - relocation count: u32; for each relocation:
+    ///     - offset: u16
+    ///     - kind: u8 (0 = word, otherwise the PC offset width in bits)
+    ///     - target: u8 (0 = absolute, 1 = section, 2 = external, followed by
+    ///       the symbol name)
+    ///     - addend: i32
+    /// - Symbol count: u32; for each symbol: name, value: u16, relocatable: u8
+    ///
+    /// Names are a u16 byte length followed by UTF-8.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+
+        out.extend_from_slice(&(self.sections.len() as u16).to_le_bytes());
+        for section in &self.sections {
+            out.push(section.origin.is_some() as u8);
+            out.extend_from_slice(&section.origin.unwrap_or(0).to_le_bytes());
+            out.extend_from_slice(&(section.code.len() as u32).to_le_bytes());
+            for word in &section.code {
+                out.extend_from_slice(&word.to_le_bytes());
+            }
+            out.extend_from_slice(&(section.relocations.len() as u32).to_le_bytes());
+            for reloc in &section.relocations {
+                out.extend_from_slice(&reloc.offset.to_le_bytes());
+                out.push(match reloc.kind {
+                    RelocKind::Word => 0,
+                    RelocKind::PcOffset(bits) => bits,
+                });
+                match &reloc.target {
+                    RelocTarget::Absolute => out.push(0),
+                    RelocTarget::Section => out.push(1),
+                    RelocTarget::External(name) => {
+                        out.push(2);
+                        write_name(&mut out, name);
+                    }
+                }
+                out.extend_from_slice(&reloc.addend.to_le_bytes());
+            }
+        }
+
+        out.extend_from_slice(&(self.symbols.len() as u32).to_le_bytes());
+        for symbol in &self.symbols {
+            write_name(&mut out, &symbol.name);
+            out.extend_from_slice(&symbol.value.to_le_bytes());
+            out.push(symbol.relocatable as u8);
+        }
+        out
+    }
+
+    /// Decode an object file.
+    ///
+    /// Returns an error if the file is malformed or has wrong magic/version.
+    pub fn decode(data: &[u8]) -> Result<Self, String> {
+        if !is_object_format(data) {
+            return Err("Invalid object file magic header".into());
+        }
+        let mut reader = Reader {
+            data,
+            offset: MAGIC.len(),
+        };
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(format!("Unsupported object file version {version}"));
+        }
+
+        let mut object = Object::default();
+        for _ in 0..reader.u16()? {
+            let has_origin = reader.u8()? != 0;
+            let origin = reader.u16()?;
+            let mut section = Section {
+                origin: has_origin.then_some(origin),
+                ..Section::default()
+            };
+            for _ in 0..reader.u32()? {
+                section.code.push(reader.u16()?);
+            }
+            for _ in 0..reader.u32()? {
+                let offset = reader.u16()?;
+                let kind = match reader.u8()? {
+                    0 => RelocKind::Word,
+                    bits => RelocKind::PcOffset(bits),
+                };
+                let target = match reader.u8()? {
+                    0 => RelocTarget::Absolute,
+                    1 => RelocTarget::Section,
+                    2 => RelocTarget::External(reader.name()?),
+                    other => return Err(format!("Invalid relocation target {other}")),
+                };
+                let addend = reader.u32()? as i32;
+                section.relocations.push(Relocation {
+                    offset,
+                    kind,
+                    target,
+                    addend,
+                });
+            }
+            object.sections.push(section);
+        }
+
+        for _ in 0..reader.u32()? {
+            let name = reader.name()?;
+            let value = reader.u16()?;
+            let relocatable = reader.u8()? != 0;
+            object.symbols.push(ObjectSymbol {
+                name,
+                value,
+                relocatable,
+            });
+        }
+        Ok(object)
+    }
+}
+
+/// Check if data looks like an object file (has correct magic header).
+pub fn is_object_format(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    out.extend_from_slice(name.as_bytes());
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, n: usize) -> Result<&[u8], String> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + n)
+            .ok_or("Truncated object file")?;
+        self.offset += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| "Invalid symbol name".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let object = Object {
+            sections: vec![
+                Section {
+                    origin: None,
+                    code: vec![0x4800, 0x0000],
+                    relocations: vec![
+                        Relocation {
+                            offset: 0,
+                            kind: RelocKind::PcOffset(11),
+                            target: RelocTarget::External("PRINT".into()),
+                            addend: 0,
+                        },
+                        Relocation {
+                            offset: 1,
+                            kind: RelocKind::Word,
+                            target: RelocTarget::Section,
+                            addend: -1,
+                        },
+                    ],
+                },
+                Section {
+                    origin: Some(0x4000),
+                    code: vec![0xF025],
+                    relocations: vec![],
+                },
+            ],
+            symbols: vec![ObjectSymbol {
+                name: "MAIN".into(),
+                value: 0,
+                relocatable: true,
+            }],
+        };
+        let data = object.encode();
+        assert!(is_object_format(&data));
+        assert_eq!(Object::decode(&data), Ok(object));
+        assert!(Object::decode(&data[..data.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,266 @@
+//! Pseudo-instructions. Each expands into real instructions; LDIMM, JMP and
+//! CALL load their operand from a literal pool placed at the end of the
+//! segment.
+
+use crate::listing::{self, Entry};
+use crate::{
+    AddSrc2, AndSrc2, Assembler, Code, Expr, Instruction, Pseudo, Register, SemanticError,
+};
+use crate::{Span, Spanned, SpannedLine};
+
+/// The stack pointer used by PUSH and POP.
+const SP: Register = Register(6);
+/// The register through which JMP and CALL reach their target.
+const LINK: Register = Register(7);
+
+/// The literals of the segment being emitted.
+#[derive(Default)]
+pub(crate) struct LiteralPool {
+    /// The literals so far, placed at the end of the segment.
+    pub(crate) entries: Vec<Entry>,
+    /// The number of the next literal; numbers run on across segments.
+    pub(crate) next: usize,
+}
+
+/// The number of words `op` expands to, not counting its literal.
+pub(crate) fn size(op: &Pseudo) -> u16 {
+    match op {
+        Pseudo::Mov { .. }
+        | Pseudo::Clr { .. }
+        | Pseudo::Inc { .. }
+        | Pseudo::Dec { .. }
+        | Pseudo::Ldimm { .. } => 1,
+        Pseudo::Neg { .. }
+        | Pseudo::Push { .. }
+        | Pseudo::Pop { .. }
+        | Pseudo::Jmp { .. }
+        | Pseudo::Call { .. } => 2,
+        Pseudo::Sub { dr, sr1, src2 } => match src2 {
+            AddSrc2::Immediate(_) => 1,
+            AddSrc2::Register(sr2) if dr == sr1 && sr1 == sr2 => 1,
+            AddSrc2::Register(_) => 3,
+        },
+    }
+}
+
+/// The value `op` loads from the literal pool, if any.
+pub(crate) fn literal(op: &Pseudo) -> Option<&Spanned<Expr>> {
+    match op {
+        Pseudo::Ldimm { value, .. } => Some(value),
+        Pseudo::Jmp { target } | Pseudo::Call { target } => Some(target),
+        _ => None,
+    }
+}
+
+/// The symbol giving the address of the `n`th literal; it cannot clash with
+/// a label.
+pub(crate) fn literal_name(n: usize) -> String {
+    format!("={n}")
+}
+
+/// The mnemonic of `op`, for messages.
+fn mnemonic(op: &Pseudo) -> &'static str {
+    match op {
+        Pseudo::Mov { .. } => "MOV",
+        Pseudo::Clr { .. } => "CLR",
+        Pseudo::Inc { .. } => "INC",
+        Pseudo::Dec { .. } => "DEC",
+        Pseudo::Neg { .. } => "NEG",
+        Pseudo::Sub { .. } => "SUB",
+        Pseudo::Push { .. } => "PUSH",
+        Pseudo::Pop { .. } => "POP",
+        Pseudo::Ldimm { .. } => "LDIMM",
+        Pseudo::Jmp { .. } => "JMP",
+        Pseudo::Call { .. } => "CALL",
+    }
+}
+
+impl Assembler {
+    /// Expand `op` into real instructions. `literal` names its pool entry;
+    /// `span` is given to the immediates the expansion makes up.
+    pub(crate) fn expand_pseudo(
+        &self,
+        op: &Pseudo,
+        literal: &str,
+        span: &Span,
+        source: &str,
+        errors: &mut Vec<SemanticError>,
+    ) -> Vec<Instruction> {
+        let imm = |n: i32| Spanned::new(Expr::Number(n), span.clone());
+        let add = |dr, sr1, n| Instruction::Add {
+            dr,
+            sr1,
+            src2: AddSrc2::Immediate(imm(n)),
+        };
+        let add_reg = |dr, sr1, sr2| Instruction::Add {
+            dr,
+            sr1,
+            src2: AddSrc2::Register(sr2),
+        };
+        let clear = |dr| Instruction::And {
+            dr,
+            sr1: dr,
+            src2: AndSrc2::Immediate(imm(0)),
+        };
+        let load = |dr, value: &Spanned<Expr>| Instruction::Ld {
+            dr,
+            label: Spanned::new(Expr::Symbol(literal.to_string()), value.span.clone()),
+        };
+        match op {
+            Pseudo::Mov { dr, sr } => vec![add(*dr, *sr, 0)],
+            Pseudo::Clr { dr } => vec![clear(*dr)],
+            Pseudo::Inc { dr } => vec![add(*dr, *dr, 1)],
+            Pseudo::Dec { dr } => vec![add(*dr, *dr, -1)],
+            Pseudo::Neg { dr, sr } => vec![Instruction::Not { dr: *dr, sr: *sr }, add(*dr, *dr, 1)],
+            Pseudo::Sub {
+                dr,
+                sr1,
+                src2: AddSrc2::Immediate(value),
+            } => {
+                let v = self.resolve_signed(value, -15..=16, "immediate value", source, errors);
+                vec![add(*dr, *sr1, -v.unwrap_or(0))]
+            }
+            Pseudo::Sub {
+                dr,
+                sr1,
+                src2: AddSrc2::Register(sr2),
+            } => {
+                let (dr, sr1, sr2) = (*dr, *sr1, *sr2);
+                if dr == sr1 && sr1 == sr2 {
+                    vec![clear(dr)]
+                } else if dr == sr2 {
+                    // DR = -SR2 + SR1
+                    vec![
+                        Instruction::Not { dr, sr: sr2 },
+                        add(dr, dr, 1),
+                        add_reg(dr, dr, sr1),
+                    ]
+                } else {
+                    // DR = ~(~SR1 + SR2), which leaves SR2 alone
+                    vec![
+                        Instruction::Not { dr, sr: sr1 },
+                        add_reg(dr, dr, sr2),
+                        Instruction::Not { dr, sr: dr },
+                    ]
+                }
+            }
+            Pseudo::Push { sr } => vec![
+                add(SP, SP, -1),
+                Instruction::Str {
+                    sr: *sr,
+                    base: SP,
+                    offset: imm(0),
+                },
+            ],
+            Pseudo::Pop { dr } => vec![
+                Instruction::Ldr {
+                    dr: *dr,
+                    base: SP,
+                    offset: imm(0),
+                },
+                add(SP, SP, 1),
+            ],
+            Pseudo::Ldimm { dr, value } => vec![load(*dr, value)],
+            Pseudo::Jmp { target } => vec![load(LINK, target), Instruction::Jmp { base: LINK }],
+            Pseudo::Call { target } => vec![load(LINK, target), Instruction::Jsrr { base: LINK }],
+        }
+    }
+
+    /// Emit `op`, on `line`, at `pc`, adding its literal, if any, to the
+    /// segment's `pool`. Returns the words and their instructions as text,
+    /// for the listing.
+    pub(crate) fn emit_pseudo(
+        &mut self,
+        op: &Pseudo,
+        pc: u16,
+        line: &SpannedLine,
+        pool: &mut LiteralPool,
+        source: &str,
+        errors: &mut Vec<SemanticError>,
+    ) -> (Vec<u16>, Vec<String>) {
+        let span = &line.span;
+        let name = literal_name(pool.next);
+        let mut text = String::new();
+        if let Some(value) = literal(op) {
+            pool.next += 1;
+            let address = self.literals[&name];
+            let word = self.fill_word(value, address, source, errors).unwrap_or(0);
+            text = match &value.value {
+                Expr::Symbol(symbol) => symbol.clone(),
+                _ => format!("x{word:04X}"),
+            };
+            pool.entries.push(Entry::literal(
+                address,
+                word,
+                listing::line_span(line),
+                format!(".FILL {text}"),
+            ));
+            let distance = address.wrapping_sub(pc.wrapping_add(1));
+            if distance > 0xFF {
+                errors.push(crate::make_error(
+                    source,
+                    value.span.clone(),
+                    Code::OffsetOutOfRange,
+                    format!(
+                        "the literal pool at x{address:04X} is out of range of {} \
+                         ({distance} words ahead, at most 255); split the segment",
+                        mnemonic(op)
+                    ),
+                ));
+                let words = vec![0; size(op) as usize];
+                let texts = vec![String::new(); words.len()];
+                return (words, texts);
+            }
+        }
+
+        let mut words = Vec::new();
+        let mut texts = Vec::new();
+        for (i, instr) in self
+            .expand_pseudo(op, &name, span, source, errors)
+            .iter()
+            .enumerate()
+        {
+            let at = pc.wrapping_add(i as u16);
+            words.push(self.emit_instruction(instr, at, source, span.clone(), errors));
+            texts.push(show(instr, &format!("={text}")));
+        }
+        (words, texts)
+    }
+}
+
+/// An instruction of an expansion as assembly; `literal` stands for the
+/// operand of LD.
+fn show(instr: &Instruction, literal: &str) -> String {
+    let number = |value: &Spanned<Expr>| match value.value {
+        Expr::Number(n) => format!("#{n}"),
+        _ => "?".to_string(),
+    };
+    match instr {
+        Instruction::Add {
+            dr,
+            sr1,
+            src2: AddSrc2::Register(sr2),
+        } => format!("ADD R{}, R{}, R{}", dr.0, sr1.0, sr2.0),
+        Instruction::Add {
+            dr,
+            sr1,
+            src2: AddSrc2::Immediate(value),
+        } => format!("ADD R{}, R{}, {}", dr.0, sr1.0, number(value)),
+        Instruction::And {
+            dr,
+            sr1,
+            src2: AndSrc2::Immediate(value),
+        } => format!("AND R{}, R{}, {}", dr.0, sr1.0, number(value)),
+        Instruction::Not { dr, sr } => format!("NOT R{}, R{}", dr.0, sr.0),
+        Instruction::Ldr { dr, base, offset } => {
+            format!("LDR R{}, R{}, {}", dr.0, base.0, number(offset))
+        }
+        Instruction::Str { sr, base, offset } => {
+            format!("STR R{}, R{}, {}", sr.0, base.0, number(offset))
+        }
+        Instruction::Ld { dr, .. } => format!("LD R{}, {literal}", dr.0),
+        Instruction::Jmp { base } => format!("JMP R{}", base.0),
+        Instruction::Jsrr { base } => format!("JSRR R{}", base.0),
+        _ => unreachable!("not part of an expansion: {instr:?}"),
+    }
+}
@@ -0,0 +1,229 @@
+//! Relaxation: a PC-relative instruction whose target is out of range is
+//! rewritten to reach it through the target's address, stored in a literal
+//! next to the rewritten code.
+
+use crate::object::RelocTarget;
+use crate::{
+    Assembler, Code, Expr, Instruction, Line, Program, Register, SemanticError, Spanned,
+    SpannedLine,
+};
+
+/// `BRnzp #1`, which skips the literal.
+const SKIP_LITERAL: u16 = 0x0E01;
+/// `JSRR R7`.
+const JSRR_R7: u16 = 0x41C0;
+
+/// The target of a PC-relative instruction that can be rewritten, and the
+/// width of its offset. STI cannot be rewritten without a spare register.
+fn target(instr: &Instruction) -> Option<(&Spanned<Expr>, u8)> {
+    match instr {
+        Instruction::Br { label, .. }
+        | Instruction::Ld { label, .. }
+        | Instruction::Ldi { label, .. }
+        | Instruction::Lea { label, .. }
+        | Instruction::St { label, .. } => Some((label, 9)),
+        Instruction::Jsr { label } => Some((label, 11)),
+        _ => None,
+    }
+}
+
+/// The rewritten instruction: each word and its text, and `None` where the
+/// literal goes. Branches jump through `scratch`; calls go through R7,
+/// which they overwrite anyway.
+fn rewrite(instr: &Instruction, scratch: Register) -> Vec<Option<(u16, String)>> {
+    let reg = |r: u8| (r as u16) << 9;
+    let skip = Some((SKIP_LITERAL, "BRnzp #1".to_string()));
+    let load = Some((0x2001 | reg(scratch.0), format!("LD R{}, #1", scratch.0)));
+    let jump = Some((
+        0xC000 | (scratch.0 as u16) << 6,
+        format!("JMP R{}", scratch.0),
+    ));
+    match instr {
+        Instruction::Br { n, z, p, .. } if *n && *z && *p => vec![load, jump, None],
+        Instruction::Br { n, z, p, .. } => {
+            // Branch over the jump when the condition does not hold
+            let (n, z, p) = (!n, !z, !p);
+            let flags = (n as u16) << 11 | (z as u16) << 10 | (p as u16) << 9;
+            vec![
+                Some((flags | 3, format!("{} #3", branch_name(n, z, p)))),
+                load,
+                jump,
+                None,
+            ]
+        }
+        Instruction::Jsr { .. } => vec![
+            skip,
+            None,
+            Some((0x2FFE, "LD R7, #-2".into())),
+            Some((JSRR_R7, "JSRR R7".into())),
+        ],
+        Instruction::Ld { dr, .. } => vec![
+            Some((0xA001 | reg(dr.0), format!("LDI R{}, #1", dr.0))),
+            skip,
+            None,
+        ],
+        Instruction::Ldi { dr, .. } => vec![
+            Some((0xA002 | reg(dr.0), format!("LDI R{}, #2", dr.0))),
+            Some((
+                0x6000 | reg(dr.0) | (dr.0 as u16) << 6,
+                format!("LDR R{0}, R{0}, #0", dr.0),
+            )),
+            skip,
+            None,
+        ],
+        Instruction::Lea { dr, .. } => vec![
+            Some((0x2001 | reg(dr.0), format!("LD R{}, #1", dr.0))),
+            skip,
+            None,
+        ],
+        Instruction::St { sr, .. } => vec![
+            Some((0xB001 | reg(sr.0), format!("STI R{}, #1", sr.0))),
+            skip,
+            None,
+        ],
+        _ => unreachable!("not a PC-relative instruction: {instr:?}"),
+    }
+}
+
+/// The number of words `instr` takes once rewritten.
+pub(crate) fn size(instr: &Instruction) -> u16 {
+    rewrite(instr, Register(7)).len() as u16
+}
+
+fn branch_name(n: bool, z: bool, p: bool) -> String {
+    let flags = [(n, 'n'), (z, 'z'), (p, 'p')];
+    let flags: String = flags
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|&(_, c)| c)
+        .collect();
+    format!("BR{flags}")
+}
+
+fn mnemonic(instr: &Instruction) -> String {
+    match instr {
+        Instruction::Br { n, z, p, .. } => branch_name(*n, *z, *p),
+        Instruction::Jsr { .. } => "JSR".into(),
+        Instruction::Ld { .. } => "LD".into(),
+        Instruction::Ldi { .. } => "LDI".into(),
+        Instruction::Lea { .. } => "LEA".into(),
+        _ => "ST".into(),
+    }
+}
+
+impl Assembler {
+    /// Find the lines whose instruction must be rewritten because its
+    /// target is out of range. Rewriting makes code longer, which may put
+    /// other targets out of range, so repeat until nothing more must be.
+    pub(crate) fn find_far_instructions(&mut self, program: &Program, source: &str) {
+        let (origin, relocatable) = (self.origin, self.relocatable);
+        loop {
+            // Errors are reported by the real passes
+            let addresses = self.first_pass(program, source, &mut Vec::new());
+            let far: Vec<usize> = program
+                .lines
+                .iter()
+                .zip(addresses)
+                .enumerate()
+                .filter(|&(i, (line, pc))| {
+                    !self.relaxed.contains(&i) && self.is_far(&line.line, pc, source)
+                })
+                .map(|(i, _)| i)
+                .collect();
+
+            // Forget the layout, keeping what was decided before it; the
+            // first pass lays it out again
+            let mut relaxed = std::mem::take(&mut self.relaxed);
+            self.reset();
+            (self.origin, self.relocatable) = (origin, relocatable);
+            if far.is_empty() {
+                self.relaxed = relaxed;
+                return;
+            }
+            relaxed.extend(far);
+            self.relaxed = relaxed;
+        }
+    }
+
+    /// Whether `line`, at `pc`, holds an instruction whose target is known
+    /// to be out of range.
+    fn is_far(&self, line: &Line, pc: u16, source: &str) -> bool {
+        let (Line::Instruction(instr) | Line::LabeledInstruction(_, instr)) = line else {
+            return false;
+        };
+        let Some((label, bits)) = target(instr) else {
+            return false;
+        };
+        let Some((value, target)) = self.relocate(label, source, &mut Vec::new()) else {
+            return false;
+        };
+        // Offsets to addresses known only after linking are the linker's
+        let known = match target {
+            RelocTarget::Absolute => !self.relocatable,
+            RelocTarget::Section => true,
+            RelocTarget::External(_) => false,
+        };
+        let Ok(addr) = u16::try_from(value) else {
+            return false;
+        };
+        let offset = addr.wrapping_sub(pc.wrapping_add(1)) as i16 as i32;
+        known && !(-(1 << (bits - 1))..1 << (bits - 1)).contains(&offset)
+    }
+
+    /// Emit the rewritten `instr`, on `line`, at `pc`, and report the
+    /// rewrite. Returns the words and their instructions as text, for the
+    /// listing.
+    pub(crate) fn emit_relaxed(
+        &mut self,
+        instr: &Instruction,
+        pc: u16,
+        line: &SpannedLine,
+        source: &str,
+        errors: &mut Vec<SemanticError>,
+    ) -> (Vec<u16>, Vec<String>) {
+        let (label, _) = target(instr).expect("only PC-relative instructions are rewritten");
+        let mut words = Vec::new();
+        let mut texts = Vec::new();
+        let mut name = String::new();
+        let scratch = self.relax_register();
+        for (i, word) in rewrite(instr, scratch).into_iter().enumerate() {
+            let (word, text) = match word {
+                Some(word) => word,
+                None => {
+                    let at = pc.wrapping_add(i as u16);
+                    let word = self.fill_word(label, at, source, errors).unwrap_or(0);
+                    name = match &label.value {
+                        Expr::Symbol(symbol) => symbol.clone(),
+                        _ => format!("x{word:04X}"),
+                    };
+                    (word, format!(".FILL {name}"))
+                }
+            };
+            words.push(word);
+            texts.push(text);
+        }
+
+        // The loaded address sets the condition codes before a far branch
+        // or call, so they no longer hold what the code before it computed
+        let clobbers = match instr {
+            Instruction::Br { .. } => {
+                format!(" that clobber R{} and the condition codes", scratch.0)
+            }
+            Instruction::Jsr { .. } => " that change the condition codes".into(),
+            _ => String::new(),
+        };
+        let mut warning = crate::make_error(
+            source,
+            label.span.clone(),
+            Code::Relaxed,
+            format!(
+                "{} target {name} is out of range; rewritten as {} words{clobbers}",
+                mnemonic(instr),
+                words.len()
+            ),
+        );
+        warning.expanded_from = line.expanded_from.clone();
+        self.warnings.push(warning);
+        (words, texts)
+    }
+}
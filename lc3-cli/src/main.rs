@@ -1,7 +1,12 @@
-use clap::{Parser, Subcommand};
-use lc3_assembler::{Assembler, lc3tools_format};
-use lc3_core::{LC3, VMError, VMEvent};
-use std::io::{self, Write};
+use clap::{Parser, Subcommand, ValueEnum};
+use lc3_assembler::{
+    Assembler, AssemblyError, IncludeResolver, Register, SourceFile, formats, formats::Format,
+    join_path, lc3tools_format, normalize_path,
+};
+use lc3_core::{Isa, LC3, StdConsole, VMError, VMEvent};
+use lc3_disasm::{SymbolTable, sym};
+use lc3_linker::{Linker, Object};
+use std::collections::HashMap;
 use std::{fs, process};
 
 #[derive(Parser)]
@@ -11,14 +16,97 @@ struct Cli {
     command: Command,
 }
 
+/// Instruction set selectable on the command line.
+#[derive(Clone, Copy, Default, ValueEnum)]
+enum IsaArg {
+    #[default]
+    Lc3,
+    Lc3b,
+}
+
+impl From<IsaArg> for Isa {
+    fn from(arg: IsaArg) -> Self {
+        match arg {
+            IsaArg::Lc3 => Isa::Lc3,
+            IsaArg::Lc3b => Isa::Lc3b,
+        }
+    }
+}
+
+/// How `lc3 assemble` reports errors and warnings.
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum MessageFormat {
+    /// With source context, on stderr
+    #[default]
+    Human,
+    /// One JSON object per line on stdout; other messages go to stderr
+    Json,
+}
+
 #[derive(Subcommand)]
 enum Command {
     /// Assemble LC-3 source to binary
     Assemble {
         /// Input assembly file
         input: String,
-        /// Output binary file (defaults to input with .obj extension)
+        /// Output binary file (defaults to input with .obj extension, or .o
+        /// with --object). The symbol table is written next to it, with
+        /// .sym extension.
         output: Option<String>,
+        /// Instruction set of the source
+        #[arg(long, value_enum, default_value_t)]
+        isa: IsaArg,
+        /// Write a relocatable object file for `lc3 link`
+        #[arg(long)]
+        object: bool,
+        /// Also write a listing of every word with its source line
+        #[arg(long, value_name = "FILE")]
+        listing: Option<String>,
+        /// Allow code in system space (x0000-x2FFF), as for an operating
+        /// system
+        #[arg(long)]
+        system: bool,
+        /// Accept pseudo-instructions such as MOV, PUSH and LDIMM
+        #[arg(long)]
+        pseudo_ops: bool,
+        /// Rewrite branches, calls and loads whose target is out of range
+        /// instead of failing. A far branch overwrites the --relax-reg
+        /// register, so it must not hold a live value at any branch that
+        /// is rewritten, and far branches and calls change the condition
+        /// codes
+        #[arg(long)]
+        relax: bool,
+        /// The register far branches jump through with --relax
+        #[arg(long, value_name = "REG", default_value = "R7", value_parser = parse_register)]
+        relax_reg: Register,
+        /// Define a constant for `.IF` and `.IFDEF`, as NAME=VALUE or just
+        /// NAME (which is 1)
+        #[arg(short = 'D', value_name = "NAME[=VALUE]", value_parser = parse_define)]
+        define: Vec<(String, i32)>,
+        /// Output format: obj, legacy, hex, bin, ihex or memh (defaults to
+        /// the one the output extension names, else obj). Object files
+        /// have a format of their own.
+        #[arg(long, value_parser = parse_format, conflicts_with = "object")]
+        format: Option<Format>,
+        /// How to report errors and warnings
+        #[arg(long, value_enum, default_value_t)]
+        message_format: MessageFormat,
+    },
+    /// Link object files into a binary program
+    Link {
+        /// Object files, in the order to lay them out
+        #[arg(required = true)]
+        inputs: Vec<String>,
+        /// Output binary file
+        #[arg(short, long, default_value = "a.obj")]
+        output: String,
+        /// Address to place relocatable code from
+        #[arg(long, default_value = "x3000", value_parser = parse_address)]
+        base: u16,
+        /// Output format: obj, legacy, hex, bin, ihex or memh (defaults to
+        /// the one the output extension names, else obj)
+        #[arg(long, value_parser = parse_format)]
+        format: Option<Format>,
     },
     /// Run an LC-3 binary program
     Run {
@@ -27,6 +115,27 @@ enum Command {
         /// Path to OS image (optional)
         #[arg(long)]
         os: Option<String>,
+        /// Symbol table file (defaults to the program with .sym extension,
+        /// if it exists)
+        #[arg(long, value_name = "FILE")]
+        symbols: Option<String>,
+        /// Instruction set of the program
+        #[arg(long, value_enum, default_value_t)]
+        isa: IsaArg,
+    },
+    /// Convert a program between formats
+    Convert {
+        /// Input program file
+        input: String,
+        /// Output program file
+        output: String,
+        /// Format of the input (detected from its contents by default)
+        #[arg(long, value_parser = parse_format)]
+        from: Option<Format>,
+        /// Format of the output (defaults to the one the output extension
+        /// names)
+        #[arg(long, value_parser = parse_format)]
+        to: Option<Format>,
     },
 }
 
@@ -34,12 +143,96 @@ fn main() {
     let cli = Cli::parse();
 
     match cli.command {
-        Command::Assemble { input, output } => assemble(&input, output),
-        Command::Run { program, os } => run(&program, os),
+        Command::Assemble {
+            input,
+            output,
+            isa,
+            object: false,
+            listing,
+            system,
+            pseudo_ops,
+            relax,
+            relax_reg,
+            define,
+            format,
+            message_format,
+        } => assemble(
+            &input,
+            output,
+            format,
+            message_format,
+            new_assembler(
+                &input,
+                isa.into(),
+                AssemblerOptions {
+                    system,
+                    pseudo_ops,
+                    relax,
+                    relax_reg,
+                    define,
+                },
+            ),
+            listing,
+        ),
+        Command::Assemble {
+            input,
+            output,
+            isa,
+            object: true,
+            listing,
+            system,
+            pseudo_ops,
+            relax,
+            relax_reg,
+            define,
+            format: _,
+            message_format,
+        } => assemble_object(
+            &input,
+            output,
+            message_format,
+            new_assembler(
+                &input,
+                isa.into(),
+                AssemblerOptions {
+                    system,
+                    pseudo_ops,
+                    relax,
+                    relax_reg,
+                    define,
+                },
+            ),
+            listing,
+        ),
+        Command::Link {
+            inputs,
+            output,
+            base,
+            format,
+        } => link(&inputs, &output, base, format),
+        Command::Run {
+            program,
+            os,
+            symbols,
+            isa,
+        } => run(&program, os, symbols, isa.into()),
+        Command::Convert {
+            input,
+            output,
+            from,
+            to,
+        } => convert(&input, &output, from, to),
     }
 }
 
-fn assemble(input: &str, output: Option<String>) {
+fn assemble(
+    input: &str,
+    output: Option<String>,
+    format: Option<Format>,
+    messages: MessageFormat,
+    mut asm: Assembler,
+    listing: Option<String>,
+) {
     let output = output.unwrap_or_else(|| {
         if input.ends_with(".asm") {
             input.replace(".asm", ".obj")
@@ -53,13 +246,10 @@ fn assemble(input: &str, output: Option<String>) {
         process::exit(1);
     });
 
-    let mut asm = Assembler::new();
-    let binary = match asm.assemble_to_lc3tools(&source) {
-        Ok(b) => b,
-        Err(e) => {
-            eprintln!("{}", asm.format_error(input, &source, &e));
-            process::exit(1);
-        }
+    let result = asm.assemble_to_lc3tools(&source);
+    report(&asm, input, &source, result.as_ref().err(), messages);
+    let Ok(binary) = result else {
+        process::exit(1);
     };
 
     let segments = asm.segments();
@@ -68,43 +258,336 @@ fn assemble(input: &str, output: Option<String>) {
         process::exit(1);
     }
 
+    let format = format
+        .or_else(|| Format::from_path(&output))
+        .unwrap_or(Format::Lc3tools);
+    let binary = match format {
+        Format::Lc3tools => binary,
+        _ => format.encode(segments).unwrap_or_else(|e| {
+            eprintln!("Error: {e}");
+            process::exit(1);
+        }),
+    };
     fs::write(&output, &binary).unwrap_or_else(|e| {
         eprintln!("Error writing '{output}': {e}");
         process::exit(1);
     });
+    let symbols = with_extension(&output, "sym");
+    let table = asm
+        .symbols()
+        .iter()
+        .map(|(name, &addr)| (name.as_str(), addr));
+    fs::write(&symbols, sym::format(table)).unwrap_or_else(|e| {
+        eprintln!("Error writing '{symbols}': {e}");
+        process::exit(1);
+    });
 
     let total_words: usize = segments.iter().map(|s| s.code.len()).sum();
 
+    let mut summary = String::new();
     if segments.len() == 1 {
-        println!(
-            "Assembled {} words to {output} (origin: x{:04X}, lc3tools format)",
-            total_words, segments[0].origin
+        summary += &format!(
+            "Assembled {} words to {output} (origin: x{:04X}, {} format)\n",
+            total_words,
+            segments[0].origin,
+            format.description()
         );
     } else {
-        println!(
-            "Assembled {} words in {} segments to {output} (lc3tools format):",
+        summary += &format!(
+            "Assembled {} words in {} segments to {output} ({} format):\n",
             total_words,
-            segments.len()
+            segments.len(),
+            format.description()
         );
         for (i, seg) in segments.iter().enumerate() {
-            println!(
-                "  Segment {}: {} words at x{:04X}",
+            summary += &format!(
+                "  Segment {}: {} words at x{:04X}\n",
                 i + 1,
                 seg.code.len(),
                 seg.origin
             );
         }
     }
+    status(messages, &summary);
+    if let Some(listing) = listing {
+        write_listing(&asm, &listing, messages);
+    }
+}
+
+fn assemble_object(
+    input: &str,
+    output: Option<String>,
+    messages: MessageFormat,
+    mut asm: Assembler,
+    listing: Option<String>,
+) {
+    let output = output.unwrap_or_else(|| match input.strip_suffix(".asm") {
+        Some(stem) => format!("{stem}.o"),
+        None => format!("{input}.o"),
+    });
+
+    let source = fs::read_to_string(input).unwrap_or_else(|e| {
+        eprintln!("Error reading '{input}': {e}");
+        process::exit(1);
+    });
+
+    let result = asm.assemble_object(&source);
+    report(&asm, input, &source, result.as_ref().err(), messages);
+    let Ok(object) = result else {
+        process::exit(1);
+    };
+
+    fs::write(&output, object.encode()).unwrap_or_else(|e| {
+        eprintln!("Error writing '{output}': {e}");
+        process::exit(1);
+    });
+
+    let words: usize = object.sections.iter().map(|s| s.code.len()).sum();
+    let relocations: usize = object.sections.iter().map(|s| s.relocations.len()).sum();
+    let summary = format!(
+        "Assembled {words} words to {output} ({relocations} relocations, {} global symbols)\n",
+        object.symbols.len()
+    );
+    status(messages, &summary);
+    if let Some(listing) = listing {
+        write_listing(&asm, &listing, messages);
+    }
+}
+
+fn convert(input: &str, output: &str, from: Option<Format>, to: Option<Format>) {
+    let data = fs::read(input).unwrap_or_else(|e| {
+        eprintln!("Error reading '{input}': {e}");
+        process::exit(1);
+    });
+    let from = from.unwrap_or_else(|| Format::detect(&data));
+    let Some(to) = to.or_else(|| Format::from_path(output)) else {
+        eprintln!("Error: cannot tell the format of '{output}' from its extension; use --to");
+        process::exit(1);
+    };
+    let segments = from.decode(&data).unwrap_or_else(|e| {
+        eprintln!("Error loading '{input}' as {}: {e}", from.description());
+        process::exit(1);
+    });
+    let binary = to.encode(&segments).unwrap_or_else(|e| {
+        eprintln!("Error: {e}");
+        process::exit(1);
+    });
+    fs::write(output, binary).unwrap_or_else(|e| {
+        eprintln!("Error writing '{output}': {e}");
+        process::exit(1);
+    });
+
+    let total_words: usize = segments.iter().map(|s| s.code.len()).sum();
+    println!(
+        "Converted {total_words} words in {} segments from {} to {} format",
+        segments.len(),
+        from.description(),
+        to.description()
+    );
+}
+
+/// Assembler settings from the command line.
+struct AssemblerOptions {
+    system: bool,
+    pseudo_ops: bool,
+    relax: bool,
+    relax_reg: Register,
+    define: Vec<(String, i32)>,
+}
+
+/// An assembler for `input`, reading its includes from disk.
+fn new_assembler(input: &str, isa: Isa, options: AssemblerOptions) -> Assembler {
+    let mut asm = Assembler::new();
+    asm.set_isa(isa);
+    asm.set_system_space(options.system);
+    asm.set_pseudo_ops(options.pseudo_ops);
+    asm.set_relax(options.relax);
+    asm.set_relax_register(options.relax_reg);
+    for (name, value) in &options.define {
+        asm.define(name, *value);
+    }
+    asm.set_resolver(normalize_path(input), FileResolver);
+    asm
+}
+
+/// `path` with its extension replaced by (or, without one, followed by)
+/// `extension`.
+fn with_extension(path: &str, extension: &str) -> String {
+    std::path::Path::new(path)
+        .with_extension(extension)
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn write_listing(asm: &Assembler, path: &str, messages: MessageFormat) {
+    fs::write(path, asm.listing()).unwrap_or_else(|e| {
+        eprintln!("Error writing '{path}': {e}");
+        process::exit(1);
+    });
+    status(messages, &format!("Wrote listing to {path}\n"));
+}
+
+/// Report the warnings of the last assembly, and `error`.
+fn report(
+    asm: &Assembler,
+    input: &str,
+    source: &str,
+    error: Option<&AssemblyError>,
+    messages: MessageFormat,
+) {
+    match messages {
+        MessageFormat::Human => {
+            eprint!("{}", asm.format_warnings());
+            if let Some(error) = error {
+                eprintln!("{}", asm.format_error(input, source, error));
+            }
+        }
+        MessageFormat::Json => print!("{}", asm.format_json(input, source, error)),
+    }
+}
+
+/// Print a summary line, leaving stdout to the diagnostics when they are
+/// JSON.
+fn status(messages: MessageFormat, text: &str) {
+    match messages {
+        MessageFormat::Human => print!("{text}"),
+        MessageFormat::Json => eprint!("{text}"),
+    }
+}
+
+fn link(inputs: &[String], output: &str, base: u16, format: Option<Format>) {
+    let mut linker = Linker::new();
+    linker.set_base(base);
+    for input in inputs {
+        let data = fs::read(input).unwrap_or_else(|e| {
+            eprintln!("Error reading '{input}': {e}");
+            process::exit(1);
+        });
+        let object = Object::decode(&data).unwrap_or_else(|e| {
+            eprintln!("Error loading '{input}': {e}");
+            process::exit(1);
+        });
+        linker.add(input.as_str(), object);
+    }
+
+    let segments = linker.link().unwrap_or_else(|errors| {
+        for e in errors {
+            eprintln!("Error: {e}");
+        }
+        process::exit(1);
+    });
+
+    let format = format
+        .or_else(|| Format::from_path(output))
+        .unwrap_or(Format::Lc3tools);
+    let binary = format.encode(&segments).unwrap_or_else(|e| {
+        eprintln!("Error: {e}");
+        process::exit(1);
+    });
+    fs::write(output, binary).unwrap_or_else(|e| {
+        eprintln!("Error writing '{output}': {e}");
+        process::exit(1);
+    });
+
+    let total_words: usize = segments.iter().map(|s| s.code.len()).sum();
+    println!(
+        "Linked {} files into {total_words} words in {} segments to {output} ({} format)",
+        inputs.len(),
+        segments.len(),
+        format.description()
+    );
+}
+
+/// Parse an address such as `x3000`, `0x3000` or `12288`.
+fn parse_address(s: &str) -> Result<u16, String> {
+    let hex = s
+        .strip_prefix(['x', 'X'])
+        .or_else(|| s.strip_prefix("0x"))
+        .or_else(|| s.strip_prefix("0X"));
+    match hex {
+        Some(digits) => u16::from_str_radix(digits, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| format!("invalid address '{s}': {e}"))
+}
+
+/// Parse a register name, `R0` to `R7`.
+fn parse_register(s: &str) -> Result<Register, String> {
+    match s.strip_prefix(['r', 'R']).and_then(|n| n.parse().ok()) {
+        Some(n @ 0..=7) => Ok(Register(n)),
+        _ => Err(format!("invalid register '{s}'; expected R0 to R7")),
+    }
+}
+
+/// Parse a program format name such as `ihex`.
+fn parse_format(s: &str) -> Result<Format, String> {
+    Format::from_name(s).ok_or_else(|| {
+        let names: Vec<&str> = Format::ALL.iter().map(|f| f.name()).collect();
+        format!("unknown format '{s}'; expected one of {}", names.join(", "))
+    })
+}
+
+/// Parse a definition such as `DEBUG`, `LEVEL=2`, `MASK=x00FF` or
+/// `N=#-1`.
+fn parse_define(s: &str) -> Result<(String, i32), String> {
+    let (name, value) = s.split_once('=').unwrap_or((s, "1"));
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!("invalid name '{name}'"));
+    }
+    let number = value.strip_prefix('#').unwrap_or(value);
+    let (negative, number) = match number.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, number),
+    };
+    let parsed = if let Some(hex) = number
+        .strip_prefix(['x', 'X'])
+        .or_else(|| number.strip_prefix("0x"))
+        .or_else(|| number.strip_prefix("0X"))
+    {
+        i32::from_str_radix(hex, 16)
+    } else if let Some(bin) = number
+        .strip_prefix(['b', 'B'])
+        .or_else(|| number.strip_prefix("0b"))
+        .or_else(|| number.strip_prefix("0B"))
+    {
+        i32::from_str_radix(bin, 2)
+    } else {
+        number.parse()
+    }
+    .map_err(|e| format!("invalid value '{value}': {e}"))?;
+    Ok((name.to_string(), if negative { -parsed } else { parsed }))
+}
+
+/// Reads `.INCLUDE`d files from disk, relative to the including file.
+struct FileResolver;
+
+impl IncludeResolver for FileResolver {
+    fn resolve(&mut self, path: &str, from: &str) -> Result<SourceFile, String> {
+        let name = join_path(from, path);
+        let text = fs::read_to_string(&name).map_err(|e| e.to_string())?;
+        Ok(SourceFile { name, text })
+    }
 }
 
 /// Load an .obj file into the VM.
 /// Supports both lc3tools format (with magic header) and legacy format.
-/// Returns the first origin (start PC).
-fn load_obj_file(vm: &mut LC3, data: &[u8]) -> Result<u16, String> {
+/// Returns the first origin (start PC). Source lines stored in the file are
+/// added to `lines`.
+fn load_obj_file(
+    vm: &mut LC3,
+    data: &[u8],
+    lines: &mut HashMap<u16, String>,
+) -> Result<u16, String> {
     if lc3tools_format::is_lc3tools_format(data) {
         // lc3tools format: explicit is_orig flags
         let entries = lc3tools_format::decode(data)?;
         let segments = lc3tools_format::entries_to_segments(&entries);
+        lines.extend(lc3tools_format::entries_to_lines(
+            &entries,
+            vm.isa().word_size(),
+        ));
 
         if segments.is_empty() {
             return Err("No segments found in .obj file".into());
@@ -113,38 +596,64 @@ fn load_obj_file(vm: &mut LC3, data: &[u8]) -> Result<u16, String> {
         let first_origin = segments[0].origin;
 
         for seg in &segments {
-            for (i, &word) in seg.code.iter().enumerate() {
-                vm.memory[seg.origin as usize + i] = word;
-            }
+            vm.load(seg.origin, &seg.code);
         }
 
         Ok(first_origin)
     } else {
-        // Legacy format: [origin:u16][code...] (single segment only for safety)
-        if data.len() < 4 || data.len() % 2 != 0 {
-            return Err("Invalid .obj file: must have even byte count".into());
+        // Legacy .obj, or one of the text formats
+        let segments = formats::decode(data)?;
+        let Some(first) = segments.first() else {
+            return Err("No segments found in program file".into());
+        };
+        let first_origin = first.origin;
+        for seg in &segments {
+            vm.load(seg.origin, &seg.code);
         }
+        Ok(first_origin)
+    }
+}
 
-        let origin = u16::from_be_bytes([data[0], data[1]]);
-        let mut addr = origin as usize;
-
-        for chunk in data[2..].chunks(2) {
-            let word = u16::from_be_bytes([chunk[0], chunk[1]]);
-            vm.memory[addr] = word;
-            addr += 1;
+/// Load the symbol table at `path`, or else the one next to `program`,
+/// if there is one.
+fn load_symbols(program: &str, path: Option<String>) -> SymbolTable {
+    match path {
+        Some(path) => {
+            let text = fs::read_to_string(&path).unwrap_or_else(|e| {
+                eprintln!("Error reading '{path}': {e}");
+                process::exit(1);
+            });
+            sym::parse(&text)
         }
+        None => fs::read_to_string(with_extension(program, "sym"))
+            .map(|text| sym::parse(&text))
+            .unwrap_or_default(),
+    }
+}
 
-        Ok(origin)
+/// `addr` with the nearest label at or before it, e.g. `x3005 (LOOP+2)`.
+fn describe_address(addr: u16, symbols: &SymbolTable) -> String {
+    let label = symbols
+        .iter()
+        .filter(|&(&at, _)| at <= addr)
+        .max_by_key(|&(&at, _)| at);
+    match label {
+        Some((&at, name)) if at == addr => format!("x{addr:04X} ({name})"),
+        Some((&at, name)) => format!("x{addr:04X} ({name}+{})", addr - at),
+        None => format!("x{addr:04X}"),
     }
 }
 
-fn run(path: &str, os_path: Option<String>) {
+fn run(path: &str, os_path: Option<String>, symbols_path: Option<String>, isa: Isa) {
     let data = fs::read(path).unwrap_or_else(|e| {
         eprintln!("Error reading '{path}': {e}");
         process::exit(1);
     });
+    let symbols = load_symbols(path, symbols_path);
+    let mut lines = HashMap::new();
 
     let mut vm = LC3::default();
+    vm.set_isa(isa);
 
     // If OS is provided, load it and enable OS mode
     if let Some(os_p) = os_path {
@@ -152,7 +661,7 @@ fn run(path: &str, os_path: Option<String>) {
             eprintln!("Error reading OS image '{os_p}': {e}");
             process::exit(1);
         });
-        load_obj_file(&mut vm, &os_data).unwrap_or_else(|e| {
+        load_obj_file(&mut vm, &os_data, &mut lines).unwrap_or_else(|e| {
             eprintln!("Error loading OS: {e}");
             process::exit(1);
         });
@@ -162,7 +671,7 @@ fn run(path: &str, os_path: Option<String>) {
         println!("Loaded OS from {os_p}");
     }
 
-    let start_pc = match load_obj_file(&mut vm, &data) {
+    let start_pc = match load_obj_file(&mut vm, &data, &mut lines) {
         Ok(pc) => pc,
         Err(e) => {
             eprintln!("Error: {e}");
@@ -173,50 +682,36 @@ fn run(path: &str, os_path: Option<String>) {
     vm.pc = start_pc;
 
     println!(
-        "Starting at x{:04X} (OS mode: {})...\n",
-        vm.pc,
+        "Starting at {} (OS mode: {})...\n",
+        describe_address(vm.pc, &symbols),
         vm.os_mode()
     );
 
-    let stdin = io::stdin();
-    let mut stdout = io::stdout();
-
-    loop {
-        match vm.run() {
-            VMEvent::None => unreachable!(),
-            VMEvent::Output(c) => {
-                print!("{}", c as char);
-                let _ = stdout.flush();
-            }
-            VMEvent::OutputString(chars) => {
-                print!("{}", chars.iter().map(|&c| c as char).collect::<String>());
-                let _ = stdout.flush();
-            }
-            VMEvent::Halt => {
-                println!("\nProgram halted.");
-                break;
-            }
-            VMEvent::ReadChar => {
-                let _ = stdout.flush();
-                let mut buf = String::new();
-                if stdin.read_line(&mut buf).is_ok() {
-                    vm.regs[0] = buf.chars().next().unwrap_or('\0') as u16;
+    match vm.run_with_io(StdConsole::new()) {
+        VMEvent::Halt => println!("\nProgram halted."),
+        VMEvent::ReadChar => {
+            eprintln!("\nInput ended while the program was waiting for a character");
+            process::exit(1);
+        }
+        VMEvent::Error(e) => {
+            let msg = match e {
+                VMError::ReservedOpcode(op) => format!("Reserved opcode: {op:#06b}"),
+                VMError::UnimplementedTrap(vec) => {
+                    format!("Unimplemented TRAP vector: {vec:#04x}")
                 }
+                VMError::PrivilegeViolation => "Privilege violation: RTI in user mode".to_string(),
+                VMError::UnalignedAccess(addr) => {
+                    format!("Unaligned word access at x{addr:04X}")
+                }
+            };
+            let pc = vm.pc.wrapping_sub(vm.isa().word_size());
+            eprintln!("\nError at PC {}: {msg}", describe_address(pc, &symbols));
+            if let Some(line) = lines.get(&pc) {
+                eprintln!("  {}", line.trim());
             }
-            VMEvent::Error(e) => {
-                let msg = match e {
-                    VMError::ReservedOpcode(op) => format!("Reserved opcode: {op:#06b}"),
-                    VMError::UnimplementedTrap(vec) => {
-                        format!("Unimplemented TRAP vector: {vec:#04x}")
-                    }
-                    VMError::PrivilegeViolation => {
-                        "Privilege violation: RTI in user mode".to_string()
-                    }
-                };
-                eprintln!("\nError at PC x{:04X}: {}", vm.pc.wrapping_sub(1), msg);
-                process::exit(1);
-            }
+            process::exit(1);
         }
+        _ => unreachable!(),
     }
 
     println!("\nRegisters:");
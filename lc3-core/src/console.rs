@@ -0,0 +1,309 @@
+//! Character I/O for hosts that drive the VM with [`LC3::run_with_io`].
+//!
+//! A [`Console`] receives every character the program prints and supplies
+//! characters when it asks for input. Blocking consoles ([`StdConsole`])
+//! wait for the user; non-blocking ones ([`BufferConsole`],
+//! [`ScriptedConsole`]) return `None` when no input is available, which
+//! makes `run_with_io` return so the host can resume later.
+//!
+//! The trait and `run_with_io` are available without `std`; the console
+//! implementations need the `std` feature.
+
+use crate::{LC3, VMEvent};
+#[cfg(feature = "std")]
+use std::collections::VecDeque;
+#[cfg(feature = "std")]
+use std::io::{Read, Write};
+
+/// Host side of the VM's keyboard and display.
+pub trait Console {
+    /// Print one character of program output.
+    fn write(&mut self, c: u8);
+
+    /// Get the next input character, or `None` if none is available.
+    fn read(&mut self) -> Option<u8>;
+
+    /// Flush buffered output, e.g. before waiting for input.
+    fn flush(&mut self) {}
+}
+
+impl<C: Console + ?Sized> Console for &mut C {
+    fn write(&mut self, c: u8) {
+        (**self).write(c);
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        (**self).read()
+    }
+
+    fn flush(&mut self) {
+        (**self).flush();
+    }
+}
+
+#[cfg(feature = "std")]
+/// Blocking console on the process's stdin and stdout.
+///
+/// `read` returns `None` only at end of input.
+#[derive(Debug, Default)]
+pub struct StdConsole;
+
+#[cfg(feature = "std")]
+impl StdConsole {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+#[cfg(feature = "std")]
+impl Console for StdConsole {
+    fn write(&mut self, c: u8) {
+        let _ = std::io::stdout().write_all(&[c]);
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        let mut buf = [0u8];
+        match std::io::stdin().read(&mut buf) {
+            Ok(1) => Some(buf[0]),
+            _ => None,
+        }
+    }
+
+    fn flush(&mut self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+#[cfg(feature = "std")]
+/// Non-blocking console backed by in-memory buffers.
+#[derive(Debug, Clone, Default)]
+pub struct BufferConsole {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl BufferConsole {
+    /// Create a console whose input is `input`.
+    pub fn new(input: impl AsRef<[u8]>) -> Self {
+        Self {
+            input: input.as_ref().iter().copied().collect(),
+            output: Vec::new(),
+        }
+    }
+
+    /// Queue more input characters.
+    pub fn push_input(&mut self, input: impl AsRef<[u8]>) {
+        self.input.extend(input.as_ref());
+    }
+
+    /// Everything the program has printed so far.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// Take the printed output, leaving the buffer empty.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Console for BufferConsole {
+    fn write(&mut self, c: u8) {
+        self.output.push(c);
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+}
+
+#[cfg(feature = "std")]
+/// Non-blocking console that answers prompts.
+///
+/// Each step's reply becomes readable only once the program has printed the
+/// step's prompt, so a test fails (with `ReadChar`) instead of feeding input
+/// to the wrong question.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptedConsole {
+    steps: VecDeque<(Vec<u8>, Vec<u8>)>,
+    pending: VecDeque<u8>,
+    output: Vec<u8>,
+    /// Start of the output not yet matched against a prompt.
+    unmatched: usize,
+}
+
+#[cfg(feature = "std")]
+impl ScriptedConsole {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reply with `input` after the program prints `prompt`.
+    pub fn expect(mut self, prompt: impl AsRef<[u8]>, input: impl AsRef<[u8]>) -> Self {
+        self.steps
+            .push_back((prompt.as_ref().to_vec(), input.as_ref().to_vec()));
+        self
+    }
+
+    /// Everything the program has printed so far.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// Whether every scripted reply has been consumed.
+    pub fn is_finished(&self) -> bool {
+        self.steps.is_empty() && self.pending.is_empty()
+    }
+
+    fn advance(&mut self) {
+        while self.pending.is_empty() {
+            let Some((prompt, _)) = self.steps.front() else {
+                return;
+            };
+            let unmatched = &self.output[self.unmatched..];
+            let Some(pos) = find(unmatched, prompt) else {
+                return;
+            };
+            self.unmatched += pos + prompt.len();
+            let (_, input) = self.steps.pop_front().unwrap();
+            self.pending.extend(input);
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(feature = "std")]
+impl Console for ScriptedConsole {
+    fn write(&mut self, c: u8) {
+        self.output.push(c);
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.advance();
+        self.pending.pop_front()
+    }
+}
+
+impl LC3 {
+    /// Deliver an input character after a [`VMEvent::ReadChar`].
+    ///
+    /// In OS mode the character goes to the keyboard (KBSR/KBDR); in
+    /// shortcut mode it is the GETC result in R0.
+    pub fn set_input(&mut self, c: u8) {
+        if self.os_mode() {
+            self.set_keyboard_input(c);
+        } else {
+            self.regs[0] = c as u16;
+        }
+    }
+
+    /// Run until the program halts or fails, doing all I/O through `console`.
+    ///
+    /// Returns [`VMEvent::Halt`], [`VMEvent::Error`], or [`VMEvent::ReadChar`]
+    /// if the console has no input available; call again to resume.
+    pub fn run_with_io(&mut self, mut console: impl Console) -> VMEvent {
+        // Resuming inside the OS's GETC: it polls KBSR, so fill the keyboard first
+        if self.os_mode()
+            && !self.has_keyboard_input()
+            && let Some(c) = console.read()
+        {
+            self.set_keyboard_input(c);
+        }
+        loop {
+            match self.run() {
+                VMEvent::None => {}
+                VMEvent::Output(c) => console.write(c),
+                VMEvent::OutputString(addr) => {
+                    for c in self.string_at(addr) {
+                        console.write(c);
+                    }
+                }
+                VMEvent::ReadChar => {
+                    console.flush();
+                    match console.read() {
+                        Some(c) => self.set_input(c),
+                        None => {
+                            // In shortcut mode, re-execute the GETC once input arrives
+                            if !self.os_mode() {
+                                self.pc = self.pc.wrapping_sub(self.isa().word_size());
+                            }
+                            return VMEvent::ReadChar;
+                        }
+                    }
+                }
+                event => {
+                    console.flush();
+                    return event;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    /// Prints "?", then echoes two input characters.
+    fn echo_twice() -> LC3 {
+        let mut vm = LC3::default();
+        vm.memory[0x3000] = 0xE002; // LEA R0, PROMPT
+        vm.memory[0x3001] = 0xF022; // PUTS
+        vm.memory[0x3002] = 0x0E02; // BR +2
+        vm.memory[0x3003] = 0x003F; // PROMPT "?"
+        vm.memory[0x3004] = 0x0000;
+        vm.memory[0x3005] = 0xF020; // GETC
+        vm.memory[0x3006] = 0xF021; // OUT
+        vm.memory[0x3007] = 0xF020; // GETC
+        vm.memory[0x3008] = 0xF021; // OUT
+        vm.memory[0x3009] = 0xF025; // HALT
+        vm
+    }
+
+    #[test]
+    fn test_buffer_console() {
+        let mut vm = echo_twice();
+        let mut console = BufferConsole::new("a");
+        assert_eq!(vm.run_with_io(&mut console), VMEvent::ReadChar);
+        assert_eq!(console.output(), b"?a");
+
+        console.push_input("b");
+        assert_eq!(vm.run_with_io(&mut console), VMEvent::Halt);
+        assert_eq!(console.output(), b"?ab");
+    }
+
+    #[test]
+    fn test_scripted_console_waits_for_prompt() {
+        let mut vm = echo_twice();
+        let mut console = ScriptedConsole::new().expect("!", "xy");
+        assert_eq!(vm.run_with_io(&mut console), VMEvent::ReadChar);
+        assert_eq!(console.output(), b"?");
+
+        let mut vm = echo_twice();
+        let mut console = ScriptedConsole::new().expect("?", "xy");
+        assert_eq!(vm.run_with_io(&mut console), VMEvent::Halt);
+        assert_eq!(console.output(), b"?xy");
+        assert!(console.is_finished());
+    }
+
+    #[test]
+    fn test_input_goes_to_keyboard_in_os_mode() {
+        let mut vm = LC3::default();
+        vm.set_os_mode(true);
+        vm.regs[0] = 0x1234;
+        vm.set_input(b'k');
+        assert_eq!(vm.regs[0], 0x1234);
+        assert!(vm.has_keyboard_input());
+    }
+}
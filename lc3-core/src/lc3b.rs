@@ -0,0 +1,355 @@
+//! LC-3b execution.
+//!
+//! LC-3b shares the register file, PSR and device registers with LC-3, but
+//! memory is byte-addressed: `memory[i]` stores the little-endian word at byte
+//! address `2 * i`, and PC-relative and base+offset word accesses are scaled
+//! by 2. Addresses in the device page (xFE00 and up) go through the regular
+//! memory-mapped I/O handlers.
+
+use crate::{Isa, LC3, VMError, VMEvent, mmio, sign_extend};
+
+impl LC3 {
+    /// Execute a single LC-3b instruction.
+    pub(crate) fn step_lc3b(&mut self) -> VMEvent {
+        debug_assert_eq!(self.isa, Isa::Lc3b);
+
+        if self.os_mode && self.memory[mmio::MCR as usize] & 0x8000 == 0 {
+            return VMEvent::Halt;
+        }
+
+        let instr = self.memory[(self.pc >> 1) as usize];
+        self.pc = self.pc.wrapping_add(2);
+
+        match instr >> 12 {
+            0b0001 => self.add(instr),
+            0b0101 => self.and(instr),
+            0b1001 => self.xor(instr),
+            0b0000 => self.br_b(instr),
+            0b1100 => self.jmp(instr),
+            0b0100 => self.jsr_b(instr),
+            0b0010 => self.ldb(instr),
+            0b0110 => {
+                if let Err(e) = self.ldw(instr) {
+                    return VMEvent::Error(e);
+                }
+            }
+            0b1110 => self.lea_b(instr),
+            0b0011 => self.stb(instr),
+            0b0111 => {
+                if let Err(e) = self.stw(instr) {
+                    return VMEvent::Error(e);
+                }
+            }
+            0b1101 => self.shf(instr),
+            0b1111 => return self.trap_b(instr),
+            0b1000 => return self.rti_b(),
+            op => return VMEvent::Error(VMError::ReservedOpcode(op as u8)),
+        }
+
+        if let Some(c) = self.pending_output.take() {
+            return VMEvent::Output(c);
+        }
+
+        VMEvent::None
+    }
+
+    /// Read the word at an even byte address.
+    fn read_word_b(&mut self, addr: u16) -> u16 {
+        if addr >= 0xFE00 {
+            self.mem_read(addr)
+        } else {
+            self.memory[(addr >> 1) as usize]
+        }
+    }
+
+    /// Write the word at an even byte address.
+    fn write_word_b(&mut self, addr: u16, val: u16) {
+        if addr >= 0xFE00 {
+            self.mem_write(addr, val);
+        } else {
+            self.memory[(addr >> 1) as usize] = val;
+        }
+    }
+
+    fn read_byte_b(&mut self, addr: u16) -> u8 {
+        let word = self.read_word_b(addr & !1);
+        if addr & 1 != 0 {
+            (word >> 8) as u8
+        } else {
+            word as u8
+        }
+    }
+
+    fn write_byte_b(&mut self, addr: u16, val: u8) {
+        if addr >= 0xFE00 {
+            // Device registers only see the low byte
+            if addr & 1 == 0 {
+                self.mem_write(addr, val as u16);
+            }
+            return;
+        }
+        let slot = &mut self.memory[(addr >> 1) as usize];
+        *slot = if addr & 1 != 0 {
+            (*slot & 0x00FF) | (val as u16) << 8
+        } else {
+            (*slot & 0xFF00) | val as u16
+        };
+    }
+
+    fn xor(&mut self, instr: u16) {
+        let dr = ((instr >> 9) & 0x7) as usize;
+        let sr1 = self.regs[((instr >> 6) & 0x7) as usize];
+        let val = if instr & 0x20 != 0 {
+            sign_extend(instr & 0x1F, 5)
+        } else {
+            self.regs[(instr & 0x7) as usize]
+        };
+        self.regs[dr] = sr1 ^ val;
+        self.update_flags(dr);
+    }
+
+    fn br_b(&mut self, instr: u16) {
+        let cond = ((instr >> 9) & 0x7) as u8;
+        if cond & self.cond() != 0 {
+            self.pc = self.pc.wrapping_add(sign_extend(instr & 0x1FF, 9) << 1);
+        }
+    }
+
+    fn jsr_b(&mut self, instr: u16) {
+        let target = if instr & 0x800 != 0 {
+            self.pc.wrapping_add(sign_extend(instr & 0x7FF, 11) << 1)
+        } else {
+            self.regs[((instr >> 6) & 0x7) as usize]
+        };
+        self.regs[7] = self.pc;
+        self.pc = target;
+    }
+
+    fn ldb(&mut self, instr: u16) {
+        let dr = ((instr >> 9) & 0x7) as usize;
+        let base = self.regs[((instr >> 6) & 0x7) as usize];
+        let addr = base.wrapping_add(sign_extend(instr & 0x3F, 6));
+        self.regs[dr] = sign_extend(self.read_byte_b(addr) as u16, 8);
+        self.update_flags(dr);
+    }
+
+    fn ldw(&mut self, instr: u16) -> Result<(), VMError> {
+        let dr = ((instr >> 9) & 0x7) as usize;
+        let base = self.regs[((instr >> 6) & 0x7) as usize];
+        let addr = base.wrapping_add(sign_extend(instr & 0x3F, 6) << 1);
+        if addr & 1 != 0 {
+            return Err(VMError::UnalignedAccess(addr));
+        }
+        self.regs[dr] = self.read_word_b(addr);
+        self.update_flags(dr);
+        Ok(())
+    }
+
+    /// LEA does not set condition codes on LC-3b.
+    fn lea_b(&mut self, instr: u16) {
+        let dr = ((instr >> 9) & 0x7) as usize;
+        self.regs[dr] = self.pc.wrapping_add(sign_extend(instr & 0x1FF, 9) << 1);
+    }
+
+    fn stb(&mut self, instr: u16) {
+        let sr = self.regs[((instr >> 9) & 0x7) as usize];
+        let base = self.regs[((instr >> 6) & 0x7) as usize];
+        let addr = base.wrapping_add(sign_extend(instr & 0x3F, 6));
+        self.write_byte_b(addr, sr as u8);
+    }
+
+    fn stw(&mut self, instr: u16) -> Result<(), VMError> {
+        let sr = self.regs[((instr >> 9) & 0x7) as usize];
+        let base = self.regs[((instr >> 6) & 0x7) as usize];
+        let addr = base.wrapping_add(sign_extend(instr & 0x3F, 6) << 1);
+        if addr & 1 != 0 {
+            return Err(VMError::UnalignedAccess(addr));
+        }
+        self.write_word_b(addr, sr);
+        Ok(())
+    }
+
+    /// SHF: bit 4 selects right shift, bit 5 selects arithmetic right shift.
+    fn shf(&mut self, instr: u16) {
+        let dr = ((instr >> 9) & 0x7) as usize;
+        let sr = self.regs[((instr >> 6) & 0x7) as usize];
+        let amount = (instr & 0xF) as u32;
+        self.regs[dr] = match (instr >> 4) & 0x3 {
+            0b00 | 0b10 => sr << amount,
+            0b01 => sr >> amount,
+            _ => ((sr as i16) >> amount) as u16,
+        };
+        self.update_flags(dr);
+    }
+
+    fn trap_b(&mut self, instr: u16) -> VMEvent {
+        let trap_vec = instr & 0xFF;
+        self.regs[7] = self.pc;
+
+        if self.os_mode {
+            // LC-3b TRAP: R7 = PC; PC = MEM[LSHF(ZEXT(trapvect8), 1)]
+            self.pc = self.memory[trap_vec as usize];
+
+            if trap_vec == 0x20 && self.keyboard_data.is_none() {
+                return VMEvent::ReadChar;
+            }
+
+            VMEvent::None
+        } else {
+            match trap_vec {
+                0x20 => VMEvent::ReadChar,
+                0x21 => VMEvent::Output(self.regs[0] as u8),
+                0x22 => VMEvent::OutputString(self.regs[0]),
+                0x25 => VMEvent::Halt,
+                vec => VMEvent::Error(VMError::UnimplementedTrap(vec as u8)),
+            }
+        }
+    }
+
+    fn rti_b(&mut self) -> VMEvent {
+        if !self.os_mode {
+            return VMEvent::None;
+        }
+        if !self.is_supervisor() {
+            return VMEvent::Error(VMError::PrivilegeViolation);
+        }
+
+        let sp = self.regs[6];
+        self.pc = self.read_word_b(sp);
+        self.psr = self.read_word_b(sp.wrapping_add(2));
+        self.regs[6] = sp.wrapping_add(4);
+
+        if !self.is_supervisor() {
+            self.saved_ssp = self.regs[6];
+            self.regs[6] = self.saved_usp;
+        }
+
+        VMEvent::None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vm() -> LC3 {
+        let mut vm = LC3::default();
+        vm.set_isa(Isa::Lc3b);
+        vm
+    }
+
+    #[test]
+    fn test_pc_advances_by_two() {
+        let mut vm = vm();
+        vm.load(0x3000, &[0x1021, 0x1021]); // ADD R0, R0, #1 (x2)
+        vm.step();
+        assert_eq!(vm.pc, 0x3002);
+        vm.step();
+        assert_eq!(vm.pc, 0x3004);
+        assert_eq!(vm.regs[0], 2);
+    }
+
+    #[test]
+    fn test_branch_offset_scaled() {
+        let mut vm = vm();
+        vm.load(0x3000, &[0x0E02]); // BRnzp +2 words
+        vm.step();
+        assert_eq!(vm.pc, 0x3006);
+    }
+
+    #[test]
+    fn test_ldb_sign_extends() {
+        let mut vm = vm();
+        vm.load(0x3000, &[0x2042]); // LDB R0, R1, #2
+        vm.load(0x4000, &[0x0000, 0x7F80]); // bytes at x4002 = x80, x4003 = x7F
+        vm.regs[1] = 0x4000;
+        vm.step();
+        assert_eq!(vm.regs[0], 0xFF80);
+        assert!(vm.n());
+    }
+
+    #[test]
+    fn test_stb_preserves_other_byte() {
+        let mut vm = vm();
+        vm.load(0x3000, &[0x3041]); // STB R0, R1, #1
+        vm.load(0x4000, &[0x1234]);
+        vm.regs[0] = 0xABCD;
+        vm.regs[1] = 0x4000;
+        vm.step();
+        assert_eq!(vm.memory[0x2000], 0xCD34);
+    }
+
+    #[test]
+    fn test_ldw_scales_offset() {
+        let mut vm = vm();
+        vm.load(0x3000, &[0x6041]); // LDW R0, R1, #1 -> byte offset 2
+        vm.load(0x4000, &[0x1111, 0x2222]);
+        vm.regs[1] = 0x4000;
+        vm.step();
+        assert_eq!(vm.regs[0], 0x2222);
+    }
+
+    #[test]
+    fn test_ldw_unaligned() {
+        let mut vm = vm();
+        vm.load(0x3000, &[0x6040]); // LDW R0, R1, #0
+        vm.regs[1] = 0x4001;
+        assert_eq!(vm.step(), VMEvent::Error(VMError::UnalignedAccess(0x4001)));
+    }
+
+    #[test]
+    fn test_shf() {
+        let mut vm = vm();
+        vm.load(0x3000, &[0xD044, 0xD054, 0xD074]); // LSHF/RSHFL/RSHFA R0, R1, #4
+        vm.regs[1] = 0x8010;
+        vm.step();
+        assert_eq!(vm.regs[0], 0x0100);
+        vm.step();
+        assert_eq!(vm.regs[0], 0x0801);
+        vm.step();
+        assert_eq!(vm.regs[0], 0xF801);
+    }
+
+    #[test]
+    fn test_xor_replaces_not() {
+        let mut vm = vm();
+        vm.load(0x3000, &[0x9042, 0x907F]); // XOR R0, R1, R2; NOT R0, R1
+        vm.regs[1] = 0x00FF;
+        vm.regs[2] = 0x0F0F;
+        vm.step();
+        assert_eq!(vm.regs[0], 0x0FF0);
+        vm.step();
+        assert_eq!(vm.regs[0], 0xFF00);
+    }
+
+    #[test]
+    fn test_lea_keeps_flags() {
+        let mut vm = vm();
+        vm.load(0x3000, &[0xE003]); // LEA R0, +3 words
+        vm.step();
+        assert_eq!(vm.regs[0], 0x3008);
+        assert!(vm.z());
+    }
+
+    #[test]
+    fn test_puts_reads_bytes() {
+        let mut vm = vm();
+        vm.load(0x3000, &[0xF022]);
+        vm.load(0x4000, &[0x6948, 0x0021]); // "Hi!\0"
+        vm.regs[0] = 0x4000;
+        assert_eq!(vm.step(), VMEvent::OutputString(0x4000));
+        assert!(vm.string_at(0x4000).eq(*b"Hi!"));
+    }
+
+    #[test]
+    fn test_trap_vector_table_os_mode() {
+        let mut vm = vm();
+        vm.set_os_mode(true);
+        vm.memory[0xFFFE] = 0x8000;
+        vm.load(0x0042, &[0x1200]); // vector x21 at byte address x42
+        vm.load(0x3000, &[0xF021]);
+        vm.step();
+        assert_eq!(vm.pc, 0x1200);
+        assert_eq!(vm.regs[7], 0x3002);
+    }
+}
@@ -20,6 +20,40 @@
 //! - `0xFE04` - DSR (Display Status Register)
 //! - `0xFE06` - DDR (Display Data Register)
 //! - `0xFFFE` - MCR (Machine Control Register)
+//! - `0xFE10` - TSR (Test-and-Set Register, opt-in)
+//!
+//! # LC-3b
+//!
+//! The VM can also execute the byte-addressable LC-3b variant (see [`Isa`]).
+//! In that mode addresses are byte addresses, the PC advances by 2, and
+//! opcodes 0010/0011/1001/1101 become LDB/STB/XOR/SHF.
+//!
+//! # Multi-core
+//!
+//! [`System`] runs several cores with private registers over one shared
+//! memory, interleaved by a deterministic, seedable [`Schedule`].
+//!
+//! # `no_std`
+//!
+//! The VM itself needs neither `std` nor an allocator. Disable the default
+//! `std` feature to build it for embedded targets; only the [`Console`]
+//! implementations in [`console`] require `std`.
+//!
+//! # Custom instructions
+//!
+//! On LC-3 the reserved opcode 1101 can be given an implementation with
+//! [`LC3::set_extension`], e.g. to try out a MUL instruction.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub mod console;
+mod lc3b;
+pub mod multicore;
+
+pub use console::Console;
+#[cfg(feature = "std")]
+pub use console::{BufferConsole, ScriptedConsole, StdConsole};
+pub use multicore::{Schedule, System};
 
 /// Memory-mapped I/O addresses
 pub mod mmio {
@@ -33,6 +67,38 @@ pub mod mmio {
     pub const DDR: u16 = 0xFE06;
     /// Machine Control Register - bit 15 is clock enable (0 = halt)
     pub const MCR: u16 = 0xFFFE;
+    /// Test-and-Set Register - reading returns the value and sets it to 1
+    /// (only when enabled with [`LC3::set_test_and_set`](crate::LC3::set_test_and_set))
+    pub const TSR: u16 = 0xFE10;
+}
+
+/// Instruction set architecture executed by the VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Isa {
+    /// Word-addressable LC-3.
+    #[default]
+    Lc3,
+    /// Byte-addressable LC-3b: LDB/STB/LDW/STW/XOR/SHF, PC offsets scaled by 2.
+    Lc3b,
+}
+
+impl core::fmt::Display for Isa {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Isa::Lc3 => "LC-3",
+            Isa::Lc3b => "LC-3b",
+        })
+    }
+}
+
+impl Isa {
+    /// Number of addresses occupied by one 16-bit word.
+    pub const fn word_size(self) -> u16 {
+        match self {
+            Isa::Lc3 => 1,
+            Isa::Lc3b => 2,
+        }
+    }
 }
 
 /// Events emitted by the VM during execution.
@@ -42,11 +108,12 @@ pub enum VMEvent {
     None,
     /// Output a single character (from DDR write in OS mode, or TRAP x21 in shortcut mode)
     Output(u8),
-    /// TRAP x22 (PUTS) - output a null-terminated string starting at address in R0 (shortcut mode only).
-    OutputString(Vec<u8>),
+    /// TRAP x22 (PUTS) - output the null-terminated string at this address (shortcut mode only).
+    /// Read it with [`LC3::string_at`].
+    OutputString(u16),
     /// VM halted (MCR bit 15 cleared in OS mode, or TRAP x25 in shortcut mode).
     Halt,
-    /// VM requests character input. Call `set_keyboard_input` before continuing.
+    /// VM requests character input. Call `set_input` before continuing.
     ReadChar,
     /// An error occurred during execution.
     Error(VMError),
@@ -61,8 +128,14 @@ pub enum VMError {
     UnimplementedTrap(u8),
     /// Privilege mode violation (RTI in user mode).
     PrivilegeViolation,
+    /// Word access to an odd address (LC-3b only).
+    UnalignedAccess(u16),
 }
 
+/// Implementation of the reserved opcode (1101), called with the full
+/// instruction word after the PC has been incremented.
+pub type ExtensionHandler = fn(&mut LC3, u16) -> VMEvent;
+
 /// LC-3 Virtual Machine state.
 #[derive(Clone)]
 pub struct LC3 {
@@ -87,6 +160,12 @@ pub struct LC3 {
     keyboard_data: Option<u8>,
     /// Pending output character (for DDR writes).
     pending_output: Option<u8>,
+    /// Instruction set being executed.
+    isa: Isa,
+    /// Handler for the reserved opcode (LC-3 only).
+    extension: Option<ExtensionHandler>,
+    /// Whether reads of TSR are test-and-set.
+    test_and_set: bool,
 }
 
 impl Default for LC3 {
@@ -102,6 +181,9 @@ impl Default for LC3 {
             os_mode: false,
             keyboard_data: None,
             pending_output: None,
+            isa: Isa::Lc3,
+            extension: None,
+            test_and_set: false,
         }
     }
 }
@@ -118,7 +200,51 @@ impl LC3 {
         self.saved_usp = 0x0000;
         self.keyboard_data = None;
         self.pending_output = None;
-        // Note: os_mode is preserved across reset
+        // Note: os_mode, isa, extension and test_and_set are preserved across reset
+    }
+
+    /// Select the instruction set to execute.
+    ///
+    /// On LC-3b, `memory[i]` holds the word at byte address `2 * i`, and device
+    /// registers keep their usual (even) addresses.
+    pub fn set_isa(&mut self, isa: Isa) {
+        self.isa = isa;
+    }
+
+    /// Get the instruction set being executed.
+    pub fn isa(&self) -> Isa {
+        self.isa
+    }
+
+    /// Install a handler for the reserved opcode 1101 (`None` traps again).
+    pub fn set_extension(&mut self, handler: Option<ExtensionHandler>) {
+        self.extension = handler;
+    }
+
+    /// Get the handler for the reserved opcode, if any.
+    pub fn extension(&self) -> Option<ExtensionHandler> {
+        self.extension
+    }
+
+    /// Enable or disable the test-and-set register at [`mmio::TSR`].
+    pub fn set_test_and_set(&mut self, enabled: bool) {
+        self.test_and_set = enabled;
+    }
+
+    /// Check if the test-and-set register is enabled.
+    pub fn test_and_set(&self) -> bool {
+        self.test_and_set
+    }
+
+    /// Copy `words` into memory starting at address `origin`.
+    pub fn load(&mut self, origin: u16, words: &[u16]) {
+        let start = match self.isa {
+            Isa::Lc3 => origin,
+            Isa::Lc3b => origin >> 1,
+        };
+        for (i, &word) in words.iter().enumerate() {
+            self.memory[start.wrapping_add(i as u16) as usize] = word;
+        }
     }
 
     /// Enable or disable OS mode.
@@ -157,6 +283,38 @@ impl LC3 {
     pub fn is_supervisor(&self) -> bool {
         self.psr & 0x8000 == 0
     }
+
+    /// Characters of the null-terminated string at `addr`, as printed by PUTS.
+    ///
+    /// On LC-3 each word holds one character; on LC-3b strings are packed
+    /// one character per byte.
+    pub fn string_at(&self, addr: u16) -> impl Iterator<Item = u8> + '_ {
+        (0..=u16::MAX).map_while(move |i| {
+            let at = addr.wrapping_add(i);
+            // LC-3 stops at a zero word, LC-3b at a zero byte
+            let (unit, c) = match self.isa {
+                Isa::Lc3 => {
+                    let word = self.memory[at as usize];
+                    (word, word as u8)
+                }
+                Isa::Lc3b => {
+                    let word = self.memory[(at >> 1) as usize];
+                    let c = if at & 1 != 0 {
+                        (word >> 8) as u8
+                    } else {
+                        word as u8
+                    };
+                    (c as u16, c)
+                }
+            };
+            (unit != 0).then_some(c)
+        })
+    }
+
+    /// Set the condition codes from the value in register `r`.
+    pub fn set_cc(&mut self, r: usize) {
+        self.update_flags(r);
+    }
 }
 
 impl LC3 {
@@ -194,10 +352,7 @@ impl LC3 {
                     0x0000
                 }
             }
-            mmio::KBDR => {
-                let data = self.keyboard_data.take().unwrap_or(0) as u16;
-                data
-            }
+            mmio::KBDR => self.keyboard_data.take().unwrap_or(0) as u16,
             mmio::DSR => {
                 // Display is always ready
                 0x8000
@@ -210,6 +365,10 @@ impl LC3 {
                 // Return MCR with clock running (bit 15 = 1)
                 self.memory[addr as usize] | 0x8000
             }
+            mmio::TSR if self.test_and_set => {
+                // Atomic: no other core runs within one instruction
+                core::mem::replace(&mut self.memory[addr as usize], 1)
+            }
             _ => self.memory[addr as usize],
         }
     }
@@ -245,6 +404,10 @@ impl LC3 {
     /// The PC is incremented before the instruction executes (as per LC-3 spec),
     /// so PC-relative addressing is calculated from PC+1.
     pub fn step(&mut self) -> VMEvent {
+        if self.isa == Isa::Lc3b {
+            return self.step_lc3b();
+        }
+
         // Check if MCR clock bit is cleared (halt condition in OS mode)
         if self.os_mode && self.memory[mmio::MCR as usize] & 0x8000 == 0 {
             return VMEvent::Halt;
@@ -269,6 +432,15 @@ impl LC3 {
             0b0111 => self.str_instr(instr),
             0b1111 => return self.trap(instr),
             0b1000 => return self.rti(),
+            0b1101 => match self.extension {
+                Some(handler) => {
+                    let event = handler(self, instr);
+                    if event != VMEvent::None {
+                        return event;
+                    }
+                }
+                None => return VMEvent::Error(VMError::ReservedOpcode(0b1101)),
+            },
             op => return VMEvent::Error(VMError::ReservedOpcode(op as u8)),
         }
 
@@ -333,12 +505,14 @@ impl LC3 {
     }
 
     fn jsr(&mut self, instr: u16) {
-        self.regs[7] = self.pc;
-        self.pc = if instr & 0x800 != 0 {
+        // Read the base register first, so that JSRR R7 works
+        let target = if instr & 0x800 != 0 {
             self.pc.wrapping_add(sign_extend(instr & 0x7FF, 11))
         } else {
             self.regs[((instr >> 6) & 0x7) as usize]
         };
+        self.regs[7] = self.pc;
+        self.pc = target;
     }
 
     fn ld(&mut self, instr: u16) {
@@ -427,15 +601,7 @@ impl LC3 {
             match trap_vec {
                 0x20 => VMEvent::ReadChar,
                 0x21 => VMEvent::Output(self.regs[0] as u8),
-                0x22 => {
-                    let mut addr = self.regs[0] as usize;
-                    let mut chars = Vec::new();
-                    while self.memory[addr] != 0 {
-                        chars.push(self.memory[addr] as u8);
-                        addr += 1;
-                    }
-                    VMEvent::OutputString(chars)
-                }
+                0x22 => VMEvent::OutputString(self.regs[0]),
                 0x25 => VMEvent::Halt,
                 vec => VMEvent::Error(VMError::UnimplementedTrap(vec as u8)),
             }
@@ -526,6 +692,32 @@ mod tests {
         assert_eq!(vm.regs[0], 15);
     }
 
+    #[test]
+    fn test_reserved_opcode_extension() {
+        fn mul(vm: &mut LC3, instr: u16) -> VMEvent {
+            let dr = ((instr >> 9) & 0x7) as usize;
+            let a = vm.regs[((instr >> 6) & 0x7) as usize];
+            let b = vm.regs[(instr & 0x7) as usize];
+            vm.regs[dr] = a.wrapping_mul(b);
+            vm.set_cc(dr);
+            VMEvent::None
+        }
+
+        let mut vm = LC3::default();
+        vm.regs[1] = 6;
+        vm.regs[2] = 7;
+        vm.memory[0x3000] = 0xD042; // MUL R0, R1, R2
+        assert_eq!(
+            vm.clone().step(),
+            VMEvent::Error(VMError::ReservedOpcode(0b1101))
+        );
+
+        vm.set_extension(Some(mul));
+        assert_eq!(vm.step(), VMEvent::None);
+        assert_eq!(vm.regs[0], 42);
+        assert!(vm.p());
+    }
+
     #[test]
     fn test_halt_shortcut_mode() {
         let mut vm = LC3::default();
@@ -542,6 +734,16 @@ mod tests {
         assert_eq!(vm.pc, 0x3003);
     }
 
+    #[test]
+    fn test_jsrr_r7() {
+        let mut vm = LC3::default();
+        vm.regs[7] = 0x4000;
+        vm.memory[0x3000] = 0x41C0; // JSRR R7
+        vm.step();
+        assert_eq!(vm.pc, 0x4000);
+        assert_eq!(vm.regs[7], 0x3001);
+    }
+
     #[test]
     fn test_mmio_dsr_always_ready() {
         let mut vm = LC3::default();
@@ -0,0 +1,342 @@
+//! Several LC-3 cores sharing one memory.
+//!
+//! Each core has its own registers, PC and PSR; memory and devices are
+//! shared. Cores are interleaved one instruction at a time by a
+//! [`Schedule`], so every instruction is atomic and a run is fully
+//! reproducible from its schedule (including the seed).
+
+use crate::{LC3, VMEvent};
+
+/// Interleaving policy of a [`System`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    /// Cores take turns, each running `quantum` instructions.
+    RoundRobin { quantum: u32 },
+    /// A pseudo-random core runs for 1 to `max_quantum` instructions.
+    /// The same seed always produces the same interleaving.
+    Random { seed: u64, max_quantum: u32 },
+}
+
+/// Private state of one core.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Core {
+    pub regs: [u16; 8],
+    pub pc: u16,
+    psr: u16,
+    saved_ssp: u16,
+    saved_usp: u16,
+    halted: bool,
+}
+
+impl Default for Core {
+    fn default() -> Self {
+        // Same reset state as `LC3::default`
+        Self {
+            regs: [0; 8],
+            pc: 0x3000,
+            psr: 0x8002,
+            saved_ssp: 0x3000,
+            saved_usp: 0x0000,
+            halted: false,
+        }
+    }
+}
+
+impl Core {
+    /// Get the PSR value.
+    pub fn psr(&self) -> u16 {
+        self.psr
+    }
+
+    /// Set the PSR value.
+    pub fn set_psr(&mut self, psr: u16) {
+        self.psr = psr;
+    }
+
+    /// Check if the core has executed HALT.
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Resume a halted core (e.g. after changing its PC).
+    pub fn resume(&mut self) {
+        self.halted = false;
+    }
+}
+
+/// `N` LC-3 cores over one shared 64K memory.
+#[derive(Clone)]
+pub struct System<const N: usize> {
+    /// Shared memory and devices; its registers belong to the running core.
+    vm: LC3,
+    cores: [Core; N],
+    schedule: Schedule,
+    rng: u64,
+    current: usize,
+    /// Instructions left in the current core's quantum.
+    remaining: u32,
+}
+
+impl<const N: usize> System<N> {
+    /// Create a system whose cores all start at x3000 in user mode.
+    pub fn new(schedule: Schedule) -> Self {
+        let rng = match schedule {
+            Schedule::RoundRobin { .. } => 0,
+            Schedule::Random { seed, .. } => seed,
+        };
+        Self {
+            vm: LC3::default(),
+            cores: [Core::default(); N],
+            schedule,
+            rng,
+            current: N.saturating_sub(1),
+            remaining: 0,
+        }
+    }
+
+    /// Shared memory, devices and VM settings (OS mode, ISA, test-and-set).
+    ///
+    /// Its registers are overwritten by the scheduled core on every step;
+    /// use [`System::core_mut`] to change a core's registers.
+    pub fn vm(&self) -> &LC3 {
+        &self.vm
+    }
+
+    /// Mutable access to the shared VM; see [`System::vm`].
+    pub fn vm_mut(&mut self) -> &mut LC3 {
+        &mut self.vm
+    }
+
+    /// Get the state of core `i`.
+    pub fn core(&self, i: usize) -> &Core {
+        &self.cores[i]
+    }
+
+    /// Mutable access to the state of core `i`.
+    pub fn core_mut(&mut self, i: usize) -> &mut Core {
+        &mut self.cores[i]
+    }
+
+    /// Get the state of all cores.
+    pub fn cores(&self) -> &[Core; N] {
+        &self.cores
+    }
+
+    /// Index of the core that executed the last instruction.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Check if every core has halted.
+    pub fn is_halted(&self) -> bool {
+        self.cores.iter().all(|core| core.halted)
+    }
+
+    /// Deliver an input character to `core` after it reported `ReadChar`.
+    ///
+    /// In OS mode the keyboard is shared, so `core` is ignored.
+    pub fn set_input(&mut self, core: usize, c: u8) {
+        if self.vm.os_mode() {
+            self.vm.set_keyboard_input(c);
+        } else {
+            self.cores[core].regs[0] = c as u16;
+        }
+    }
+
+    /// Execute one instruction on the next scheduled core.
+    ///
+    /// Returns the core that ran and its event, or `None` once every core
+    /// has halted.
+    pub fn step(&mut self) -> Option<(usize, VMEvent)> {
+        let i = self.pick()?;
+        self.switch_in(i);
+        let event = self.vm.step();
+        self.switch_out(i);
+
+        if event == VMEvent::Halt {
+            if self.vm.os_mode() {
+                // MCR is shared: clearing the clock stops every core
+                self.cores.iter_mut().for_each(|core| core.halted = true);
+            } else {
+                self.cores[i].halted = true;
+            }
+        }
+        Some((i, event))
+    }
+
+    /// Execute instructions until some core produces an event.
+    pub fn run(&mut self) -> Option<(usize, VMEvent)> {
+        loop {
+            match self.step()? {
+                (_, VMEvent::None) => continue,
+                result => return Some(result),
+            }
+        }
+    }
+
+    /// Choose the core for the next instruction.
+    fn pick(&mut self) -> Option<usize> {
+        if self.remaining > 0 && !self.cores[self.current].halted {
+            self.remaining -= 1;
+            return Some(self.current);
+        }
+
+        let running = self.cores.iter().filter(|core| !core.halted).count();
+        if running == 0 {
+            return None;
+        }
+
+        let (next, quantum) = match self.schedule {
+            Schedule::RoundRobin { quantum } => {
+                let next = (1..=N)
+                    .map(|k| (self.current + k) % N)
+                    .find(|&j| !self.cores[j].halted)?;
+                (next, quantum)
+            }
+            Schedule::Random { max_quantum, .. } => {
+                let nth = (self.next_random() % running as u64) as usize;
+                let next = (0..N).filter(|&j| !self.cores[j].halted).nth(nth)?;
+                let quantum = 1 + (self.next_random() % max_quantum.max(1) as u64) as u32;
+                (next, quantum)
+            }
+        };
+
+        self.current = next;
+        self.remaining = quantum.max(1) - 1;
+        Some(next)
+    }
+
+    /// SplitMix64, so interleavings don't depend on any external RNG.
+    fn next_random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn switch_in(&mut self, i: usize) {
+        let core = &self.cores[i];
+        self.vm.regs = core.regs;
+        self.vm.pc = core.pc;
+        self.vm.psr = core.psr;
+        self.vm.saved_ssp = core.saved_ssp;
+        self.vm.saved_usp = core.saved_usp;
+    }
+
+    fn switch_out(&mut self, i: usize) {
+        let core = &mut self.cores[i];
+        core.regs = self.vm.regs;
+        core.pc = self.vm.pc;
+        core.psr = self.vm.psr;
+        core.saved_ssp = self.vm.saved_ssp;
+        core.saved_usp = self.vm.saved_usp;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmio;
+
+    const ITERATIONS: u16 = 50;
+    const COUNTER: u16 = 0x4000;
+
+    /// Increment the counter at R3 R2 times without a lock.
+    const RACY: [u16; 6] = [
+        0x62C0, // LDR R1, R3, #0
+        0x1261, // ADD R1, R1, #1
+        0x72C0, // STR R1, R3, #0
+        0x14BF, // ADD R2, R2, #-1
+        0x03FB, // BRp x3000
+        0xF025, // HALT
+    ];
+
+    /// Same, guarded by a spinlock on the TSR at R5.
+    const LOCKED: [u16; 10] = [
+        0x6940, // LDR R4, R5, #0  ; test-and-set
+        0x03FE, // BRp x3000       ; spin while held
+        0x62C0, // LDR R1, R3, #0
+        0x1261, // ADD R1, R1, #1
+        0x72C0, // STR R1, R3, #0
+        0x5920, // AND R4, R4, #0
+        0x7940, // STR R4, R5, #0  ; release
+        0x14BF, // ADD R2, R2, #-1
+        0x03F7, // BRp x3000
+        0xF025, // HALT
+    ];
+
+    fn system(program: &[u16], schedule: Schedule) -> System<2> {
+        let mut sys = System::new(schedule);
+        sys.vm_mut().load(0x3000, program);
+        sys.vm_mut().set_test_and_set(true);
+        for i in 0..2 {
+            let core = sys.core_mut(i);
+            core.regs[2] = ITERATIONS;
+            core.regs[3] = COUNTER;
+            core.regs[5] = mmio::TSR;
+        }
+        sys
+    }
+
+    fn run_to_halt(sys: &mut System<2>) -> u16 {
+        while let Some((_, event)) = sys.run() {
+            assert_eq!(event, VMEvent::Halt);
+        }
+        sys.vm().memory[COUNTER as usize]
+    }
+
+    #[test]
+    fn test_round_robin_exposes_race() {
+        let mut sys = system(&RACY, Schedule::RoundRobin { quantum: 1 });
+        // Lockstep cores read the same value every time
+        assert_eq!(run_to_halt(&mut sys), ITERATIONS);
+
+        let mut sys = system(&RACY, Schedule::RoundRobin { quantum: 1000 });
+        assert_eq!(run_to_halt(&mut sys), 2 * ITERATIONS);
+    }
+
+    #[test]
+    fn test_random_schedule_is_reproducible() {
+        let schedule = Schedule::Random {
+            seed: 42,
+            max_quantum: 4,
+        };
+        let mut a = system(&RACY, schedule);
+        let mut b = system(&RACY, schedule);
+        let mut ran = [false; 2];
+        while let Some((core, _)) = a.step() {
+            ran[core] = true;
+            assert_eq!(b.step().map(|(core, _)| core), Some(core));
+        }
+        assert!(b.step().is_none());
+        assert_eq!(ran, [true, true]);
+        assert!(a.vm().memory[COUNTER as usize] < 2 * ITERATIONS);
+    }
+
+    #[test]
+    fn test_spinlock_with_test_and_set() {
+        for seed in 0..8 {
+            let mut sys = system(
+                &LOCKED,
+                Schedule::Random {
+                    seed,
+                    max_quantum: 3,
+                },
+            );
+            assert_eq!(run_to_halt(&mut sys), 2 * ITERATIONS);
+            assert_eq!(sys.vm().memory[mmio::TSR as usize], 0);
+        }
+    }
+
+    #[test]
+    fn test_tsr_is_plain_memory_when_disabled() {
+        let mut vm = LC3::default();
+        vm.memory[mmio::TSR as usize] = 0;
+        assert_eq!(vm.mem_read(mmio::TSR), 0);
+        assert_eq!(vm.mem_read(mmio::TSR), 0);
+        vm.set_test_and_set(true);
+        assert_eq!(vm.mem_read(mmio::TSR), 0);
+        assert_eq!(vm.mem_read(mmio::TSR), 1);
+    }
+}
@@ -1,12 +1,33 @@
 //! LC-3 Disassembler
 //!
 //! Converts 16-bit LC-3 machine code instructions to human-readable assembly format.
+//! LC-3b code is supported through [`disassemble_with_isa`], and custom
+//! instructions on the reserved opcode through [`disassemble_with`].
+//! Symbol tables can be read from `.sym` files with [`sym::parse`].
+
+pub use lc3_core::Isa;
+
+pub mod sym;
 
 use std::collections::HashMap;
 
 /// Symbol table type - maps address to label name
 pub type SymbolTable = HashMap<u16, String>;
 
+/// Formatting hook for the reserved opcode (1101).
+///
+/// Receives the instruction, the PC after fetch and the symbol table, and
+/// returns `None` to fall back to `.FILL`.
+pub type ReservedFormatter = fn(u16, u16, Option<&SymbolTable>) -> Option<String>;
+
+/// Disassembly settings.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Options {
+    pub isa: Isa,
+    /// Formatter for custom instructions on the reserved opcode (LC-3 only).
+    pub reserved: Option<ReservedFormatter>,
+}
+
 /// Sign-extend a value from `bits` width to a signed 16-bit value.
 #[inline]
 fn sign_extend(val: u16, bits: u8) -> i16 {
@@ -19,14 +40,22 @@ fn sign_extend(val: u16, bits: u8) -> i16 {
 }
 
 /// Format a PC-relative offset, using a label if available.
-fn format_pc_offset(pc: u16, offset: u16, bits: u8, symbols: Option<&SymbolTable>) -> String {
-    let signed = sign_extend(offset, bits);
+///
+/// On LC-3b the offset counts words, so it is scaled by 2.
+fn format_pc_offset(
+    pc: u16,
+    offset: u16,
+    bits: u8,
+    isa: Isa,
+    symbols: Option<&SymbolTable>,
+) -> String {
+    let signed = sign_extend(offset, bits).wrapping_mul(isa.word_size() as i16);
     let target_addr = pc.wrapping_add_signed(signed);
 
-    if let Some(syms) = symbols {
-        if let Some(label) = syms.get(&target_addr) {
-            return label.clone();
-        }
+    if let Some(syms) = symbols
+        && let Some(label) = syms.get(&target_addr)
+    {
+        return label.clone();
     }
 
     format!("x{:04X}", target_addr)
@@ -61,6 +90,50 @@ fn format_trap_vector(vec: u16) -> String {
 /// # Returns
 /// Human-readable assembly instruction string
 pub fn disassemble(instr: u16, pc: u16, symbols: Option<&SymbolTable>) -> String {
+    disassemble_with_isa(instr, pc, symbols, Isa::Lc3)
+}
+
+/// Disassemble a single instruction for the given instruction set.
+///
+/// For LC-3b, `pc` is the byte address of the next instruction (this
+/// instruction's address + 2).
+pub fn disassemble_with_isa(
+    instr: u16,
+    pc: u16,
+    symbols: Option<&SymbolTable>,
+    isa: Isa,
+) -> String {
+    disassemble_with(
+        instr,
+        pc,
+        symbols,
+        &Options {
+            isa,
+            ..Options::default()
+        },
+    )
+}
+
+/// Disassemble a single instruction with the given settings.
+pub fn disassemble_with(
+    instr: u16,
+    pc: u16,
+    symbols: Option<&SymbolTable>,
+    options: &Options,
+) -> String {
+    let isa = options.isa;
+    if isa == Isa::Lc3b
+        && let Some(text) = disassemble_lc3b_only(instr)
+    {
+        return text;
+    }
+    if isa == Isa::Lc3
+        && instr >> 12 == 0b1101
+        && let Some(text) = options.reserved.and_then(|f| f(instr, pc, symbols))
+    {
+        return text;
+    }
+
     let opcode = (instr >> 12) & 0xF;
 
     match opcode {
@@ -120,7 +193,7 @@ pub fn disassemble(instr: u16, pc: u16, symbols: Option<&SymbolTable>) -> String
                 cond.clear();
             }
 
-            let target = format_pc_offset(pc, offset9, 9, symbols);
+            let target = format_pc_offset(pc, offset9, 9, isa, symbols);
             format!("BR{} {}", cond, target)
         }
 
@@ -139,7 +212,7 @@ pub fn disassemble(instr: u16, pc: u16, symbols: Option<&SymbolTable>) -> String
             if instr & 0x800 != 0 {
                 // JSR - PC-relative
                 let offset11 = instr & 0x7FF;
-                let target = format_pc_offset(pc, offset11, 11, symbols);
+                let target = format_pc_offset(pc, offset11, 11, isa, symbols);
                 format!("JSR {}", target)
             } else {
                 // JSRR - register
@@ -152,7 +225,7 @@ pub fn disassemble(instr: u16, pc: u16, symbols: Option<&SymbolTable>) -> String
             // LD
             let dr = (instr >> 9) & 0x7;
             let offset9 = instr & 0x1FF;
-            let target = format_pc_offset(pc, offset9, 9, symbols);
+            let target = format_pc_offset(pc, offset9, 9, isa, symbols);
             format!("LD R{}, {}", dr, target)
         }
 
@@ -160,7 +233,7 @@ pub fn disassemble(instr: u16, pc: u16, symbols: Option<&SymbolTable>) -> String
             // LDI
             let dr = (instr >> 9) & 0x7;
             let offset9 = instr & 0x1FF;
-            let target = format_pc_offset(pc, offset9, 9, symbols);
+            let target = format_pc_offset(pc, offset9, 9, isa, symbols);
             format!("LDI R{}, {}", dr, target)
         }
 
@@ -176,7 +249,7 @@ pub fn disassemble(instr: u16, pc: u16, symbols: Option<&SymbolTable>) -> String
             // LEA
             let dr = (instr >> 9) & 0x7;
             let offset9 = instr & 0x1FF;
-            let target = format_pc_offset(pc, offset9, 9, symbols);
+            let target = format_pc_offset(pc, offset9, 9, isa, symbols);
             format!("LEA R{}, {}", dr, target)
         }
 
@@ -184,7 +257,7 @@ pub fn disassemble(instr: u16, pc: u16, symbols: Option<&SymbolTable>) -> String
             // ST
             let sr = (instr >> 9) & 0x7;
             let offset9 = instr & 0x1FF;
-            let target = format_pc_offset(pc, offset9, 9, symbols);
+            let target = format_pc_offset(pc, offset9, 9, isa, symbols);
             format!("ST R{}, {}", sr, target)
         }
 
@@ -192,7 +265,7 @@ pub fn disassemble(instr: u16, pc: u16, symbols: Option<&SymbolTable>) -> String
             // STI
             let sr = (instr >> 9) & 0x7;
             let offset9 = instr & 0x1FF;
-            let target = format_pc_offset(pc, offset9, 9, symbols);
+            let target = format_pc_offset(pc, offset9, 9, isa, symbols);
             format!("STI R{}, {}", sr, target)
         }
 
@@ -222,6 +295,49 @@ pub fn disassemble(instr: u16, pc: u16, symbols: Option<&SymbolTable>) -> String
     }
 }
 
+/// Disassemble the LC-3b opcodes whose meaning differs from LC-3.
+///
+/// Returns `None` for opcodes shared by both instruction sets.
+fn disassemble_lc3b_only(instr: u16) -> Option<String> {
+    let opcode = (instr >> 12) & 0xF;
+    let r9 = (instr >> 9) & 0x7;
+    let r6 = (instr >> 6) & 0x7;
+    let offset6 = instr & 0x3F;
+
+    let text = match opcode {
+        0b0010 => format!("LDB R{}, R{}, {}", r9, r6, format_immediate(offset6, 6)),
+        0b0011 => format!("STB R{}, R{}, {}", r9, r6, format_immediate(offset6, 6)),
+        0b0110 => format!("LDW R{}, R{}, {}", r9, r6, format_immediate(offset6, 6)),
+        0b0111 => format!("STW R{}, R{}, {}", r9, r6, format_immediate(offset6, 6)),
+        0b1001 => {
+            if instr & 0x3F == 0x3F {
+                format!("NOT R{}, R{}", r9, r6)
+            } else if instr & 0x20 != 0 {
+                format!(
+                    "XOR R{}, R{}, {}",
+                    r9,
+                    r6,
+                    format_immediate(instr & 0x1F, 5)
+                )
+            } else {
+                format!("XOR R{}, R{}, R{}", r9, r6, instr & 0x7)
+            }
+        }
+        0b1101 => {
+            let mnemonic = match (instr >> 4) & 0x3 {
+                0b01 => "RSHFL",
+                0b11 => "RSHFA",
+                _ => "LSHF",
+            };
+            format!("{} R{}, R{}, #{}", mnemonic, r9, r6, instr & 0xF)
+        }
+        // LDI/STI do not exist on LC-3b
+        0b1010 | 0b1011 => format!(".FILL x{:04X}", instr),
+        _ => return None,
+    };
+    Some(text)
+}
+
 /// Disassemble a single instruction without symbol table.
 ///
 /// Convenience function that calls `disassemble` with `symbols = None`.
@@ -235,11 +351,19 @@ pub fn disassemble_simple(instr: u16, pc: u16) -> String {
 /// - Reserved opcode (0b1101)
 /// - All zeros (uninitialized memory)
 pub fn is_likely_instruction(instr: u16) -> bool {
+    is_likely_instruction_with(instr, &Options::default())
+}
+
+/// Like [`is_likely_instruction`], but accepts the reserved opcode when
+/// `options` has a formatter that recognizes it.
+pub fn is_likely_instruction_with(instr: u16, options: &Options) -> bool {
     let opcode = (instr >> 12) & 0xF;
 
-    // Reserved opcode
-    if opcode == 0b1101 {
-        return false;
+    // Reserved opcode, unless it is a custom instruction
+    if opcode == 0b1101 && options.isa == Isa::Lc3 {
+        return options
+            .reserved
+            .is_some_and(|f| f(instr, 0, None).is_some());
     }
 
     // All zeros is likely uninitialized memory
@@ -358,6 +482,36 @@ mod tests {
     fn test_reserved_opcode() {
         // Opcode 1101 (reserved)
         assert_eq!(disassemble_simple(0xD000, 0x3001), ".FILL xD000");
+        assert!(!is_likely_instruction(0xD042));
+    }
+
+    #[test]
+    fn test_reserved_formatter() {
+        fn mul(instr: u16, _pc: u16, _symbols: Option<&SymbolTable>) -> Option<String> {
+            (instr & 0x38 == 0).then(|| {
+                format!(
+                    "MUL R{}, R{}, R{}",
+                    (instr >> 9) & 0x7,
+                    (instr >> 6) & 0x7,
+                    instr & 0x7
+                )
+            })
+        }
+
+        let options = Options {
+            reserved: Some(mul),
+            ..Options::default()
+        };
+        assert_eq!(
+            disassemble_with(0xD042, 0x3001, None, &options),
+            "MUL R0, R1, R2"
+        );
+        assert_eq!(
+            disassemble_with(0xD0FF, 0x3001, None, &options),
+            ".FILL xD0FF"
+        );
+        assert!(is_likely_instruction_with(0xD042, &options));
+        assert!(!is_likely_instruction_with(0xD0FF, &options));
     }
 
     #[test]
@@ -369,6 +523,20 @@ mod tests {
         assert_eq!(disassemble(0x0E03, 0x3001, Some(&symbols)), "BR LOOP");
     }
 
+    #[test]
+    fn test_lc3b() {
+        let lc3b = |instr, pc| disassemble_with_isa(instr, pc, None, Isa::Lc3b);
+        assert_eq!(lc3b(0x2042, 0x3002), "LDB R0, R1, #2");
+        assert_eq!(lc3b(0x7041, 0x3002), "STW R0, R1, #1");
+        assert_eq!(lc3b(0x9042, 0x3002), "XOR R0, R1, R2");
+        assert_eq!(lc3b(0x907F, 0x3002), "NOT R0, R1");
+        assert_eq!(lc3b(0xD074, 0x3002), "RSHFA R0, R1, #4");
+        assert_eq!(lc3b(0xA000, 0x3002), ".FILL xA000");
+        // BRnzp +3 words from x3002
+        assert_eq!(lc3b(0x0E03, 0x3002), "BR x3008");
+        assert_eq!(lc3b(0xE1FF, 0x3002), "LEA R0, x3000");
+    }
+
     #[test]
     fn test_is_likely_instruction() {
         assert!(is_likely_instruction(0x1042)); // ADD
@@ -0,0 +1,92 @@
+//! Symbol table files (`.sym`), as written by the classic `lc3as`, where the
+//! indented lines start with a tab:
+//!
+//! ```text
+//! // Symbol table
+//! // Scope level 0:
+//! //    Symbol Name       Page Address
+//! //    ----------------  ------------
+//! //    LOOP              3002
+//! ```
+
+use crate::SymbolTable;
+
+/// Write symbols in the `lc3as` format, sorted by address.
+pub fn format<'a>(symbols: impl IntoIterator<Item = (&'a str, u16)>) -> String {
+    let mut symbols: Vec<_> = symbols.into_iter().collect();
+    symbols.sort_by_key(|&(name, address)| (address, name));
+
+    let mut out = String::from(
+        "// Symbol table\n\
+         // Scope level 0:\n\
+         //\tSymbol Name       Page Address\n\
+         //\t----------------  ------------\n",
+    );
+    for (name, address) in symbols {
+        out.push_str(&format!("//\t{name:<16}  {address:04X}\n"));
+    }
+    out.push('\n');
+    out
+}
+
+/// Read a symbol file. Besides the `lc3as` format, lines of just a name
+/// and an address (`LOOP x3002`) are accepted; anything else is skipped.
+///
+/// If several names share an address, the first one is kept.
+pub fn parse(text: &str) -> SymbolTable {
+    let mut symbols = SymbolTable::new();
+    for line in text.lines() {
+        let line = line.trim_start().trim_start_matches('/');
+        let mut fields = line.split_whitespace();
+        let (Some(name), Some(address), None) = (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        // Local labels are `GLOBAL.NAME`, or `.NAME` above the first global
+        let is_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '@' | '.'));
+        let digits = address
+            .strip_prefix(['x', 'X'])
+            .or_else(|| address.strip_prefix("0x"))
+            .unwrap_or(address);
+        if let (true, Ok(address)) = (is_name, u16::from_str_radix(digits, 16)) {
+            symbols.entry(address).or_insert_with(|| name.to_string());
+        }
+    }
+    symbols
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let text = format([
+            ("LOOP", 0x3002),
+            ("MAIN", 0x3000),
+            ("DATA_1", 0x4000),
+            ("MAIN.LOOP", 0x3001),
+        ]);
+        assert_eq!(
+            text,
+            "// Symbol table\n\
+             // Scope level 0:\n\
+             //\tSymbol Name       Page Address\n\
+             //\t----------------  ------------\n\
+             //\tMAIN              3000\n\
+             //\tMAIN.LOOP         3001\n\
+             //\tLOOP              3002\n\
+             //\tDATA_1            4000\n\n"
+        );
+        let symbols = parse(&text);
+        assert_eq!(symbols.len(), 4);
+        assert_eq!(symbols[&0x3002], "LOOP");
+        assert_eq!(symbols[&0x3001], "MAIN.LOOP");
+
+        let symbols = parse("START x3000\n; comment\nEND 3010 extra\n");
+        assert_eq!(symbols, SymbolTable::from([(0x3000, "START".into())]));
+    }
+}
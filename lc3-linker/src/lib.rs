@@ -0,0 +1,397 @@
+//! LC-3 Linker
+//!
+//! Combines object files from [`Assembler::assemble_object`] into a
+//! program: places the relocatable sections, resolves `.EXTERNAL` symbols
+//! against the `.GLOBAL` symbols of the other modules and applies the
+//! relocations.
+//!
+//! ```
+//! use lc3_assembler::Assembler;
+//! use lc3_linker::Linker;
+//!
+//! let mut asm = Assembler::new();
+//! let main = asm.assemble_object(".EXTERNAL PRINT\nJSR PRINT\nHALT\n").unwrap();
+//! let lib = asm.assemble_object(".GLOBAL PRINT\nPRINT PUTS\nRET\n").unwrap();
+//!
+//! let mut linker = Linker::new();
+//! linker.add("main.o", main);
+//! linker.add("lib.o", lib);
+//! let segments = linker.link().unwrap();
+//! assert_eq!(segments[0].origin, 0x3000);
+//! assert_eq!(segments[0].code, [0x4801, 0xF025]); // JSR x3002
+//! ```
+//!
+//! [`Assembler::assemble_object`]: lc3_assembler::Assembler::assemble_object
+
+pub use lc3_assembler::Segment;
+pub use lc3_assembler::object::Object;
+
+use lc3_assembler::object::{RelocKind, RelocTarget, Relocation};
+use std::collections::HashMap;
+
+/// A problem found while linking.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkError {
+    /// The module the problem is in, if any.
+    pub module: Option<String>,
+    pub message: String,
+}
+
+impl std::fmt::Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(module) = &self.module {
+            write!(f, "{module}: ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+/// Links object files into a program.
+#[derive(Debug)]
+pub struct Linker {
+    modules: Vec<(String, Object)>,
+    base: u16,
+}
+
+impl Default for Linker {
+    fn default() -> Self {
+        Self {
+            modules: Vec::new(),
+            base: 0x3000,
+        }
+    }
+}
+
+/// Where a section ended up.
+#[derive(Debug, Clone, Copy)]
+struct Placement {
+    module: usize,
+    section: usize,
+    start: u32,
+    end: u32,
+    relocatable: bool,
+}
+
+impl Linker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the address the relocatable sections are placed from (x3000 by
+    /// default).
+    pub fn set_base(&mut self, base: u16) {
+        self.base = base;
+    }
+
+    /// Add a module; `name` identifies it in errors.
+    pub fn add(&mut self, name: impl Into<String>, object: Object) {
+        self.modules.push((name.into(), object));
+    }
+
+    /// Link the modules into segments, one per section, in the order the
+    /// modules were added.
+    pub fn link(&self) -> Result<Vec<Segment>, Vec<LinkError>> {
+        let mut errors = Vec::new();
+        let placements = self.layout(&mut errors);
+        let globals = self.globals(&placements, &mut errors);
+
+        let mut segments = Vec::new();
+        for placement in &placements {
+            let (name, object) = &self.modules[placement.module];
+            let section = &object.sections[placement.section];
+            let mut code = section.code.clone();
+            for relocation in &section.relocations {
+                let error = |message| LinkError {
+                    module: Some(name.clone()),
+                    message,
+                };
+                let Some(word) = code.get_mut(relocation.offset as usize) else {
+                    errors.push(error(format!(
+                        "relocation at offset {} is outside its section",
+                        relocation.offset
+                    )));
+                    continue;
+                };
+                let address = placement.start + relocation.offset as u32;
+                match self.patch(
+                    *word,
+                    address as u16,
+                    relocation,
+                    placement,
+                    &placements,
+                    &globals,
+                ) {
+                    Ok(patched) => *word = patched,
+                    Err(message) => errors.push(error(message)),
+                }
+            }
+            segments.push(Segment {
+                origin: placement.start as u16,
+                code,
+            });
+        }
+
+        if errors.is_empty() {
+            Ok(segments)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Place every section. Sections with an origin stay there; the others
+    /// follow each other from the base address, around the fixed ones.
+    fn layout(&self, errors: &mut Vec<LinkError>) -> Vec<Placement> {
+        let mut placements = Vec::new();
+        for (m, (_, object)) in self.modules.iter().enumerate() {
+            for (s, section) in object.sections.iter().enumerate() {
+                if let Some(origin) = section.origin {
+                    placements.push(Placement {
+                        module: m,
+                        section: s,
+                        start: origin as u32,
+                        end: origin as u32 + section.code.len() as u32,
+                        relocatable: false,
+                    });
+                }
+            }
+        }
+        let fixed = placements.clone();
+
+        let mut next = self.base as u32;
+        for (m, (name, object)) in self.modules.iter().enumerate() {
+            for (s, section) in object.sections.iter().enumerate() {
+                if section.origin.is_some() {
+                    continue;
+                }
+                let len = section.code.len() as u32;
+                while let Some(other) = fixed
+                    .iter()
+                    .find(|other| len > 0 && other.start < next + len && next < other.end)
+                {
+                    next = other.end;
+                }
+                placements.push(Placement {
+                    module: m,
+                    section: s,
+                    start: next,
+                    end: next + len,
+                    relocatable: true,
+                });
+                if next + len > 0x10000 {
+                    errors.push(LinkError {
+                        module: Some(name.clone()),
+                        message: format!("section of {len} words does not fit in memory"),
+                    });
+                }
+                next += len;
+            }
+        }
+
+        for (i, a) in fixed.iter().enumerate() {
+            let name = &self.modules[a.module].0;
+            if a.end > 0x10000 {
+                errors.push(LinkError {
+                    module: Some(name.clone()),
+                    message: format!("section at x{:04X} runs past the end of memory", a.start),
+                });
+            }
+            for b in &fixed[i + 1..] {
+                if a.start < b.end && b.start < a.end {
+                    errors.push(LinkError {
+                        module: Some(name.clone()),
+                        message: format!(
+                            "section at x{:04X} overlaps section of {} at x{:04X}",
+                            a.start, self.modules[b.module].0, b.start
+                        ),
+                    });
+                }
+            }
+        }
+        // Keep the modules in order, so the first module's code comes first
+        placements.sort_by_key(|p| (p.module, p.section));
+        placements
+    }
+
+    /// The address of each exported symbol after placement.
+    fn globals(
+        &self,
+        placements: &[Placement],
+        errors: &mut Vec<LinkError>,
+    ) -> HashMap<&str, (u16, usize)> {
+        let mut globals: HashMap<&str, (u16, usize)> = HashMap::new();
+        for (m, (name, object)) in self.modules.iter().enumerate() {
+            for symbol in &object.symbols {
+                let value = if symbol.relocatable {
+                    match section_start(placements, m) {
+                        Some(start) => symbol.value.wrapping_add(start),
+                        None => {
+                            errors.push(LinkError {
+                                module: Some(name.clone()),
+                                message: format!(
+                                    "{} is in a section that does not exist",
+                                    symbol.name
+                                ),
+                            });
+                            continue;
+                        }
+                    }
+                } else {
+                    symbol.value
+                };
+                if let Some(&(_, other)) = globals.get(symbol.name.as_str()) {
+                    errors.push(LinkError {
+                        module: Some(name.clone()),
+                        message: format!(
+                            "{} is already defined in {}",
+                            symbol.name, self.modules[other].0
+                        ),
+                    });
+                    continue;
+                }
+                globals.insert(&symbol.name, (value, m));
+            }
+        }
+        globals
+    }
+
+    /// Apply `relocation` to `word`, which has been placed at `address`.
+    fn patch(
+        &self,
+        word: u16,
+        address: u16,
+        relocation: &Relocation,
+        placement: &Placement,
+        placements: &[Placement],
+        globals: &HashMap<&str, (u16, usize)>,
+    ) -> Result<u16, String> {
+        let target = match &relocation.target {
+            RelocTarget::Absolute => 0,
+            RelocTarget::Section => match section_start(placements, placement.module) {
+                Some(start) => start as i32,
+                None => return Err("relocation refers to a section that does not exist".into()),
+            },
+            RelocTarget::External(name) => match globals.get(name.as_str()) {
+                Some(&(value, _)) => value as i32,
+                None => return Err(format!("undefined symbol: {name}")),
+            },
+        };
+        let value = target + relocation.addend;
+        let symbol = match &relocation.target {
+            RelocTarget::External(name) if relocation.addend == 0 => name.clone(),
+            _ => format!("x{:04X}", value as u16),
+        };
+        match relocation.kind {
+            RelocKind::Word => {
+                if !(-0x8000..=0xFFFF).contains(&value) {
+                    return Err(format!(
+                        "value of {symbol} does not fit in 16 bits at x{address:04X}"
+                    ));
+                }
+                Ok(value as u16)
+            }
+            RelocKind::PcOffset(bits) => {
+                let offset = value - (address as i32 + 1);
+                let max = (1 << (bits - 1)) - 1;
+                let min = -(1 << (bits - 1));
+                if offset < min || offset > max {
+                    return Err(format!(
+                        "PC offset from x{address:04X} to {symbol} is {offset}, \
+                         out of range ({min} to {max}) after placement"
+                    ));
+                }
+                let mask = (1u16 << bits) - 1;
+                Ok(word & !mask | offset as u16 & mask)
+            }
+        }
+    }
+}
+
+/// The address of module `module`'s relocatable section.
+fn section_start(placements: &[Placement], module: usize) -> Option<u16> {
+    placements
+        .iter()
+        .find(|p| p.module == module && p.relocatable)
+        .map(|p| p.start as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lc3_assembler::Assembler;
+
+    fn object(source: &str) -> Object {
+        Assembler::new().assemble_object(source).unwrap()
+    }
+
+    fn messages(errors: &[LinkError]) -> Vec<String> {
+        errors.iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn test_link() {
+        let main = object(
+            ".EXTERNAL PRINT, MSG\n\
+             LEA R0, MSG\n\
+             JSR PRINT\n\
+             HALT\n\
+             .FILL MSG + 1\n\
+             .FILL DATA\n\
+             LD R1, DATA\n\
+             BR x3020\n\
+             DATA .FILL 7\n",
+        );
+        let lib = object(
+            ".GLOBAL PRINT, MSG\n\
+             PRINT PUTS\n\
+             RET\n\
+             MSG .STRINGZ \"hi\"\n",
+        );
+        let data = object(".ORIG x3009\n.FILL 1\n.END\n");
+
+        let mut linker = Linker::new();
+        linker.add("main.o", main);
+        linker.add("lib.o", lib);
+        linker.add("data.o", data);
+        let segments = linker.link().unwrap();
+        let origins: Vec<_> = segments.iter().map(|s| s.origin).collect();
+        // lib.o goes around the fixed word at x3009
+        assert_eq!(origins, [0x3000, 0x300A, 0x3009]);
+        assert_eq!(
+            segments[0].code,
+            [0xE00B, 0x4808, 0xF025, 0x300D, 0x3007, 0x2201, 0x0E19, 7]
+        );
+        assert_eq!(segments[1].code, [0xF022, 0xC1C0, 0x68, 0x69, 0]);
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let main = object(".EXTERNAL FAR, NONE\nJSR FAR\nLD R0, FAR\n.FILL NONE\n");
+        let far = object(".ORIG x4000\n.GLOBAL FAR\nFAR RET\n.END\n");
+        let other = object(".ORIG x4000\n.GLOBAL FAR\nFAR RET\n.END\n");
+
+        let mut linker = Linker::new();
+        linker.add("main.o", main);
+        linker.add("far.o", far);
+        linker.add("other.o", other);
+        assert_eq!(
+            messages(&linker.link().unwrap_err()),
+            [
+                "far.o: section at x4000 overlaps section of other.o at x4000",
+                "other.o: FAR is already defined in far.o",
+                "main.o: PC offset from x3000 to FAR is 4095, out of range (-1024 to 1023) after placement",
+                "main.o: PC offset from x3001 to FAR is 4094, out of range (-256 to 255) after placement",
+                "main.o: undefined symbol: NONE",
+            ]
+        );
+
+        // Placed closer, the JSR reaches
+        let mut linker = Linker::new();
+        linker.set_base(0x3F00);
+        linker.add("main.o", object(".EXTERNAL FAR\nJSR FAR\n"));
+        linker.add("far.o", object(".ORIG x4000\n.GLOBAL FAR\nFAR RET\n.END\n"));
+        let segments = linker.link().unwrap();
+        assert_eq!(segments[0].code, [0x48FF]);
+    }
+}
@@ -0,0 +1,278 @@
+//! A lossless concrete syntax tree. [`Program`] keeps only what the
+//! assembler needs; the syntax tree keeps every character of the source,
+//! whitespace and comments included, as tokens on lines. Printing it gives
+//! back the source exactly, so tools that rewrite source, such as a
+//! formatter or a rename, change only what they edit.
+//!
+//! [`Program`]: crate::Program
+
+use crate::{DIRECTIVES, Span};
+
+/// What a token is, as far as can be told without parsing the line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// Spaces, tabs and carriage returns.
+    Whitespace,
+    /// A `;` comment, up to the end of the line.
+    Comment,
+    /// A label, mnemonic, macro or symbol; local labels such as `.loop` and
+    /// `PRINT.loop` included.
+    Name,
+    /// A directive such as `.ORIG`.
+    Directive,
+    /// A register, `R0` to `R7`.
+    Register,
+    /// A number: `#-5`, `5`, `x3000`, `0x3000` or `b101`.
+    Number,
+    /// A string in double quotes.
+    String,
+    /// A character in single quotes.
+    Char,
+    /// `,`, `:`, `\`, parentheses and operators.
+    Punct,
+    /// A character that starts no token, or an unclosed quote.
+    Error,
+}
+
+/// A token and its text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub text: String,
+    pub span: Span,
+}
+
+/// A line of source, without its `\n`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxLine {
+    pub tokens: Vec<Token>,
+    pub span: Span,
+}
+
+impl SyntaxLine {
+    /// The comment at the end of the line.
+    pub fn comment(&self) -> Option<&Token> {
+        self.tokens
+            .last()
+            .filter(|token| token.kind == TokenKind::Comment)
+    }
+
+    /// The tokens other than whitespace and the comment.
+    pub fn code(&self) -> impl Iterator<Item = &Token> {
+        self.tokens
+            .iter()
+            .filter(|token| !matches!(token.kind, TokenKind::Whitespace | TokenKind::Comment))
+    }
+
+    /// Whether the line holds nothing but whitespace and a comment.
+    pub fn is_blank(&self) -> bool {
+        self.code().next().is_none()
+    }
+}
+
+/// The source as lines of tokens. Displaying the tree gives the source it
+/// was made from, with any changes made to the tokens' text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxTree {
+    pub lines: Vec<SyntaxLine>,
+}
+
+impl SyntaxTree {
+    /// Split `source` into lines of tokens. Every character belongs to a
+    /// token, so this cannot fail.
+    pub fn parse(source: &str) -> Self {
+        let mut lines = Vec::new();
+        let mut start = 0;
+        for text in source.split('\n') {
+            lines.push(SyntaxLine {
+                tokens: tokenize(text, start),
+                span: start..start + text.len(),
+            });
+            start += text.len() + 1;
+        }
+        Self { lines }
+    }
+
+    /// Every token, in order.
+    pub fn tokens(&self) -> impl Iterator<Item = &Token> {
+        self.lines.iter().flat_map(|line| &line.tokens)
+    }
+}
+
+impl std::fmt::Display for SyntaxTree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, line) in self.lines.iter().enumerate() {
+            if i > 0 {
+                f.write_str("\n")?;
+            }
+            for token in &line.tokens {
+                f.write_str(&token.text)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Split one line, which starts at `base` in the source, into tokens.
+fn tokenize(line: &str, base: usize) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        let (kind, len) = match c {
+            ' ' | '\t' | '\r' => (
+                TokenKind::Whitespace,
+                rest.find(|c| !matches!(c, ' ' | '\t' | '\r'))
+                    .unwrap_or(rest.len()),
+            ),
+            ';' => (TokenKind::Comment, rest.len()),
+            '"' => quoted(rest, '"', TokenKind::String),
+            '\'' => quoted(rest, '\'', TokenKind::Char),
+            '#' => (TokenKind::Number, 1 + number_len(&rest[1..])),
+            '0'..='9' => (TokenKind::Number, number_len(rest)),
+            '<' | '>' if rest[1..].starts_with(c) => (TokenKind::Punct, 2),
+            ',' | ':' | '\\' | '(' | ')' | '+' | '-' | '*' | '/' | '&' | '|' | '^' | '~' => {
+                (TokenKind::Punct, 1)
+            }
+            '.' if rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') => {
+                let len = word_len(rest);
+                let directive = DIRECTIVES
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(&rest[1..len]));
+                let kind = if directive {
+                    TokenKind::Directive
+                } else {
+                    TokenKind::Name
+                };
+                (kind, len)
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let len = word_len(rest);
+                (word_kind(&rest[..len]), len)
+            }
+            c => (TokenKind::Error, c.len_utf8()),
+        };
+        let start = base + line.len() - rest.len();
+        tokens.push(Token {
+            kind,
+            text: rest[..len].to_string(),
+            span: start..start + len,
+        });
+        rest = &rest[len..];
+    }
+    tokens
+}
+
+/// The length of the quoted text at the start of `text`, and its kind; an
+/// unclosed quote runs to the end of the line, as an error.
+fn quoted(text: &str, quote: char, kind: TokenKind) -> (TokenKind, usize) {
+    let mut escaped = false;
+    for (i, c) in text.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            c if c == quote => return (kind, i + 1),
+            _ => {}
+        }
+    }
+    (TokenKind::Error, text.len())
+}
+
+/// The length of a decimal number at the start of `text`, sign included.
+fn number_len(text: &str) -> usize {
+    let sign = usize::from(text.starts_with('-'));
+    sign + text[sign..]
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(text.len() - sign)
+}
+
+/// The length of the name at the start of `text`, dots included.
+fn word_len(text: &str) -> usize {
+    text.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+        .unwrap_or(text.len())
+}
+
+/// Whether `word` reads as a register, a hex or binary number, or a name.
+fn word_kind(word: &str) -> TokenKind {
+    let upper = word.to_ascii_uppercase();
+    let digits = |prefix: &str, radix: u32| {
+        upper
+            .strip_prefix(prefix)
+            .is_some_and(|d| !d.is_empty() && d.chars().all(|c| c.is_digit(radix)))
+    };
+    if matches!(
+        upper.as_str(),
+        "R0" | "R1" | "R2" | "R3" | "R4" | "R5" | "R6" | "R7"
+    ) {
+        TokenKind::Register
+    } else if digits("X", 16) || digits("B", 2) {
+        TokenKind::Number
+    } else {
+        TokenKind::Name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let sources = [
+            "",
+            "\n\n",
+            ".ORIG x3000\n  LOOP:\tADD R1, R1, #-1 ; count down\r\n\tBRp LOOP\n.END",
+            "MSG .STRINGZ \"semi;colon \\\" quote\" ; ok\n.FILL ';', (A+1)<<2 ; é\n",
+            "  PUSH \\reg\n.stringz \"unclosed\n@ $",
+        ];
+        for source in sources {
+            assert_eq!(SyntaxTree::parse(source).to_string(), source);
+        }
+    }
+
+    #[test]
+    fn test_tokens() {
+        let source = "LOOP ADD R1, R1, x1F ; step\n.loop .FILL #-3\nBR PRINT.loop";
+        let tree = SyntaxTree::parse(source);
+        let kinds: Vec<_> = tree.lines[0]
+            .code()
+            .map(|token| (token.kind, token.text.as_str()))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (TokenKind::Name, "LOOP"),
+                (TokenKind::Name, "ADD"),
+                (TokenKind::Register, "R1"),
+                (TokenKind::Punct, ","),
+                (TokenKind::Register, "R1"),
+                (TokenKind::Punct, ","),
+                (TokenKind::Number, "x1F"),
+            ]
+        );
+        let comment = tree.lines[0].comment().unwrap();
+        assert_eq!(
+            (comment.text.as_str(), &source[comment.span.clone()]),
+            ("; step", "; step")
+        );
+        let kinds: Vec<_> = tree.lines[1].code().map(|token| token.kind).collect();
+        assert_eq!(
+            kinds,
+            [TokenKind::Name, TokenKind::Directive, TokenKind::Number]
+        );
+        assert_eq!(tree.lines[2].tokens[2].text, "PRINT.loop");
+
+        // Renaming a label is editing its tokens
+        let mut tree = tree;
+        for line in &mut tree.lines {
+            for token in &mut line.tokens {
+                if token.kind == TokenKind::Name && token.text == "LOOP" {
+                    token.text = "AGAIN".into();
+                }
+            }
+        }
+        assert!(
+            tree.to_string()
+                .starts_with("AGAIN ADD R1, R1, x1F ; step\n")
+        );
+    }
+}
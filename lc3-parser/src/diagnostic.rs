@@ -0,0 +1,459 @@
+//! Stable codes for errors and warnings, the detail a diagnostic can carry
+//! besides its message, and its rendering as a report or as JSON.
+
+use crate::{SourceMap, Span, offset_to_pos};
+use serde::Serialize;
+
+/// How serious a diagnostic is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+/// A stable identifier for a kind of diagnostic: `E` and a number for
+/// errors, `W` and a number for warnings. A code keeps its number when its
+/// message changes, so that tools can match on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Code {
+    /// A name that is neither a label nor a constant.
+    UndefinedSymbol,
+    /// A name defined twice.
+    DuplicateDefinition,
+    /// A PC-relative target too far from the instruction.
+    OffsetOutOfRange,
+    /// A value that does not fit its field, or the machine.
+    ValueOutOfRange,
+    /// Text that does not parse.
+    Syntax,
+    /// A malformed number.
+    InvalidNumber,
+    /// A register other than R0-R7.
+    InvalidRegister,
+    /// A keyword used as a name.
+    ReservedName,
+    /// An instruction that does not exist, or is not enabled.
+    UnknownInstruction,
+    /// An operand of the wrong kind.
+    InvalidOperand,
+    /// A constant defined in terms of itself.
+    CircularDefinition,
+    /// Overflow, division by zero or a bad shift in an expression.
+    Arithmetic,
+    /// A call to a macro that is not defined.
+    UndefinedMacro,
+    /// A macro call with the wrong number of arguments.
+    MacroArguments,
+    /// Macro calls nested too deeply.
+    MacroRecursion,
+    /// A `.MACRO` or `.IF` that is never closed.
+    UnclosedBlock,
+    /// `.ELSE`, `.ELSEIF` or `.ENDIF` out of place.
+    UnmatchedConditional,
+    /// A condition that depends on a label.
+    NonConstantCondition,
+    /// A file that cannot be included.
+    IncludeFailed,
+    /// Files that include each other.
+    IncludeCycle,
+    /// A label where none is allowed, or a constant without one.
+    MisplacedLabel,
+    /// Segments that overlap or do not fit in memory.
+    SegmentLayout,
+    /// Separate assembly: `.GLOBAL`, `.EXTERNAL` and relocation.
+    Linking,
+    /// A label that reads as a register or a number.
+    ConfusingLabel,
+    /// Code in system space (x0000-x2FFF).
+    SystemSpace,
+    /// An out-of-range instruction rewritten by relaxation.
+    Relaxed,
+    /// Code after `.END`, outside any segment.
+    CodeAfterEnd,
+    /// A segment that is never closed by `.END`.
+    MissingEnd,
+    /// A program that never reaches HALT.
+    NoHalt,
+    /// Execution that runs off an instruction into data.
+    FallIntoData,
+    /// `BR` without condition flags, which always branches.
+    BareBranch,
+    /// A label nothing refers to.
+    UnusedLabel,
+    /// A hex immediate that is negative in its signed field.
+    SignedHex,
+    /// A label written in different cases, which name the same label.
+    LabelCase,
+}
+
+impl Code {
+    /// Every code, in order of number.
+    pub const ALL: [Code; 34] = [
+        Code::UndefinedSymbol,
+        Code::DuplicateDefinition,
+        Code::OffsetOutOfRange,
+        Code::ValueOutOfRange,
+        Code::Syntax,
+        Code::InvalidNumber,
+        Code::InvalidRegister,
+        Code::ReservedName,
+        Code::UnknownInstruction,
+        Code::InvalidOperand,
+        Code::CircularDefinition,
+        Code::Arithmetic,
+        Code::UndefinedMacro,
+        Code::MacroArguments,
+        Code::MacroRecursion,
+        Code::UnclosedBlock,
+        Code::UnmatchedConditional,
+        Code::NonConstantCondition,
+        Code::IncludeFailed,
+        Code::IncludeCycle,
+        Code::MisplacedLabel,
+        Code::SegmentLayout,
+        Code::Linking,
+        Code::ConfusingLabel,
+        Code::SystemSpace,
+        Code::Relaxed,
+        Code::CodeAfterEnd,
+        Code::MissingEnd,
+        Code::NoHalt,
+        Code::FallIntoData,
+        Code::BareBranch,
+        Code::UnusedLabel,
+        Code::SignedHex,
+        Code::LabelCase,
+    ];
+
+    /// The code as written, e.g. `E0001`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Code::UndefinedSymbol => "E0001",
+            Code::DuplicateDefinition => "E0002",
+            Code::OffsetOutOfRange => "E0003",
+            Code::ValueOutOfRange => "E0004",
+            Code::Syntax => "E0005",
+            Code::InvalidNumber => "E0006",
+            Code::InvalidRegister => "E0007",
+            Code::ReservedName => "E0008",
+            Code::UnknownInstruction => "E0009",
+            Code::InvalidOperand => "E0010",
+            Code::CircularDefinition => "E0011",
+            Code::Arithmetic => "E0012",
+            Code::UndefinedMacro => "E0013",
+            Code::MacroArguments => "E0014",
+            Code::MacroRecursion => "E0015",
+            Code::UnclosedBlock => "E0016",
+            Code::UnmatchedConditional => "E0017",
+            Code::NonConstantCondition => "E0018",
+            Code::IncludeFailed => "E0019",
+            Code::IncludeCycle => "E0020",
+            Code::MisplacedLabel => "E0021",
+            Code::SegmentLayout => "E0022",
+            Code::Linking => "E0023",
+            Code::ConfusingLabel => "W0001",
+            Code::SystemSpace => "W0002",
+            Code::Relaxed => "W0003",
+            Code::CodeAfterEnd => "W0004",
+            Code::MissingEnd => "W0005",
+            Code::NoHalt => "W0006",
+            Code::FallIntoData => "W0007",
+            Code::BareBranch => "W0008",
+            Code::UnusedLabel => "W0009",
+            Code::SignedHex => "W0010",
+            Code::LabelCase => "W0011",
+        }
+    }
+
+    pub fn severity(self) -> Severity {
+        if self.as_str().starts_with('W') {
+            Severity::Warning
+        } else {
+            Severity::Error
+        }
+    }
+}
+
+impl std::fmt::Display for Code {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for Code {
+    type Err = String;
+
+    /// Parse a code such as `E0001`, ignoring case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Code::ALL
+            .into_iter()
+            .find(|code| code.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown diagnostic code '{s}'"))
+    }
+}
+
+/// Another place that bears on a diagnostic, such as an earlier definition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Related {
+    pub span: Span,
+    pub message: String,
+}
+
+/// A fix for a diagnostic: replace the text at `span` with `replacement`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Suggestion {
+    pub message: String,
+    pub span: Span,
+    pub replacement: String,
+}
+
+/// An error or warning as rendered by [`SourceMap::report`] and
+/// [`SourceMap::json`], borrowed from a [`ParseError`] or an assembler error.
+///
+/// [`SourceMap::report`]: crate::SourceMap::report
+/// [`SourceMap::json`]: crate::SourceMap::json
+/// [`ParseError`]: crate::ParseError
+#[derive(Debug, Clone, Copy)]
+pub struct Diagnostic<'a> {
+    pub code: Code,
+    pub message: &'a str,
+    pub span: &'a Span,
+    /// Span of the macro invocation, for diagnostics inside an expansion.
+    pub expanded_from: Option<&'a Span>,
+    pub related: &'a [Related],
+    pub suggestions: &'a [Suggestion],
+}
+
+impl SourceMap {
+    /// Render a diagnostic with its source lines, as for
+    /// [`format_errors`](crate::format_errors): its code, its message at its
+    /// span, the macro invocation it is inside, its related spans and its
+    /// suggestions.
+    pub fn report(&self, diagnostic: &Diagnostic) -> String {
+        use ariadne::{Color, Label, Report, ReportKind};
+
+        let (kind, color) = match diagnostic.code.severity() {
+            Severity::Error => (ReportKind::Error, Color::Red),
+            Severity::Warning => (ReportKind::Warning, Color::Yellow),
+        };
+        let locate = |span: &Span| {
+            let (file, span) = self.locate_span(span);
+            (self.files()[file].name.clone(), span)
+        };
+        let mut report = Report::build(kind, locate(diagnostic.span))
+            .with_code(diagnostic.code)
+            .with_message(diagnostic.message)
+            .with_label(
+                Label::new(locate(diagnostic.span))
+                    .with_message(diagnostic.message)
+                    .with_color(color),
+            );
+        if let Some(call) = diagnostic.expanded_from {
+            report = report.with_label(
+                Label::new(locate(call))
+                    .with_message("in this macro expansion")
+                    .with_color(Color::Blue),
+            );
+        }
+        for related in diagnostic.related {
+            report = report.with_label(
+                Label::new(locate(&related.span))
+                    .with_message(&related.message)
+                    .with_color(Color::Blue),
+            );
+        }
+        for suggestion in diagnostic.suggestions {
+            report = report.with_help(&suggestion.message);
+        }
+        let sources = ariadne::sources(
+            self.files()
+                .iter()
+                .map(|file| (file.name.clone(), file.text.as_str())),
+        );
+        let mut output = Vec::new();
+        report.finish().write(sources, &mut output).unwrap();
+        String::from_utf8(output).unwrap_or_else(|_| "error formatting output".into())
+    }
+
+    /// A diagnostic as a single line of JSON, for tools: its code,
+    /// severity and message, and the location of its span, macro invocation,
+    /// related spans and suggestions. A location gives the file name, the
+    /// 1-based line and column of its start and end, and its byte offsets
+    /// in that file.
+    pub fn json(&self, diagnostic: &Diagnostic) -> String {
+        let location = |span: &Span| {
+            let (file, within) = self.locate_span(span);
+            let file = &self.files()[file];
+            let (line, column) = offset_to_pos(&file.text, within.start);
+            let (end_line, end_column) = offset_to_pos(&file.text, within.end);
+            JsonLocation {
+                file: &file.name,
+                line,
+                column,
+                end_line,
+                end_column,
+                start: within.start,
+                end: within.end,
+            }
+        };
+        let json = Json {
+            code: diagnostic.code.as_str(),
+            severity: diagnostic.code.severity().as_str(),
+            message: diagnostic.message,
+            location: location(diagnostic.span),
+            expanded_from: diagnostic.expanded_from.map(location),
+            related: (diagnostic.related.iter())
+                .map(|related| JsonRelated {
+                    message: &related.message,
+                    location: location(&related.span),
+                })
+                .collect(),
+            suggestions: (diagnostic.suggestions.iter())
+                .map(|suggestion| JsonSuggestion {
+                    message: &suggestion.message,
+                    replacement: &suggestion.replacement,
+                    location: location(&suggestion.span),
+                })
+                .collect(),
+        };
+        serde_json::to_string(&json).unwrap()
+    }
+}
+
+/// A diagnostic as [`SourceMap::json`] writes it.
+#[derive(Serialize)]
+struct Json<'a> {
+    code: &'static str,
+    severity: &'static str,
+    message: &'a str,
+    location: JsonLocation<'a>,
+    expanded_from: Option<JsonLocation<'a>>,
+    related: Vec<JsonRelated<'a>>,
+    suggestions: Vec<JsonSuggestion<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonRelated<'a> {
+    message: &'a str,
+    location: JsonLocation<'a>,
+}
+
+#[derive(Serialize)]
+struct JsonSuggestion<'a> {
+    message: &'a str,
+    replacement: &'a str,
+    location: JsonLocation<'a>,
+}
+
+/// A span in a file, with 1-based lines and columns and byte offsets.
+#[derive(Serialize)]
+struct JsonLocation<'a> {
+    file: &'a str,
+    line: usize,
+    column: usize,
+    end_line: usize,
+    end_column: usize,
+    start: usize,
+    end: usize,
+}
+
+/// The suggestion to write one of `candidates` instead of the misspelled
+/// `name` at `span`, if one is close enough.
+pub fn did_you_mean<'a>(
+    name: &str,
+    span: Span,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<Suggestion> {
+    let limit = (name.chars().count() / 3).max(1);
+    let (distance, closest) = candidates
+        .into_iter()
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .min()?;
+    (distance <= limit).then(|| Suggestion {
+        message: format!("did you mean {closest}?"),
+        span,
+        replacement: closest.to_string(),
+    })
+}
+
+/// The Levenshtein distance between `a` and `b`, ignoring case.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().map(|c| c.to_ascii_uppercase()).collect();
+    let b: Vec<char> = b.chars().map(|c| c.to_ascii_uppercase()).collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, &x) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &y) in b.iter().enumerate() {
+            let next = (diagonal + usize::from(x != y))
+                .min(row[j] + 1)
+                .min(row[j + 1] + 1);
+            diagonal = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes() {
+        for (i, code) in Code::ALL.iter().enumerate() {
+            assert_eq!(code.as_str().parse::<Code>(), Ok(*code));
+            let unique = Code::ALL[..i].iter().all(|c| c.as_str() != code.as_str());
+            assert!(unique, "{code} is used twice");
+        }
+        assert_eq!("w0001".parse::<Code>(), Ok(Code::ConfusingLabel));
+        assert_eq!(Code::ConfusingLabel.severity(), Severity::Warning);
+        assert_eq!(Code::UndefinedSymbol.severity(), Severity::Error);
+        assert!("E9999".parse::<Code>().is_err());
+    }
+
+    #[test]
+    fn test_json() {
+        let sources = SourceMap::new("a \"b\".asm", "ADD R0\nHALT\n");
+        let diagnostic = Diagnostic {
+            code: Code::Syntax,
+            message: "expected ',' or \"\\n\"",
+            span: &(7..11),
+            expanded_from: None,
+            related: &[],
+            suggestions: &[],
+        };
+        assert_eq!(
+            sources.json(&diagnostic),
+            "{\"code\":\"E0005\",\"severity\":\"error\",\
+             \"message\":\"expected ',' or \\\"\\\\n\\\"\",\
+             \"location\":{\"file\":\"a \\\"b\\\".asm\",\"line\":2,\"column\":1,\
+             \"end_line\":2,\"end_column\":5,\"start\":7,\"end\":11},\
+             \"expanded_from\":null,\"related\":[],\"suggestions\":[]}"
+        );
+    }
+
+    #[test]
+    fn test_did_you_mean() {
+        let labels = ["LOOP", "DONE", "PRINT_NUM"];
+        let suggestion = did_you_mean("LOPP", 4..8, labels).unwrap();
+        assert_eq!(suggestion.replacement, "LOOP");
+        assert_eq!(suggestion.message, "did you mean LOOP?");
+        assert_eq!(suggestion.span, 4..8);
+        assert_eq!(
+            did_you_mean("PRINT_NMU", 0..9, labels).unwrap().replacement,
+            "PRINT_NUM"
+        );
+        assert!(did_you_mean("COUNT", 0..5, labels).is_none());
+    }
+}
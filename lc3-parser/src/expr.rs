@@ -0,0 +1,282 @@
+//! Constant expressions in operands, e.g. `TABLE+3` or `(END-START)>>1`.
+//!
+//! Expressions are evaluated in `i32`; callers check that the result fits
+//! the field it is encoded into.
+
+use crate::{
+    Code, ParserExtra, ParserInput, Span, Spanned, Suggestion, label_reference, number, word_end,
+    ws,
+};
+use chumsky::prelude::*;
+use chumsky::span::SimpleSpan;
+
+/// A prefix operator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    /// `-x`
+    Neg,
+    /// `~x`
+    Not,
+}
+
+/// An infix operator; precedence follows C.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Mul,
+    Div,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    And,
+    Xor,
+    Or,
+}
+
+/// A constant expression over numbers and symbols.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(i32),
+    /// A label (or other symbol), uppercased like all identifiers.
+    Symbol(String),
+    Unary(UnaryOp, Box<Spanned<Expr>>),
+    Binary(BinaryOp, Box<Spanned<Expr>>, Box<Spanned<Expr>>),
+}
+
+/// Why an expression could not be evaluated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvalError {
+    pub message: String,
+    pub span: Span,
+    pub code: Code,
+    pub suggestions: Vec<Suggestion>,
+}
+
+impl EvalError {
+    pub fn new(span: Span, code: Code, message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            span,
+            code,
+            suggestions: Vec::new(),
+        }
+    }
+}
+
+impl Spanned<Expr> {
+    /// Evaluate the expression, resolving symbols with `lookup`.
+    pub fn eval(
+        &self,
+        lookup: &mut impl FnMut(&str, &Span) -> Result<i32, EvalError>,
+    ) -> Result<i32, EvalError> {
+        let overflow = || {
+            EvalError::new(
+                self.span.clone(),
+                Code::Arithmetic,
+                "arithmetic overflow in expression",
+            )
+        };
+        match &self.value {
+            Expr::Number(n) => Ok(*n),
+            Expr::Symbol(name) => lookup(name, &self.span),
+            Expr::Unary(op, operand) => {
+                let v = operand.eval(lookup)?;
+                match op {
+                    UnaryOp::Neg => v.checked_neg().ok_or_else(overflow),
+                    UnaryOp::Not => Ok(!v),
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let a = lhs.eval(lookup)?;
+                let b = rhs.eval(lookup)?;
+                let shift = || {
+                    u32::try_from(b).ok().filter(|&b| b < 32).ok_or_else(|| {
+                        EvalError::new(
+                            rhs.span.clone(),
+                            Code::Arithmetic,
+                            "shift amount out of range",
+                        )
+                    })
+                };
+                match op {
+                    BinaryOp::Mul => a.checked_mul(b).ok_or_else(overflow),
+                    BinaryOp::Div if b == 0 => Err(EvalError::new(
+                        rhs.span.clone(),
+                        Code::Arithmetic,
+                        "division by zero",
+                    )),
+                    BinaryOp::Div => a.checked_div(b).ok_or_else(overflow),
+                    BinaryOp::Add => a.checked_add(b).ok_or_else(overflow),
+                    BinaryOp::Sub => a.checked_sub(b).ok_or_else(overflow),
+                    BinaryOp::Shl => {
+                        let shifted = a.checked_mul(1 << shift()?);
+                        shifted.ok_or_else(overflow)
+                    }
+                    BinaryOp::Shr => Ok(a >> shift()?),
+                    BinaryOp::And => Ok(a & b),
+                    BinaryOp::Xor => Ok(a ^ b),
+                    BinaryOp::Or => Ok(a | b),
+                }
+            }
+        }
+    }
+
+    /// The symbol name, if the expression is a bare symbol.
+    pub fn as_symbol(&self) -> Option<&str> {
+        match &self.value {
+            Expr::Symbol(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Call `f` on every symbol reference with its span.
+    pub fn symbols(&self, f: &mut impl FnMut(&str, &Span)) {
+        match &self.value {
+            Expr::Number(_) => {}
+            Expr::Symbol(name) => f(name, &self.span),
+            Expr::Unary(_, operand) => operand.symbols(f),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.symbols(f);
+                rhs.symbols(f);
+            }
+        }
+    }
+
+    /// Call `f` on the span of every node, and on the name of every symbol.
+    pub fn visit_mut(&mut self, f: &mut impl FnMut(&mut Span, Option<&mut String>)) {
+        match &mut self.value {
+            Expr::Number(_) => f(&mut self.span, None),
+            Expr::Symbol(name) => f(&mut self.span, Some(name)),
+            Expr::Unary(_, operand) => {
+                f(&mut self.span, None);
+                operand.visit_mut(f);
+            }
+            Expr::Binary(_, lhs, rhs) => {
+                f(&mut self.span, None);
+                lhs.visit_mut(f);
+                rhs.visit_mut(f);
+            }
+        }
+    }
+}
+
+pub(crate) fn expr<'a>() -> impl Parser<'a, ParserInput<'a>, Spanned<Expr>, ParserExtra<'a>> + Clone
+{
+    // `x1F` and `#3` must not run into a following letter, so `XAB` is a label
+    let number = number().then_ignore(word_end()).map_with(|n, e| {
+        let span: SimpleSpan = e.span();
+        Spanned::new(Expr::Number(n), span.into_range())
+    });
+
+    recursive(|expr| {
+        let parens = just('(')
+            .ignore_then(ws())
+            .ignore_then(expr)
+            .then_ignore(ws())
+            .then_ignore(just(')'))
+            .map_with(|inner: Spanned<Expr>, e| {
+                let span: SimpleSpan = e.span();
+                Spanned::new(inner.value, span.into_range())
+            });
+        let atom = choice((
+            number,
+            label_reference().map(|name| name.map(Expr::Symbol)),
+            parens,
+        ));
+
+        let unary_op = choice((just('-').to(UnaryOp::Neg), just('~').to(UnaryOp::Not)))
+            .map_with(|op, e| {
+                let span: SimpleSpan = e.span();
+                (op, span.start)
+            })
+            .then_ignore(ws());
+        let unary = unary_op.repeated().foldr(atom, |(op, start), operand| {
+            let span = start..operand.span.end;
+            Spanned::new(Expr::Unary(op, Box::new(operand)), span)
+        });
+
+        let product = binary(
+            unary,
+            choice((just("*").to(BinaryOp::Mul), just("/").to(BinaryOp::Div))),
+        );
+        let sum = binary(
+            product,
+            choice((just("+").to(BinaryOp::Add), just("-").to(BinaryOp::Sub))),
+        );
+        let shift = binary(
+            sum,
+            choice((just("<<").to(BinaryOp::Shl), just(">>").to(BinaryOp::Shr))),
+        );
+        let and = binary(shift, just("&").to(BinaryOp::And));
+        let xor = binary(and, just("^").to(BinaryOp::Xor));
+        binary(xor, just("|").to(BinaryOp::Or)).boxed()
+    })
+    .labelled("expression")
+}
+
+/// Left-associative chain of `operand (op operand)*`.
+fn binary<'a>(
+    operand: impl Parser<'a, ParserInput<'a>, Spanned<Expr>, ParserExtra<'a>> + Clone,
+    op: impl Parser<'a, ParserInput<'a>, BinaryOp, ParserExtra<'a>> + Clone,
+) -> impl Parser<'a, ParserInput<'a>, Spanned<Expr>, ParserExtra<'a>> + Clone {
+    operand.clone().foldl(
+        ws().ignore_then(op)
+            .then_ignore(ws())
+            .then(operand)
+            .repeated(),
+        |lhs, (op, rhs)| {
+            let span = lhs.span.start..rhs.span.end;
+            Spanned::new(Expr::Binary(op, Box::new(lhs), Box::new(rhs)), span)
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str) -> Result<i32, EvalError> {
+        let expr = expr()
+            .then_ignore(end())
+            .parse(source)
+            .into_result()
+            .unwrap();
+        expr.eval(&mut |name, span| match name {
+            "TABLE" => Ok(0x3010),
+            _ => Err(EvalError::new(
+                span.clone(),
+                Code::UndefinedSymbol,
+                format!("undefined symbol: {name}"),
+            )),
+        })
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(eval("TABLE+3"), Ok(0x3013));
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("10 - 4 - 3"), Ok(3));
+        assert_eq!(eval("1 << 4 | x0F & 3"), Ok(0x13));
+        assert_eq!(eval("-TABLE"), Ok(-0x3010));
+        assert_eq!(eval("~0 ^ #-2"), Ok(1));
+        assert_eq!(eval("TABLE >> 4 - 1"), Ok(0x0602));
+        assert_eq!(eval("xFFFF"), Ok(0xFFFF));
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = eval("TABLE / (3 - 3)").unwrap_err();
+        assert_eq!(
+            (err.message.as_str(), err.span),
+            ("division by zero", 8..15)
+        );
+        let err = eval("2 + MISSING").unwrap_err();
+        assert_eq!(err.span, 4..11);
+        assert!(eval("x7FFF * x7FFF * 4").is_err());
+        assert!(eval("1 << 32").is_err());
+        assert!(eval("1 << -1").is_err());
+        // Hex digits followed by letters form a label
+        assert!(eval("XABZ").is_err());
+    }
+}
@@ -0,0 +1,350 @@
+//! `.INCLUDE` resolution.
+//!
+//! Includes are textual: each `.INCLUDE "file"` line is replaced by the
+//! text of the file, before parsing. A [`SourceMap`] records where every
+//! part of the combined text came from, so that spans into it can be traced
+//! back to a file and line.
+
+use crate::{Code, Directive, ParseError, Span, comment, directive, offset_to_pos, ws};
+use chumsky::prelude::*;
+use std::collections::HashMap;
+
+/// A source file and its name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceFile {
+    /// Name used in error messages; also identifies the file for cycle
+    /// detection, so it should be normalized.
+    pub name: String,
+    pub text: String,
+}
+
+/// Finds the files named by `.INCLUDE`.
+pub trait IncludeResolver {
+    /// Load `path` as written in an `.INCLUDE` in the file named `from`.
+    fn resolve(&mut self, path: &str, from: &str) -> Result<SourceFile, String>;
+}
+
+/// Resolves paths relative to the including file among files held in
+/// memory, keyed by normalized path.
+impl IncludeResolver for HashMap<String, String> {
+    fn resolve(&mut self, path: &str, from: &str) -> Result<SourceFile, String> {
+        let name = join_path(from, path);
+        match self.get(&name) {
+            Some(text) => Ok(SourceFile {
+                text: text.clone(),
+                name,
+            }),
+            None => Err(format!("file not found: {name}")),
+        }
+    }
+}
+
+/// The text of a program with its includes expanded, and where each part
+/// of it came from.
+#[derive(Debug, Clone)]
+pub struct SourceMap {
+    text: String,
+    files: Vec<SourceFile>,
+    chunks: Vec<Chunk>,
+}
+
+impl Default for SourceMap {
+    fn default() -> Self {
+        Self::new("", "")
+    }
+}
+
+/// From `start` in the combined text up to the next chunk, the text is
+/// copied from `file` starting at `offset`.
+#[derive(Debug, Clone)]
+struct Chunk {
+    start: usize,
+    file: usize,
+    offset: usize,
+}
+
+impl SourceMap {
+    /// A program without includes.
+    pub fn new(name: impl Into<String>, text: impl Into<String>) -> Self {
+        let text = text.into();
+        Self {
+            files: vec![SourceFile {
+                name: name.into(),
+                text: text.clone(),
+            }],
+            text,
+            chunks: vec![Chunk {
+                start: 0,
+                file: 0,
+                offset: 0,
+            }],
+        }
+    }
+
+    /// The combined text; spans in parse results index into it.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The main file followed by every included file, in order of inclusion.
+    pub fn files(&self) -> &[SourceFile] {
+        &self.files
+    }
+
+    /// The file index and offset within that file of `offset` in the
+    /// combined text.
+    pub fn locate(&self, offset: usize) -> (usize, usize) {
+        let i = self.chunks.partition_point(|chunk| chunk.start <= offset);
+        let chunk = &self.chunks[i.saturating_sub(1)];
+        (chunk.file, chunk.offset + offset - chunk.start)
+    }
+
+    /// The file index and span within that file of `span`. A span that
+    /// crosses into another file is cut off at the end of its first file.
+    pub fn locate_span(&self, span: &Span) -> (usize, Span) {
+        let (file, start) = self.locate(span.start);
+        let len = self.files[file].text.len();
+        (file, start..(start + span.len()).min(len))
+    }
+
+    /// The name of an included file containing `offset` (`None` in the main
+    /// file), and the 1-based line and column there.
+    pub fn position(&self, offset: usize) -> (Option<&str>, usize, usize) {
+        let (file, offset) = self.locate(offset);
+        let (line, column) = offset_to_pos(&self.files[file].text, offset);
+        let name = (file > 0).then(|| self.files[file].name.as_str());
+        (name, line, column)
+    }
+
+    fn push_chunk(&mut self, file: usize, offset: usize) {
+        let start = self.text.len();
+        // A chunk that is empty is replaced
+        if self.chunks.last().is_some_and(|chunk| chunk.start == start) {
+            self.chunks.pop();
+        }
+        self.chunks.push(Chunk {
+            start,
+            file,
+            offset,
+        });
+    }
+}
+
+/// Replace every `.INCLUDE` line in `source`, recursively, with the text of
+/// the file it names. `name` is the name of `source` itself.
+///
+/// Lines that cannot be included are left in place and reported in the
+/// returned errors, whose spans index the returned text.
+pub fn resolve_includes(
+    name: &str,
+    source: &str,
+    resolver: &mut dyn IncludeResolver,
+) -> (SourceMap, Vec<ParseError>) {
+    let mut map = SourceMap {
+        text: String::with_capacity(source.len()),
+        files: vec![SourceFile {
+            name: name.to_string(),
+            text: source.to_string(),
+        }],
+        chunks: Vec::new(),
+    };
+    let mut errors = Vec::new();
+    include(&mut map, 0, &mut vec![0], resolver, &mut errors);
+    (map, errors)
+}
+
+/// Append file `file` to the combined text. `stack` holds the files being
+/// included, outermost first.
+fn include(
+    map: &mut SourceMap,
+    file: usize,
+    stack: &mut Vec<usize>,
+    resolver: &mut dyn IncludeResolver,
+    errors: &mut Vec<ParseError>,
+) {
+    let text = map.files[file].text.clone();
+    let mut copied = 0;
+    map.push_chunk(file, 0);
+
+    let mut start = 0;
+    for line in text.split('\n') {
+        let line_start = start;
+        start += line.len() + 1;
+        let Some(path) = include_path(line) else {
+            continue;
+        };
+        map.text.push_str(&text[copied..line_start]);
+        copied = line_start;
+        let at = map.text.len();
+        let span = at + path.span.start..at + path.span.end;
+
+        let from = &map.files[file].name;
+        let included = match resolver.resolve(&path.value, from) {
+            Ok(included) => included,
+            Err(message) => {
+                errors.push(error(
+                    map,
+                    span,
+                    Code::IncludeFailed,
+                    format!("cannot include {}: {message}", path.value),
+                ));
+                continue;
+            }
+        };
+        if let Some(i) = stack
+            .iter()
+            .position(|&f| map.files[f].name == included.name)
+        {
+            let mut cycle: Vec<_> = stack[i..]
+                .iter()
+                .map(|&f| map.files[f].name.as_str())
+                .collect();
+            cycle.push(&included.name);
+            let message = format!("include cycle: {}", cycle.join(" -> "));
+            errors.push(error(map, span, Code::IncludeCycle, message));
+            continue;
+        }
+
+        // The `.INCLUDE` line itself is dropped; its newline is kept
+        copied = line_start + line.len();
+        let index = map.files.len();
+        map.files.push(included);
+        stack.push(index);
+        include(map, index, stack, resolver, errors);
+        stack.pop();
+        map.push_chunk(file, copied);
+    }
+    map.text.push_str(&text[copied..]);
+}
+
+/// The path of an `.INCLUDE` line, with its span in the line.
+fn include_path(line: &str) -> Option<crate::Spanned<String>> {
+    // Cheap test first; most lines are not includes
+    if !line.to_ascii_uppercase().contains(".INCLUDE") {
+        return None;
+    }
+    let parser = ws()
+        .ignore_then(directive())
+        .then_ignore(ws())
+        .then_ignore(comment().or_not())
+        .then_ignore(one_of("\r").or_not())
+        .then_ignore(end());
+    match parser.parse(line).into_result() {
+        Ok(Directive::Include(path)) => Some(path),
+        _ => None,
+    }
+}
+
+/// An error at `span` in the combined text built so far.
+fn error(map: &SourceMap, span: Span, code: Code, message: String) -> ParseError {
+    let (file, line, column) = map.position(span.start);
+    ParseError {
+        message,
+        code,
+        related: Vec::new(),
+        suggestions: Vec::new(),
+        line,
+        column,
+        span,
+        expanded_from: None,
+        file: file.map(str::to_string),
+    }
+}
+
+/// Normalize `path` lexically: resolve `.` and `..` and use `/` throughout.
+pub fn normalize_path(path: &str) -> String {
+    let absolute = path.starts_with(['/', '\\']);
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split(['/', '\\']) {
+        match part {
+            "" | "." => {}
+            ".." if parts.last().is_some_and(|&p| p != "..") => {
+                parts.pop();
+            }
+            ".." if absolute => {}
+            _ => parts.push(part),
+        }
+    }
+    let joined = parts.join("/");
+    if absolute {
+        format!("/{joined}")
+    } else {
+        joined
+    }
+}
+
+/// Resolve `path` relative to the directory of the file named `from`.
+pub fn join_path(from: &str, path: &str) -> String {
+    if path.starts_with(['/', '\\']) {
+        return normalize_path(path);
+    }
+    match from.rfind(['/', '\\']) {
+        Some(i) => normalize_path(&format!("{}/{path}", &from[..i])),
+        None => normalize_path(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries
+            .iter()
+            .map(|(name, text)| (name.to_string(), text.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_resolve_includes() {
+        let mut resolver = files(&[
+            ("lib/io.asm", "PUTS_NL PUTS\n.include \"consts.asm\"\nRET"),
+            ("lib/consts.asm", "NL .EQU x0A\n"),
+        ]);
+        let source = "HALT\n  .INCLUDE \"lib/io.asm\" ; helpers\nLOOP BR LOOP\n";
+        let (map, errors) = resolve_includes("main.asm", source, &mut resolver);
+        assert!(errors.is_empty());
+
+        assert_eq!(
+            map.text(),
+            "HALT\nPUTS_NL PUTS\nNL .EQU x0A\n\nRET\nLOOP BR LOOP\n"
+        );
+        let names: Vec<_> = map.files().iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["main.asm", "lib/io.asm", "lib/consts.asm"]);
+
+        let offset = |s: &str| map.text().find(s).unwrap();
+        assert_eq!(map.position(offset("HALT")), (None, 1, 1));
+        assert_eq!(map.position(offset("PUTS\n")), (Some("lib/io.asm"), 1, 9));
+        assert_eq!(map.position(offset("x0A")), (Some("lib/consts.asm"), 1, 9));
+        assert_eq!(map.position(offset("RET")), (Some("lib/io.asm"), 3, 1));
+        assert_eq!(map.position(offset("LOOP")), (None, 3, 1));
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let mut resolver = files(&[
+            ("a.asm", ".INCLUDE \"b.asm\""),
+            ("b.asm", ".INCLUDE \"./a.asm\""),
+        ]);
+        let source = ".INCLUDE \"missing.asm\"\n.INCLUDE \"a.asm\"";
+        let (map, errors) = resolve_includes("main.asm", source, &mut resolver);
+        let messages: Vec<_> = errors.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "cannot include missing.asm: file not found: missing.asm",
+                "include cycle: a.asm -> b.asm -> a.asm",
+            ]
+        );
+        assert_eq!(&map.text()[errors[0].span.clone()], "\"missing.asm\"");
+        assert_eq!(map.position(errors[1].span.start), (Some("b.asm"), 1, 10));
+    }
+
+    #[test]
+    fn test_paths() {
+        assert_eq!(join_path("src/main.asm", "../lib/./io.asm"), "lib/io.asm");
+        assert_eq!(join_path("main.asm", "io.asm"), "io.asm");
+        assert_eq!(join_path("a/b.asm", "/abs/c.asm"), "/abs/c.asm");
+        assert_eq!(normalize_path("./x/../../y.asm"), "../y.asm");
+    }
+}
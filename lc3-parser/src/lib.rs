@@ -2,6 +2,27 @@
 //!
 //! Uses the `chumsky` parser combinator library to parse LC-3 assembly source
 
+mod cst;
+mod diagnostic;
+mod expr;
+mod include;
+mod locals;
+mod macros;
+mod visit;
+
+pub use cst::{SyntaxLine, SyntaxTree, Token, TokenKind};
+pub use diagnostic::{Code, Diagnostic, Related, Severity, Suggestion, did_you_mean};
+pub use expr::{BinaryOp, EvalError, Expr, UnaryOp};
+pub use include::{
+    IncludeResolver, SourceFile, SourceMap, join_path, normalize_path, resolve_includes,
+};
+pub use lc3_core::Isa;
+pub use locals::scope_local_labels;
+pub use macros::expand_macros;
+pub use visit::symbol_references;
+
+use chumsky::input::{Emitter, MapExtra};
+use chumsky::label::LabelError;
 use chumsky::prelude::*;
 use chumsky::recovery::via_parser;
 use chumsky::span::SimpleSpan;
@@ -45,33 +66,79 @@ pub struct Register(pub u8);
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Operand {
     Register(Register),
-    Immediate(i16),
-    Label(Spanned<String>),
+    /// A number, a label, or an expression combining them.
+    Expr(Spanned<Expr>),
     String(String),
 }
 
 /// Assembly directives.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Directive {
-    Orig(u16),
-    Fill(Operand),
-    Blkw(u16),
+    Orig(Spanned<Expr>),
+    /// `.FILL a, b, c`: one word for each value.
+    Fill(Vec<Operand>),
+    /// `.BLKW count` or `.BLKW count, value`: `count` words of `value`, or
+    /// of zero.
+    Blkw(Spanned<Expr>, Option<Spanned<Expr>>),
     Stringz(String),
+    /// `.STRINGP "text"`: a string packed two characters per word, low
+    /// byte first, as PUTSP prints it; a zero byte ends it.
+    Stringp(String),
     End,
+    /// `NAME .EQU value`: a constant, usable anywhere in the program.
+    Equ(Spanned<Expr>),
+    /// `NAME .SET value`: a constant that may be redefined; each use sees
+    /// the latest definition above it.
+    Set(Spanned<Expr>),
+    /// `.INCLUDE "file"`, replaced by the file's text; see [`resolve_includes`].
+    Include(Spanned<String>),
+    /// `.EXTERNAL A, B`: symbols defined in another module, resolved when linking.
+    External(Vec<Spanned<String>>),
+    /// `.GLOBAL A, B`: symbols this module exports to others.
+    Global(Vec<Spanned<String>>),
+    /// `.IF condition`: assemble the lines up to the matching `.ELSEIF`,
+    /// `.ELSE` or `.ENDIF` only if the condition is nonzero.
+    If(Spanned<Expr>),
+    /// `.IFDEF NAME`: like `.IF`, taken if `NAME` is a defined constant.
+    Ifdef(Spanned<String>),
+    /// `.IFNDEF NAME`: like `.IF`, taken if `NAME` is not a defined constant.
+    Ifndef(Spanned<String>),
+    /// `.ELSEIF condition`, within an `.IF` block.
+    Elseif(Spanned<Expr>),
+    Else,
+    Endif,
 }
 
 /// Second operand for ADD (register or 5-bit immediate).
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AddSrc2 {
     Register(Register),
-    Immediate(i8),
+    Immediate(Spanned<Expr>),
 }
 
 /// Second operand for AND (register or 5-bit immediate).
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AndSrc2 {
     Register(Register),
-    Immediate(i8),
+    Immediate(Spanned<Expr>),
+}
+
+/// Second operand for XOR (register or 5-bit immediate, LC-3b only).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum XorSrc2 {
+    Register(Register),
+    Immediate(Spanned<Expr>),
+}
+
+/// Shift kind for the LC-3b SHF instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShiftKind {
+    /// LSHF: logical left shift.
+    Left,
+    /// RSHFL: logical right shift.
+    RightLogical,
+    /// RSHFA: arithmetic right shift.
+    RightArithmetic,
 }
 
 /// LC-3 instructions.
@@ -95,50 +162,50 @@ pub enum Instruction {
         n: bool,
         z: bool,
         p: bool,
-        label: Spanned<String>,
+        label: Spanned<Expr>,
     },
     Jmp {
         base: Register,
     },
     Ret,
     Jsr {
-        label: Spanned<String>,
+        label: Spanned<Expr>,
     },
     Jsrr {
         base: Register,
     },
     Ld {
         dr: Register,
-        label: Spanned<String>,
+        label: Spanned<Expr>,
     },
     Ldi {
         dr: Register,
-        label: Spanned<String>,
+        label: Spanned<Expr>,
     },
     Ldr {
         dr: Register,
         base: Register,
-        offset: i8,
+        offset: Spanned<Expr>,
     },
     Lea {
         dr: Register,
-        label: Spanned<String>,
+        label: Spanned<Expr>,
     },
     St {
         sr: Register,
-        label: Spanned<String>,
+        label: Spanned<Expr>,
     },
     Sti {
         sr: Register,
-        label: Spanned<String>,
+        label: Spanned<Expr>,
     },
     Str {
         sr: Register,
         base: Register,
-        offset: i8,
+        offset: Spanned<Expr>,
     },
     Trap {
-        trapvect: u8,
+        trapvect: Spanned<Expr>,
     },
     Getc,
     Out,
@@ -147,6 +214,182 @@ pub enum Instruction {
     Putsp,
     Halt,
     Rti,
+    /// LC-3b: `DR = SR1 ^ SR2/imm5`.
+    Xor {
+        dr: Register,
+        sr1: Register,
+        src2: XorSrc2,
+    },
+    /// LC-3b: LSHF/RSHFL/RSHFA.
+    Shf {
+        dr: Register,
+        sr: Register,
+        kind: ShiftKind,
+        amount: Spanned<Expr>,
+    },
+    /// LC-3b: load sign-extended byte at `base + offset`.
+    Ldb {
+        dr: Register,
+        base: Register,
+        offset: Spanned<Expr>,
+    },
+    /// LC-3b: store low byte at `base + offset`.
+    Stb {
+        sr: Register,
+        base: Register,
+        offset: Spanned<Expr>,
+    },
+    /// LC-3b: load word at `base + 2 * offset`.
+    Ldw {
+        dr: Register,
+        base: Register,
+        offset: Spanned<Expr>,
+    },
+    /// LC-3b: store word at `base + 2 * offset`.
+    Stw {
+        sr: Register,
+        base: Register,
+        offset: Spanned<Expr>,
+    },
+    /// User-defined instruction on the reserved opcode (1101).
+    Custom {
+        mnemonic: String,
+        operands: Vec<Operand>,
+    },
+    /// Pseudo-instruction, expanded by the assembler (LC-3 only).
+    Pseudo(Pseudo),
+}
+
+/// Pseudo-instructions, accepted when [`Dialect::pseudo_ops`] is set. The
+/// assembler expands each into one or more real instructions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pseudo {
+    /// `MOV DR, SR`: `ADD DR, SR, #0`.
+    Mov { dr: Register, sr: Register },
+    /// `CLR DR`: `AND DR, DR, #0`.
+    Clr { dr: Register },
+    /// `INC DR`: `ADD DR, DR, #1`.
+    Inc { dr: Register },
+    /// `DEC DR`: `ADD DR, DR, #-1`.
+    Dec { dr: Register },
+    /// `NEG DR` or `NEG DR, SR`: `DR = -SR`.
+    Neg { dr: Register, sr: Register },
+    /// `SUB DR, SR1, SR2` or `SUB DR, SR1, imm`: `DR = SR1 - SR2/imm`.
+    Sub {
+        dr: Register,
+        sr1: Register,
+        src2: AddSrc2,
+    },
+    /// `PUSH SR` onto the stack at R6.
+    Push { sr: Register },
+    /// `POP DR` off the stack at R6.
+    Pop { dr: Register },
+    /// `LDIMM DR, value`: load a 16-bit constant from the literal pool.
+    Ldimm { dr: Register, value: Spanned<Expr> },
+    /// `JMP label`: jump anywhere, through R7.
+    Jmp { target: Spanned<Expr> },
+    /// `CALL label`: call a subroutine anywhere, through R7.
+    Call { target: Spanned<Expr> },
+}
+
+/// Operand layout of a custom instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtensionFormat {
+    /// No operands.
+    None,
+    /// `DR, SR1, SR2` or `DR, SR1, imm5`, encoded like ADD.
+    Alu,
+    /// `DR, SR`, encoded like NOT without the trailing ones.
+    RegReg,
+    /// `DR, BaseR, offset6`, encoded like LDR.
+    BaseOffset,
+    /// `DR, LABEL`, encoded like LD with a 9-bit PC offset.
+    PcOffset,
+}
+
+impl ExtensionFormat {
+    /// Operand syntax, for error messages.
+    pub fn syntax(self) -> &'static str {
+        match self {
+            Self::None => "no operands",
+            Self::Alu => "DR, SR1, SR2/imm5",
+            Self::RegReg => "DR, SR",
+            Self::BaseOffset => "DR, BaseR, offset6",
+            Self::PcOffset => "DR, LABEL",
+        }
+    }
+
+    fn accepts(self, operands: &[Operand]) -> bool {
+        use Operand::{Expr as E, Register as R};
+        matches!(
+            (self, operands),
+            (Self::None, [])
+                | (Self::Alu, [R(_), R(_), R(_) | E(_)])
+                | (Self::RegReg, [R(_), R(_)])
+                | (Self::BaseOffset, [R(_), R(_), E(_)])
+                | (Self::PcOffset, [R(_), E(_)])
+        )
+    }
+}
+
+/// Syntax of a custom instruction occupying the reserved opcode (1101).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extension {
+    /// Mnemonic, matched case-insensitively.
+    pub mnemonic: String,
+    pub format: ExtensionFormat,
+    /// Fixed bits OR'd into the low 12 bits, e.g. to tell extensions apart.
+    pub bits: u16,
+}
+
+impl Extension {
+    pub fn new(mnemonic: impl Into<String>, format: ExtensionFormat) -> Self {
+        Self {
+            mnemonic: mnemonic.into().to_uppercase(),
+            format,
+            bits: 0,
+        }
+    }
+
+    pub fn with_bits(mut self, bits: u16) -> Self {
+        self.bits = bits & 0x0FFF;
+        self
+    }
+}
+
+/// Source language accepted by the parser.
+#[derive(Debug, Clone, Default)]
+pub struct Dialect {
+    /// Instruction set whose mnemonics are recognized.
+    pub isa: Isa,
+    /// Custom instructions on the reserved opcode (LC-3 only).
+    pub extensions: Vec<Extension>,
+    /// Whether pseudo-instructions such as MOV and PUSH are accepted.
+    pub pseudo_ops: bool,
+}
+
+impl Dialect {
+    fn extension(&self, name: &str) -> Option<&Extension> {
+        self.extensions
+            .iter()
+            .find(|ext| ext.mnemonic.eq_ignore_ascii_case(name))
+    }
+}
+
+/// A `.MACRO NAME p1, p2` ... `.ENDM` definition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacroDef {
+    pub name: Spanned<String>,
+    pub params: Vec<Spanned<String>>,
+    /// Unparsed body lines; parameters are referenced as `\p1`.
+    pub body: Vec<Spanned<String>>,
+}
+
+/// An invocation of a macro, with its arguments as unparsed text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacroCall {
+    pub name: Spanned<String>,
+    pub args: Vec<Spanned<String>>,
 }
 
 /// A parsed line of assembly.
@@ -157,6 +400,9 @@ pub enum Line {
     LabeledInstruction(Spanned<String>, Instruction),
     Directive(Directive),
     Instruction(Instruction),
+    Macro(MacroDef),
+    MacroCall(MacroCall),
+    LabeledMacroCall(Spanned<String>, MacroCall),
     Empty,
     Error,
 }
@@ -166,6 +412,12 @@ pub enum Line {
 pub struct SpannedLine {
     pub line: Line,
     pub span: Span,
+    /// For lines produced by [`expand_macros`], the span of the invocation;
+    /// `span` then points at the line in the macro body.
+    pub expanded_from: Option<Span>,
+    /// The comment at the end of the line: its text after the `;`, and the
+    /// span from the `;` on. Lines from macro bodies have none.
+    pub comment: Option<Spanned<String>>,
 }
 
 /// A complete parsed program.
@@ -174,8 +426,126 @@ pub struct Program {
     pub lines: Vec<SpannedLine>,
 }
 
+impl Program {
+    /// The documentation of the line at `index`, such as a subroutine's
+    /// label: the comment lines right above it, or else its own comment.
+    /// Leading `;`s and rules such as `;------` are dropped.
+    pub fn doc_comment(&self, index: usize) -> Option<String> {
+        let above: Vec<&Spanned<String>> = self.lines[..index]
+            .iter()
+            .rev()
+            .map_while(|line| match (&line.line, &line.comment) {
+                (Line::Empty, Some(comment)) => Some(comment),
+                _ => None,
+            })
+            .collect();
+        let comments: Vec<&Spanned<String>> = if above.is_empty() {
+            self.lines.get(index)?.comment.iter().collect()
+        } else {
+            above.into_iter().rev().collect()
+        };
+        let text: Vec<&str> = comments
+            .iter()
+            .map(|comment| comment.value.trim_start_matches(';').trim())
+            .filter(|text| !text.chars().all(|c| "-=*#~_".contains(c)) || text.is_empty())
+            .collect();
+        let text = text.join("\n");
+        let text = text.trim_matches('\n');
+        (!text.is_empty()).then(|| text.to_string())
+    }
+}
+
+/// Everything the line parser needs to know besides the source text.
+struct Syntax {
+    dialect: Dialect,
+    /// Names of macros defined anywhere in the source.
+    macros: Vec<String>,
+}
+
+impl Syntax {
+    fn new(dialect: &Dialect, source: &str) -> Self {
+        Self {
+            dialect: dialect.clone(),
+            macros: macro_names(source),
+        }
+    }
+}
+
+/// Find the names of all `.MACRO` definitions, so that invocations can be
+/// recognized before the definitions are parsed.
+fn macro_names(source: &str) -> Vec<String> {
+    source
+        .lines()
+        .filter_map(|line| {
+            let (directive, rest) = line.trim_start().split_once([' ', '\t'])?;
+            if !directive.eq_ignore_ascii_case(".MACRO") {
+                return None;
+            }
+            let name: String = rest
+                .trim_start()
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+                .collect();
+            (!name.is_empty()).then(|| name.to_uppercase())
+        })
+        .collect()
+}
+
 type ParserInput<'a> = &'a str;
-type ParserExtra<'a> = extra::Err<Rich<'a, char>>;
+type ParserExtra<'a> = extra::Err<CodedError<'a>>;
+
+/// A parse error, with the code of an error raised by the grammar itself
+/// through [`CodedError::custom`]. Errors chumsky raises are syntax errors.
+#[derive(Debug, Clone, PartialEq)]
+struct CodedError<'a> {
+    rich: Rich<'a, char>,
+    code: Code,
+}
+
+impl CodedError<'_> {
+    fn custom(span: SimpleSpan, code: Code, message: impl ToString) -> Self {
+        Self {
+            rich: Rich::custom(span, message),
+            code,
+        }
+    }
+}
+
+impl<'a> chumsky::error::Error<'a, ParserInput<'a>> for CodedError<'a> {
+    fn merge(self, other: Self) -> Self {
+        // As in `Rich`, a custom error wins over what was expected
+        let code = match self.rich.reason() {
+            chumsky::error::RichReason::Custom(_) => self.code,
+            _ => other.code,
+        };
+        let rich = chumsky::error::Error::<ParserInput<'a>>::merge(self.rich, other.rich);
+        Self { rich, code }
+    }
+}
+
+impl<'a, L> LabelError<'a, ParserInput<'a>, L> for CodedError<'a>
+where
+    Rich<'a, char>: LabelError<'a, ParserInput<'a>, L>,
+{
+    fn expected_found<E: IntoIterator<Item = L>>(
+        expected: E,
+        found: Option<chumsky::util::MaybeRef<'a, char>>,
+        span: SimpleSpan,
+    ) -> Self {
+        Self {
+            rich: LabelError::<ParserInput<'a>, L>::expected_found(expected, found, span),
+            code: Code::Syntax,
+        }
+    }
+
+    fn label_with(&mut self, label: L) {
+        LabelError::<ParserInput<'a>, L>::label_with(&mut self.rich, label);
+    }
+
+    fn in_context(&mut self, label: L, span: SimpleSpan) {
+        LabelError::<ParserInput<'a>, L>::in_context(&mut self.rich, label, span);
+    }
+}
 
 // ============================================================================
 // Primitive parsers
@@ -191,7 +561,11 @@ fn kw<'a>(keyword: &'a str) -> impl Parser<'a, ParserInput<'a>, &'a str, ParserE
             if s.eq_ignore_ascii_case(keyword) {
                 Ok(s)
             } else {
-                Err(Rich::custom(span, format!("expected '{keyword}'")))
+                Err(CodedError::custom(
+                    span,
+                    Code::Syntax,
+                    format!("expected '{keyword}'"),
+                ))
             }
         })
 }
@@ -208,17 +582,26 @@ fn comma<'a>() -> impl Parser<'a, ParserInput<'a>, (), ParserExtra<'a>> + Clone
     ws().then(just(',').or_not()).then(ws()).ignored()
 }
 
+/// Succeed, without consuming anything, unless a word continues here.
+fn word_end<'a>() -> impl Parser<'a, ParserInput<'a>, (), ParserExtra<'a>> + Clone {
+    any()
+        .filter(|c: &char| c.is_ascii_alphanumeric() || *c == '_')
+        .not()
+}
+
 fn register<'a>() -> impl Parser<'a, ParserInput<'a>, Register, ParserExtra<'a>> + Clone {
     just('R')
         .or(just('r'))
         .ignore_then(any().filter(|c: &char| c.is_ascii_digit()).to_slice())
+        .then_ignore(word_end())
         .validate(|s: &str, e, emitter| {
             let digit = s.chars().next().unwrap().to_digit(10).unwrap() as u8;
             if digit <= 7 {
                 Register(digit)
             } else {
-                emitter.emit(Rich::custom(
+                emitter.emit(CodedError::custom(
                     e.span(),
+                    Code::InvalidRegister,
                     format!("R{digit} is not valid, use R0-R7"),
                 ));
                 Register(0)
@@ -228,8 +611,7 @@ fn register<'a>() -> impl Parser<'a, ParserInput<'a>, Register, ParserExtra<'a>>
 }
 
 fn hex_number<'a>() -> impl Parser<'a, ParserInput<'a>, u16, ParserExtra<'a>> + Clone {
-    just('x')
-        .or(just('X'))
+    choice((just("0x"), just("0X"), just("x"), just("X")))
         .ignore_then(
             any()
                 .filter(|c: &char| c.is_ascii_hexdigit())
@@ -238,10 +620,30 @@ fn hex_number<'a>() -> impl Parser<'a, ParserInput<'a>, u16, ParserExtra<'a>> +
                 .to_slice(),
         )
         .try_map(|s: &str, span| {
-            u16::from_str_radix(s, 16).map_err(|_| Rich::custom(span, "invalid hex number"))
+            u16::from_str_radix(s, 16)
+                .map_err(|_| CodedError::custom(span, Code::InvalidNumber, "invalid hex number"))
+        })
+}
+
+/// A binary number, `0b101` or `b101`. In operands the bare `b` form wins
+/// over a label spelled the same, such as `B1`.
+fn binary_number<'a>() -> impl Parser<'a, ParserInput<'a>, u16, ParserExtra<'a>> + Clone {
+    choice((just("0b"), just("0B"), just("b"), just("B")))
+        .ignore_then(one_of("01").repeated().at_least(1).to_slice())
+        .try_map(|s: &str, span| {
+            u16::from_str_radix(s, 2)
+                .map_err(|_| CodedError::custom(span, Code::InvalidNumber, "invalid binary number"))
         })
 }
 
+/// A character literal such as `'A'` or `'\n'`, standing for its code.
+fn char_literal<'a>() -> impl Parser<'a, ParserInput<'a>, char, ParserExtra<'a>> + Clone {
+    just('\'')
+        .ignore_then(escape().or(none_of("\\'")))
+        .then_ignore(just('\''))
+        .labelled("character")
+}
+
 fn decimal_number<'a>() -> impl Parser<'a, ParserInput<'a>, i16, ParserExtra<'a>> + Clone {
     just('#')
         .or_not()
@@ -257,16 +659,22 @@ fn decimal_number<'a>() -> impl Parser<'a, ParserInput<'a>, i16, ParserExtra<'a>
                 .to_slice(),
         )
         .try_map(|s: &str, span| {
-            s.parse::<i16>()
-                .map_err(|_| Rich::custom(span, "invalid decimal number"))
+            s.parse::<i16>().map_err(|_| {
+                CodedError::custom(span, Code::InvalidNumber, "invalid decimal number")
+            })
         })
 }
 
-fn number<'a>() -> impl Parser<'a, ParserInput<'a>, i16, ParserExtra<'a>> + Clone {
-    hex_number()
-        .map(|n| n as i16)
-        .or(decimal_number())
-        .labelled("number")
+/// A numeric literal; hex and binary literals are unsigned (`xFFFF` is
+/// 65535).
+fn number<'a>() -> impl Parser<'a, ParserInput<'a>, i32, ParserExtra<'a>> + Clone {
+    choice((
+        hex_number().map(i32::from),
+        binary_number().map(i32::from),
+        char_literal().map(|c| c as i32),
+        decimal_number().map(i32::from),
+    ))
+    .labelled("number")
 }
 
 fn identifier<'a>() -> impl Parser<'a, ParserInput<'a>, Spanned<String>, ParserExtra<'a>> + Clone {
@@ -285,21 +693,66 @@ fn identifier<'a>() -> impl Parser<'a, ParserInput<'a>, Spanned<String>, ParserE
         .labelled("label")
 }
 
-fn string_literal<'a>() -> impl Parser<'a, ParserInput<'a>, String, ParserExtra<'a>> + Clone {
-    let escape = just('\\').ignore_then(choice((
+/// Directive names, which a local label may not take.
+const DIRECTIVES: &[&str] = &[
+    "ORIG", "FILL", "BLKW", "STRINGZ", "STRINGP", "END", "EQU", "SET", "INCLUDE", "EXTERNAL",
+    "GLOBAL", "IF", "IFDEF", "IFNDEF", "ELSEIF", "ELSE", "ENDIF", "MACRO", "ENDM",
+];
+
+/// A local label, `.name`; see [`scope_local_labels`].
+fn local_label<'a>() -> impl Parser<'a, ParserInput<'a>, Spanned<String>, ParserExtra<'a>> + Clone {
+    just('.')
+        .ignore_then(identifier())
+        .try_map(|name: Spanned<String>, span| {
+            if DIRECTIVES.contains(&name.value.as_str()) {
+                Err(CodedError::custom(span, Code::Syntax, "expected label"))
+            } else {
+                Ok(Spanned::new(format!(".{}", name.value), span.into_range()))
+            }
+        })
+        .labelled("label")
+}
+
+/// A label as defined: global, or local with a leading dot.
+fn label_name<'a>() -> impl Parser<'a, ParserInput<'a>, Spanned<String>, ParserExtra<'a>> + Clone {
+    choice((local_label(), identifier()))
+}
+
+/// A label as referred to: also a local label qualified by the global label
+/// it belongs to, `GLOBAL.name`.
+fn label_reference<'a>()
+-> impl Parser<'a, ParserInput<'a>, Spanned<String>, ParserExtra<'a>> + Clone {
+    let qualified = identifier()
+        .then(local_label().or_not())
+        .map_with(|(global, local), e| match local {
+            Some(local) => {
+                let span: SimpleSpan = e.span();
+                Spanned::new(global.value + &local.value, span.into_range())
+            }
+            None => global,
+        });
+    choice((local_label(), qualified))
+}
+
+/// An escape sequence in a string or character literal.
+fn escape<'a>() -> impl Parser<'a, ParserInput<'a>, char, ParserExtra<'a>> + Clone {
+    just('\\').ignore_then(choice((
         just('n').to('\n'),
         just('r').to('\r'),
         just('t').to('\t'),
         just('\\').to('\\'),
         just('"').to('"'),
+        just('\'').to('\''),
         just('0').to('\0'),
-    )));
+    )))
+}
 
+fn string_literal<'a>() -> impl Parser<'a, ParserInput<'a>, String, ParserExtra<'a>> + Clone {
     let regular_char = none_of("\\\"");
 
     just('"')
         .ignore_then(
-            choice((escape, regular_char))
+            choice((escape(), regular_char))
                 .repeated()
                 .collect::<String>(),
         )
@@ -314,32 +767,93 @@ fn string_literal<'a>() -> impl Parser<'a, ParserInput<'a>, String, ParserExtra<
 fn directive<'a>() -> impl Parser<'a, ParserInput<'a>, Directive, ParserExtra<'a>> + Clone {
     let orig = kw("ORIG")
         .ignore_then(ws1())
-        .ignore_then(hex_number().or(decimal_number().map(|n| n as u16)))
+        .ignore_then(expr::expr())
         .map(Directive::Orig);
 
     let fill = kw("FILL")
         .ignore_then(ws1())
-        .ignore_then(choice((
-            hex_number().map(|n| Operand::Immediate(n as i16)),
-            decimal_number().map(Operand::Immediate),
-            identifier().map(Operand::Label),
-        )))
+        .ignore_then(
+            expr::expr()
+                .map(Operand::Expr)
+                .separated_by(comma())
+                .at_least(1)
+                .collect(),
+        )
         .map(Directive::Fill);
 
     let blkw = kw("BLKW")
         .ignore_then(ws1())
-        .ignore_then(hex_number().or(decimal_number().map(|n| n as u16)))
-        .map(Directive::Blkw);
+        .ignore_then(expr::expr())
+        .then(comma().ignore_then(expr::expr()).or_not())
+        .map(|(count, value)| Directive::Blkw(count, value));
 
     let stringz = kw("STRINGZ")
         .ignore_then(ws1())
         .ignore_then(string_literal())
         .map(Directive::Stringz);
 
+    let stringp = kw("STRINGP")
+        .ignore_then(ws1())
+        .ignore_then(string_literal())
+        .map(Directive::Stringp);
+
     let end = kw("END").to(Directive::End);
 
+    let equ = kw("EQU")
+        .ignore_then(ws1())
+        .ignore_then(expr::expr())
+        .map(Directive::Equ);
+
+    let set = kw("SET")
+        .ignore_then(ws1())
+        .ignore_then(expr::expr())
+        .map(Directive::Set);
+
+    let include = kw("INCLUDE")
+        .ignore_then(ws1())
+        .ignore_then(string_literal().map_with(|path, e| {
+            let span: SimpleSpan = e.span();
+            Spanned::new(path, span.into_range())
+        }))
+        .map(Directive::Include);
+
+    let names = || {
+        ws1().ignore_then(
+            identifier()
+                .separated_by(ws().then(just(',')).then(ws()))
+                .at_least(1)
+                .collect::<Vec<_>>(),
+        )
+    };
+    let external = kw("EXTERNAL").ignore_then(names()).map(Directive::External);
+    let global = kw("GLOBAL").ignore_then(names()).map(Directive::Global);
+
+    let condition = || ws1().ignore_then(expr::expr());
+    let name = || ws1().ignore_then(identifier());
+    let conditional = choice((
+        kw("IFDEF").ignore_then(name()).map(Directive::Ifdef),
+        kw("IFNDEF").ignore_then(name()).map(Directive::Ifndef),
+        kw("IF").ignore_then(condition()).map(Directive::If),
+        kw("ELSEIF").ignore_then(condition()).map(Directive::Elseif),
+        kw("ELSE").to(Directive::Else),
+        kw("ENDIF").to(Directive::Endif),
+    ));
+
     just('.')
-        .ignore_then(choice((orig, fill, blkw, stringz, end)))
+        .ignore_then(choice((
+            orig,
+            fill,
+            blkw,
+            stringz,
+            stringp,
+            end,
+            equ,
+            set,
+            include,
+            external,
+            global,
+            conditional,
+        )))
         .labelled("directive")
 }
 
@@ -356,7 +870,7 @@ fn instr_add<'a>() -> impl Parser<'a, ParserInput<'a>, Instruction, ParserExtra<
         .then_ignore(comma())
         .then(choice((
             register().map(AddSrc2::Register),
-            number().map(|n| AddSrc2::Immediate(n as i8)),
+            expr::expr().map(AddSrc2::Immediate),
         )))
         .map(|((dr, sr1), src2)| Instruction::Add { dr, sr1, src2 })
 }
@@ -370,11 +884,45 @@ fn instr_and<'a>() -> impl Parser<'a, ParserInput<'a>, Instruction, ParserExtra<
         .then_ignore(comma())
         .then(choice((
             register().map(AndSrc2::Register),
-            number().map(|n| AndSrc2::Immediate(n as i8)),
+            expr::expr().map(AndSrc2::Immediate),
         )))
         .map(|((dr, sr1), src2)| Instruction::And { dr, sr1, src2 })
 }
 
+fn instr_xor<'a>() -> impl Parser<'a, ParserInput<'a>, Instruction, ParserExtra<'a>> + Clone {
+    kw("XOR")
+        .ignore_then(ws1())
+        .ignore_then(register())
+        .then_ignore(comma())
+        .then(register())
+        .then_ignore(comma())
+        .then(choice((
+            register().map(XorSrc2::Register),
+            expr::expr().map(XorSrc2::Immediate),
+        )))
+        .map(|((dr, sr1), src2)| Instruction::Xor { dr, sr1, src2 })
+}
+
+fn instr_shf<'a>() -> impl Parser<'a, ParserInput<'a>, Instruction, ParserExtra<'a>> + Clone {
+    choice((
+        kw("LSHF").to(ShiftKind::Left),
+        kw("RSHFL").to(ShiftKind::RightLogical),
+        kw("RSHFA").to(ShiftKind::RightArithmetic),
+    ))
+    .then_ignore(ws1())
+    .then(register())
+    .then_ignore(comma())
+    .then(register())
+    .then_ignore(comma())
+    .then(expr::expr())
+    .map(|(((kind, dr), sr), amount)| Instruction::Shf {
+        dr,
+        sr,
+        kind,
+        amount,
+    })
+}
+
 fn instr_not<'a>() -> impl Parser<'a, ParserInput<'a>, Instruction, ParserExtra<'a>> + Clone {
     kw("NOT")
         .ignore_then(ws1())
@@ -394,11 +942,19 @@ fn branch_condition<'a>()
         .try_map(|s: &str, span| {
             let upper = s.to_ascii_uppercase();
             if !upper.starts_with("BR") {
-                return Err(Rich::custom(span, "expected branch instruction"));
+                return Err(CodedError::custom(
+                    span,
+                    Code::Syntax,
+                    "expected branch instruction",
+                ));
             }
             let flags = &upper[2..];
             if !flags.chars().all(|c| matches!(c, 'N' | 'Z' | 'P')) {
-                return Err(Rich::custom(span, "invalid branch flags"));
+                return Err(CodedError::custom(
+                    span,
+                    Code::Syntax,
+                    "invalid branch flags",
+                ));
             }
             let n = flags.contains('N') || flags.is_empty();
             let z = flags.contains('Z') || flags.is_empty();
@@ -410,7 +966,7 @@ fn branch_condition<'a>()
 fn instr_br<'a>() -> impl Parser<'a, ParserInput<'a>, Instruction, ParserExtra<'a>> + Clone {
     branch_condition()
         .then_ignore(ws1())
-        .then(identifier())
+        .then(expr::expr())
         .map(|((n, z, p), label)| Instruction::Br { n, z, p, label })
 }
 
@@ -438,7 +994,7 @@ macro_rules! label_instr {
         fn $name<'a>() -> impl Parser<'a, ParserInput<'a>, Instruction, ParserExtra<'a>> + Clone {
             kw($kw)
                 .ignore_then(ws1())
-                .ignore_then(identifier())
+                .ignore_then(expr::expr())
                 .map(|$field| Instruction::$variant { $field })
         }
     };
@@ -451,7 +1007,7 @@ macro_rules! reg_label_instr {
                 .ignore_then(ws1())
                 .ignore_then(register())
                 .then_ignore(comma())
-                .then(identifier())
+                .then(expr::expr())
                 .map(|($reg, label)| Instruction::$variant { $reg, label })
         }
     };
@@ -466,12 +1022,8 @@ macro_rules! reg_base_offset_instr {
                 .then_ignore(comma())
                 .then(register())
                 .then_ignore(comma())
-                .then(number())
-                .map(|(($reg, base), offset)| Instruction::$variant {
-                    $reg,
-                    base,
-                    offset: offset as i8,
-                })
+                .then(expr::expr())
+                .map(|(($reg, base), offset)| Instruction::$variant { $reg, base, offset })
         }
     };
 }
@@ -498,18 +1050,140 @@ reg_label_instr!(instr_sti, "STI", Sti, sr);
 
 reg_base_offset_instr!(instr_ldr, "LDR", Ldr, dr);
 reg_base_offset_instr!(instr_str, "STR", Str, sr);
+reg_base_offset_instr!(instr_ldb, "LDB", Ldb, dr);
+reg_base_offset_instr!(instr_stb, "STB", Stb, sr);
+reg_base_offset_instr!(instr_ldw, "LDW", Ldw, dr);
+reg_base_offset_instr!(instr_stw, "STW", Stw, sr);
 
 fn instr_trap<'a>() -> impl Parser<'a, ParserInput<'a>, Instruction, ParserExtra<'a>> + Clone {
     kw("TRAP")
         .ignore_then(ws1())
-        .ignore_then(hex_number().or(decimal_number().map(|n| n as u16)))
-        .map(|trapvect| Instruction::Trap {
-            trapvect: trapvect as u8,
+        .ignore_then(expr::expr())
+        .map(|trapvect| Instruction::Trap { trapvect })
+}
+
+fn instr_custom<'a>(
+    dialect: &'a Dialect,
+) -> impl Parser<'a, ParserInput<'a>, Instruction, ParserExtra<'a>> + Clone {
+    let operand = choice((
+        register().map(Operand::Register),
+        expr::expr().map(Operand::Expr),
+    ));
+
+    identifier()
+        .try_map(move |name: Spanned<String>, span| {
+            dialect
+                .extension(&name.value)
+                .ok_or_else(|| CodedError::custom(span, Code::Syntax, "expected instruction"))
         })
+        .then(
+            ws1()
+                .ignore_then(operand.separated_by(comma()).at_least(1).collect())
+                .or_not(),
+        )
+        .validate(
+            move |(ext, operands): (&Extension, Option<Vec<Operand>>), e, emitter| {
+                let operands = operands.unwrap_or_default();
+                if !ext.format.accepts(&operands) {
+                    emitter.emit(CodedError::custom(
+                        e.span(),
+                        Code::InvalidOperand,
+                        format!("{} expects {}", ext.mnemonic, ext.format.syntax()),
+                    ));
+                }
+                Instruction::Custom {
+                    mnemonic: ext.mnemonic.clone(),
+                    operands,
+                }
+            },
+        )
 }
 
-fn instruction<'a>() -> impl Parser<'a, ParserInput<'a>, Instruction, ParserExtra<'a>> + Clone {
+fn pseudo<'a>() -> impl Parser<'a, ParserInput<'a>, Pseudo, ParserExtra<'a>> + Clone {
+    let reg_reg = |name| {
+        kw(name)
+            .ignore_then(ws1())
+            .ignore_then(register())
+            .then_ignore(comma())
+            .then(register())
+    };
+    let reg = |name| kw(name).ignore_then(ws1()).ignore_then(register());
+    let reg_expr = |name| {
+        kw(name)
+            .ignore_then(ws1())
+            .ignore_then(register())
+            .then_ignore(comma())
+            .then(expr::expr())
+    };
+    let target = |name| kw(name).ignore_then(ws1()).ignore_then(expr::expr());
+
     choice((
+        reg_reg("MOV").map(|(dr, sr)| Pseudo::Mov { dr, sr }),
+        reg("CLR").map(|dr| Pseudo::Clr { dr }),
+        reg("INC").map(|dr| Pseudo::Inc { dr }),
+        reg("DEC").map(|dr| Pseudo::Dec { dr }),
+        reg("NEG")
+            .then(comma().ignore_then(register()).or_not())
+            .map(|(dr, sr)| Pseudo::Neg {
+                dr,
+                sr: sr.unwrap_or(dr),
+            }),
+        reg_reg("SUB")
+            .then_ignore(comma())
+            .then(choice((
+                register().map(AddSrc2::Register),
+                expr::expr().map(AddSrc2::Immediate),
+            )))
+            .map(|((dr, sr1), src2)| Pseudo::Sub { dr, sr1, src2 }),
+        reg("PUSH").map(|sr| Pseudo::Push { sr }),
+        reg("POP").map(|dr| Pseudo::Pop { dr }),
+        reg_expr("LDIMM").map(|(dr, value)| Pseudo::Ldimm { dr, value }),
+        target("JMP").map(|target| Pseudo::Jmp { target }),
+        target("CALL").map(|target| Pseudo::Call { target }),
+    ))
+}
+
+/// Accept pseudo-instructions only if the dialect enables them, so that
+/// otherwise their names remain free for labels and macros.
+fn instr_pseudo<'a>(
+    dialect: &'a Dialect,
+) -> impl Parser<'a, ParserInput<'a>, Instruction, ParserExtra<'a>> + Clone {
+    let enabled = dialect.pseudo_ops;
+    pseudo().try_map(move |op, span| {
+        if enabled {
+            Ok(Instruction::Pseudo(op))
+        } else {
+            Err(CodedError::custom(
+                span,
+                Code::UnknownInstruction,
+                "pseudo-instructions are not enabled",
+            ))
+        }
+    })
+}
+
+/// Reject an instruction that exists only on `only` when parsing for `isa`.
+fn require_isa<'a>(
+    parser: impl Parser<'a, ParserInput<'a>, Instruction, ParserExtra<'a>> + Clone,
+    isa: Isa,
+    only: Isa,
+) -> impl Parser<'a, ParserInput<'a>, Instruction, ParserExtra<'a>> + Clone {
+    parser.validate(move |instr, e, emitter| {
+        if isa != only {
+            emitter.emit(CodedError::custom(
+                e.span(),
+                Code::UnknownInstruction,
+                format!("instruction is only available on {only}"),
+            ));
+        }
+        instr
+    })
+}
+
+fn instruction<'a>(
+    dialect: &'a Dialect,
+) -> impl Parser<'a, ParserInput<'a>, Instruction, ParserExtra<'a>> + Clone {
+    let common = choice((
         instr_add(),
         instr_and(),
         instr_not(),
@@ -518,13 +1192,7 @@ fn instruction<'a>() -> impl Parser<'a, ParserInput<'a>, Instruction, ParserExtr
         instr_ret(),
         instr_jsrr(),
         instr_jsr(),
-        instr_ldi(),
-        instr_ldr(),
-        instr_ld(),
         instr_lea(),
-        instr_sti(),
-        instr_str(),
-        instr_st(),
         instr_trap(),
         instr_getc(),
         instr_out(),
@@ -533,6 +1201,33 @@ fn instruction<'a>() -> impl Parser<'a, ParserInput<'a>, Instruction, ParserExtr
         instr_putsp(),
         instr_halt(),
         instr_rti(),
+    ));
+
+    let lc3_only = choice((
+        instr_ldi(),
+        instr_ldr(),
+        instr_ld(),
+        instr_sti(),
+        instr_str(),
+        instr_st(),
+    ));
+
+    let lc3b_only = choice((
+        instr_xor(),
+        instr_shf(),
+        instr_ldb(),
+        instr_stb(),
+        instr_ldw(),
+        instr_stw(),
+    ));
+
+    let isa = dialect.isa;
+    choice((
+        common,
+        require_isa(lc3_only, isa, Isa::Lc3),
+        require_isa(lc3b_only, isa, Isa::Lc3b),
+        require_isa(instr_custom(dialect), isa, Isa::Lc3),
+        require_isa(instr_pseudo(dialect), isa, Isa::Lc3),
     ))
     .labelled("instruction")
 }
@@ -541,10 +1236,14 @@ fn instruction<'a>() -> impl Parser<'a, ParserInput<'a>, Instruction, ParserExtr
 // Line parsing
 // ============================================================================
 
-fn comment<'a>() -> impl Parser<'a, ParserInput<'a>, (), ParserExtra<'a>> + Clone {
+/// A `;` comment, as the text after the `;` and the span of the whole.
+fn comment<'a>() -> impl Parser<'a, ParserInput<'a>, Spanned<String>, ParserExtra<'a>> + Clone {
     just(';')
-        .then(any().and_is(just('\n').not()).repeated())
-        .ignored()
+        .ignore_then(any().and_is(just('\n').not()).repeated().to_slice())
+        .map_with(|text: &str, e| {
+            let span: SimpleSpan = e.span();
+            Spanned::new(text.to_string(), span.into_range())
+        })
 }
 
 const RESERVED: &[&str] = &[
@@ -553,21 +1252,32 @@ const RESERVED: &[&str] = &[
     "PUTS", "IN", "PUTSP", "HALT",
 ];
 
-fn is_reserved(name: &str) -> bool {
+const RESERVED_LC3B: &[&str] = &["XOR", "LSHF", "RSHFL", "RSHFA", "LDB", "STB", "LDW", "STW"];
+
+const RESERVED_PSEUDO: &[&str] = &[
+    "MOV", "CLR", "INC", "DEC", "NEG", "SUB", "PUSH", "POP", "LDIMM", "CALL",
+];
+
+fn is_reserved(name: &str, dialect: &Dialect) -> bool {
     RESERVED.contains(&name)
+        || (dialect.isa == Isa::Lc3b && RESERVED_LC3B.contains(&name))
+        || (dialect.pseudo_ops && RESERVED_PSEUDO.contains(&name))
+        || dialect.extension(name).is_some()
 }
 
 fn label_with_colon<'a>()
 -> impl Parser<'a, ParserInput<'a>, Spanned<String>, ParserExtra<'a>> + Clone {
-    identifier().then_ignore(just(':'))
+    label_name().then_ignore(just(':'))
 }
 
-fn label_without_colon<'a>()
--> impl Parser<'a, ParserInput<'a>, Spanned<String>, ParserExtra<'a>> + Clone {
-    identifier().try_map(|spanned: Spanned<String>, span| {
-        if is_reserved(&spanned.value) {
-            Err(Rich::custom(
+fn label_without_colon<'a>(
+    syntax: &'a Syntax,
+) -> impl Parser<'a, ParserInput<'a>, Spanned<String>, ParserExtra<'a>> + Clone {
+    label_name().try_map(move |spanned: Spanned<String>, span| {
+        if is_reserved(&spanned.value, &syntax.dialect) || syntax.macros.contains(&spanned.value) {
+            Err(CodedError::custom(
                 span,
+                Code::ReservedName,
                 format!("'{}' is a reserved keyword", spanned.value),
             ))
         } else {
@@ -576,43 +1286,206 @@ fn label_without_colon<'a>()
     })
 }
 
-fn line<'a>() -> impl Parser<'a, ParserInput<'a>, SpannedLine, ParserExtra<'a>> + Clone {
+/// Split macro arguments at commas outside string literals.
+fn split_args(text: &str, start: usize) -> Vec<Spanned<String>> {
+    let mut args = Vec::new();
+    let mut arg_start = 0;
+    let mut in_string = false;
+    let mut push = |from: usize, to: usize| {
+        let raw = &text[from..to];
+        let trimmed = raw.trim();
+        let offset = from + (raw.len() - raw.trim_start().len());
+        args.push(Spanned::new(
+            trimmed.to_string(),
+            start + offset..start + offset + trimmed.len(),
+        ));
+    };
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            ',' if !in_string => {
+                push(arg_start, i);
+                arg_start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if !text[arg_start..].trim().is_empty() || arg_start > 0 {
+        push(arg_start, text.len());
+    }
+    args
+}
+
+type MacroHeader = (
+    (Spanned<String>, Vec<Spanned<String>>),
+    Vec<Spanned<String>>,
+);
+
+fn macro_def<'a>() -> impl Parser<'a, ParserInput<'a>, MacroDef, ParserExtra<'a>> + Clone {
+    let endm = ws().then(just('.')).then(kw("ENDM")).ignored();
+    let eol = ws().then(comment().or_not()).ignored();
+    let params = ws1()
+        .ignore_then(identifier().separated_by(comma()).at_least(1).collect())
+        .or_not()
+        .map(Option::unwrap_or_default);
+    let body_line = any()
+        .and_is(just('\n').not())
+        .repeated()
+        .to_slice()
+        .and_is(endm.clone().not())
+        .map_with(|text: &str, e| {
+            let span: SimpleSpan = e.span();
+            Spanned::new(text.to_string(), span.into_range())
+        })
+        .then_ignore(just('\n'));
+
+    just('.')
+        .ignore_then(kw("MACRO"))
+        .ignore_then(ws1())
+        .ignore_then(identifier())
+        .then(params)
+        .then_ignore(eol)
+        .then_ignore(just('\n'))
+        .then(body_line.repeated().collect())
+        .then(endm.or_not())
+        .validate(
+            |(((name, params), body), endm): (MacroHeader, Option<()>), e, emitter| {
+                if endm.is_none() {
+                    emitter.emit(CodedError::custom(
+                        e.span(),
+                        Code::UnclosedBlock,
+                        format!("macro {} is missing .ENDM", name.value),
+                    ));
+                }
+                MacroDef { name, params, body }
+            },
+        )
+}
+
+fn macro_call<'a>(
+    syntax: &'a Syntax,
+) -> impl Parser<'a, ParserInput<'a>, MacroCall, ParserExtra<'a>> + Clone {
+    let args = any()
+        .and_is(one_of(";\n").not())
+        .repeated()
+        .to_slice()
+        .map_with(|text: &str, e| {
+            let span: SimpleSpan = e.span();
+            split_args(text, span.start)
+        });
+
+    identifier()
+        .try_map(move |name: Spanned<String>, span| {
+            if syntax.macros.contains(&name.value) {
+                Ok(name)
+            } else {
+                Err(CodedError::custom(span, Code::Syntax, "expected macro"))
+            }
+        })
+        .then(ws1().ignore_then(args).or_not())
+        .map(|(name, args)| MacroCall {
+            name,
+            args: args.unwrap_or_default(),
+        })
+}
+
+fn labeled_directive<'a>(
+    (label, dir): (Spanned<String>, Directive),
+    e: &mut MapExtra<'a, '_, ParserInput<'a>, ParserExtra<'a>>,
+    emitter: &mut Emitter<CodedError<'a>>,
+) -> Line {
+    match dir {
+        Directive::Include(_) => {
+            emitter.emit(CodedError::custom(
+                e.span(),
+                Code::MisplacedLabel,
+                ".INCLUDE cannot have a label",
+            ));
+        }
+        Directive::If(_)
+        | Directive::Ifdef(_)
+        | Directive::Ifndef(_)
+        | Directive::Elseif(_)
+        | Directive::Else
+        | Directive::Endif => {
+            emitter.emit(CodedError::custom(
+                e.span(),
+                Code::MisplacedLabel,
+                "a conditional cannot have a label",
+            ));
+        }
+        _ => {}
+    }
+    Line::LabeledDirective(label, dir)
+}
+
+fn line<'a>(
+    syntax: &'a Syntax,
+) -> impl Parser<'a, ParserInput<'a>, SpannedLine, ParserExtra<'a>> + Clone {
+    let dialect = &syntax.dialect;
     let labeled_colon_dir = label_with_colon()
         .then_ignore(ws())
         .then(directive())
-        .map(|(l, d)| Line::LabeledDirective(l, d));
+        .validate(labeled_directive);
     let labeled_colon_instr = label_with_colon()
         .then_ignore(ws())
-        .then(instruction())
+        .then(instruction(dialect))
         .map(|(l, i)| Line::LabeledInstruction(l, i));
+    let labeled_colon_call = label_with_colon()
+        .then_ignore(ws())
+        .then(macro_call(syntax))
+        .map(|(l, c)| Line::LabeledMacroCall(l, c));
     let label_colon_only = label_with_colon().map(Line::Label);
 
-    let labeled_space_dir = label_without_colon()
+    let labeled_space_dir = label_without_colon(syntax)
         .then_ignore(ws1())
         .then(directive())
-        .map(|(l, d)| Line::LabeledDirective(l, d));
-    let labeled_space_instr = label_without_colon()
+        .validate(labeled_directive);
+    let labeled_space_instr = label_without_colon(syntax)
         .then_ignore(ws1())
-        .then(instruction())
+        .then(instruction(dialect))
         .map(|(l, i)| Line::LabeledInstruction(l, i));
-
-    let directive_only = directive().map(Line::Directive);
-    let instruction_only = instruction().map(Line::Instruction);
-    let label_only = label_without_colon().map(Line::Label);
+    let labeled_space_call = label_without_colon(syntax)
+        .then_ignore(ws1())
+        .then(macro_call(syntax))
+        .map(|(l, c)| Line::LabeledMacroCall(l, c));
+
+    let macro_def = macro_def().map(Line::Macro);
+    let directive_only = directive().validate(|dir, e, emitter| {
+        if let Directive::Equ(_) | Directive::Set(_) = dir {
+            emitter.emit(CodedError::custom(
+                e.span(),
+                Code::MisplacedLabel,
+                "constant is missing a name",
+            ));
+        }
+        Line::Directive(dir)
+    });
+    let instruction_only = instruction(dialect).map(Line::Instruction);
+    let call_only = macro_call(syntax).map(Line::MacroCall);
+    // A local label on its own needs a colon, so that a misspelled
+    // directive is not taken for one
+    let label_only = label_without_colon(syntax)
+        .filter(|label| !label.value.starts_with('.'))
+        .map(Line::Label);
     let empty = empty().to(Line::Empty);
 
-    let eol = ws().then(comment().or_not()).ignored();
-    let skip_to_eol = any().and_is(just('\n').not()).repeated().ignored();
+    let eol = ws().ignore_then(comment().or_not());
+    let skip_to_eol = any().and_is(just('\n').not()).repeated().to(None);
     let recovery = any().and_is(just('\n').not()).repeated().to(Line::Error);
 
     ws().ignore_then(choice((
+        macro_def,
         labeled_colon_dir,
         labeled_colon_instr,
+        labeled_colon_call,
         label_colon_only,
         labeled_space_dir,
         labeled_space_instr,
+        labeled_space_call,
         directive_only,
         instruction_only,
+        call_only,
         label_only,
         empty,
     )))
@@ -621,20 +1494,25 @@ fn line<'a>() -> impl Parser<'a, ParserInput<'a>, SpannedLine, ParserExtra<'a>>
         SpannedLine {
             line,
             span: span.into_range(),
+            expanded_from: None,
+            comment: None,
         }
     })
-    .then_ignore(eol.recover_with(via_parser(skip_to_eol)))
+    .then(eol.recover_with(via_parser(skip_to_eol)))
+    .map(|(line, comment)| SpannedLine { comment, ..line })
     .recover_with(via_parser(recovery.map_with(|line, e| {
         let span: SimpleSpan = e.span();
         SpannedLine {
             line,
             span: span.into_range(),
+            expanded_from: None,
+            comment: None,
         }
     })))
 }
 
-fn program<'a>() -> impl Parser<'a, ParserInput<'a>, Program, ParserExtra<'a>> {
-    line()
+fn program<'a>(syntax: &'a Syntax) -> impl Parser<'a, ParserInput<'a>, Program, ParserExtra<'a>> {
+    line(syntax)
         .separated_by(just('\n'))
         .allow_trailing()
         .collect()
@@ -652,17 +1530,75 @@ pub struct ParseError {
     pub line: usize,
     pub column: usize,
     pub span: std::ops::Range<usize>,
+    /// Span of the macro invocation, for errors inside an expansion.
+    pub expanded_from: Option<Span>,
+    /// The included file the error is in; `None` for the main file.
+    /// `line` and `column` count within that file.
+    pub file: Option<String>,
+    pub code: Code,
+    /// Other places that bear on the error.
+    pub related: Vec<Related>,
+    pub suggestions: Vec<Suggestion>,
+}
+
+impl ParseError {
+    /// The error, for [`SourceMap::report`] and [`SourceMap::json`].
+    pub fn diagnostic(&self) -> Diagnostic<'_> {
+        Diagnostic {
+            code: self.code,
+            message: &self.message,
+            span: &self.span,
+            expanded_from: self.expanded_from.as_ref(),
+            related: &self.related,
+            suggestions: &self.suggestions,
+        }
+    }
 }
 
 impl std::fmt::Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{file}:")?;
+        }
         write!(f, "{}:{}: {}", self.line, self.column, self.message)
     }
 }
 
+/// Why `name` makes a confusing label, if it does: it looks like a register,
+/// or reads as a hex or binary number (`XAB`, `B1`), so an operand spelled
+/// like it is not a reference to the label.
+pub fn confusing_label(name: &str) -> Option<String> {
+    let upper = name.to_ascii_uppercase();
+    let digits = |prefix: char, radix: u32| {
+        upper
+            .strip_prefix(prefix)
+            .filter(|d| !d.is_empty() && d.chars().all(|c| c.is_digit(radix)))
+    };
+    if let Some(digit) = upper.strip_prefix('R')
+        && matches!(digit, "0" | "1" | "2" | "3" | "4" | "5" | "6" | "7")
+    {
+        Some(format!("label {name} looks like register R{digit}"))
+    } else if let Some(hex) = digits('X', 16) {
+        Some(format!(
+            "label {name} reads as the number x{hex} in operands"
+        ))
+    } else {
+        digits('B', 2).map(|bin| format!("label {name} reads as the number b{bin} in operands"))
+    }
+}
+
 /// Parse LC-3 assembly source code.
 pub fn parse(source: &str) -> Result<Program, Vec<ParseError>> {
-    match program().parse(source).into_result() {
+    parse_with(source, &Dialect::default())
+}
+
+/// Parse assembly source code written in the given dialect.
+///
+/// Macro definitions and invocations are kept as [`Line::Macro`] and
+/// [`Line::MacroCall`]; see [`expand_macros`].
+pub fn parse_with(source: &str, dialect: &Dialect) -> Result<Program, Vec<ParseError>> {
+    let syntax = Syntax::new(dialect, source);
+    match program(&syntax).parse(source).into_result() {
         Ok(p) => Ok(p),
         Err(errors) => Err(errors
             .into_iter()
@@ -671,12 +1607,13 @@ pub fn parse(source: &str) -> Result<Program, Vec<ParseError>> {
     }
 }
 
-fn to_parse_error(source: &str, e: Rich<'_, char>) -> ParseError {
+fn to_parse_error(source: &str, e: CodedError<'_>) -> ParseError {
+    let CodedError { rich: e, code } = e;
     let span = e.span();
     let (line, column) = offset_to_pos(source, span.start);
 
-    let message = match e.reason() {
-        chumsky::error::RichReason::Custom(msg) => msg.to_string(),
+    let (code, message) = match e.reason() {
+        chumsky::error::RichReason::Custom(msg) => (code, msg.to_string()),
         _ => {
             let mut msg = match e.found() {
                 Some(c) => format!("unexpected {}", format_char(*c)),
@@ -702,7 +1639,7 @@ fn to_parse_error(source: &str, e: Rich<'_, char>) -> ParseError {
                     ));
                 }
             }
-            msg
+            (Code::Syntax, msg)
         }
     };
 
@@ -711,6 +1648,11 @@ fn to_parse_error(source: &str, e: Rich<'_, char>) -> ParseError {
         line,
         column,
         span: span.start..span.end,
+        expanded_from: None,
+        file: None,
+        code,
+        related: Vec::new(),
+        suggestions: Vec::new(),
     }
 }
 
@@ -809,25 +1751,15 @@ fn simplify_expected(mut expected: Vec<String>) -> Vec<String> {
 
 /// Format parse errors with source context for pretty display.
 pub fn format_errors(filename: &str, source: &str, errors: &[ParseError]) -> String {
-    use ariadne::{Color, Label, Report, ReportKind, Source};
+    format_errors_in(&SourceMap::new(filename, source), errors)
+}
 
-    let mut output = Vec::new();
-    for error in errors {
-        Report::<(&str, std::ops::Range<usize>)>::build(
-            ReportKind::Error,
-            (filename, error.span.clone()),
-        )
-        .with_message(&error.message)
-        .with_label(
-            Label::new((filename, error.span.clone()))
-                .with_message(&error.message)
-                .with_color(Color::Red),
-        )
-        .finish()
-        .write((filename, Source::from(source)), &mut output)
-        .unwrap();
-    }
-    String::from_utf8(output).unwrap_or_else(|_| "error formatting output".into())
+/// Format parse errors in a program that may include other files.
+pub fn format_errors_in(sources: &SourceMap, errors: &[ParseError]) -> String {
+    errors
+        .iter()
+        .map(|error| sources.report(&error.diagnostic()))
+        .collect()
 }
 
 #[cfg(test)]
@@ -850,6 +1782,44 @@ mod tests {
         assert_eq!(decimal_number().parse("#-5").into_result(), Ok(-5));
     }
 
+    #[test]
+    fn test_literals() {
+        assert_eq!(number().parse("0x1F").into_result(), Ok(0x1F));
+        assert_eq!(number().parse("b0101").into_result(), Ok(5));
+        assert_eq!(number().parse("0b0101").into_result(), Ok(5));
+        assert_eq!(number().parse("0B11").into_result(), Ok(3));
+        assert_eq!(number().parse("'A'").into_result(), Ok(65));
+        assert_eq!(number().parse("'\\n'").into_result(), Ok(10));
+        assert_eq!(number().parse("'\\''").into_result(), Ok(39));
+        assert!(number().parse("b012").into_result().is_err());
+        assert!(number().parse("0b012").into_result().is_err());
+
+        assert_eq!(
+            confusing_label("XA").as_deref(),
+            Some("label XA reads as the number xA in operands")
+        );
+        assert_eq!(
+            confusing_label("B1").as_deref(),
+            Some("label B1 reads as the number b1 in operands")
+        );
+        assert_eq!(
+            confusing_label("b0101").as_deref(),
+            Some("label b0101 reads as the number b0101 in operands")
+        );
+        assert!(confusing_label("B2").is_none());
+        assert!(confusing_label("R1").is_some());
+        assert!(confusing_label("XYZ").is_none());
+
+        let program = parse("AND R0, R0, b1111\n.FILL 'z'\n").unwrap();
+        assert_eq!(
+            program.lines[1].line,
+            Line::Directive(Directive::Fill(vec![Operand::Expr(Spanned::new(
+                Expr::Number(122),
+                24..27
+            ))]))
+        );
+    }
+
     #[test]
     fn test_add() {
         assert!(instr_add().parse("ADD R0, R1, R2").into_result().is_ok());
@@ -860,9 +1830,256 @@ mod tests {
     fn test_directive() {
         assert_eq!(
             directive().parse(".ORIG x3000").into_result(),
-            Ok(Directive::Orig(0x3000))
+            Ok(Directive::Orig(Spanned::new(Expr::Number(0x3000), 6..11)))
         );
         assert_eq!(directive().parse(".END").into_result(), Ok(Directive::End));
+        assert_eq!(
+            directive().parse(".GLOBAL MAIN, Loop").into_result(),
+            Ok(Directive::Global(vec![
+                Spanned::new("MAIN".into(), 8..12),
+                Spanned::new("LOOP".into(), 14..18),
+            ]))
+        );
+        let errors = parse("LIB .INCLUDE \"lib.asm\"").unwrap_err();
+        assert_eq!(errors[0].message, ".INCLUDE cannot have a label");
+
+        assert_eq!(
+            directive().parse(".IFDEF debug").into_result(),
+            Ok(Directive::Ifdef(Spanned::new("DEBUG".into(), 7..12)))
+        );
+        assert_eq!(
+            directive().parse(".IF 1").into_result(),
+            Ok(Directive::If(Spanned::new(Expr::Number(1), 4..5)))
+        );
+        assert_eq!(
+            directive().parse(".ELSE").into_result(),
+            Ok(Directive::Else)
+        );
+        assert_eq!(
+            directive().parse(".ENDIF").into_result(),
+            Ok(Directive::Endif)
+        );
+        assert_eq!(
+            directive().parse(".FILL 1, 2").into_result(),
+            Ok(Directive::Fill(vec![
+                Operand::Expr(Spanned::new(Expr::Number(1), 6..7)),
+                Operand::Expr(Spanned::new(Expr::Number(2), 9..10)),
+            ]))
+        );
+        assert_eq!(
+            directive().parse(".BLKW 2, #-1").into_result(),
+            Ok(Directive::Blkw(
+                Spanned::new(Expr::Number(2), 6..7),
+                Some(Spanned::new(Expr::Number(-1), 9..12))
+            ))
+        );
+        assert_eq!(
+            directive().parse(".STRINGP \"ab\"").into_result(),
+            Ok(Directive::Stringp("ab".into()))
+        );
+        let errors = parse("X .ENDIF").unwrap_err();
+        assert_eq!(errors[0].message, "a conditional cannot have a label");
+    }
+
+    #[test]
+    fn test_local_labels() {
+        let source = ".loop\nA\n.loop: BR .loop\n.x HALT\nB BR .x\n.y .EQU 1\nLD R0, A.x + .y\n";
+        // Alone on a line, a local label needs its colon
+        assert!(parse(source).is_err());
+        let mut program = parse(&source[6..]).unwrap();
+        scope_local_labels(&mut program);
+        let mut names = Vec::new();
+        for line in &mut program.lines {
+            visit::visit_symbols(&mut line.line, &mut |span, name| {
+                if let Some(name) = name {
+                    names.push((name.clone(), span.clone()));
+                }
+            });
+        }
+        let names: Vec<_> = names.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "A", "A.LOOP", "A.LOOP", "A.X", "B", "B.X", "B.Y", "A.X", "B.Y"
+            ]
+        );
+        assert!(parse(".end: HALT").is_err());
+    }
+
+    #[test]
+    fn test_constants() {
+        let source = "NL .EQU x0A\nMASK: .SET NL | 1\nADD R0, R0, NL - R1X\n";
+        let program = parse(source).unwrap();
+        assert_eq!(
+            program.lines[0].line,
+            Line::LabeledDirective(
+                Spanned::new("NL".into(), 0..2),
+                Directive::Equ(Spanned::new(Expr::Number(10), 8..11))
+            )
+        );
+        assert!(matches!(
+            program.lines[1].line,
+            Line::LabeledDirective(_, Directive::Set(_))
+        ));
+        // R1X is a symbol, not a register
+        let Line::Instruction(Instruction::Add {
+            src2: AddSrc2::Immediate(imm),
+            ..
+        }) = &program.lines[2].line
+        else {
+            panic!("unexpected {:?}", program.lines[2].line);
+        };
+        let mut symbols = Vec::new();
+        imm.symbols(&mut |name, _| symbols.push(name.to_string()));
+        assert_eq!(symbols, ["NL", "R1X"]);
+
+        let errors = parse(".EQU 3").unwrap_err();
+        assert_eq!(errors[0].message, "constant is missing a name");
+    }
+
+    #[test]
+    fn test_lc3b_mnemonics() {
+        let lc3b = Dialect {
+            isa: Isa::Lc3b,
+            ..Dialect::default()
+        };
+        let source = ".ORIG x3000\nLDB R0, R1, #-3\nRSHFA R2, R3, #15\nXOR R0, R0, #-1\n.END";
+        let program = parse_with(source, &lc3b).unwrap();
+        assert_eq!(
+            program.lines[2].line,
+            Line::Instruction(Instruction::Shf {
+                dr: Register(2),
+                sr: Register(3),
+                kind: ShiftKind::RightArithmetic,
+                amount: Spanned::new(Expr::Number(15), 42..45),
+            })
+        );
+        // LC-3b mnemonics are rejected on LC-3, and vice versa
+        assert!(parse(source).is_err());
+        assert!(parse_with(".ORIG x3000\nLDI R0, X\n.END", &lc3b).is_err());
+    }
+
+    #[test]
+    fn test_lc3b_mnemonics_are_labels_on_lc3() {
+        assert!(parse(".ORIG x3000\nXOR ADD R0, R0, #1\nLDB\n.END").is_ok());
+    }
+
+    #[test]
+    fn test_custom_instructions() {
+        let dialect = Dialect {
+            extensions: vec![
+                Extension::new("mul", ExtensionFormat::Alu),
+                Extension::new("NOP2", ExtensionFormat::None),
+            ],
+            ..Dialect::default()
+        };
+        let source = ".ORIG x3000\nLOOP MUL R0, R1, #3 ; triple\nnop2\n.END";
+        let program = parse_with(source, &dialect).unwrap();
+        assert_eq!(
+            program.lines[1].line,
+            Line::LabeledInstruction(
+                Spanned::new("LOOP".into(), 12..16),
+                Instruction::Custom {
+                    mnemonic: "MUL".into(),
+                    operands: vec![
+                        Operand::Register(Register(0)),
+                        Operand::Register(Register(1)),
+                        Operand::Expr(Spanned::new(Expr::Number(3), 29..31)),
+                    ],
+                }
+            )
+        );
+        assert_eq!(
+            program.lines[2].line,
+            Line::Instruction(Instruction::Custom {
+                mnemonic: "NOP2".into(),
+                operands: vec![],
+            })
+        );
+
+        let errors = parse_with(".ORIG x3000\nMUL R0, #1\n.END", &dialect).unwrap_err();
+        assert!(errors[0].message.contains("MUL expects DR, SR1, SR2/imm5"));
+        // Without the extension MUL is just a label
+        assert!(parse(".ORIG x3000\nMUL\n.END").is_ok());
+    }
+
+    #[test]
+    fn test_pseudo_ops() {
+        let dialect = Dialect {
+            pseudo_ops: true,
+            ..Dialect::default()
+        };
+        let source = ".ORIG x3000\nNEG R1\nJMP R2\nJMP FAR\nSUB R0, R1, #2\n.END";
+        let program = parse_with(source, &dialect).unwrap();
+        let lines: Vec<_> = program.lines.iter().map(|l| &l.line).collect();
+        assert_eq!(
+            *lines[1],
+            Line::Instruction(Instruction::Pseudo(Pseudo::Neg {
+                dr: Register(1),
+                sr: Register(1),
+            }))
+        );
+        // A register operand makes JMP the real instruction
+        assert_eq!(
+            *lines[2],
+            Line::Instruction(Instruction::Jmp { base: Register(2) })
+        );
+        assert_eq!(
+            *lines[3],
+            Line::Instruction(Instruction::Pseudo(Pseudo::Jmp {
+                target: Spanned::new(Expr::Symbol("FAR".into()), 30..33),
+            }))
+        );
+        assert!(matches!(
+            lines[4],
+            Line::Instruction(Instruction::Pseudo(Pseudo::Sub {
+                src2: AddSrc2::Immediate(_),
+                ..
+            }))
+        ));
+        // Their names are reserved only when enabled, and free for labels
+        // otherwise
+        assert!(parse_with(".ORIG x3000\nPUSH ADD R0, R0, #1\n.END", &dialect).is_err());
+        assert!(parse(".ORIG x3000\nPUSH ADD R0, R0, #1\n.END").is_ok());
+        assert!(parse(".ORIG x3000\nMOV R0, R1\n.END").is_err());
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let code = |source: &str| parse(source).unwrap_err()[0].code;
+        assert_eq!(code("ADD R8, R1, R1\n"), Code::InvalidRegister);
+        assert_eq!(code("LDB R1, R2, #1\n"), Code::UnknownInstruction);
+        assert_eq!(code("ADD R1, R1\n"), Code::Syntax);
+        assert_eq!(code(".MACRO M\nHALT\n"), Code::UnclosedBlock);
+        assert_eq!(code("L .INCLUDE \"a.asm\"\n"), Code::MisplacedLabel);
+        assert_eq!(code(".EQU 4\n"), Code::MisplacedLabel);
+    }
+
+    #[test]
+    fn test_comments() {
+        let source = "\
+.ORIG x3000 ; start
+;-------------------
+; PRINT: print R0
+;
+; Clobbers R7.
+PRINT   OUT ; one character
+        RET
+DONE    HALT ; stop; really
+.END";
+        let program = parse(source).unwrap();
+        let comment = program.lines[0].comment.as_ref().unwrap();
+        assert_eq!(
+            (comment.value.as_str(), &source[comment.span.clone()]),
+            (" start", "; start")
+        );
+        assert!(program.lines[6].comment.is_none());
+        assert_eq!(
+            program.doc_comment(5).as_deref(),
+            Some("PRINT: print R0\n\nClobbers R7.")
+        );
+        assert_eq!(program.doc_comment(7).as_deref(), Some("stop; really"));
+        assert_eq!(program.doc_comment(6), None);
     }
 
     #[test]
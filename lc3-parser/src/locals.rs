@@ -0,0 +1,44 @@
+//! Local labels. A label written `.name` belongs to the global label above
+//! it, so that every subroutine can have its own `.loop`; elsewhere it is
+//! reachable as `GLOBAL.name`.
+
+use crate::visit::{visit_label, visit_symbols};
+use crate::{Directive, Line, Program};
+
+/// Rename every local label, and every reference to one, to
+/// `GLOBAL.NAME`, after the nearest global label above it. Constants and
+/// labels made by macro expansions do not start a scope; local labels
+/// above the first global label keep their name.
+pub fn scope_local_labels(program: &mut Program) {
+    let mut scope = String::new();
+    for spanned_line in &mut program.lines {
+        let label = match &spanned_line.line {
+            Line::LabeledDirective(_, Directive::Equ(_) | Directive::Set(_)) => None,
+            Line::Label(label)
+            | Line::LabeledDirective(label, _)
+            | Line::LabeledInstruction(label, _)
+            | Line::LabeledMacroCall(label, _) => Some(&label.value),
+            _ => None,
+        };
+        if let Some(label) = label
+            && !label.starts_with('.')
+            && spanned_line.expanded_from.is_none()
+        {
+            scope.clone_from(label);
+        }
+
+        let mut rename = |_: &mut _, name: Option<&mut String>| {
+            if let Some(name) = name
+                && name.starts_with('.')
+            {
+                name.insert_str(0, &scope);
+            }
+        };
+        match &mut spanned_line.line {
+            // Arguments are text until the invocation is expanded
+            Line::LabeledMacroCall(label, _) => visit_label(label, &mut rename),
+            Line::MacroCall(_) => {}
+            line => visit_symbols(line, &mut rename),
+        }
+    }
+}
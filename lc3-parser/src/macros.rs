@@ -0,0 +1,387 @@
+//! Macro expansion.
+//!
+//! A macro body is kept as raw text. Each invocation substitutes `\param`
+//! with the argument text, parses the resulting lines, and renames labels
+//! defined in the body so that every expansion gets its own copy (`LOOP`
+//! becomes `LOOP@3`). Spans in the expanded lines point at the macro body,
+//! and `expanded_from` at the invocation.
+
+use crate::visit::visit_symbols;
+use crate::{
+    Code, Dialect, Line, MacroCall, MacroDef, ParseError, Program, Related, Span, Spanned,
+    SpannedLine, Syntax, did_you_mean, is_reserved, line, offset_to_pos, to_parse_error,
+};
+use chumsky::prelude::*;
+use std::collections::{HashMap, HashSet};
+
+/// Nesting limit, so that recursive macros fail instead of looping.
+const MAX_DEPTH: usize = 32;
+
+/// Replace macro definitions and invocations in `program` with the
+/// expanded lines. `source` and `dialect` must be the ones it was parsed with.
+pub fn expand_macros(
+    program: Program,
+    source: &str,
+    dialect: &Dialect,
+) -> Result<Program, Vec<ParseError>> {
+    let mut expander = Expander {
+        source,
+        syntax: Syntax::new(dialect, source),
+        macros: HashMap::new(),
+        expansions: 0,
+        errors: Vec::new(),
+    };
+
+    for spanned in &program.lines {
+        if let Line::Macro(def) = &spanned.line {
+            expander.define(def);
+        }
+    }
+
+    let mut lines = Vec::with_capacity(program.lines.len());
+    for spanned in program.lines {
+        match spanned.line {
+            Line::Macro(_) => {}
+            Line::MacroCall(call) => expander.expand(call, &spanned.span, &mut lines),
+            Line::LabeledMacroCall(label, call) => {
+                lines.push(SpannedLine {
+                    line: Line::Label(label),
+                    span: spanned.span.clone(),
+                    expanded_from: None,
+                    comment: spanned.comment,
+                });
+                expander.expand(call, &spanned.span, &mut lines);
+            }
+            _ => lines.push(spanned),
+        }
+    }
+
+    if expander.errors.is_empty() {
+        Ok(Program { lines })
+    } else {
+        Err(expander.errors)
+    }
+}
+
+struct Expander<'s> {
+    source: &'s str,
+    syntax: Syntax,
+    macros: HashMap<String, MacroDef>,
+    /// Number of expansions so far, used to make local labels unique.
+    expansions: usize,
+    errors: Vec<ParseError>,
+}
+
+impl Expander<'_> {
+    fn error(&mut self, span: Span, code: Code, message: String, expanded_from: Option<Span>) {
+        let (line, column) = offset_to_pos(self.source, span.start);
+        self.errors.push(ParseError {
+            message,
+            line,
+            column,
+            span,
+            expanded_from,
+            file: None,
+            code,
+            related: Vec::new(),
+            suggestions: Vec::new(),
+        });
+    }
+
+    fn define(&mut self, def: &MacroDef) {
+        let name = &def.name;
+        if is_reserved(&name.value, &self.syntax.dialect) {
+            let message = format!("'{}' is a reserved keyword", name.value);
+            self.error(name.span.clone(), Code::ReservedName, message, None);
+        } else if let Some(first) = self.macros.get(&name.value) {
+            let first = first.name.span.clone();
+            let message = format!("macro {} is already defined", name.value);
+            self.error(name.span.clone(), Code::DuplicateDefinition, message, None);
+            if let Some(error) = self.errors.last_mut() {
+                error.related.push(Related {
+                    span: first,
+                    message: "first defined here".into(),
+                });
+            }
+        } else {
+            self.macros.insert(name.value.clone(), def.clone());
+        }
+    }
+
+    /// Append the full expansion of `call`, including nested invocations,
+    /// to `out`.
+    fn expand(&mut self, call: MacroCall, invocation: &Span, out: &mut Vec<SpannedLine>) {
+        // Explicit stack rather than recursion: nesting is bounded only by MAX_DEPTH
+        let mut stack = vec![Item::Call(call, 0)];
+        while let Some(item) = stack.pop() {
+            match item {
+                Item::Line(line) => out.push(line),
+                Item::Call(call, depth) => {
+                    let items = self.expand_one(&call, invocation, depth);
+                    stack.extend(items.into_iter().rev());
+                }
+            }
+        }
+    }
+
+    /// Expand one level of `call`. `invocation` is the span of the outermost
+    /// invocation, which is what the user wrote.
+    fn expand_one(&mut self, call: &MacroCall, invocation: &Span, depth: usize) -> Vec<Item> {
+        let Some(def) = self.macros.get(&call.name.value).cloned() else {
+            // The definition itself failed to parse or was rejected
+            let message = format!("undefined macro: {}", call.name.value);
+            self.error(call.name.span.clone(), Code::UndefinedMacro, message, None);
+            let names = self.macros.keys().map(String::as_str);
+            let suggestion = did_you_mean(&call.name.value, call.name.span.clone(), names);
+            if let Some(error) = self.errors.last_mut() {
+                error.suggestions.extend(suggestion);
+            }
+            return Vec::new();
+        };
+        let from = (depth > 0).then(|| invocation.clone());
+        if depth >= MAX_DEPTH {
+            let message = format!(
+                "macro {} nested too deeply (is it recursive?)",
+                def.name.value
+            );
+            self.error(call.name.span.clone(), Code::MacroRecursion, message, from);
+            return Vec::new();
+        }
+        if call.args.len() != def.params.len() {
+            let message = format!(
+                "macro {} expects {} argument{}, found {}",
+                def.name.value,
+                def.params.len(),
+                if def.params.len() == 1 { "" } else { "s" },
+                call.args.len()
+            );
+            self.error(call.name.span.clone(), Code::MacroArguments, message, from);
+            return Vec::new();
+        }
+
+        self.expansions += 1;
+        let suffix = format!("@{}", self.expansions);
+
+        let mut parsed = Vec::with_capacity(def.body.len());
+        for body in &def.body {
+            let expanded = substitute(body, &def.params, &call.args);
+            let (output, errors) = line(&self.syntax)
+                .then_ignore(end())
+                .parse(expanded.text.as_str())
+                .into_output_errors();
+            let errors: Vec<_> = errors
+                .into_iter()
+                .map(|e| to_parse_error(&expanded.text, e))
+                .collect();
+            for e in errors {
+                let span = expanded.map(&e.span);
+                self.error(span, e.code, e.message, Some(invocation.clone()));
+            }
+            if let Some(output) = output {
+                parsed.push((output.line, body.span.clone(), expanded));
+            }
+        }
+
+        let locals: HashSet<String> = parsed
+            .iter()
+            .filter_map(|(line, ..)| defined_label(line))
+            .cloned()
+            .collect();
+        let rename = |span: &mut Span, name: Option<&mut String>, expanded: &Expanded| {
+            *span = expanded.map(span);
+            if let Some(name) = name
+                && locals.contains(name)
+            {
+                name.push_str(&suffix);
+            }
+        };
+
+        let mut items = Vec::with_capacity(parsed.len());
+        for (mut line, span, expanded) in parsed {
+            visit_symbols(&mut line, &mut |span, name| rename(span, name, &expanded));
+            let (label, call) = match line {
+                Line::Empty => continue,
+                Line::MacroCall(call) => (None, call),
+                Line::LabeledMacroCall(label, call) => (Some(label), call),
+                line => {
+                    items.push(Item::Line(SpannedLine {
+                        line,
+                        span,
+                        expanded_from: Some(invocation.clone()),
+                        comment: None,
+                    }));
+                    continue;
+                }
+            };
+            if let Some(label) = label {
+                items.push(Item::Line(SpannedLine {
+                    line: Line::Label(label),
+                    span,
+                    expanded_from: Some(invocation.clone()),
+                    comment: None,
+                }));
+            }
+            items.push(Item::Call(call, depth + 1));
+        }
+        items
+    }
+}
+
+/// Pending output of an expansion.
+enum Item {
+    Line(SpannedLine),
+    /// A nested invocation and its nesting depth.
+    Call(MacroCall, usize),
+}
+
+/// A body line after parameter substitution, with a map back to the source.
+struct Expanded {
+    text: String,
+    /// Source span of each byte of `text` (plus one past the end).
+    origins: Vec<Span>,
+}
+
+impl Expanded {
+    /// Map a span in `text` to a span in the source.
+    fn map(&self, span: &Span) -> Span {
+        let start = self.origins[span.start.min(self.text.len())].start;
+        if span.end > span.start {
+            start..self.origins[(span.end - 1).min(self.text.len())].end
+        } else {
+            start..start
+        }
+    }
+}
+
+/// Replace every `\param` in `body` with the matching argument.
+fn substitute(
+    body: &Spanned<String>,
+    params: &[Spanned<String>],
+    args: &[Spanned<String>],
+) -> Expanded {
+    let base = body.span.start;
+    let src = body.value.as_str();
+    let mut text = String::with_capacity(src.len());
+    let mut origins = Vec::with_capacity(src.len() + 1);
+
+    let mut i = 0;
+    while i < src.len() {
+        if src.as_bytes()[i] == b'\\' {
+            let name_len = src[i + 1..]
+                .bytes()
+                .take_while(|c| c.is_ascii_alphanumeric() || *c == b'_')
+                .count();
+            let name = &src[i + 1..i + 1 + name_len];
+            if let Some(pos) = params
+                .iter()
+                .position(|p| p.value.eq_ignore_ascii_case(name))
+            {
+                let token = base + i..base + i + 1 + name_len;
+                text.push_str(&args[pos].value);
+                origins.extend(std::iter::repeat_n(token, args[pos].value.len()));
+                i += 1 + name_len;
+                continue;
+            }
+        }
+        let len = src[i..].chars().next().map_or(1, char::len_utf8);
+        text.push_str(&src[i..i + len]);
+        origins.extend((i..i + len).map(|j| base + j..base + j + 1));
+        i += len;
+    }
+    origins.push(base + src.len()..base + src.len());
+
+    Expanded { text, origins }
+}
+
+fn defined_label(line: &Line) -> Option<&String> {
+    match line {
+        Line::Label(label)
+        | Line::LabeledDirective(label, _)
+        | Line::LabeledInstruction(label, _)
+        | Line::LabeledMacroCall(label, _) => Some(&label.value),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Instruction, Register, parse};
+
+    fn expand(source: &str) -> Result<Program, Vec<ParseError>> {
+        expand_macros(parse(source).unwrap(), source, &Dialect::default())
+    }
+
+    #[test]
+    fn test_parameters_and_local_labels() {
+        let source = "\
+.MACRO WAIT reg
+LOOP ADD \\reg, \\reg, #-1
+     BRp LOOP
+.ENDM
+.ORIG x3000
+WAIT R1
+WAIT R2
+.END";
+        let program = expand(source).unwrap();
+        let lines: Vec<_> = program.lines.iter().map(|l| &l.line).collect();
+        assert_eq!(lines.len(), 6);
+        let Line::LabeledInstruction(label, Instruction::Add { dr, .. }) = lines[3] else {
+            panic!("unexpected {:?}", lines[3]);
+        };
+        assert_eq!(label.value, "LOOP@2");
+        assert_eq!(*dr, Register(2));
+        let Line::Instruction(Instruction::Br { label, .. }) = lines[4] else {
+            panic!("unexpected {:?}", lines[4]);
+        };
+        assert_eq!(label.as_symbol(), Some("LOOP@2"));
+
+        // Spans point at the body, expanded_from at the invocation
+        let body = source.find("BRp LOOP").unwrap();
+        assert_eq!(label.span, body + 4..body + 8);
+        let call = source.find("WAIT R2").unwrap();
+        assert_eq!(program.lines[4].expanded_from, Some(call..call + 7));
+    }
+
+    #[test]
+    fn test_nested_and_labeled_invocations() {
+        let source = "\
+.MACRO INC r
+ADD \\r, \\r, #1
+.ENDM
+.MACRO INC2 r
+INC \\r
+INC \\r
+.ENDM
+.ORIG x3000
+TOP INC2 R3
+.END";
+        let program = expand(source).unwrap();
+        let top = source.find("TOP").unwrap();
+        assert_eq!(
+            program.lines[1].line,
+            Line::Label(Spanned::new("TOP".into(), top..top + 3))
+        );
+        let adds = program
+            .lines
+            .iter()
+            .filter(|l| matches!(l.line, Line::Instruction(Instruction::Add { .. })))
+            .count();
+        assert_eq!(adds, 2);
+    }
+
+    #[test]
+    fn test_expansion_errors() {
+        let errors = expand(".MACRO M a\nADD \\a, \\a, #1\n.ENDM\nM R1, R2\n").unwrap_err();
+        assert!(errors[0].message.contains("expects 1 argument, found 2"));
+
+        let source = ".MACRO M a\nADD \\a, #1\n.ENDM\nM R1\n";
+        let errors = expand(source).unwrap_err();
+        assert_eq!(errors[0].line, 2);
+        let call = source.rfind("M R1").unwrap();
+        assert_eq!(errors[0].expanded_from, Some(call..call + 4));
+
+        let errors = expand(".MACRO M\nM\n.ENDM\nM\n").unwrap_err();
+        assert!(errors[0].message.contains("nested too deeply"));
+    }
+}
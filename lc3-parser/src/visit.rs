@@ -0,0 +1,155 @@
+//! Walking the symbols of a parsed line: label definitions, references and
+//! expressions. Macro expansion, local label scoping, the assembler's
+//! checks and the editor analysis all go through these.
+
+use crate::{
+    AddSrc2, AndSrc2, Directive, Instruction, Line, MacroCall, Operand, Span, Spanned, XorSrc2,
+};
+
+/// Call `f` on the span of every label definition, label reference and
+/// expression node in `line`, and on the name and arguments of a macro
+/// invocation; names are passed along for symbols.
+pub(crate) fn visit_symbols(line: &mut Line, f: &mut impl FnMut(&mut Span, Option<&mut String>)) {
+    match line {
+        Line::Label(l) => visit_label(l, f),
+        Line::LabeledDirective(l, dir) => {
+            visit_label(l, f);
+            visit_directive(dir, f);
+        }
+        Line::LabeledInstruction(l, instr) => {
+            visit_label(l, f);
+            visit_instruction(instr, f);
+        }
+        Line::Directive(dir) => visit_directive(dir, f),
+        Line::Instruction(instr) => visit_instruction(instr, f),
+        Line::LabeledMacroCall(l, call) => {
+            visit_label(l, f);
+            visit_call(call, f);
+        }
+        Line::MacroCall(call) => visit_call(call, f),
+        Line::Macro(_) | Line::Empty | Line::Error => {}
+    }
+}
+
+/// The symbols `line` refers to, with the spans of the references; the
+/// label the line defines is not one of them.
+pub fn symbol_references(line: &Line) -> Vec<Spanned<String>> {
+    let mut line = line.clone();
+    let defined = match &line {
+        Line::Label(label)
+        | Line::LabeledDirective(label, _)
+        | Line::LabeledInstruction(label, _)
+        | Line::LabeledMacroCall(label, _) => Some(label.span.clone()),
+        _ => None,
+    };
+    let mut references = Vec::new();
+    visit_symbols(&mut line, &mut |span, name| {
+        if let Some(name) = name
+            && defined.as_ref() != Some(span)
+        {
+            references.push(Spanned::new(name.clone(), span.clone()));
+        }
+    });
+    references
+}
+
+fn visit_call(call: &mut MacroCall, f: &mut impl FnMut(&mut Span, Option<&mut String>)) {
+    // A local label passed on to a nested macro is renamed like any other
+    // reference; the macro name itself never matches a label.
+    visit_label(&mut call.name, f);
+    for arg in &mut call.args {
+        visit_label(arg, f);
+    }
+}
+
+pub(crate) fn visit_label(
+    label: &mut Spanned<String>,
+    f: &mut impl FnMut(&mut Span, Option<&mut String>),
+) {
+    f(&mut label.span, Some(&mut label.value));
+}
+
+fn visit_directive(dir: &mut Directive, f: &mut impl FnMut(&mut Span, Option<&mut String>)) {
+    match dir {
+        Directive::Fill(values) => {
+            for value in values {
+                if let Operand::Expr(value) = value {
+                    value.visit_mut(f);
+                }
+            }
+        }
+        Directive::Blkw(count, value) => {
+            count.visit_mut(f);
+            if let Some(value) = value {
+                value.visit_mut(f);
+            }
+        }
+        Directive::Orig(value)
+        | Directive::Equ(value)
+        | Directive::Set(value)
+        | Directive::If(value)
+        | Directive::Elseif(value) => value.visit_mut(f),
+        Directive::Ifdef(name) | Directive::Ifndef(name) => visit_label(name, f),
+        Directive::Include(path) => f(&mut path.span, None),
+        Directive::External(names) | Directive::Global(names) => {
+            for name in names {
+                visit_label(name, f);
+            }
+        }
+        Directive::Stringz(_)
+        | Directive::Stringp(_)
+        | Directive::End
+        | Directive::Else
+        | Directive::Endif => {}
+    }
+}
+
+fn visit_instruction(instr: &mut Instruction, f: &mut impl FnMut(&mut Span, Option<&mut String>)) {
+    use Instruction::*;
+    match instr {
+        Br { label, .. }
+        | Jsr { label }
+        | Ld { label, .. }
+        | Ldi { label, .. }
+        | Lea { label, .. }
+        | St { label, .. }
+        | Sti { label, .. } => label.visit_mut(f),
+        Add {
+            src2: AddSrc2::Immediate(value),
+            ..
+        }
+        | And {
+            src2: AndSrc2::Immediate(value),
+            ..
+        }
+        | Xor {
+            src2: XorSrc2::Immediate(value),
+            ..
+        }
+        | Ldr { offset: value, .. }
+        | Str { offset: value, .. }
+        | Ldb { offset: value, .. }
+        | Stb { offset: value, .. }
+        | Ldw { offset: value, .. }
+        | Stw { offset: value, .. }
+        | Trap { trapvect: value }
+        | Shf { amount: value, .. }
+        | Pseudo(
+            crate::Pseudo::Sub {
+                src2: AddSrc2::Immediate(value),
+                ..
+            }
+            | crate::Pseudo::Ldimm { value, .. }
+            | crate::Pseudo::Jmp { target: value }
+            | crate::Pseudo::Call { target: value },
+        ) => value.visit_mut(f),
+        Custom { operands, .. } => {
+            for operand in operands {
+                if let Operand::Expr(value) = operand {
+                    value.visit_mut(f);
+                }
+            }
+        }
+        _ => {}
+    }
+}
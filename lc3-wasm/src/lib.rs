@@ -3,9 +3,12 @@
 //! This crate provides WebAssembly bindings for the LC-3 virtual machine
 //! and assembler, enabling browser-based LC-3 development environments.
 
-use lc3_assembler::{Assembler, lc3tools_format};
+use lc3_assembler::{
+    Assembler, AssemblyError, SemanticError, Span, formats, lc3tools_format, normalize_path,
+};
 use lc3_core::{LC3, VMError, VMEvent};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use wasm_bindgen::prelude::*;
 
 /// Result of a VM execution step.
@@ -26,18 +29,20 @@ pub enum StepResult {
     Error(String),
 }
 
-impl From<VMEvent> for StepResult {
-    fn from(event: VMEvent) -> Self {
+impl StepResult {
+    /// Convert a VM event, reading PUTS strings out of the VM's memory.
+    fn from_event(vm: &LC3, event: VMEvent) -> Self {
         match event {
             VMEvent::None => StepResult::None,
             VMEvent::Output(c) => StepResult::Output(c),
-            VMEvent::OutputString(s) => StepResult::OutputString(s),
+            VMEvent::OutputString(addr) => StepResult::OutputString(vm.string_at(addr).collect()),
             VMEvent::Halt => StepResult::Halt,
             VMEvent::ReadChar => StepResult::ReadChar,
             VMEvent::Error(e) => StepResult::Error(match e {
                 VMError::ReservedOpcode(op) => format!("Reserved opcode: {op:#06b}"),
                 VMError::UnimplementedTrap(vec) => format!("Unimplemented TRAP vector: {vec:#04x}"),
                 VMError::PrivilegeViolation => "Privilege violation: RTI in user mode".to_string(),
+                VMError::UnalignedAccess(addr) => format!("Unaligned word access at x{addr:04X}"),
             }),
         }
     }
@@ -47,6 +52,8 @@ impl From<VMEvent> for StepResult {
 #[wasm_bindgen]
 pub struct WasmLC3 {
     vm: LC3,
+    /// Source lines from loaded lc3tools `.obj` files, by address.
+    lines: HashMap<u16, String>,
 }
 
 #[wasm_bindgen]
@@ -54,13 +61,17 @@ impl WasmLC3 {
     /// Create a new LC-3 VM instance.
     #[wasm_bindgen(constructor)]
     pub fn new() -> Self {
-        Self { vm: LC3::default() }
+        Self {
+            vm: LC3::default(),
+            lines: HashMap::new(),
+        }
     }
 
     /// Reset the VM to its initial state.
     /// Uses in-place clearing to avoid memory allocation issues in WASM.
     pub fn reset(&mut self) {
         self.vm.clear();
+        self.lines.clear();
     }
 
     /// Load a program into memory at the specified origin.
@@ -75,7 +86,8 @@ impl WasmLC3 {
 
     /// Load a program from raw bytes.
     ///
-    /// Supports both lc3tools format (with magic header) and legacy format.
+    /// Supports lc3tools format (with magic header), legacy format and the
+    /// other formats of [`formats::Format`], detected from the contents.
     /// Legacy format: first 2 bytes are the origin (big-endian), followed by 2-byte words.
     pub fn load_bytes(&mut self, bytes: &[u8]) -> Result<(), JsError> {
         if bytes.len() < 2 {
@@ -86,6 +98,8 @@ impl WasmLC3 {
             // lc3tools format
             let entries = lc3tools_format::decode(bytes).map_err(|e| JsError::new(&e))?;
             let segments = lc3tools_format::entries_to_segments(&entries);
+            self.lines
+                .extend(lc3tools_format::entries_to_lines(&entries, 1));
 
             if segments.is_empty() {
                 return Err(JsError::new("No segments in program"));
@@ -101,17 +115,16 @@ impl WasmLC3 {
                 }
             }
         } else {
-            // Legacy big-endian format
-            if bytes.len() % 2 != 0 {
-                return Err(JsError::new("Program must have even number of bytes"));
-            }
-
-            let origin = u16::from_be_bytes([bytes[0], bytes[1]]);
-            self.vm.pc = origin;
-
-            for (i, chunk) in bytes[2..].chunks(2).enumerate() {
-                let word = u16::from_be_bytes([chunk[0], chunk[1]]);
-                self.vm.memory[origin as usize + i] = word;
+            // Legacy big-endian format, or one of the text formats
+            let segments = formats::decode(bytes).map_err(|e| JsError::new(&e))?;
+            let Some(first) = segments.first() else {
+                return Err(JsError::new("No segments in program"));
+            };
+            self.vm.pc = first.origin;
+            for seg in &segments {
+                for (i, &word) in seg.code.iter().enumerate() {
+                    self.vm.memory[seg.origin as usize + i] = word;
+                }
             }
         }
 
@@ -122,7 +135,8 @@ impl WasmLC3 {
     ///
     /// Returns a JavaScript object describing the result.
     pub fn step(&mut self) -> JsValue {
-        let step_result = StepResult::from(self.vm.step());
+        let event = self.vm.step();
+        let step_result = StepResult::from_event(&self.vm, event);
         serde_wasm_bindgen::to_value(&step_result).unwrap_or(JsValue::NULL)
     }
 
@@ -130,21 +144,17 @@ impl WasmLC3 {
     ///
     /// Returns a JavaScript object describing the result.
     pub fn run(&mut self) -> JsValue {
-        let step_result = StepResult::from(self.vm.run());
+        let event = self.vm.run();
+        let step_result = StepResult::from_event(&self.vm, event);
         serde_wasm_bindgen::to_value(&step_result).unwrap_or(JsValue::NULL)
     }
 
     /// Set the input character (for GETC/IN traps).
     ///
     /// Call this after receiving a `ReadChar` event, then continue execution.
+    /// In OS mode the character goes to the keyboard buffer, otherwise to R0.
     pub fn set_input(&mut self, c: u8) {
-        if self.vm.os_mode() {
-            // In OS mode, set keyboard buffer for MMIO
-            self.vm.set_keyboard_input(c);
-        } else {
-            // In shortcut mode, set R0 directly
-            self.vm.regs[0] = c as u16;
-        }
+        self.vm.set_input(c);
     }
 
     /// Enable or disable OS mode.
@@ -178,7 +188,7 @@ impl WasmLC3 {
     ///
     /// This is used to load the operating system before loading a user program.
     /// Unlike load_bytes, this does not change the PC.
-    /// Supports both lc3tools format (with magic header) and legacy format.
+    /// Supports the same formats as load_bytes.
     pub fn load_os_bytes(&mut self, bytes: &[u8]) -> Result<(), JsError> {
         if bytes.len() < 4 {
             return Err(JsError::new("OS image too short"));
@@ -188,6 +198,8 @@ impl WasmLC3 {
             // lc3tools format: explicit is_orig flags
             let entries = lc3tools_format::decode(bytes).map_err(|e| JsError::new(&e))?;
             let segments = lc3tools_format::entries_to_segments(&entries);
+            self.lines
+                .extend(lc3tools_format::entries_to_lines(&entries, 1));
 
             for seg in &segments {
                 for (i, &word) in seg.code.iter().enumerate() {
@@ -195,24 +207,23 @@ impl WasmLC3 {
                 }
             }
         } else {
-            // Legacy big-endian format (single segment only for safety)
-            if bytes.len() % 2 != 0 {
-                return Err(JsError::new("OS image must have even number of bytes"));
-            }
-
-            let origin = u16::from_be_bytes([bytes[0], bytes[1]]);
-            let mut addr = origin as usize;
-
-            for chunk in bytes[2..].chunks(2) {
-                let word = u16::from_be_bytes([chunk[0], chunk[1]]);
-                self.vm.memory[addr] = word;
-                addr += 1;
+            // Legacy big-endian format, or one of the text formats
+            let segments = formats::decode(bytes).map_err(|e| JsError::new(&e))?;
+            for seg in &segments {
+                for (i, &word) in seg.code.iter().enumerate() {
+                    self.vm.memory[seg.origin as usize + i] = word;
+                }
             }
         }
 
         Ok(())
     }
 
+    /// The source line stored for `addr` by a loaded lc3tools `.obj` file.
+    pub fn source_line(&self, addr: u16) -> Option<String> {
+        self.lines.get(&addr).cloned()
+    }
+
     /// Initialize MCR with clock running (bit 15 = 1).
     /// This should be called after loading the OS but before running.
     pub fn init_mcr(&mut self) {
@@ -307,6 +318,7 @@ pub struct AssemblyResult {
     pub code: Option<Vec<u16>>,
     pub origin: Option<u16>,
     pub error: Option<String>,
+    pub diagnostics: Vec<AssemblyDiagnostic>,
 }
 
 /// A single code segment with its origin address.
@@ -322,6 +334,49 @@ pub struct AssemblyResultWithSegments {
     pub success: bool,
     pub segments: Option<Vec<WasmSegment>>,
     pub error: Option<String>,
+    pub diagnostics: Vec<AssemblyDiagnostic>,
+}
+
+/// An assembler error or warning, with its stable code (e.g. `E0001`).
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssemblyDiagnostic {
+    pub code: String,
+    /// `"error"` or `"warning"`.
+    pub severity: String,
+    pub message: String,
+    pub location: AssemblyLocation,
+    /// The macro invocation, for diagnostics inside an expansion.
+    pub expanded_from: Option<AssemblyLocation>,
+    pub related: Vec<AssemblyRelated>,
+    pub suggestions: Vec<AssemblySuggestion>,
+}
+
+/// Where a diagnostic points; lines and columns are 1-based.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssemblyLocation {
+    /// The included file; `None` for the main source.
+    pub file: Option<String>,
+    pub start_line: usize,
+    pub start_column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+/// Another place that bears on a diagnostic.
+#[derive(Serialize, Deserialize)]
+pub struct AssemblyRelated {
+    pub message: String,
+    pub location: AssemblyLocation,
+}
+
+/// A fix for a diagnostic: replace the text at `location`.
+#[derive(Serialize, Deserialize)]
+pub struct AssemblySuggestion {
+    pub message: String,
+    pub replacement: String,
+    pub location: AssemblyLocation,
 }
 
 /// Assemble LC-3 source code into machine code.
@@ -331,22 +386,27 @@ pub struct AssemblyResultWithSegments {
 /// - `code`: array of 16-bit words (if successful)
 /// - `origin`: the origin address from .ORIG directive (if successful)
 /// - `error`: error message (if failed)
+/// - `diagnostics`: the warnings and errors, each with a `code`,
+///   `severity`, `message`, `location`, `expandedFrom`, `related` spans and
+///   `suggestions`
 #[wasm_bindgen]
 pub fn assemble(source: &str) -> JsValue {
     let mut asm = Assembler::new();
 
-    let result = match asm.assemble(source) {
+    let result = match asm.assemble_with_errors(source) {
         Ok(code) => AssemblyResult {
             success: true,
             code: Some(code),
             origin: Some(asm.origin()),
             error: None,
+            diagnostics: diagnostics(&asm, None),
         },
         Err(e) => AssemblyResult {
             success: false,
             code: None,
             origin: None,
-            error: Some(e),
+            error: Some(e.to_string()),
+            diagnostics: diagnostics(&asm, Some(&e)),
         },
     };
 
@@ -359,13 +419,79 @@ pub fn assemble(source: &str) -> JsValue {
 /// - `success`: boolean indicating success
 /// - `segments`: array of segments, each with `origin` and `code` (if successful)
 /// - `error`: error message (if failed)
+/// - `diagnostics`: the warnings and errors, as for `assemble`
 ///
 /// This function properly handles multiple `.ORIG` directives, loading each
 /// segment at its correct memory location.
 #[wasm_bindgen]
 pub fn assemble_with_segments(source: &str) -> JsValue {
     let mut asm = Assembler::new();
+    segments_result(&mut asm, source)
+}
+
+/// Assemble LC-3 source code that may `.INCLUDE` other files.
+///
+/// `name` is the path of the source, and `files` an object mapping the
+/// paths of the other files (e.g. `"lib/io.asm"`) to their text. Included
+/// paths are resolved relative to the including file. Returns the same
+/// object as `assemble_with_segments`; errors in included files are
+/// prefixed with the file path.
+#[wasm_bindgen]
+pub fn assemble_with_includes(source: &str, name: &str, files: JsValue) -> JsValue {
+    let files: HashMap<String, String> = serde_wasm_bindgen::from_value(files).unwrap_or_default();
+    let files = files
+        .into_iter()
+        .map(|(path, text)| (normalize_path(&path), text))
+        .collect::<HashMap<_, _>>();
 
+    let mut asm = Assembler::new();
+    asm.set_resolver(normalize_path(name), files);
+    segments_result(&mut asm, source)
+}
+
+/// Result of [`assemble_listing`].
+#[derive(Serialize, Deserialize)]
+pub struct ListingResult {
+    pub success: bool,
+    pub listing: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Assemble LC-3 source code and return its listing: the address, hex and
+/// binary word and source line of every emitted word, then the symbol table.
+///
+/// `name` and `files` are as for `assemble_with_includes`; `files` may be
+/// `undefined`. Returns an object with:
+/// - `success`: boolean indicating success
+/// - `listing`: the listing text (if successful)
+/// - `error`: error message (if failed)
+#[wasm_bindgen]
+pub fn assemble_listing(source: &str, name: &str, files: JsValue) -> JsValue {
+    let files: HashMap<String, String> = serde_wasm_bindgen::from_value(files).unwrap_or_default();
+    let files = files
+        .into_iter()
+        .map(|(path, text)| (normalize_path(&path), text))
+        .collect::<HashMap<_, _>>();
+
+    let mut asm = Assembler::new();
+    asm.set_resolver(normalize_path(name), files);
+    let result = match asm.assemble_segments(source) {
+        Ok(_) => ListingResult {
+            success: true,
+            listing: Some(asm.listing()),
+            error: None,
+        },
+        Err(e) => ListingResult {
+            success: false,
+            listing: None,
+            error: Some(e.to_string()),
+        },
+    };
+
+    serde_wasm_bindgen::to_value(&result).unwrap_or(JsValue::NULL)
+}
+
+fn segments_result(asm: &mut Assembler, source: &str) -> JsValue {
     let result = match asm.assemble_segments(source) {
         Ok(segments) => AssemblyResultWithSegments {
             success: true,
@@ -379,39 +505,74 @@ pub fn assemble_with_segments(source: &str) -> JsValue {
                     .collect(),
             ),
             error: None,
+            diagnostics: diagnostics(asm, None),
         },
         Err(e) => AssemblyResultWithSegments {
             success: false,
             segments: None,
             error: Some(e.to_string()),
+            diagnostics: diagnostics(asm, Some(&e)),
         },
     };
 
     serde_wasm_bindgen::to_value(&result).unwrap_or(JsValue::NULL)
 }
 
+/// The warnings of the last assembly and the errors of `error`.
+fn diagnostics(asm: &Assembler, error: Option<&AssemblyError>) -> Vec<AssemblyDiagnostic> {
+    let sources = asm.sources();
+    let location = |span: &Span| {
+        let (file, start_line, start_column) = sources.position(span.start);
+        let (_, end_line, end_column) = sources.position(span.end);
+        AssemblyLocation {
+            file: file.map(str::to_string),
+            start_line,
+            start_column,
+            end_line,
+            end_column,
+        }
+    };
+    let warnings = asm.warnings().iter().map(SemanticError::diagnostic);
+    let errors = error.map(AssemblyError::diagnostics).unwrap_or_default();
+    warnings
+        .chain(errors)
+        .map(|d| AssemblyDiagnostic {
+            code: d.code.to_string(),
+            severity: d.code.severity().as_str().into(),
+            message: d.message.to_string(),
+            location: location(d.span),
+            expanded_from: d.expanded_from.map(location),
+            related: d
+                .related
+                .iter()
+                .map(|related| AssemblyRelated {
+                    message: related.message.clone(),
+                    location: location(&related.span),
+                })
+                .collect(),
+            suggestions: d
+                .suggestions
+                .iter()
+                .map(|suggestion| AssemblySuggestion {
+                    message: suggestion.message.clone(),
+                    replacement: suggestion.replacement.clone(),
+                    location: location(&suggestion.span),
+                })
+                .collect(),
+        })
+        .collect()
+}
+
 /// Assemble LC-3 source code and return raw bytes in lc3tools format.
 ///
 /// This format properly supports multiple `.ORIG` directives.
-/// Returns bytes suitable for `load_bytes()`, or throws on error.
+/// Returns bytes suitable for `load_bytes()`, with the source line of every
+/// word, or throws on error.
 #[wasm_bindgen]
 pub fn assemble_to_bytes(source: &str) -> Result<Vec<u8>, JsError> {
     let mut asm = Assembler::new();
-
-    let segments = asm
-        .assemble_segments(source)
-        .map_err(|e| JsError::new(&e.to_string()))?;
-
-    // Convert to lc3_assembler::Segment type
-    let assembler_segments: Vec<lc3_assembler::Segment> = segments
-        .into_iter()
-        .map(|s| lc3_assembler::Segment {
-            origin: s.origin,
-            code: s.code,
-        })
-        .collect();
-
-    Ok(lc3tools_format::encode(&assembler_segments))
+    asm.assemble_to_lc3tools(source)
+        .map_err(|e| JsError::new(&e.to_string()))
 }
 
 /// Initialize the WASM module.
@@ -431,6 +592,7 @@ use lc3_analysis::AnalyzedDocument;
 ///
 /// Returns an array of diagnostic objects with:
 /// - message: string
+/// - code: string, e.g. "E0001"
 /// - severity: "error" | "warning" | "info" | "hint"
 /// - startLineNumber: number (1-based)
 /// - startColumn: number (1-based)
@@ -445,6 +607,7 @@ pub fn analyze_diagnostics(source: &str) -> JsValue {
         .into_iter()
         .map(|d| MonacoDiagnostic {
             message: d.message,
+            code: d.code,
             severity: match d.severity {
                 lc3_analysis::Severity::Error => "error",
                 lc3_analysis::Severity::Warning => "warning",
@@ -563,8 +726,9 @@ pub fn analyze_completions(source: &str, line: u32, column: u32) -> JsValue {
 ///
 /// Returns an array of symbol objects with:
 /// - name: string
-/// - kind: "label" | "subroutine" | "data"
-/// - address: hex string (e.g., "x3000")
+/// - kind: "label" | "subroutine" | "data" | "constant" | "external"
+/// - address: hex string (e.g., "x3000"), absent for constants and externals
+/// - value: number, for constants
 /// - range: { startLineNumber, startColumn, endLineNumber, endColumn }
 #[wasm_bindgen]
 pub fn analyze_symbols(source: &str) -> JsValue {
@@ -579,8 +743,11 @@ pub fn analyze_symbols(source: &str) -> JsValue {
                 lc3_analysis::SymbolKind::Label => "label",
                 lc3_analysis::SymbolKind::Subroutine => "subroutine",
                 lc3_analysis::SymbolKind::Data => "data",
+                lc3_analysis::SymbolKind::Constant => "constant",
+                lc3_analysis::SymbolKind::External => "external",
             },
             address: s.address.map(|a| format!("x{:04X}", a)),
+            value: s.value,
             range: MonacoRange {
                 start_line_number: s.location.start_line,
                 start_column: s.location.start_col,
@@ -635,6 +802,7 @@ pub fn analyze_tokens(source: &str) -> JsValue {
 #[serde(rename_all = "camelCase")]
 struct MonacoDiagnostic {
     message: String,
+    code: String,
     severity: &'static str,
     start_line_number: u32,
     start_column: u32,
@@ -672,6 +840,7 @@ struct MonacoSymbol {
     name: String,
     kind: &'static str,
     address: Option<String>,
+    value: Option<i32>,
     range: MonacoRange,
 }
 
@@ -727,6 +896,15 @@ pub fn disassemble_with_symbols(instr: u16, pc: u16, symbols: JsValue) -> String
     }
 }
 
+/// Parse a `.sym` symbol file, as written by `lc3 assemble` or `lc3as`.
+///
+/// # Returns
+/// Map from addresses (as numbers) to label names
+#[wasm_bindgen]
+pub fn parse_symbol_file(text: &str) -> JsValue {
+    serde_wasm_bindgen::to_value(&lc3_disasm::sym::parse(text)).unwrap_or(JsValue::NULL)
+}
+
 /// Disassemble a range of memory.
 ///
 /// # Arguments
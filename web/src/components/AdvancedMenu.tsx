@@ -1,13 +1,14 @@
 import { useStore } from '@tanstack/react-store'
 import { useCallback, useRef } from 'react'
 import { toast } from 'sonner'
-import { Settings, ChevronDown, Check, Upload, Cpu } from 'lucide-react'
+import { Settings, ChevronDown, Check, Upload, Cpu, Tags } from 'lucide-react'
 import {
   osStore,
   setOSType,
   setCustomOS,
   type OSType,
 } from '@/lib/os-store'
+import { loadSymbols } from '@/lib/lc3-store'
 import {
   DropdownMenu,
   DropdownMenuContent,
@@ -22,6 +23,7 @@ export function AdvancedMenu() {
   const osType = useStore(osStore, (s) => s.osType)
   const customOSName = useStore(osStore, (s) => s.customOSName)
   const fileInputRef = useRef<HTMLInputElement>(null)
+  const symbolInputRef = useRef<HTMLInputElement>(null)
 
   const handleOSSelect = useCallback((type: OSType) => {
     setOSType(type)
@@ -62,8 +64,36 @@ export function AdvancedMenu() {
     e.target.value = ''
   }, [])
 
+  const handleSymbolFileChange = useCallback((e: React.ChangeEvent<HTMLInputElement>) => {
+    const file = e.target.files?.[0]
+    if (!file) return
+
+    const reader = new FileReader()
+    reader.onload = () => {
+      const count = loadSymbols(reader.result as string)
+      if (count === 0) {
+        toast.error('No symbols found', { description: file.name })
+        return
+      }
+      toast.success(`Loaded ${count} symbols from ${file.name}`)
+    }
+    reader.onerror = () => {
+      toast.error('Failed to read file')
+    }
+    reader.readAsText(file)
+
+    e.target.value = ''
+  }, [])
+
   return (
     <>
+      <input
+        ref={symbolInputRef}
+        type="file"
+        accept=".sym"
+        onChange={handleSymbolFileChange}
+        className="hidden"
+      />
       <input
         ref={fileInputRef}
         type="file"
@@ -124,6 +154,14 @@ export function AdvancedMenu() {
               {osType === 'custom' && <Check className="h-4 w-4 text-green-500" />}
             </div>
           </DropdownMenuItem>
+
+          <DropdownMenuSeparator />
+          <DropdownMenuItem onClick={() => symbolInputRef.current?.click()}>
+            <div className="flex items-center gap-2 font-medium">
+              <Tags className="h-3 w-3" />
+              Load Symbols (.sym)...
+            </div>
+          </DropdownMenuItem>
         </DropdownMenuContent>
       </DropdownMenu>
     </>
@@ -21,7 +21,7 @@ const LC3_MONARCH_TOKENS: Monaco.languages.IMonarchLanguage = {
     'ST', 'STI', 'STR', 'TRAP', 'GETC', 'OUT', 'PUTS', 'IN', 'PUTSP', 'HALT',
   ],
 
-  directives: ['.ORIG', '.END', '.FILL', '.BLKW', '.STRINGZ', '.EXTERNAL', '.GLOBAL'],
+  directives: ['.ORIG', '.END', '.FILL', '.BLKW', '.STRINGZ', '.STRINGP', '.EXTERNAL', '.GLOBAL', '.IF', '.IFDEF', '.IFNDEF', '.ELSEIF', '.ELSE', '.ENDIF'],
 
   registers: ['R0', 'R1', 'R2', 'R3', 'R4', 'R5', 'R6', 'R7'],
 
@@ -34,8 +34,8 @@ const LC3_MONARCH_TOKENS: Monaco.languages.IMonarchLanguage = {
       [/"([^"\\]|\\.)*$/, 'string.invalid'], // unterminated string
       [/"/, 'string', '@string'],
 
-      // Directives
-      [/\.[a-zA-Z]+/, {
+      // Directives, and local labels such as .loop
+      [/\.[a-zA-Z_][a-zA-Z0-9_]*/, {
         cases: {
           '@directives': 'keyword.directive',
           '@default': 'identifier',
@@ -46,7 +46,13 @@ const LC3_MONARCH_TOKENS: Monaco.languages.IMonarchLanguage = {
       [/[rR][0-7]/, 'variable.register'],
 
       // Hex numbers
-      [/[xX][0-9a-fA-F]+/, 'number.hex'],
+      [/0?[xX][0-9a-fA-F]+/, 'number.hex'],
+
+      // Binary numbers
+      [/0?[bB][01]+\b/, 'number.binary'],
+
+      // Character literals
+      [/'(\\.|[^\\'])'/, 'number.char'],
 
       // Decimal numbers
       [/#?-?[0-9]+/, 'number'],
@@ -213,6 +219,7 @@ export function LC3Editor() {
             ? monacoRef.current!.MarkerSeverity.Warning
             : monacoRef.current!.MarkerSeverity.Info,
       message: d.message,
+      code: d.code,
       startLineNumber: d.startLineNumber,
       startColumn: d.startColumn,
       endLineNumber: d.endLineNumber,
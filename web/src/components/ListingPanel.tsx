@@ -0,0 +1,33 @@
+import { useStore } from '@tanstack/react-store'
+import { FileText } from 'lucide-react'
+import { lc3Store } from '@/lib/lc3-store'
+import { Card, CardContent, CardHeader, CardTitle } from '@/components/ui/card'
+import { ScrollArea } from '@/components/ui/scroll-area'
+
+export function ListingPanel() {
+  const listing = useStore(lc3Store, (s) => s.listing)
+
+  return (
+    <Card className="flex h-full flex-col border-zinc-800 bg-zinc-950/50 backdrop-blur">
+      <CardHeader className="flex-shrink-0 pb-2">
+        <div className="flex items-center gap-2">
+          <FileText className="h-4 w-4 text-zinc-500" />
+          <CardTitle className="text-sm font-medium text-zinc-300">
+            Listing
+          </CardTitle>
+        </div>
+      </CardHeader>
+      <CardContent className="flex flex-1 flex-col overflow-hidden pt-0">
+        <ScrollArea className="flex-1 rounded-md bg-black/50 p-3">
+          <pre className="min-h-full font-mono text-xs text-zinc-300">
+            {listing || (
+              <span className="text-zinc-600 italic">
+                Assemble a program to see its listing...
+              </span>
+            )}
+          </pre>
+        </ScrollArea>
+      </CardContent>
+    </Card>
+  )
+}
@@ -1,7 +1,7 @@
 import { useStore } from '@tanstack/react-store'
 import { useState, useCallback, useEffect } from 'react'
 import { Database, ChevronUp, ChevronDown } from 'lucide-react'
-import { lc3Store, getMemory, disassembleMemory } from '@/lib/lc3-store'
+import { lc3Store, getMemory, disassembleMemory, getSourceLine } from '@/lib/lc3-store'
 import { Card, CardContent, CardHeader, CardTitle } from '@/components/ui/card'
 import { Button } from '@/components/ui/button'
 import { ScrollArea } from '@/components/ui/scroll-area'
@@ -205,7 +205,7 @@ export function MemoryPanel() {
                       isData ? 'italic text-zinc-400' : 'text-cyan-400',
                       isPC && (isData ? 'text-blue-200' : 'text-cyan-300')
                     )}
-                    title={instruction}
+                    title={getSourceLine(addr)?.trim() ?? instruction}
                   >
                     {displayValue}
                   </span>
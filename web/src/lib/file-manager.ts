@@ -13,8 +13,15 @@ import {
   updateFile,
   deleteFile,
   getFile,
+  getAllFiles,
 } from './file-storage'
-import { lc3Store, updateSourceCode, setOnSourceCodeChange } from './lc3-store'
+import {
+  lc3Store,
+  updateSourceCode,
+  setOnSourceCodeChange,
+  setIncludeFilesProvider,
+  type IncludeFiles,
+} from './lc3-store'
 
 // File System Access API types
 declare global {
@@ -106,9 +113,38 @@ export async function initFileManager() {
     fileManagerStore.setState((s) => ({ ...s, hasUnsavedChanges: true }))
   })
 
+  // Resolve `.INCLUDE` against the open folder or browser storage
+  setIncludeFilesProvider(collectIncludeFiles)
+
   fileManagerStore.setState((s) => ({ ...s, isLoading: false }))
 }
 
+/**
+ * Collect the files that `.INCLUDE` may refer to: the open folder's files by
+ * path, or else the files in browser storage by name
+ */
+async function collectIncludeFiles(): Promise<IncludeFiles> {
+  const { openFolder, currentFilePath, currentFileName, currentFileId } = fileManagerStore.state
+  const files: Record<string, string> = {}
+
+  if (openFolder) {
+    for (const file of openFolder.files) {
+      if (file.path === currentFilePath) continue
+      try {
+        files[file.path] = await (await file.handle.getFile()).text()
+      } catch (err) {
+        console.error(`Failed to read ${file.path}:`, err)
+      }
+    }
+  } else {
+    for (const file of getAllFiles()) {
+      if (file.id !== currentFileId) files[file.name] = file.content
+    }
+  }
+
+  return { name: currentFilePath ?? currentFileName, files }
+}
+
 /**
  * Load shared code from URL parameter
  */
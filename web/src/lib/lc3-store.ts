@@ -9,6 +9,7 @@ export interface StepResult {
 
 export interface Diagnostic {
   message: string
+  code?: string
   severity: 'error' | 'warning' | 'info' | 'hint'
   startLineNumber: number
   startColumn: number
@@ -16,6 +17,20 @@ export interface Diagnostic {
   endColumn: number
 }
 
+// An error or warning from the assembler, as returned by the WASM assemble functions
+export interface AssemblyDiagnostic {
+  code: string
+  severity: 'error' | 'warning'
+  message: string
+  location: {
+    file: string | null
+    startLine: number
+    startColumn: number
+    endLine: number
+    endColumn: number
+  }
+}
+
 export interface LC3State {
   // Editor state
   sourceCode: string
@@ -57,6 +72,9 @@ export interface LC3State {
   // Symbol table (address -> label name)
   symbolTable: Map<number, string>
 
+  // Listing of the last assembly (address, word, source line)
+  listing: string
+
   // WASM initialization state
   wasmReady: boolean
 }
@@ -98,6 +116,7 @@ export const lc3Store = new Store<LC3State>({
   pcToLine: new Map(),
   lineToPC: new Map(),
   symbolTable: new Map(),
+  listing: '',
   wasmReady: false,
 })
 
@@ -162,6 +181,20 @@ export async function initWasm() {
   return initPromise
 }
 
+// Other files of the project, for resolving `.INCLUDE` (set by file manager)
+export interface IncludeFiles {
+  // Path of the file being assembled
+  name: string
+  // Path -> text of every other file
+  files: Record<string, string>
+}
+
+let includeFilesProvider: (() => Promise<IncludeFiles>) | null = null
+
+export function setIncludeFilesProvider(provider: (() => Promise<IncludeFiles>) | null) {
+  includeFilesProvider = provider
+}
+
 // Callback for when source code changes (used by file manager)
 let onSourceCodeChangeCallback: (() => void) | null = null
 
@@ -191,20 +224,42 @@ export async function assemble(): Promise<boolean> {
   if (!wasmModule || !vm) return false
 
   const state = lc3Store.state
-  const result = wasmModule.assemble_with_segments(state.sourceCode) as {
+  const includes = includeFilesProvider ? await includeFilesProvider() : null
+  const result = (includes
+    ? wasmModule.assemble_with_includes(state.sourceCode, includes.name, includes.files)
+    : wasmModule.assemble_with_segments(state.sourceCode)) as {
     success: boolean
     segments?: Array<{ origin: number; code: number[] }>
     error?: string
+    diagnostics: AssemblyDiagnostic[]
   }
 
+  // Show the errors and warnings in the main file on top of the editor's own analysis
+  const markers: Diagnostic[] = result.diagnostics
+    .filter((d) => d.location.file == null)
+    .map((d) => ({
+      message: d.message,
+      code: d.code,
+      severity: d.severity,
+      startLineNumber: d.location.startLine,
+      startColumn: d.location.startColumn,
+      endLineNumber: d.location.endLine,
+      endColumn: d.location.endColumn,
+    }))
+
   if (!result.success || !result.segments || result.segments.length === 0) {
     lc3Store.setState((s) => ({
       ...s,
+      diagnostics: [...s.diagnostics, ...markers],
       consoleOutput: s.consoleOutput + `Assembly error: ${result.error}\n`,
     }))
     return false
   }
 
+  if (markers.length > 0) {
+    lc3Store.setState((s) => ({ ...s, diagnostics: [...s.diagnostics, ...markers] }))
+  }
+
   // First segment's origin is the main program origin
   const origin = result.segments[0].origin
 
@@ -288,6 +343,13 @@ export async function assemble(): Promise<boolean> {
     }
   }
 
+  const listingResult = wasmModule.assemble_listing(
+    state.sourceCode,
+    includes?.name ?? '',
+    includes?.files
+  ) as { success: boolean; listing?: string }
+  const listing = listingResult.listing ?? ''
+
   // Build message about loaded segments
   const segmentInfo = result.segments
     .map((seg) => `x${seg.origin.toString(16).toUpperCase()}`)
@@ -315,6 +377,7 @@ export async function assemble(): Promise<boolean> {
     pcToLine,
     lineToPC,
     symbolTable,
+    listing,
   }))
 
   return true
@@ -642,6 +705,22 @@ function stopAutoRun() {
   wasInstantMode = false
 }
 
+// Add labels from a .sym file to the symbol table, for the disassembly.
+// Returns how many symbols were read.
+export function loadSymbols(text: string): number {
+  if (!wasmModule) return 0
+  const symbols = wasmModule.parse_symbol_file(text) as Map<number, string> | null
+  if (!symbols) return 0
+  lc3Store.setState((s) => {
+    const symbolTable = new Map(s.symbolTable)
+    for (const [addr, name] of symbols) {
+      symbolTable.set(Number(addr), name)
+    }
+    return { ...s, symbolTable }
+  })
+  return symbols.size
+}
+
 // Memory access
 export function getMemory(start: number, length: number): number[] {
   if (!vm) return []
@@ -653,6 +732,11 @@ export function getMemoryValue(addr: number): number {
   return vm.mem(addr)
 }
 
+// Source line stored for an address by a loaded lc3tools .obj file (e.g. the OS)
+export function getSourceLine(addr: number): string | undefined {
+  return vm?.source_line(addr) ?? undefined
+}
+
 // Disassemble memory range
 export function disassembleMemory(start: number, length: number): string[] {
   if (!wasmModule || !vm) return []
@@ -1,5 +1,5 @@
 import { createFileRoute, useNavigate, Link } from '@tanstack/react-router'
-import { useEffect } from 'react'
+import { useEffect, useState } from 'react'
 import { useStore } from '@tanstack/react-store'
 import {
   Panel,
@@ -14,12 +14,14 @@ import { RegistersPanel } from '@/components/RegistersPanel'
 import { ControlPanel } from '@/components/ControlPanel'
 import { ConsolePanel } from '@/components/ConsolePanel'
 import { MemoryPanel } from '@/components/MemoryPanel'
+import { ListingPanel } from '@/components/ListingPanel'
 import { FileToolbar } from '@/components/FileToolbar'
 import { FileBrowser } from '@/components/FileBrowser'
 import { AdvancedMenu } from '@/components/AdvancedMenu'
 import { initWasm } from '@/lib/lc3-store'
 import { initFileManager, saveCurrentFile, fileManagerStore, toggleSidebar, loadSharedCode } from '@/lib/file-manager'
 import { Button } from '@/components/ui/button'
+import { cn } from '@/lib/utils'
 
 export const Route = createFileRoute('/')({
   validateSearch: (search: Record<string, unknown>) => {
@@ -34,6 +36,7 @@ function IDE() {
   const isSidebarOpen = useStore(fileManagerStore, (s) => s.isSidebarOpen)
   const { code } = Route.useSearch()
   const navigate = useNavigate()
+  const [memoryTab, setMemoryTab] = useState<'memory' | 'listing'>('memory')
 
   // Initialize WASM and file manager on mount
   useEffect(() => {
@@ -201,9 +204,29 @@ function IDE() {
 
                   <PanelResizeHandle className="mx-1" />
 
-                  {/* Memory */}
+                  {/* Memory / Listing */}
                   <Panel defaultSize={55} minSize={30}>
-                    <MemoryPanel />
+                    <div className="flex h-full flex-col gap-1">
+                      <div className="flex gap-1 px-1">
+                        {(['memory', 'listing'] as const).map((tab) => (
+                          <button
+                            key={tab}
+                            onClick={() => setMemoryTab(tab)}
+                            className={cn(
+                              'rounded px-2 py-0.5 text-xs capitalize transition-colors',
+                              memoryTab === tab
+                                ? 'bg-zinc-800 text-zinc-200'
+                                : 'text-zinc-500 hover:text-zinc-300'
+                            )}
+                          >
+                            {tab}
+                          </button>
+                        ))}
+                      </div>
+                      <div className="flex-1 overflow-hidden">
+                        {memoryTab === 'memory' ? <MemoryPanel /> : <ListingPanel />}
+                      </div>
+                    </div>
                   </Panel>
                 </PanelGroup>
               </Panel>